@@ -0,0 +1,328 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::{error, info};
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+pub const DEFAULT_USAGE_FILE: &str = "rdnat-usage.csv";
+pub const DEFAULT_FLUSH_SECS: u64 = 60;
+pub const ANONYMOUS_USER: &str = "-";
+
+const CSV_HEADER: &str = "date,user,bytes_sent,bytes_received";
+
+type UsageKey = (String, String);
+type UsageMap = BTreeMap<UsageKey, (u64, u64)>;
+
+/*************************************************
+ * Accounting
+ *************************************************/
+
+pub struct Accounting {
+    path: String,
+    pending: Mutex<UsageMap>,
+    /// Bytes each user may transfer per day, both directions together.
+    quota: Option<u64>,
+    /// The day and what each user has transferred on it, file included,
+    /// kept only with a quota.
+    used: Mutex<(String, HashMap<String, u64>)>,
+}
+
+impl Accounting {
+    /// With a `quota`, today's usage already in the file counts towards it.
+    pub fn new(path: &str, quota: Option<u64>) -> Self {
+        let today = today();
+        let mut used = HashMap::new();
+        if quota.is_some() {
+            match load_usage(path) {
+                Ok(usage) => {
+                    for ((date, user), (sent, received)) in usage {
+                        if date == today {
+                            used.insert(user, sent + received);
+                        }
+                    }
+                }
+                Err(e) => error!("Failed to load usage from {}, quotas start from zero: {}", path, e),
+            }
+        }
+        Accounting {
+            path: path.to_string(),
+            pending: Mutex::new(BTreeMap::new()),
+            quota,
+            used: Mutex::new((today, used)),
+        }
+    }
+
+    /// Count a transfer of `user`. Returns the quota if this transfer used
+    /// it up.
+    pub fn record(&self, user: &str, sent: u64, received: u64) -> Option<u64> {
+        if sent == 0 && received == 0 {
+            return None;
+        }
+        let today = today();
+        {
+            let mut pending = self.pending.lock().unwrap();
+            let entry = pending.entry((today.clone(), user.to_string())).or_insert((0, 0));
+            entry.0 += sent;
+            entry.1 += received;
+        }
+        let quota = self.quota?;
+        let mut used = self.used.lock().unwrap();
+        if used.0 != today {
+            *used = (today, HashMap::new());
+        }
+        let total = used.1.entry(user.to_string()).or_insert(0);
+        let before = *total;
+        *total += sent + received;
+        (before < quota && *total >= quota).then_some(quota)
+    }
+
+    /// Whether `user` has used up today's quota.
+    pub fn exhausted(&self, user: &str) -> bool {
+        let Some(quota) = self.quota else {
+            return false;
+        };
+        let used = self.used.lock().unwrap();
+        used.0 == today() && used.1.get(user).is_some_and(|total| *total >= quota)
+    }
+
+    /// Merge the counters collected since the last flush into the usage file.
+    pub fn flush(&self) -> Result<(), Box<dyn Error>> {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let mut usage = match load_usage(&self.path) {
+            Ok(usage) => usage,
+            Err(e) => {
+                self.restore(pending);
+                return Err(e);
+            }
+        };
+        for (key, (sent, received)) in &pending {
+            let entry = usage.entry(key.clone()).or_insert((0, 0));
+            entry.0 += sent;
+            entry.1 += received;
+        }
+
+        if let Err(e) = store_usage(&self.path, &usage) {
+            self.restore(pending);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn restore(&self, counters: UsageMap) {
+        let mut pending = self.pending.lock().unwrap();
+        for (key, (sent, received)) in counters {
+            let entry = pending.entry(key).or_insert((0, 0));
+            entry.0 += sent;
+            entry.1 += received;
+        }
+    }
+
+    pub fn spawn_flusher(self: Arc<Self>, interval: u64) {
+        info!("Usage accounting enabled, flushing to {}", self.path);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(interval.max(1)));
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                if let Err(e) = self.flush() {
+                    error!("Failed to flush usage to {}: {}", self.path, e);
+                }
+            }
+        });
+    }
+}
+
+/*************************************************
+ * load_usage
+ *************************************************/
+
+fn load_usage(path: &str) -> Result<UsageMap, Box<dyn Error>> {
+    let mut usage = BTreeMap::new();
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(usage),
+        Err(e) => return Err(e.into()),
+    };
+
+    for fields in parse_csv(&content).into_iter().skip(1) {
+        let Ok([date, user, sent, received]) = <[String; 4]>::try_from(fields) else {
+            continue;
+        };
+        usage.insert((date, user), (sent.parse()?, received.parse()?));
+    }
+    Ok(usage)
+}
+
+/// Split CSV into records of fields, unquoting fields per RFC 4180, so a
+/// username may hold commas, quotes or line breaks.
+fn parse_csv(content: &str) -> Vec<Vec<String>> {
+    let mut records = Vec::new();
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = content.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (true, c) => field.push(c),
+            (false, '"') if field.is_empty() => quoted = true,
+            (false, ',') => fields.push(std::mem::take(&mut field)),
+            (false, '\r') if chars.peek() == Some(&'\n') => {}
+            (false, '\n') => {
+                fields.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut fields));
+            }
+            (false, c) => field.push(c),
+        }
+    }
+    if !field.is_empty() || !fields.is_empty() {
+        fields.push(field);
+        records.push(fields);
+    }
+    records
+}
+
+/*************************************************
+ * store_usage
+ *************************************************/
+
+fn store_usage(path: &str, usage: &UsageMap) -> Result<(), Box<dyn Error>> {
+    let tmp_path = format!("{}.tmp", path);
+    std::fs::write(&tmp_path, format_csv(usage.iter()))?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+fn format_csv<'a>(rows: impl Iterator<Item = (&'a UsageKey, &'a (u64, u64))>) -> String {
+    let mut csv = format!("{}\n", CSV_HEADER);
+    for ((date, user), (sent, received)) in rows {
+        csv.push_str(&format!("{},{},{},{}\n", date, quote_field(user), sent, received));
+    }
+    csv
+}
+
+/// Quote `field` per RFC 4180 if it holds a comma, a quote or a line break.
+fn quote_field(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/*************************************************
+ * export_usage
+ *************************************************/
+
+/// Handle `rdnat usage [options]`: print the per-user/day usage as CSV.
+pub fn export_usage(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut path = String::from(DEFAULT_USAGE_FILE);
+    let mut user: Option<String> = None;
+    let mut since: Option<String> = None;
+    let mut until: Option<String> = None;
+
+    let mut i = 0;
+    while i < args.len() {
+        let value = args.get(i + 1).cloned();
+        match (args[i].as_str(), value) {
+            ("-f" | "--file", Some(value)) => path = value,
+            ("-u" | "--user", Some(value)) => user = Some(value),
+            ("--since", Some(value)) => since = Some(value),
+            ("--until", Some(value)) => until = Some(value),
+            (arg, _) => return Err(format!("Error: Invalid usage argument: {}", arg).into()),
+        }
+        i += 2;
+    }
+
+    let usage = load_usage(&path)?;
+    let rows = usage.iter().filter(|((date, name), _)| {
+        user.as_ref().is_none_or(|user| user == name)
+            && since.as_ref().is_none_or(|since| date >= since)
+            && until.as_ref().is_none_or(|until| date <= until)
+    });
+    print!("{}", format_csv(rows));
+    Ok(())
+}
+
+/*************************************************
+ * today
+ *************************************************/
+
+/// Current UTC date as `YYYY-MM-DD`.
+pub fn today() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_only_fields_that_need_it() {
+        assert_eq!(quote_field("alice"), "alice");
+        assert_eq!(quote_field("a,b"), "\"a,b\"");
+        assert_eq!(quote_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(quote_field("two\nlines"), "\"two\nlines\"");
+    }
+
+    #[test]
+    fn quotas_trip_once_and_count_both_directions() {
+        let accounting = Accounting::new("/nonexistent/rdnat-usage.csv", Some(1000));
+        assert_eq!(accounting.record("alice", 400, 500), None);
+        assert!(!accounting.exhausted("alice"));
+        assert_eq!(accounting.record("alice", 0, 100), Some(1000));
+        assert!(accounting.exhausted("alice"));
+        assert_eq!(accounting.record("alice", 10, 10), None);
+        assert!(!accounting.exhausted("bob"));
+
+        let unlimited = Accounting::new("/nonexistent/rdnat-usage.csv", None);
+        assert_eq!(unlimited.record("alice", u32::MAX as u64, 0), None);
+        assert!(!unlimited.exhausted("alice"));
+    }
+
+    #[test]
+    fn usernames_survive_a_round_trip() {
+        let mut usage = UsageMap::new();
+        for (i, user) in ["alice", "a,b", "\"quoted\"", "two\r\nlines", ANONYMOUS_USER].into_iter().enumerate() {
+            usage.insert((String::from("2024-01-02"), user.to_string()), (i as u64, 10 * i as u64));
+        }
+        let csv = format_csv(usage.iter());
+        let records = parse_csv(&csv);
+        assert_eq!(records[0], CSV_HEADER.split(',').collect::<Vec<_>>());
+        assert_eq!(records.len(), usage.len() + 1);
+        for (record, ((date, user), (sent, received))) in records[1..].iter().zip(&usage) {
+            assert_eq!(record, &[date.clone(), user.clone(), sent.to_string(), received.to_string()]);
+        }
+    }
+}
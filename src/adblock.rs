@@ -0,0 +1,94 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use std::error::Error;
+use crate::domains::DomainList;
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+/// Rule options that still mean "block the whole host" to a proxy.
+const HOST_WIDE_OPTIONS: [&str; 3] = ["all", "document", "important"];
+
+/*************************************************
+ * FilterList
+ *************************************************/
+
+/// Host-level rules from EasyList-style filter files. A proxy only sees
+/// hosts for tunneled traffic, so `||host^` block rules and `@@||host^`
+/// exceptions are compiled into domain sets; cosmetic, path and
+/// context-dependent rules (`$third-party`, `$script`, ...) are skipped.
+#[derive(Default)]
+pub struct FilterList {
+    blocked: DomainList,
+    exceptions: DomainList,
+    rules: usize,
+}
+
+impl FilterList {
+    /// Load a filter file; hosts-file lines (`0.0.0.0 host`) are accepted too.
+    pub fn load(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Error: Cannot read filter list {}: {}", path, e))?;
+        self.parse(&content);
+        Ok(())
+    }
+
+    pub fn parse(&mut self, content: &str) {
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('!') || line.starts_with('[') {
+                continue;
+            }
+            if let Some(host) = hosts_entry(line) {
+                self.blocked.add(&format!(".{}", host));
+                self.rules += 1;
+                continue;
+            }
+            let (exception, rule) = match line.strip_prefix("@@") {
+                Some(rule) => (true, rule),
+                None => (false, line),
+            };
+            // Cosmetic rules (`##`, `#@#`) never parse as host rules.
+            if let Some(host) = host_rule(rule) {
+                let list = if exception { &mut self.exceptions } else { &mut self.blocked };
+                list.add(&format!(".{}", host));
+                self.rules += 1;
+            }
+        }
+    }
+
+    /// Number of rules compiled so far.
+    pub fn rule_count(&self) -> usize {
+        self.rules
+    }
+
+    pub fn blocks(&self, host: &str) -> bool {
+        self.blocked.contains(host) && !self.exceptions.contains(host)
+    }
+}
+
+/// The host of a `||host^` rule, if the rule applies to the whole host.
+fn host_rule(rule: &str) -> Option<&str> {
+    let rule = rule.strip_prefix("||")?;
+    let (pattern, options) = rule.split_once('$').unwrap_or((rule, ""));
+    if !options.is_empty() && !options.split(',').all(|option| HOST_WIDE_OPTIONS.contains(&option)) {
+        return None;
+    }
+    let end = pattern.find(['^', '/', '|', '*']).unwrap_or(pattern.len());
+    let (host, rest) = pattern.split_at(end);
+    let host_wide = matches!(rest, "" | "^" | "^|" | "/" | "/^");
+    let valid = !host.is_empty() && host.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_'));
+    (host_wide && valid).then_some(host)
+}
+
+/// The host of a hosts-file line such as `0.0.0.0 ads.example.com`.
+fn hosts_entry(line: &str) -> Option<&str> {
+    let line = line.split('#').next().unwrap_or_default();
+    match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+        ["0.0.0.0" | "127.0.0.1" | "::" | "::1", host] if *host != "localhost" && host.contains('.') => Some(host),
+        _ => None,
+    }
+}
@@ -0,0 +1,242 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use hyper::Uri;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::probe::{millis, read_body, read_head};
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+const DEFAULT_PROXY: &str = "127.0.0.1:8000";
+const DEFAULT_CONCURRENCY: usize = 10;
+const DEFAULT_REQUESTS: u64 = 1000;
+/// Longest a single exchange may take before it counts as failed.
+const EXCHANGE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/*************************************************
+ * Bench
+ *************************************************/
+
+/// How each exchange goes through the proxy: a `GET` for the absolute URL,
+/// or a `CONNECT` tunnel with the `GET` sent through it.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+    Get,
+    Connect,
+}
+
+struct Bench {
+    proxy: String,
+    mode: Mode,
+    /// `host:port` of the target.
+    authority: String,
+    /// Request head written to the proxy (GET) or into the tunnel (CONNECT).
+    request: Vec<u8>,
+    proxy_authorization: Option<String>,
+    requests: u64,
+    duration: Option<Duration>,
+}
+
+/// What one worker measured.
+#[derive(Default)]
+struct Results {
+    latencies: Vec<Duration>,
+    errors: u64,
+    bytes: u64,
+    first_error: Option<String>,
+}
+
+/*************************************************
+ * run
+ *************************************************/
+
+/// Handle `rdnat bench [-x <proxy>] [--mode get|connect] [-c <n>] [-n <n> | -d <secs>]
+/// [-a <user> <passwd>] <url>`: drive concurrent workloads through a proxy
+/// and report the connection rate, throughput and latency percentiles.
+/// Every exchange uses a new connection, so the rate is one of connections
+/// set up through the proxy.
+pub async fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut proxy = String::from(DEFAULT_PROXY);
+    let mut mode = Mode::Get;
+    let mut concurrency = DEFAULT_CONCURRENCY;
+    let mut requests = DEFAULT_REQUESTS;
+    let mut duration = None;
+    let mut credentials = None;
+    let mut url = None;
+
+    let mut i = 0;
+    while i < args.len() {
+        let value = args.get(i + 1).cloned();
+        match (args[i].as_str(), value) {
+            ("-x" | "--proxy", Some(value)) => proxy = value,
+            ("--mode", Some(value)) => {
+                mode = match value.as_str() {
+                    "get" => Mode::Get,
+                    "connect" => Mode::Connect,
+                    _ => return Err(format!("Error: Invalid bench mode: {}", value).into()),
+                }
+            }
+            ("-c" | "--concurrency", Some(value)) => concurrency = value.parse()?,
+            ("-n" | "--requests", Some(value)) => requests = value.parse()?,
+            ("-d" | "--duration", Some(value)) => duration = Some(Duration::from_secs(value.parse()?)),
+            ("-a" | "--auth", Some(user)) => {
+                let passwd = args.get(i + 2).ok_or("Error: Missing password for --auth")?;
+                credentials = Some(format!("{}:{}", user, passwd));
+                i += 3;
+                continue;
+            }
+            (arg, _) if !arg.starts_with('-') && url.is_none() => {
+                url = Some(arg.to_string());
+                i += 1;
+                continue;
+            }
+            (arg, _) => return Err(format!("Error: Invalid bench argument: {}", arg).into()),
+        }
+        i += 2;
+    }
+    if concurrency == 0 {
+        return Err("Error: --concurrency must be at least 1".into());
+    }
+
+    let url = url.ok_or("Error: Expected a URL to fetch through the proxy")?;
+    let uri: Uri = url.parse().map_err(|_| format!("Error: Invalid URL: {}", url))?;
+    if uri.scheme_str() != Some("http") {
+        return Err(format!("Error: Only http URLs can be benchmarked: {}", url).into());
+    }
+    let host = uri.host().ok_or_else(|| format!("Error: Invalid URL: {}", url))?;
+    let authority = format!("{}:{}", host, uri.port_u16().unwrap_or(80));
+    let proxy_authorization = credentials.map(|credentials| format!("Basic {}", base64::encode(credentials)));
+    let target = match mode {
+        Mode::Get => url.clone(),
+        Mode::Connect => uri.path_and_query().map_or("/", |path| path.as_str()).to_string(),
+    };
+    let mut request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n", target, uri.authority().unwrap());
+    if let (Mode::Get, Some(authorization)) = (mode, &proxy_authorization) {
+        request.push_str(&format!("Proxy-Authorization: {}\r\n", authorization));
+    }
+    request.push_str("\r\n");
+
+    let bench = Arc::new(Bench {
+        proxy,
+        mode,
+        authority,
+        request: request.into_bytes(),
+        proxy_authorization,
+        requests,
+        duration,
+    });
+    match bench.duration {
+        Some(duration) => println!("Benchmarking {} through {} for {}s, {} at a time", url, bench.proxy, duration.as_secs(), concurrency),
+        None => println!("Benchmarking {} through {}: {} requests, {} at a time", url, bench.proxy, requests, concurrency),
+    }
+
+    let started = Instant::now();
+    let issued = Arc::new(AtomicU64::new(0));
+    let workers: Vec<_> = (0..concurrency)
+        .map(|_| tokio::spawn(worker(bench.clone(), issued.clone(), started)))
+        .collect();
+    let mut results = Results::default();
+    for worker in workers {
+        let worker = worker.await?;
+        results.latencies.extend(worker.latencies);
+        results.errors += worker.errors;
+        results.bytes += worker.bytes;
+        results.first_error = results.first_error.or(worker.first_error);
+    }
+    report(&results, started.elapsed());
+    Ok(())
+}
+
+/// Run exchanges one after another until the request count or the
+/// duration is used up.
+async fn worker(bench: Arc<Bench>, issued: Arc<AtomicU64>, started: Instant) -> Results {
+    let mut results = Results::default();
+    loop {
+        match bench.duration {
+            Some(duration) if started.elapsed() >= duration => break,
+            Some(_) => {}
+            None if issued.fetch_add(1, Ordering::Relaxed) >= bench.requests => break,
+            None => {}
+        }
+        let start = Instant::now();
+        let exchange = tokio::time::timeout(EXCHANGE_TIMEOUT, exchange(&bench)).await
+            .unwrap_or_else(|_| Err("Error: Timed out".into()));
+        match exchange {
+            Ok(bytes) => {
+                results.latencies.push(start.elapsed());
+                results.bytes += bytes;
+            }
+            Err(e) => {
+                results.errors += 1;
+                results.first_error.get_or_insert_with(|| e.to_string());
+            }
+        }
+    }
+    results
+}
+
+/// One exchange on a new connection to the proxy; the bytes of the
+/// response body.
+async fn exchange(bench: &Bench) -> Result<u64, Box<dyn Error + Send + Sync>> {
+    let mut stream = TcpStream::connect(&bench.proxy).await?;
+    stream.set_nodelay(true)?;
+    if bench.mode == Mode::Connect {
+        let mut connect = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", bench.authority);
+        if let Some(authorization) = &bench.proxy_authorization {
+            connect.push_str(&format!("Proxy-Authorization: {}\r\n", authorization));
+        }
+        connect.push_str("\r\n");
+        stream.write_all(connect.as_bytes()).await?;
+        let (status, rest) = read_head(&mut stream).await?;
+        if status != 200 {
+            return Err(format!("Error: CONNECT answered with {}", status).into());
+        }
+        if !rest.is_empty() {
+            return Err("Error: Data before the tunnel was used".into());
+        }
+    }
+    stream.write_all(&bench.request).await?;
+    let (status, rest) = read_head(&mut stream).await?;
+    if !(200..300).contains(&status) {
+        return Err(format!("Error: Answered with {}", status).into());
+    }
+    Ok(read_body(&mut stream, rest.len() as u64, u64::MAX, None).await?)
+}
+
+/*************************************************
+ * report
+ *************************************************/
+
+fn report(results: &Results, elapsed: Duration) {
+    let mut latencies = results.latencies.clone();
+    latencies.sort_unstable();
+    let completed = latencies.len();
+    let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+
+    println!();
+    println!("  {:<14} {:>12}", "Completed", completed);
+    println!("  {:<14} {:>12}", "Failed", results.errors);
+    println!("  {:<14} {:>11.2}s", "Elapsed", secs);
+    println!("  {:<14} {:>10.1}/s", "Connections", completed as f64 / secs);
+    println!("  {:<14} {:>8.2} MB/s", "Throughput", results.bytes as f64 / secs / (1024.0 * 1024.0));
+    if completed > 0 {
+        let mean = latencies.iter().sum::<Duration>() / completed as u32;
+        println!("  {:<14} {:>10.2}ms", "Latency mean", millis(mean));
+        for (name, quantile) in [("p50", 0.50), ("p90", 0.90), ("p99", 0.99)] {
+            let index = ((completed as f64 * quantile).ceil() as usize).clamp(1, completed) - 1;
+            println!("  {:<14} {:>10.2}ms", format!("Latency {}", name), millis(latencies[index]));
+        }
+        println!("  {:<14} {:>10.2}ms", "Latency max", millis(latencies[completed - 1]));
+    }
+    if let Some(error) = &results.first_error {
+        println!("\nFirst failure: {}", error);
+    }
+}
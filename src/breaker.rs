@@ -0,0 +1,81 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use log::{info, warn};
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+pub const DEFAULT_COOLDOWN_SECS: u64 = 30;
+
+/// Destinations tracked at once; past this, circuits that aren't open are
+/// forgotten.
+const MAX_CIRCUITS: usize = 10_000;
+
+/*************************************************
+ * Breaker
+ *************************************************/
+
+/// Per-destination circuit breaker. After `threshold` failures in a row a
+/// destination's circuit opens and attempts to reach it are refused for
+/// `cooldown`. Then one attempt at a time is let through to probe it: a
+/// success closes the circuit, a failure opens it for another cooldown.
+pub struct Breaker {
+    threshold: u32,
+    cooldown: Duration,
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+struct Circuit {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl Breaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Breaker { threshold: threshold.max(1), cooldown, circuits: Mutex::new(HashMap::new()) }
+    }
+
+    /// Whether `destination` may be tried; otherwise how long until it
+    /// will be. Letting a probe through re-arms the cooldown, so the
+    /// attempts after it wait for its outcome.
+    pub fn check(&self, destination: &str) -> Result<(), Duration> {
+        let mut circuits = self.circuits.lock().unwrap();
+        let Some(open_until) = circuits.get_mut(destination).and_then(|circuit| circuit.open_until.as_mut()) else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        if now < *open_until {
+            return Err(*open_until - now);
+        }
+        *open_until = now + self.cooldown;
+        Ok(())
+    }
+
+    pub fn failed(&self, destination: &str) {
+        let mut circuits = self.circuits.lock().unwrap();
+        if circuits.len() >= MAX_CIRCUITS && !circuits.contains_key(destination) {
+            circuits.retain(|_, circuit| circuit.open_until.is_some());
+        }
+        let circuit = circuits.entry(destination.to_string()).or_insert(Circuit { failures: 0, open_until: None });
+        circuit.failures += 1;
+        if circuit.failures >= self.threshold {
+            if circuit.open_until.is_none() {
+                warn!("Circuit for {} opened after {} failures in a row", destination, circuit.failures);
+            }
+            circuit.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+
+    pub fn succeeded(&self, destination: &str) {
+        let removed = self.circuits.lock().unwrap().remove(destination);
+        if removed.is_some_and(|circuit| circuit.open_until.is_some()) {
+            info!("Circuit for {} closed", destination);
+        }
+    }
+}
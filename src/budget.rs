@@ -0,0 +1,113 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/*************************************************
+ * MemoryBudget
+ *************************************************/
+
+/// A cap on the memory held by buffered data across all connections:
+/// bodies kept for retries and inspection, relay buffers and captures.
+/// Whatever would go over it is turned away instead, so a burst of large
+/// requests is shed rather than getting the process OOM-killed.
+#[derive(Clone)]
+pub struct MemoryBudget {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    /// `None` when unlimited; usage is counted either way.
+    limit: Option<usize>,
+    used: AtomicUsize,
+    /// Reservations turned down.
+    shed: AtomicU64,
+}
+
+pub struct BudgetStats {
+    pub limit: Option<usize>,
+    pub used: usize,
+    pub shed: u64,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: Option<usize>) -> Self {
+        MemoryBudget {
+            shared: Arc::new(Shared { limit, used: AtomicUsize::new(0), shed: AtomicU64::new(0) }),
+        }
+    }
+
+    /// An empty reservation, to be grown as data is buffered.
+    pub fn reservation(&self) -> Reservation {
+        Reservation { budget: self.clone(), bytes: 0 }
+    }
+
+    /// Reserve `bytes`, or `None` if they don't fit.
+    pub fn try_reserve(&self, bytes: usize) -> Option<Reservation> {
+        let mut reservation = self.reservation();
+        reservation.grow(bytes).ok()?;
+        Some(reservation)
+    }
+
+    pub fn stats(&self) -> BudgetStats {
+        BudgetStats {
+            limit: self.shared.limit,
+            used: self.shared.used.load(Ordering::Relaxed),
+            shed: self.shared.shed.load(Ordering::Relaxed),
+        }
+    }
+
+    fn take(&self, bytes: usize) -> bool {
+        let Some(limit) = self.shared.limit else {
+            self.shared.used.fetch_add(bytes, Ordering::Relaxed);
+            return true;
+        };
+        let taken = self.shared.used.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+            used.checked_add(bytes).filter(|&used| used <= limit)
+        });
+        if taken.is_err() {
+            self.shared.shed.fetch_add(1, Ordering::Relaxed);
+        }
+        taken.is_ok()
+    }
+}
+
+/// Bytes taken from a `MemoryBudget`, given back when dropped.
+pub struct Reservation {
+    budget: MemoryBudget,
+    bytes: usize,
+}
+
+impl Reservation {
+    /// Take `bytes` more; fails, leaving the reservation as it was, when
+    /// the budget can't spare them.
+    pub fn grow(&mut self, bytes: usize) -> Result<(), OverBudget> {
+        if !self.budget.take(bytes) {
+            return Err(OverBudget(bytes));
+        }
+        self.bytes += bytes;
+        Ok(())
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.shared.used.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+/// The memory budget couldn't spare this many bytes.
+#[derive(Debug)]
+pub struct OverBudget(pub usize);
+
+impl fmt::Display for OverBudget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Error: Memory budget exceeded, cannot buffer {} more bytes", self.0)
+    }
+}
+
+impl Error for OverBudget {}
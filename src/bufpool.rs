@@ -0,0 +1,76 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+pub const DEFAULT_BUFFER_SIZE: usize = 8192;
+pub const DEFAULT_POOL_SIZE: usize = 1024;
+
+/*************************************************
+ * BufferPool
+ *************************************************/
+
+/// Relay buffers of one size, handed back on drop and reused by the next
+/// tunnel, so connection churn doesn't churn the allocator. At most
+/// `max_idle` buffers are kept around; beyond that they are freed.
+pub struct BufferPool {
+    size: usize,
+    max_idle: usize,
+    idle: Mutex<Vec<Box<[u8]>>>,
+}
+
+impl BufferPool {
+    pub fn new(size: usize, max_idle: usize) -> Self {
+        BufferPool {
+            size,
+            max_idle,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn get(&self) -> PooledBuffer<'_> {
+        let buf = self.idle.lock().unwrap().pop().unwrap_or_else(|| vec![0u8; self.size].into_boxed_slice());
+        PooledBuffer { pool: self, buf: Some(buf) }
+    }
+
+    fn put(&self, buf: Box<[u8]>) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.max_idle {
+            idle.push(buf);
+        }
+    }
+}
+
+/// A buffer borrowed from a `BufferPool`; returns to it when dropped.
+pub struct PooledBuffer<'a> {
+    pool: &'a BufferPool,
+    buf: Option<Box<[u8]>>,
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.buf.as_deref().unwrap()
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.buf.as_deref_mut().unwrap()
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        if let Some(buf) = self.buf.take() {
+            self.pool.put(buf);
+        }
+    }
+}
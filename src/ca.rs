@@ -0,0 +1,147 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType, IsCa, KeyUsagePurpose};
+use time::{Duration, OffsetDateTime};
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+const DEFAULT_CA_CERT: &str = "rdnat-ca.pem";
+const DEFAULT_CA_KEY: &str = "rdnat-ca.key";
+const CA_VALIDITY_DAYS: i64 = 10 * 365;
+
+/*************************************************
+ * run
+ *************************************************/
+
+/// Handle `rdnat ca [--cert <path>] [--key <path>] init|rotate|export`:
+/// manage the CA that signs intercepted connections.
+pub fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut cert_path = String::from(DEFAULT_CA_CERT);
+    let mut key_path = String::from(DEFAULT_CA_KEY);
+    let mut output: Option<String> = None;
+    let mut der = false;
+    let mut command: Option<String> = None;
+
+    let mut i = 0;
+    while i < args.len() {
+        let value = args.get(i + 1).cloned();
+        match (args[i].as_str(), value) {
+            ("-c" | "--cert", Some(value)) => cert_path = value,
+            ("-k" | "--key", Some(value)) => key_path = value,
+            ("-o" | "--output", Some(value)) => output = Some(value),
+            ("--der", _) => {
+                der = true;
+                i += 1;
+                continue;
+            }
+            ("init" | "rotate" | "export", _) if command.is_none() => {
+                command = Some(args[i].clone());
+                i += 1;
+                continue;
+            }
+            (arg, _) => return Err(format!("Error: Invalid ca argument: {}", arg).into()),
+        }
+        i += 2;
+    }
+
+    match command.as_deref() {
+        Some("init") => init(&cert_path, &key_path),
+        Some("rotate") => rotate(&cert_path, &key_path),
+        Some("export") => export(&cert_path, output.as_deref(), der),
+        _ => Err("Error: Expected a ca command: init, rotate or export".into()),
+    }
+}
+
+/// Create the CA, refusing to replace an existing one.
+fn init(cert_path: &str, key_path: &str) -> Result<(), Box<dyn Error>> {
+    if let Some(path) = [cert_path, key_path].into_iter().find(|path| Path::new(path).exists()) {
+        return Err(format!("Error: {} already exists; use 'rdnat ca rotate' to replace the CA", path).into());
+    }
+    let (cert, key, expires) = generate()?;
+    write_new(key_path, &key, 0o600)?;
+    write_new(cert_path, &cert, 0o644)?;
+    println!("CA certificate written to {} (valid until {})", cert_path, expires);
+    println!("CA key written to {} (readable by its owner only)", key_path);
+    println!("Install the certificate on clients, e.g. with 'rdnat ca export -o rdnat-ca.crt'");
+    Ok(())
+}
+
+/// Replace the CA, keeping the previous pair as `<path>.old`.
+fn rotate(cert_path: &str, key_path: &str) -> Result<(), Box<dyn Error>> {
+    let (cert, key, expires) = generate()?;
+    let staged = |path: &str| format!("{}.new", path);
+    for (path, contents, mode) in [(key_path, &key, 0o600), (cert_path, &cert, 0o644)] {
+        let _ = fs::remove_file(staged(path));
+        write_new(&staged(path), contents, mode)?;
+    }
+    for path in [key_path, cert_path] {
+        if Path::new(path).exists() {
+            fs::rename(path, format!("{}.old", path))?;
+        }
+        fs::rename(staged(path), path)?;
+    }
+    println!("CA certificate written to {} (valid until {})", cert_path, expires);
+    println!("CA key written to {} (readable by its owner only)", key_path);
+    println!("The previous CA is kept as {}.old; install the new certificate on clients and restart the proxy", cert_path);
+    Ok(())
+}
+
+/// Write the certificate for installing on clients: PEM, or DER for
+/// systems that want a binary `.cer`/`.crt`.
+fn export(cert_path: &str, output: Option<&str>, der: bool) -> Result<(), Box<dyn Error>> {
+    let pem = fs::read(cert_path).map_err(|e| format!("Error: Cannot read {}: {}", cert_path, e))?;
+    let contents = if der {
+        rustls_pemfile::certs(&mut pem.as_slice())?
+            .into_iter()
+            .next()
+            .ok_or_else(|| format!("Error: No certificate in {}", cert_path))?
+    } else {
+        pem
+    };
+    match output {
+        Some(path) => fs::write(path, contents)?,
+        None => std::io::stdout().write_all(&contents)?,
+    }
+    Ok(())
+}
+
+/// A new CA certificate and key (PEM), and the day it expires.
+fn generate() -> Result<(String, String, time::Date), Box<dyn Error>> {
+    let now = OffsetDateTime::now_utc();
+    let expires = now + Duration::days(CA_VALIDITY_DAYS);
+    let mut params = CertificateParams::default();
+    let mut name = DistinguishedName::new();
+    // Dated, so an installed CA can be told apart from the one it replaced.
+    name.push(DnType::CommonName, format!("rdnat interception CA {}", now.date()));
+    name.push(DnType::OrganizationName, "rdnat");
+    params.distinguished_name = name;
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign, KeyUsagePurpose::DigitalSignature];
+    params.not_before = now - Duration::days(1);
+    params.not_after = expires;
+    let cert = Certificate::from_params(params)?;
+    Ok((cert.serialize_pem()?, cert.serialize_private_key_pem(), expires.date()))
+}
+
+/// Create `path` with `mode` (on Unix), failing if it exists.
+fn write_new(path: &str, contents: &str, mode: u32) -> Result<(), Box<dyn Error>> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(mode);
+    #[cfg(not(unix))]
+    let _ = mode;
+    let mut file = options.open(path).map_err(|e| format!("Error: Cannot create {}: {}", path, e))?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
@@ -0,0 +1,1004 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{HeaderName, HeaderValue, AGE, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, DATE, ETAG, EXPIRES, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, PRAGMA, RANGE, SET_COOKIE, TRANSFER_ENCODING, VARY};
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode, Uri};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use crate::budget::{MemoryBudget, Reservation};
+use crate::diskcache::{DiskBody, DiskStore};
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+/// Largest response kept in memory unless configured.
+pub const DEFAULT_MAX_OBJECT: usize = 8 * 1024 * 1024;
+/// Cap on the freshness guessed from Last-Modified.
+const HEURISTIC_MAX: Duration = Duration::from_secs(24 * 3600);
+/// Statuses that may be cached without explicit freshness (RFC 7231 6.1);
+/// 206 is left out since partial bodies aren't combined.
+const HEURISTIC_STATUSES: [u16; 10] = [200, 203, 204, 300, 301, 404, 405, 410, 414, 501];
+/// Ages and lifetimes beyond this, in seconds, are taken as this (RFC 9111
+/// 1.2.2), so an origin can't overflow the arithmetic on them.
+const MAX_DELTA_SECONDS: u64 = 1 << 31;
+
+/*************************************************
+ * ResponseCache
+ *************************************************/
+
+/// A shared HTTP cache (RFC 7234) of origin responses to GET requests,
+/// keyed by method and URL, with a variant per combination of the request
+/// headers the origin varies on. Entries are kept until `capacity` bytes
+/// are used or the memory budget runs short, least recently used first
+/// out. Responses are stored as the origin sent them; rules apply to each
+/// answer from the cache as they would to the origin's.
+///
+/// Stale responses with a validator are kept and revalidated with a
+/// conditional request before they are used again, or while they are
+/// within `stale-while-revalidate`, in the background after; a 304 from
+/// the origin brings the stored head up to date.
+///
+/// With a disk tier, every response that fits its limits is written to
+/// disk as well, and what memory doesn't hold is answered from there.
+pub struct ResponseCache {
+    capacity: usize,
+    max_object: usize,
+    budget: MemoryBudget,
+    memory: Mutex<Index<Stored>>,
+    disk: Option<Arc<DiskStore>>,
+    /// Keys being revalidated in the background, so each is once at a time.
+    revalidating: Mutex<HashSet<String>>,
+    hits: AtomicU64,
+    stale: AtomicU64,
+    revalidated: AtomicU64,
+    misses: AtomicU64,
+}
+
+pub struct CacheStats {
+    pub entries: usize,
+    pub size: u64,
+    pub capacity: usize,
+    /// Entries, bytes and capacity of the disk tier.
+    pub disk: Option<(usize, u64, u64)>,
+    /// Lookups answered as they were, found stale, and of those confirmed
+    /// by the origin; and lookups that found nothing.
+    pub hits: u64,
+    pub stale: u64,
+    pub revalidated: u64,
+    pub misses: u64,
+}
+
+struct Stored {
+    meta: Meta,
+    body: Bytes,
+    _reservation: Reservation,
+}
+
+/// What the cache has for a request.
+pub enum Lookup {
+    /// The answer, with the revalidation to run in the background when it
+    /// is stale within `stale-while-revalidate`.
+    Hit(Response<Body>, Option<Box<Revalidation>>),
+    /// A stale response, usable once the origin says it hasn't changed.
+    Stale(Cached),
+    Miss,
+}
+
+/// A stored response taken out of the cache.
+pub struct Cached {
+    meta: Meta,
+    body: CachedBody,
+}
+
+enum CachedBody {
+    Memory(Bytes),
+    Disk(DiskBody),
+}
+
+/// A stale response to refresh in the background.
+pub struct Revalidation {
+    request: CacheRequest,
+    meta: Meta,
+}
+
+/// A stored response without its body: what decides whether it can be
+/// used, and its head.
+#[derive(Clone)]
+pub struct Meta {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    /// When the response came in, and how old it was then.
+    pub response_time: SystemTime,
+    pub initial_age: Duration,
+    pub freshness: Duration,
+    pub must_revalidate: bool,
+}
+
+/// Whether a stored response can answer a request as it is.
+enum Usability {
+    Fresh,
+    StaleWhileRevalidate,
+    Stale,
+}
+
+impl Meta {
+    /// The meta of a response with `headers`, sent at `request_time` and
+    /// received at `response_time`.
+    fn new(status: StatusCode, headers: HeaderMap, request_time: SystemTime, response_time: SystemTime) -> Self {
+        let directives = Directives::parse(&headers);
+        Meta {
+            status,
+            initial_age: initial_age(&headers, request_time, response_time),
+            freshness: freshness_lifetime(status, &headers),
+            // no-cache has every use revalidated, and s-maxage implies
+            // proxy-revalidate (RFC 7234 5.2.2.9).
+            must_revalidate: directives.must_revalidate || directives.no_cache || directives.s_maxage.is_some(),
+            headers,
+            response_time,
+        }
+    }
+
+    pub fn head_size(&self) -> usize {
+        self.headers.iter().map(|(name, value)| name.as_str().len() + value.len() + 4).sum()
+    }
+
+    /// The current age (RFC 7234 4.2.3).
+    fn age(&self, now: SystemTime) -> Duration {
+        self.initial_age.saturating_add(now.duration_since(self.response_time).unwrap_or_default())
+    }
+
+    fn usability(&self, request: &CacheRequest) -> Usability {
+        let age = self.age(SystemTime::now());
+        let wanted = &request.directives;
+        let fresh_for = self.freshness.saturating_sub(age);
+        let stale_by = age.saturating_sub(self.freshness);
+        let wanted_age = wanted.max_age.is_none_or(|max_age| age <= max_age)
+            && wanted.min_fresh.is_none_or(|min_fresh| fresh_for >= min_fresh);
+        if wanted.no_cache || !wanted_age {
+            return Usability::Stale;
+        }
+        if stale_by.is_zero() {
+            return Usability::Fresh;
+        }
+        if self.must_revalidate {
+            return Usability::Stale;
+        }
+        if wanted.max_stale.is_some_and(|max_stale| max_stale.is_none_or(|max_stale| stale_by <= max_stale)) {
+            return Usability::Fresh;
+        }
+        match Directives::parse(&self.headers).stale_while_revalidate {
+            Some(window) if stale_by <= window => Usability::StaleWhileRevalidate,
+            _ => Usability::Stale,
+        }
+    }
+
+    fn has_validator(&self) -> bool {
+        self.headers.contains_key(ETAG) || self.headers.contains_key(LAST_MODIFIED)
+    }
+
+    /// The meta after a 304 with `headers` to a request sent at
+    /// `request_time`: the stored head updated with the new one (RFC 7234
+    /// 4.3.4) and the freshness starting over.
+    fn refreshed(&self, headers: &HeaderMap, request_time: SystemTime) -> Meta {
+        let mut merged = self.headers.clone();
+        for name in headers.keys().filter(|&name| name != CONTENT_LENGTH && name != TRANSFER_ENCODING) {
+            merged.remove(name);
+            for value in headers.get_all(name) {
+                merged.append(name.clone(), value.clone());
+            }
+        }
+        let response_time = SystemTime::now();
+        Meta {
+            // The age is the 304's, not the stored response's.
+            initial_age: initial_age(headers, request_time, response_time),
+            ..Meta::new(self.status, merged, request_time, response_time)
+        }
+    }
+
+    /// The response with `body`, its Age brought up to date.
+    pub fn response(&self, body: Body) -> Response<Body> {
+        let mut response = Response::new(body);
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        response.headers_mut().insert(AGE, HeaderValue::from(self.age(SystemTime::now()).as_secs()));
+        response
+    }
+}
+
+impl Cached {
+    /// Make `headers` of the request for this response conditional on it,
+    /// in place of whatever conditions the client set.
+    pub fn make_conditional(&self, headers: &mut HeaderMap) {
+        headers.remove(IF_NONE_MATCH);
+        headers.remove(IF_MODIFIED_SINCE);
+        if let Some(etag) = self.meta.headers.get(ETAG) {
+            headers.insert(IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = self.meta.headers.get(LAST_MODIFIED) {
+            headers.insert(IF_MODIFIED_SINCE, last_modified.clone());
+        }
+    }
+}
+
+impl Revalidation {
+    /// The conditional request to send to the origin.
+    pub fn request(&self) -> Request<Body> {
+        let mut request = Request::new(Body::empty());
+        *request.uri_mut() = self.request.uri.clone();
+        *request.headers_mut() = self.request.headers.clone();
+        Cached { meta: self.meta.clone(), body: CachedBody::Memory(Bytes::new()) }.make_conditional(request.headers_mut());
+        request
+    }
+}
+
+/// A request the cache may answer, and whose response it may keep.
+#[derive(Clone)]
+pub struct CacheRequest {
+    method: Method,
+    uri: Uri,
+    pub key: String,
+    pub headers: HeaderMap,
+    directives: Directives,
+    request_time: SystemTime,
+}
+
+impl CacheRequest {
+    /// `None` when the cache is to stay out of it: other methods than GET
+    /// and HEAD, range requests and `no-store`.
+    pub fn new<T>(request: &Request<T>) -> Option<Self> {
+        if !matches!(*request.method(), Method::GET | Method::HEAD) || request.headers().contains_key(RANGE) {
+            return None;
+        }
+        let mut directives = Directives::parse(request.headers());
+        if !request.headers().contains_key(CACHE_CONTROL) {
+            directives.no_cache = request.headers().get_all(PRAGMA).iter().any(|value| value.as_bytes().eq_ignore_ascii_case(b"no-cache"));
+        }
+        if directives.no_store {
+            return None;
+        }
+        Some(CacheRequest {
+            method: request.method().clone(),
+            uri: request.uri().clone(),
+            key: url_key(&request.uri().to_string()),
+            headers: request.headers().clone(),
+            directives,
+            request_time: SystemTime::now(),
+        })
+    }
+
+    /// Whether the client only wants an answer from the cache.
+    pub fn only_if_cached(&self) -> bool {
+        self.directives.only_if_cached
+    }
+}
+
+/// The key of what is stored for `url`. HEAD is answered from the GET
+/// response.
+fn url_key(url: &str) -> String {
+    format!("GET {}", url)
+}
+
+impl ResponseCache {
+    pub fn new(capacity: usize, max_object: usize, budget: MemoryBudget, disk: Option<Arc<DiskStore>>) -> Self {
+        ResponseCache {
+            capacity,
+            max_object,
+            budget,
+            memory: Mutex::new(Index::default()),
+            disk,
+            revalidating: Mutex::new(HashSet::new()),
+            hits: AtomicU64::new(0),
+            stale: AtomicU64::new(0),
+            revalidated: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// What is stored for `request`, and whether it can be used as it is.
+    pub fn lookup(&self, request: &CacheRequest) -> Lookup {
+        let from_memory = self.memory.lock().unwrap()
+            .get(&request.key, &request.headers)
+            .map(|stored| Cached { meta: stored.meta.clone(), body: CachedBody::Memory(stored.body.clone()) });
+        let cached = from_memory.or_else(|| {
+            let (meta, body) = self.disk.as_ref()?.lookup(request)?;
+            Some(Cached { meta, body: CachedBody::Disk(body) })
+        });
+        let Some(cached) = cached else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return Lookup::Miss;
+        };
+        let lookup = match cached.meta.usability(request) {
+            Usability::Fresh => Lookup::Hit(answer(request, cached), None),
+            Usability::StaleWhileRevalidate => {
+                let revalidation = self.revalidating.lock().unwrap().insert(request.key.clone()).then(|| Box::new(Revalidation {
+                    // Refreshed with a GET, so a changed response is stored.
+                    request: CacheRequest { method: Method::GET, ..request.clone() },
+                    meta: cached.meta.clone(),
+                }));
+                Lookup::Hit(answer(request, cached), revalidation)
+            }
+            Usability::Stale if cached.meta.has_validator() => Lookup::Stale(cached),
+            Usability::Stale => Lookup::Miss,
+        };
+        let counter = match lookup {
+            Lookup::Hit(..) => &self.hits,
+            Lookup::Stale(_) => &self.stale,
+            Lookup::Miss => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        lookup
+    }
+
+    /// Answer `request` with the `stale` response the origin has just
+    /// confirmed with `not_modified`, storing the refreshed head.
+    pub async fn refresh(&self, request: &CacheRequest, stale: Cached, not_modified: &Response<Body>) -> Response<Body> {
+        self.revalidated.fetch_add(1, Ordering::Relaxed);
+        let meta = stale.meta.refreshed(not_modified.headers(), request.request_time);
+        self.update(request, &stale.meta, &meta).await;
+        answer(request, Cached { meta, body: stale.body })
+    }
+
+    /// Run `revalidation`, with `response` the origin's answer to its
+    /// request, storing what comes back.
+    pub async fn revalidate(
+        self: Arc<Self>,
+        mut revalidation: Revalidation,
+        response: impl Future<Output = Result<Response<Body>, Box<dyn Error + Send + Sync>>>,
+    ) {
+        let key = revalidation.request.key.clone();
+        revalidation.request.request_time = SystemTime::now();
+        match response.await {
+            Ok(response) if response.status() == StatusCode::NOT_MODIFIED => {
+                let meta = revalidation.meta.refreshed(response.headers(), revalidation.request.request_time);
+                self.update(&revalidation.request, &revalidation.meta, &meta).await;
+                log::debug!("Revalidated {}", key);
+            }
+            Ok(response) => {
+                // Nobody reads the new response, so drain it into the cache.
+                let mut body = self.fill(revalidation.request, response).into_body();
+                while let Some(Ok(_)) = body.data().await {}
+            }
+            Err(e) => log::debug!("Cannot revalidate {}: {}", key, e),
+        }
+        self.revalidating.lock().unwrap().remove(&key);
+    }
+
+    /// Pass `response` on, keeping a copy of its body as it goes by when
+    /// it may be stored as the answer to `request`.
+    pub fn fill(self: &Arc<Self>, request: CacheRequest, response: Response<Body>) -> Response<Body> {
+        if !storable(&request, &response) {
+            return response;
+        }
+        let meta = Meta::new(response.status(), response.headers().clone(), request.request_time, SystemTime::now());
+        // A response that is stale at once is only worth keeping to
+        // revalidate.
+        if meta.freshness.is_zero() && !meta.has_validator() {
+            return response;
+        }
+        let length = response.headers().get(CONTENT_LENGTH).and_then(|value| value.to_str().ok()?.parse::<u64>().ok());
+        let to_memory = self.capacity > 0 && length.is_none_or(|length| length <= self.max_object as u64);
+        let to_disk = self.disk.as_ref().filter(|disk| disk.accepts(&response, length)).cloned();
+        if !to_memory && to_disk.is_none() {
+            return response;
+        }
+        let (parts, mut body) = response.into_parts();
+        let (mut sender, tee) = Body::channel();
+        let cache = self.clone();
+        tokio::spawn(async move {
+            let mut copy = to_memory.then(Vec::new);
+            let mut writer = match &to_disk {
+                Some(disk) => disk.writer().await.map_err(|e| log::warn!("Cannot write to the disk cache: {}", e)).ok(),
+                None => None,
+            };
+            while let Some(chunk) = body.data().await {
+                let Ok(chunk) = chunk else {
+                    sender.abort();
+                    return;
+                };
+                copy = copy.filter(|copy| copy.len() + chunk.len() <= cache.max_object).map(|mut copy| {
+                    copy.extend_from_slice(&chunk);
+                    copy
+                });
+                if let Some(disk_writer) = &mut writer {
+                    if disk_writer.write(&chunk).await.is_err() {
+                        writer = None;
+                    }
+                }
+                if sender.send_data(chunk).await.is_err() {
+                    return;
+                }
+            }
+            if let (Some(disk), Some(writer)) = (&to_disk, writer) {
+                if let Err(e) = disk.finish(writer, &request, &meta).await {
+                    log::warn!("Cannot write to the disk cache: {}", e);
+                }
+            }
+            if let Some(copy) = copy {
+                cache.insert(&request, meta, Bytes::from(copy));
+            }
+        });
+        Response::from_parts(parts, tee)
+    }
+
+    /// Forget what is stored for `url`, after a request that may have
+    /// changed it (RFC 7234 4.4).
+    pub fn invalidate(&self, url: &str) {
+        let key = url_key(url);
+        self.memory.lock().unwrap().remove(&key);
+        if let Some(disk) = &self.disk {
+            disk.invalidate(&key);
+        }
+    }
+
+    /// Forget what is stored for every URL `matches`; returns how many
+    /// responses were dropped from memory and from disk.
+    pub fn purge(&self, matches: impl Fn(&str) -> bool) -> (usize, usize) {
+        let matches = |key: &str| key.strip_prefix("GET ").is_some_and(&matches);
+        let from_memory = self.memory.lock().unwrap().remove_matching(matches).len();
+        let from_disk = self.disk.as_ref().map_or(0, |disk| disk.purge(matches));
+        (from_memory, from_disk)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let memory = self.memory.lock().unwrap();
+        CacheStats {
+            entries: memory.entries(),
+            size: memory.size(),
+            capacity: self.capacity,
+            disk: self.disk.as_ref().map(|disk| disk.stats()),
+            hits: self.hits.load(Ordering::Relaxed),
+            stale: self.stale.load(Ordering::Relaxed),
+            revalidated: self.revalidated.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    fn insert(&self, request: &CacheRequest, meta: Meta, body: Bytes) {
+        let size = (body.len() + meta.head_size()) as u64;
+        if size > self.capacity as u64 {
+            return;
+        }
+        let mut memory = self.memory.lock().unwrap();
+        memory.make_room(size, self.capacity as u64);
+        let reservation = loop {
+            match self.budget.try_reserve(size as usize) {
+                Some(reservation) => break reservation,
+                None if memory.pop_oldest().is_some() => {}
+                None => return,
+            }
+        };
+        let vary = vary_names(&meta.headers);
+        memory.insert(&request.key, &request.headers, vary, Stored { meta, body, _reservation: reservation }, size);
+        log::debug!("Cached {} ({} bytes)", request.key, size);
+    }
+
+    /// Replace the `old` meta of the response to `request` with `meta`
+    /// wherever it is still stored.
+    async fn update(&self, request: &CacheRequest, old: &Meta, meta: &Meta) {
+        if let Some(stored) = self.memory.lock().unwrap().get(&request.key, &request.headers) {
+            if stored.meta.response_time == old.response_time {
+                stored.meta = meta.clone();
+            }
+        }
+        if let Some(disk) = &self.disk {
+            if let Err(e) = disk.update(request, old, meta).await {
+                log::warn!("Cannot write to the disk cache: {}", e);
+            }
+        }
+    }
+}
+
+/// Answer `request` with `cached`: a 304 when the client's own conditions
+/// say it has the response already (RFC 7232 3.2, 3.3).
+fn answer(request: &CacheRequest, cached: Cached) -> Response<Body> {
+    if cached.meta.status.is_success() && not_modified(&request.headers, &cached.meta.headers) {
+        let mut response = cached.meta.response(Body::empty());
+        *response.status_mut() = StatusCode::NOT_MODIFIED;
+        response.headers_mut().remove(CONTENT_LENGTH);
+        return response;
+    }
+    let body = match cached.body {
+        CachedBody::Memory(body) => Body::from(body),
+        CachedBody::Disk(body) => body.into_body(),
+    };
+    cached.meta.response(body)
+}
+
+/// Whether a request with `headers` is satisfied by a 304 for a response
+/// with `stored` headers. If-None-Match takes precedence, and compares
+/// entity tags weakly.
+fn not_modified(headers: &HeaderMap, stored: &HeaderMap) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if headers.contains_key(IF_NONE_MATCH) {
+        let Some(etag) = stored.get(ETAG).and_then(|value| value.to_str().ok()).map(opaque) else {
+            return false;
+        };
+        return headers.get_all(IF_NONE_MATCH)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|tag| tag.trim() == "*" || opaque(tag) == etag);
+    }
+    match (http_date(headers, IF_MODIFIED_SINCE), http_date(stored, LAST_MODIFIED).or_else(|| http_date(stored, DATE))) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+/*************************************************
+ * Index
+ *************************************************/
+
+/// Stored variants by URL and Vary'd request headers, with their sizes
+/// and the order they were last used in.
+pub struct Index<E> {
+    resources: HashMap<String, Resource<E>>,
+    /// Variants by last use, oldest first.
+    recency: BTreeMap<u64, (String, String)>,
+    tick: u64,
+    size: u64,
+}
+
+/// The variants of one URL.
+struct Resource<E> {
+    /// The request headers the origin varies on.
+    vary: Vec<HeaderName>,
+    /// Variants by the values of those headers.
+    variants: HashMap<String, Slot<E>>,
+}
+
+struct Slot<E> {
+    entry: E,
+    size: u64,
+    last_used: u64,
+}
+
+impl<E> Default for Index<E> {
+    fn default() -> Self {
+        Index { resources: HashMap::new(), recency: BTreeMap::new(), tick: 0, size: 0 }
+    }
+}
+
+impl<E> Index<E> {
+    /// The variant of `key` matching request `headers`; counts as a use.
+    pub fn get(&mut self, key: &str, headers: &HeaderMap) -> Option<&mut E> {
+        let resource = self.resources.get_mut(key)?;
+        let secondary = secondary_key(&resource.vary, headers);
+        let slot = resource.variants.get_mut(&secondary)?;
+        self.recency.remove(&slot.last_used);
+        self.tick += 1;
+        slot.last_used = self.tick;
+        self.recency.insert(self.tick, (key.to_string(), secondary));
+        Some(&mut slot.entry)
+    }
+
+    /// Add a variant of `key` for request `headers`, as the most recently
+    /// used; returns the entries it replaces.
+    pub fn insert(&mut self, key: &str, headers: &HeaderMap, vary: Vec<HeaderName>, entry: E, size: u64) -> Vec<E> {
+        let secondary = secondary_key(&vary, headers);
+        let resource = self.resources.entry(key.to_string()).or_insert_with(|| Resource { vary: vary.clone(), variants: HashMap::new() });
+        // A change of Vary leaves the old variants unreachable.
+        let replaced: Vec<Slot<E>> = if resource.vary != vary {
+            resource.vary = vary;
+            resource.variants.drain().map(|(_, slot)| slot).collect()
+        } else {
+            resource.variants.remove(&secondary).into_iter().collect()
+        };
+        self.tick += 1;
+        resource.variants.insert(secondary.clone(), Slot { entry, size, last_used: self.tick });
+        self.recency.insert(self.tick, (key.to_string(), secondary));
+        self.size += size;
+        replaced.into_iter().map(|slot| self.forget(slot)).collect()
+    }
+
+    /// Drop every variant of `key`.
+    pub fn remove(&mut self, key: &str) -> Vec<E> {
+        let Some(resource) = self.resources.remove(key) else {
+            return Vec::new();
+        };
+        resource.variants.into_values().map(|slot| self.forget(slot)).collect()
+    }
+
+    /// Drop every variant of the keys that match.
+    pub fn remove_matching(&mut self, matches: impl Fn(&str) -> bool) -> Vec<E> {
+        let keys: Vec<String> = self.resources.keys().filter(|key| matches(key)).cloned().collect();
+        keys.iter().flat_map(|key| self.remove(key)).collect()
+    }
+
+    /// The number of variants.
+    pub fn entries(&self) -> usize {
+        self.recency.len()
+    }
+
+    /// Their bytes in all.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Drop the least recently used variant.
+    pub fn pop_oldest(&mut self) -> Option<E> {
+        let (_, (key, secondary)) = self.recency.pop_first()?;
+        let resource = self.resources.get_mut(&key)?;
+        let slot = resource.variants.remove(&secondary)?;
+        if resource.variants.is_empty() {
+            self.resources.remove(&key);
+        }
+        self.size -= slot.size;
+        Some(slot.entry)
+    }
+
+    /// Drop the least recently used variants until `size` more bytes fit
+    /// in `capacity`; returns them.
+    pub fn make_room(&mut self, size: u64, capacity: u64) -> Vec<E> {
+        let mut evicted = Vec::new();
+        while self.size + size > capacity {
+            match self.pop_oldest() {
+                Some(entry) => evicted.push(entry),
+                None => break,
+            }
+        }
+        evicted
+    }
+
+    fn forget(&mut self, slot: Slot<E>) -> E {
+        self.recency.remove(&slot.last_used);
+        self.size -= slot.size;
+        slot.entry
+    }
+}
+
+
+/// Whether a shared cache may keep `response` to `request` at all (RFC
+/// 7234 3).
+fn storable(request: &CacheRequest, response: &Response<Body>) -> bool {
+    let headers = response.headers();
+    let directives = Directives::parse(headers);
+    if request.method != Method::GET
+        // Partial and not-modified responses don't stand on their own.
+        || matches!(response.status(), StatusCode::PARTIAL_CONTENT | StatusCode::NOT_MODIFIED)
+        || directives.no_store
+        || directives.private
+        || headers.contains_key(SET_COOKIE)
+        || headers.get_all(VARY).iter().any(|value| value.to_str().is_ok_and(|value| value.split(',').any(|name| name.trim() == "*")))
+    {
+        return false;
+    }
+    !request.headers.contains_key(AUTHORIZATION) || directives.public || directives.s_maxage.is_some() || directives.must_revalidate
+}
+
+/// How long a response with `headers` stays fresh (RFC 7234 4.2.1): from
+/// s-maxage or max-age, else Expires, else a tenth of the time since it
+/// was last modified. Zero for no-cache, which is revalidated every time.
+fn freshness_lifetime(status: StatusCode, headers: &HeaderMap) -> Duration {
+    let directives = Directives::parse(headers);
+    if directives.no_cache {
+        return Duration::ZERO;
+    }
+    let date = http_date(headers, DATE);
+    match (directives.s_maxage.or(directives.max_age), headers.get(EXPIRES)) {
+        (Some(max_age), _) => max_age,
+        // An invalid date means already expired.
+        (None, Some(_)) => http_date(headers, EXPIRES)
+            .and_then(|expires| expires.duration_since(date.unwrap_or_else(SystemTime::now)).ok())
+            .unwrap_or_default(),
+        (None, None) if HEURISTIC_STATUSES.contains(&status.as_u16()) => {
+            let Some(last_modified) = http_date(headers, LAST_MODIFIED) else {
+                return Duration::ZERO;
+            };
+            let date = date.unwrap_or_else(SystemTime::now);
+            (date.duration_since(last_modified).unwrap_or_default() / 10).min(HEURISTIC_MAX)
+        }
+        (None, None) => Duration::ZERO,
+    }
+}
+
+/// How old a response was when it came in (RFC 7234 4.2.3).
+fn initial_age(headers: &HeaderMap, request_time: SystemTime, response_time: SystemTime) -> Duration {
+    let apparent_age = http_date(headers, DATE)
+        .and_then(|date| response_time.duration_since(date).ok())
+        .unwrap_or_default();
+    let age_value = headers.get(AGE)
+        .and_then(|value| delta_seconds(value.to_str().ok()?.trim()))
+        .unwrap_or_default();
+    let response_delay = response_time.duration_since(request_time).unwrap_or_default();
+    apparent_age.max(age_value.saturating_add(response_delay))
+}
+
+/// A number of seconds as in Age and max-age, capped at
+/// `MAX_DELTA_SECONDS`.
+fn delta_seconds(value: &str) -> Option<Duration> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let secs = value.parse::<u64>().unwrap_or(u64::MAX).min(MAX_DELTA_SECONDS);
+    Some(Duration::from_secs(secs))
+}
+
+fn http_date(headers: &HeaderMap, name: HeaderName) -> Option<SystemTime> {
+    httpdate::parse_http_date(headers.get(name)?.to_str().ok()?).ok()
+}
+
+pub fn vary_names(headers: &HeaderMap) -> Vec<HeaderName> {
+    let mut names: Vec<HeaderName> = headers.get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    names.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    names.dedup();
+    names
+}
+
+/// What a request sent for each header in `vary`, to tell variants apart.
+fn secondary_key(vary: &[HeaderName], headers: &HeaderMap) -> String {
+    let mut key = String::new();
+    for name in vary {
+        let values: Vec<&str> = headers.get_all(name).iter().filter_map(|value| value.to_str().ok()).map(str::trim).collect();
+        key.push_str(&values.join(","));
+        key.push('\n');
+    }
+    key
+}
+
+/*************************************************
+ * Directives
+ *************************************************/
+
+/// The Cache-Control directives the cache acts on, of a request or a
+/// response.
+#[derive(Clone, Default)]
+struct Directives {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    public: bool,
+    must_revalidate: bool,
+    max_age: Option<Duration>,
+    s_maxage: Option<Duration>,
+    min_fresh: Option<Duration>,
+    /// `Some(None)` accepts a response however stale.
+    max_stale: Option<Option<Duration>>,
+    only_if_cached: bool,
+    /// How long past its freshness a response may be served while it is
+    /// revalidated (RFC 5861).
+    stale_while_revalidate: Option<Duration>,
+}
+
+impl Directives {
+    fn parse(headers: &HeaderMap) -> Self {
+        let mut directives = Directives::default();
+        let tokens = headers.get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','));
+        for token in tokens {
+            let (name, value) = match token.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (token.trim(), None),
+            };
+            let secs = value.and_then(delta_seconds);
+            match name.to_ascii_lowercase().as_str() {
+                "no-store" => directives.no_store = true,
+                // The qualified form names headers not to reuse; treating
+                // it as plain no-cache is the safe reading.
+                "no-cache" => directives.no_cache = true,
+                "private" => directives.private = true,
+                "public" => directives.public = true,
+                "must-revalidate" | "proxy-revalidate" => directives.must_revalidate = true,
+                // An invalid age counts as already expired.
+                "max-age" => directives.max_age = Some(secs.unwrap_or_default()),
+                "s-maxage" => directives.s_maxage = Some(secs.unwrap_or_default()),
+                "min-fresh" => directives.min_fresh = secs,
+                "max-stale" => directives.max_stale = Some(secs),
+                "only-if-cached" => directives.only_if_cached = true,
+                "stale-while-revalidate" => directives.stale_while_revalidate = secs,
+                _ => {}
+            }
+        }
+        directives
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(HeaderName::from_bytes(name.as_bytes()).unwrap(), HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn caps_oversized_age() {
+        let now = SystemTime::now();
+        for age in ["18446744073709551615", "99999999999999999999999"] {
+            let meta = Meta::new(StatusCode::OK, headers(&[("cache-control", "max-age=60"), ("age", age)]), now, now);
+            assert_eq!(meta.initial_age, Duration::from_secs(MAX_DELTA_SECONDS));
+            assert_eq!(meta.age(now + Duration::from_secs(1)), Duration::from_secs(MAX_DELTA_SECONDS + 1));
+            assert_eq!(meta.response(Body::empty()).headers()[AGE], (MAX_DELTA_SECONDS).to_string().as_str());
+        }
+
+        let meta = Meta { initial_age: Duration::MAX, ..Meta::new(StatusCode::OK, HeaderMap::new(), now, now) };
+        assert_eq!(meta.age(now + Duration::from_secs(1)), Duration::MAX);
+    }
+
+    fn request(pairs: &[(&str, &str)]) -> CacheRequest {
+        let mut request = Request::get("http://example.com/page").body(()).unwrap();
+        *request.headers_mut() = headers(pairs);
+        CacheRequest::new(&request).unwrap()
+    }
+
+    fn response(status: u16, pairs: &[(&str, &str)]) -> Response<Body> {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::from_u16(status).unwrap();
+        *response.headers_mut() = headers(pairs);
+        response
+    }
+
+    /// The meta of a 200 with `pairs` that came in `age` seconds ago.
+    fn meta(pairs: &[(&str, &str)], age: u64) -> Meta {
+        let then = SystemTime::now() - Duration::from_secs(age);
+        Meta::new(StatusCode::OK, headers(pairs), then, then)
+    }
+
+    fn date(time: SystemTime) -> String {
+        httpdate::fmt_http_date(time)
+    }
+
+    #[test]
+    fn stores_only_shareable_responses() {
+        let plain = request(&[]);
+        assert!(storable(&plain, &response(200, &[("cache-control", "max-age=60")])));
+        assert!(!storable(&plain, &response(200, &[("cache-control", "private, max-age=60")])));
+        assert!(!storable(&plain, &response(200, &[("cache-control", "no-store")])));
+        assert!(!storable(&plain, &response(200, &[("cache-control", "max-age=60"), ("set-cookie", "id=1")])));
+        assert!(!storable(&plain, &response(200, &[("cache-control", "max-age=60"), ("vary", "*")])));
+        assert!(!storable(&plain, &response(200, &[("cache-control", "max-age=60"), ("vary", "Accept, *")])));
+        assert!(storable(&plain, &response(200, &[("cache-control", "max-age=60"), ("vary", "Accept")])));
+        assert!(!storable(&plain, &response(206, &[("cache-control", "max-age=60")])));
+
+        let authorized = request(&[("authorization", "Basic dTpw")]);
+        assert!(!storable(&authorized, &response(200, &[("cache-control", "max-age=60")])));
+        assert!(storable(&authorized, &response(200, &[("cache-control", "public, max-age=60")])));
+        assert!(storable(&authorized, &response(200, &[("cache-control", "s-maxage=60")])));
+        assert!(storable(&authorized, &response(200, &[("cache-control", "must-revalidate")])));
+
+        let head = CacheRequest::new(&Request::head("http://example.com/page").body(()).unwrap()).unwrap();
+        assert!(!storable(&head, &response(200, &[("cache-control", "max-age=60")])));
+        assert!(CacheRequest::new(&Request::post("http://example.com/page").body(()).unwrap()).is_none());
+        assert!(CacheRequest::new(&Request::get("http://example.com/page").header("range", "bytes=0-1").body(()).unwrap()).is_none());
+    }
+
+    #[test]
+    fn computes_freshness_lifetime() {
+        let ok = StatusCode::OK;
+        let now = SystemTime::now();
+        let secs = Duration::from_secs;
+        assert_eq!(freshness_lifetime(ok, &headers(&[("cache-control", "max-age=60")])), secs(60));
+        assert_eq!(freshness_lifetime(ok, &headers(&[("cache-control", "max-age=60, s-maxage=10")])), secs(10));
+        assert_eq!(freshness_lifetime(ok, &headers(&[("cache-control", "max-age=abc")])), Duration::ZERO);
+        assert_eq!(freshness_lifetime(ok, &headers(&[("cache-control", "no-cache, max-age=60")])), Duration::ZERO);
+
+        // Expires counts from Date, and loses to max-age.
+        let (today, later, earlier) = (date(now), date(now + secs(120)), date(now - secs(120)));
+        assert_eq!(freshness_lifetime(ok, &headers(&[("date", &today), ("expires", &later)])), secs(120));
+        assert_eq!(freshness_lifetime(ok, &headers(&[("date", &today), ("expires", &later), ("cache-control", "max-age=5")])), secs(5));
+        assert_eq!(freshness_lifetime(ok, &headers(&[("date", &today), ("expires", &earlier)])), Duration::ZERO);
+        assert_eq!(freshness_lifetime(ok, &headers(&[("expires", "0")])), Duration::ZERO);
+
+        // A tenth of the time since Last-Modified, up to a day.
+        let modified = date(now - secs(5 * 3600));
+        assert_eq!(freshness_lifetime(ok, &headers(&[("date", &today), ("last-modified", &modified)])), secs(1800));
+        let modified = date(now - secs(30 * 24 * 3600));
+        assert_eq!(freshness_lifetime(ok, &headers(&[("date", &today), ("last-modified", &modified)])), HEURISTIC_MAX);
+        assert_eq!(freshness_lifetime(StatusCode::FOUND, &headers(&[("date", &today), ("last-modified", &modified)])), Duration::ZERO);
+        assert_eq!(freshness_lifetime(ok, &headers(&[("date", &today)])), Duration::ZERO);
+    }
+
+    #[test]
+    fn fresh_responses_are_used_within_the_request_limits() {
+        let fresh = meta(&[("cache-control", "max-age=60")], 10);
+        assert!(matches!(fresh.usability(&request(&[])), Usability::Fresh));
+        assert!(matches!(fresh.usability(&request(&[("cache-control", "min-fresh=30")])), Usability::Fresh));
+        assert!(matches!(fresh.usability(&request(&[("cache-control", "min-fresh=55")])), Usability::Stale));
+        assert!(matches!(fresh.usability(&request(&[("cache-control", "max-age=5")])), Usability::Stale));
+        assert!(matches!(fresh.usability(&request(&[("cache-control", "no-cache")])), Usability::Stale));
+        assert!(matches!(fresh.usability(&request(&[("pragma", "no-cache")])), Usability::Stale));
+    }
+
+    #[test]
+    fn stale_responses_are_used_within_max_stale() {
+        // Stale by 40 seconds.
+        let stale = meta(&[("cache-control", "max-age=60")], 100);
+        assert!(matches!(stale.usability(&request(&[])), Usability::Stale));
+        assert!(matches!(stale.usability(&request(&[("cache-control", "max-stale=50")])), Usability::Fresh));
+        assert!(matches!(stale.usability(&request(&[("cache-control", "max-stale=30")])), Usability::Stale));
+        assert!(matches!(stale.usability(&request(&[("cache-control", "max-stale")])), Usability::Fresh));
+
+        let must_revalidate = meta(&[("cache-control", "max-age=60, must-revalidate")], 100);
+        assert!(matches!(must_revalidate.usability(&request(&[("cache-control", "max-stale")])), Usability::Stale));
+        let shared = meta(&[("cache-control", "s-maxage=60")], 100);
+        assert!(matches!(shared.usability(&request(&[("cache-control", "max-stale")])), Usability::Stale));
+    }
+
+    #[test]
+    fn stale_while_revalidate_covers_its_window() {
+        let within = meta(&[("cache-control", "max-age=60, stale-while-revalidate=60")], 100);
+        assert!(matches!(within.usability(&request(&[])), Usability::StaleWhileRevalidate));
+        let past = meta(&[("cache-control", "max-age=60, stale-while-revalidate=30")], 100);
+        assert!(matches!(past.usability(&request(&[])), Usability::Stale));
+        let must_revalidate = meta(&[("cache-control", "max-age=60, stale-while-revalidate=60, must-revalidate")], 100);
+        assert!(matches!(must_revalidate.usability(&request(&[])), Usability::Stale));
+    }
+
+    #[test]
+    fn compares_entity_tags_weakly() {
+        let stored = headers(&[("etag", "W/\"v1\"")]);
+        assert!(not_modified(&headers(&[("if-none-match", "\"v1\"")]), &stored));
+        assert!(not_modified(&headers(&[("if-none-match", "W/\"v1\"")]), &stored));
+        assert!(not_modified(&headers(&[("if-none-match", "\"v0\", W/\"v1\"")]), &stored));
+        assert!(not_modified(&headers(&[("if-none-match", "*")]), &stored));
+        assert!(!not_modified(&headers(&[("if-none-match", "\"v2\"")]), &stored));
+        assert!(!not_modified(&headers(&[("if-none-match", "\"v1\"")]), &HeaderMap::new()));
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence_over_dates() {
+        let now = SystemTime::now();
+        let stored = headers(&[("etag", "\"v1\""), ("last-modified", &date(now - Duration::from_secs(3600)))]);
+        assert!(not_modified(&headers(&[("if-modified-since", &date(now))]), &stored));
+        assert!(!not_modified(&headers(&[("if-modified-since", &date(now - Duration::from_secs(7200)))]), &stored));
+        assert!(!not_modified(&headers(&[("if-none-match", "\"v2\""), ("if-modified-since", &date(now))]), &stored));
+    }
+
+    #[test]
+    fn index_keeps_a_variant_per_vary_value() {
+        let accept = vec![HeaderName::from_static("accept-encoding")];
+        let gzip = headers(&[("accept-encoding", "gzip")]);
+        let br = headers(&[("accept-encoding", "br")]);
+        let mut index = Index::default();
+        assert!(index.insert("GET /a", &gzip, accept.clone(), "gzip", 10).is_empty());
+        assert!(index.insert("GET /a", &br, accept.clone(), "br", 20).is_empty());
+        assert_eq!(index.get("GET /a", &gzip), Some(&mut "gzip"));
+        assert_eq!(index.get("GET /a", &br), Some(&mut "br"));
+        assert_eq!(index.get("GET /a", &HeaderMap::new()), None);
+        assert_eq!((index.entries(), index.size()), (2, 30));
+
+        // The same variant again replaces it.
+        assert_eq!(index.insert("GET /a", &gzip, accept.clone(), "gzip 2", 5), ["gzip"]);
+        assert_eq!((index.entries(), index.size()), (2, 25));
+
+        // A change of Vary drops every old variant.
+        let mut replaced = index.insert("GET /a", &gzip, Vec::new(), "plain", 1);
+        replaced.sort();
+        assert_eq!(replaced, ["br", "gzip 2"]);
+        assert_eq!(index.get("GET /a", &br), Some(&mut "plain"));
+        assert_eq!((index.entries(), index.size()), (1, 1));
+    }
+
+    #[test]
+    fn index_evicts_least_recently_used_first() {
+        let none = HeaderMap::new();
+        let mut index = Index::default();
+        index.insert("GET /a", &none, Vec::new(), "a", 10);
+        index.insert("GET /b", &none, Vec::new(), "b", 10);
+        index.insert("GET /c", &none, Vec::new(), "c", 10);
+        index.get("GET /a", &none);
+        assert_eq!(index.make_room(15, 30), ["b", "c"]);
+        assert_eq!((index.entries(), index.size()), (1, 10));
+        assert_eq!(index.remove("GET /a"), ["a"]);
+        assert_eq!(index.pop_oldest(), None);
+        assert_eq!(index.size(), 0);
+    }
+}
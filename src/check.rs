@@ -0,0 +1,269 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use hyper::Uri;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::ServerName;
+use tokio_rustls::TlsConnector;
+use x509_parser::prelude::{FromDer, X509Certificate};
+use std::error::Error;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::bench::Mode;
+use crate::probe::{millis, read_body, read_head_text, status};
+use crate::tls::OriginTls;
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Most of the response body read to confirm it arrives.
+const MAX_BODY: u64 = 64 * 1024;
+
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+/*************************************************
+ * Check
+ *************************************************/
+
+/// One connectivity check, reported phase by phase as it goes.
+struct Check {
+    start: Instant,
+    last: Instant,
+    timeout: Duration,
+}
+
+impl Check {
+    /// Run `phase` within the timeout and print how long it took; a
+    /// failure is printed and ends the check.
+    async fn phase<T, F>(&mut self, name: &str, phase: F) -> Result<T, Box<dyn Error>>
+    where
+        F: Future<Output = Result<(T, String), Box<dyn Error + Send + Sync>>>,
+    {
+        let result = tokio::time::timeout(self.timeout, phase).await
+            .unwrap_or_else(|_| Err(format!("Timed out after {}s", self.timeout.as_secs()).into()));
+        let now = Instant::now();
+        let took = now - self.last;
+        self.last = now;
+        match result {
+            Ok((value, detail)) => {
+                println!("  {:<12} {:>9.2}ms {:>9.2}ms  {}", name, millis(took), millis(now - self.start), detail);
+                Ok(value)
+            }
+            Err(e) => {
+                println!("  {:<12} {:>9.2}ms {:>9.2}ms  FAILED: {}", name, millis(took), millis(now - self.start), e);
+                Err(format!("Error: {} failed", name).into())
+            }
+        }
+    }
+}
+
+/*************************************************
+ * run
+ *************************************************/
+
+/// Handle `rdnat check --proxy [https://]<host>:<port> --target <url>
+/// [--mode connect|get] [-a <user> <passwd>] [--ca-bundle <path>]
+/// [--insecure] [-t <secs>]`: fetch the target through the proxy once,
+/// timing each phase (connecting, TLS to the proxy, the CONNECT with its
+/// credentials, TLS with the origin, the first byte and the body) and
+/// showing what each one found, so a failure points at the step that
+/// broke. https targets always go through a CONNECT tunnel.
+pub async fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut proxy = None;
+    let mut target = None;
+    let mut mode = None;
+    let mut credentials = None;
+    let mut origin_tls = OriginTls::default();
+    let mut timeout = DEFAULT_TIMEOUT;
+
+    let mut i = 0;
+    while i < args.len() {
+        let value = args.get(i + 1).cloned();
+        match (args[i].as_str(), value) {
+            ("-x" | "--proxy", Some(value)) => proxy = Some(value),
+            ("--target", Some(value)) => target = Some(value),
+            ("--mode", Some(value)) => {
+                mode = match value.as_str() {
+                    "get" => Some(Mode::Get),
+                    "connect" => Some(Mode::Connect),
+                    _ => return Err(format!("Error: Invalid check mode: {}", value).into()),
+                }
+            }
+            ("-a" | "--auth", Some(user)) => {
+                let passwd = args.get(i + 2).ok_or("Error: Missing password for --auth")?;
+                credentials = Some(format!("{}:{}", user, passwd));
+                i += 3;
+                continue;
+            }
+            ("--ca-bundle", Some(value)) => origin_tls.set_ca_bundle(&value),
+            ("--insecure", _) => {
+                origin_tls.set_insecure();
+                i += 1;
+                continue;
+            }
+            ("-t" | "--timeout", Some(value)) => timeout = Duration::from_secs(value.parse()?),
+            (arg, _) => return Err(format!("Error: Invalid check argument: {}", arg).into()),
+        }
+        i += 2;
+    }
+
+    let proxy = proxy.ok_or("Error: Expected a proxy to check with --proxy")?;
+    let (proxy_tls, proxy_address) = match proxy.split_once("://") {
+        Some(("https", address)) => (true, address.trim_end_matches('/')),
+        Some(("http", address)) => (false, address.trim_end_matches('/')),
+        Some(_) => return Err(format!("Error: Proxy must be host:port or an http:// or https:// URL: {}", proxy).into()),
+        None => (false, proxy.as_str()),
+    };
+    let proxy_host = proxy_address.rsplit_once(':').map_or(proxy_address, |(host, _)| host).trim_matches(['[', ']']);
+    let target = target.ok_or("Error: Expected a URL to fetch with --target")?;
+    let uri: Uri = target.parse().map_err(|_| format!("Error: Invalid URL: {}", target))?;
+    let https = match uri.scheme_str() {
+        Some("https") => true,
+        Some("http") => false,
+        _ => return Err(format!("Error: Expected an http:// or https:// URL, got: {}", target).into()),
+    };
+    let host = uri.host().ok_or_else(|| format!("Error: Invalid URL: {}", target))?;
+    let authority = format!("{}:{}", host, uri.port_u16().unwrap_or(if https { 443 } else { 80 }));
+    let mode = match mode {
+        Some(Mode::Get) if https => return Err("Error: https targets can only be checked through a CONNECT tunnel".into()),
+        Some(mode) => mode,
+        None if https => Mode::Connect,
+        None => Mode::Get,
+    };
+    let proxy_authorization = credentials.map(|credentials| format!("Proxy-Authorization: Basic {}\r\n", base64::encode(credentials)));
+    let tls = Arc::new(origin_tls.client_config(None)?);
+
+    println!("Checking {} through {} ({})", target, proxy, if mode == Mode::Connect { "CONNECT" } else { "GET" });
+    println!("  {:<12} {:>11} {:>11}  Result", "Phase", "Took", "Total");
+    let now = Instant::now();
+    let mut check = Check { start: now, last: now, timeout };
+
+    let stream = check.phase("Connect", async {
+        let stream = TcpStream::connect(proxy_address).await?;
+        stream.set_nodelay(true)?;
+        let detail = format!("{} from {}", stream.peer_addr()?, stream.local_addr()?);
+        Ok((stream, detail))
+    }).await?;
+    let mut stream: Box<dyn Io> = match proxy_tls {
+        true => Box::new(check.phase("Proxy TLS", handshake(&tls, proxy_host, stream)).await?),
+        false => Box::new(stream),
+    };
+
+    let authorization = proxy_authorization.as_deref().unwrap_or_default();
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    let request_target = match mode {
+        Mode::Connect => {
+            check.phase("Tunnel", async {
+                let connect = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n{1}\r\n", authority, authorization);
+                stream.write_all(connect.as_bytes()).await?;
+                let (head, rest) = read_head_text(&mut stream).await?;
+                let status_line = head.lines().next().unwrap_or_default();
+                match status(&head) {
+                    Some(200..=299) => {}
+                    Some(407) => return Err(rejected(status_line, !authorization.is_empty())),
+                    _ => return Err(with_reason(status_line, &rest).into()),
+                }
+                if !rest.is_empty() {
+                    return Err("The proxy sent data before the tunnel was used".into());
+                }
+                let credentials = if authorization.is_empty() { "" } else { ", credentials accepted" };
+                Ok(((), format!("Tunnel to {} open: {}{}", authority, status_line, credentials)))
+            }).await?;
+            if https {
+                stream = Box::new(check.phase("Origin TLS", handshake(&tls, host, stream)).await?);
+            }
+            path
+        }
+        Mode::Get => target.as_str(),
+    };
+
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: rdnat-check\r\nAccept: */*\r\nConnection: close\r\n{}\r\n",
+        request_target,
+        uri.authority().unwrap(),
+        if mode == Mode::Get { authorization } else { "" }
+    );
+    let (code, rest) = check.phase("First byte", async {
+        stream.write_all(request.as_bytes()).await?;
+        let (head, rest) = read_head_text(&mut stream).await?;
+        let code = status(&head).ok_or("Invalid response head")?;
+        let status_line = head.lines().next().unwrap_or_default();
+        // A proxied GET learns about the credentials only now.
+        let detail = match mode {
+            Mode::Get if code == 407 => return Err(rejected(status_line, !authorization.is_empty())),
+            Mode::Get if !authorization.is_empty() => format!("{}, credentials accepted", status_line),
+            _ => status_line.to_string(),
+        };
+        let via = head.lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("via"))
+            .map(|(_, value)| format!(" (via {})", value.trim()))
+            .unwrap_or_default();
+        Ok(((code, rest), format!("{}{}", detail, via)))
+    }).await?;
+    check.phase("Body", async {
+        let bytes = read_body(&mut stream, rest.len() as u64, MAX_BODY, None).await?;
+        if bytes < MAX_BODY {
+            Ok(((), format!("{} bytes", bytes)))
+        } else {
+            Ok(((), format!("{}+ bytes, stopped reading", bytes)))
+        }
+    }).await?;
+    if code >= 400 {
+        return Err(format!("Error: {} answered with {}", target, code).into());
+    }
+    println!("OK");
+    Ok(())
+}
+
+/// Make the TLS handshake with `host` over `stream`, verifying its
+/// certificate; what was negotiated and whom the certificate is for.
+async fn handshake<S: Io>(
+    tls: &Arc<tokio_rustls::rustls::ClientConfig>,
+    host: &str,
+    stream: S,
+) -> Result<(tokio_rustls::client::TlsStream<S>, String), Box<dyn Error + Send + Sync>> {
+    let server_name = ServerName::try_from(host).map_err(|_| format!("Invalid server name: {}", host))?;
+    let stream = TlsConnector::from(tls.clone()).connect(server_name, stream).await?;
+    let (_, connection) = stream.get_ref();
+    let mut detail = format!(
+        "{:?}, {:?}",
+        connection.protocol_version().ok_or("No protocol negotiated")?,
+        connection.negotiated_cipher_suite().ok_or("No cipher suite negotiated")?.suite()
+    );
+    if let Some(alpn) = connection.alpn_protocol() {
+        detail.push_str(&format!(", ALPN {}", String::from_utf8_lossy(alpn)));
+    }
+    let certificate = connection.peer_certificates()
+        .and_then(|certificates| certificates.first())
+        .and_then(|certificate| X509Certificate::from_der(&certificate.0).ok());
+    if let Some((_, certificate)) = certificate {
+        detail.push_str(&format!("; certificate {} issued by {}, expires {}", certificate.subject(), certificate.issuer(), certificate.validity().not_after));
+    }
+    Ok((stream, detail))
+}
+
+/// A status line with the reason an error response gives in its body,
+/// as rdnat's own errors do on their last line.
+fn with_reason(status_line: &str, body: &[u8]) -> String {
+    let body = String::from_utf8_lossy(body);
+    match body.lines().map(str::trim).rfind(|line| !line.is_empty() && *line != status_line.split_once(' ').map_or("", |(_, rest)| rest)) {
+        Some(reason) => format!("{}: {}", status_line, reason.chars().take(200).collect::<String>()),
+        None => status_line.to_string(),
+    }
+}
+
+/// The failure a `407` stands for, with a hint at what to do.
+fn rejected(status_line: &str, authenticated: bool) -> Box<dyn Error + Send + Sync> {
+    match authenticated {
+        true => format!("{}: the credentials were rejected", status_line).into(),
+        false => format!("{}: the proxy wants credentials, pass them with -a <user> <passwd>", status_line).into(),
+    }
+}
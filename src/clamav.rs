@@ -0,0 +1,81 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use std::error::Error;
+use std::time::Duration;
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+/// Default largest download scanned; clamd's own StreamMaxLength defaults
+/// to 25 MiB too. Bigger downloads pass unscanned.
+pub const DEFAULT_MAX_SCAN_SIZE: usize = 25 * 1024 * 1024;
+/// Size of the INSTREAM chunks sent to clamd.
+const STREAM_CHUNK: usize = 64 * 1024;
+const SCAN_TIMEOUT: Duration = Duration::from_secs(60);
+
+type ScanError = Box<dyn Error + Send + Sync>;
+
+/*************************************************
+ * Clamd
+ *************************************************/
+
+/// A clamd daemon, reached over its local socket or `host:port`.
+pub enum Clamd {
+    Unix(String),
+    Tcp(String),
+}
+
+impl Clamd {
+    /// A path (anything containing `/`) or `host:port`.
+    pub fn parse(value: &str) -> Self {
+        if value.contains('/') {
+            Clamd::Unix(value.to_string())
+        } else {
+            Clamd::Tcp(value.to_string())
+        }
+    }
+
+    /// Scan `data`; returns the signature name when it is infected.
+    pub async fn scan(&self, data: &[u8]) -> Result<Option<String>, ScanError> {
+        let scan = async {
+            match self {
+                #[cfg(unix)]
+                Clamd::Unix(path) => instream(UnixStream::connect(path).await?, data).await,
+                #[cfg(not(unix))]
+                Clamd::Unix(path) => Err(format!("clamd socket {} needs a Unix platform; give host:port", path).into()),
+                Clamd::Tcp(address) => instream(TcpStream::connect(address).await?, data).await,
+            }
+        };
+        tokio::time::timeout(SCAN_TIMEOUT, scan).await.map_err(|_| "clamd timed out")?
+    }
+}
+
+/// Run the INSTREAM command: length-prefixed chunks, then a zero length.
+async fn instream<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, data: &[u8]) -> Result<Option<String>, ScanError> {
+    stream.write_all(b"zINSTREAM\0").await?;
+    for chunk in data.chunks(STREAM_CHUNK) {
+        stream.write_all(&(chunk.len() as u32).to_be_bytes()).await?;
+        stream.write_all(chunk).await?;
+    }
+    stream.write_all(&[0; 4]).await?;
+
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).await?;
+    let reply = String::from_utf8_lossy(&reply);
+    let reply = reply.trim_end_matches(['\0', '\n']);
+    let result = reply.strip_prefix("stream: ").unwrap_or(reply);
+    if result == "OK" {
+        Ok(None)
+    } else if let Some(signature) = result.strip_suffix(" FOUND") {
+        Ok(Some(signature.to_string()))
+    } else {
+        Err(format!("clamd answered: {}", reply).into())
+    }
+}
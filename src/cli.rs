@@ -0,0 +1,991 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use hyper::StatusCode;
+use crate::acme::ChallengeType;
+use crate::adblock::FilterList;
+use crate::clamav::Clamd;
+use crate::decoy::Decoy;
+use crate::dns::Server;
+use crate::dnsproxy::BlockResponse;
+use crate::domains::DomainList;
+use crate::icap::IcapService;
+use crate::logfilter::LogAction;
+use crate::outbound::Rotation;
+use crate::remote::{ListFormat, RemoteList};
+use crate::replay::Replay;
+use crate::rules::{parse_ports, parse_size, Timezone};
+use crate::sockopt::Keepalive;
+use crate::throttle::Link;
+use crate::upstream::{Balance, Pool, Probe, Sticky};
+use crate::{accounting, acme, bench, breaker, bufpool, ca, cache, check, compress, discovery, dnsproxy, outbound, relay, socks, speedtest, upstream, whoami};
+#[cfg(unix)]
+use crate::ctl;
+use crate::{build_runtime, serve, ClientAddrHeader, Config};
+use crate::{DEFAULT_LOGPATH, DEFAULT_POOL_IDLE_TIMEOUT_SECS, DEFAULT_POOL_MAX_IDLE, DEFAULT_THREAD_NAME, DEFAULT_TLS_WATCH_SECS};
+
+/*************************************************
+ * run
+ *************************************************/
+
+/// Run the `rdnat` command line: `args` as the binary gets them, program
+/// name first.
+pub fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut config = Config::default();
+
+    if args.len() > 1 && args[1] == "usage" {
+        return accounting::export_usage(&args[2..]);
+    }
+    if args.len() > 1 && args[1] == "ctl" {
+        #[cfg(unix)]
+        return build_runtime(&config)?.block_on(ctl::run(&args[2..]));
+        #[cfg(not(unix))]
+        return Err("Error: The control socket is only supported on Unix platforms".into());
+    }
+    if args.len() > 1 && args[1] == "ca" {
+        return ca::run(&args[2..]);
+    }
+    if args.len() > 1 && args[1] == "bench" {
+        return build_runtime(&config)?.block_on(bench::run(&args[2..]));
+    }
+    if args.len() > 1 && args[1] == "check" {
+        return build_runtime(&config)?.block_on(check::run(&args[2..]));
+    }
+    if args.len() > 1 && args[1] == "socks" {
+        return build_runtime(&config)?.block_on(socks::run(&args[2..]));
+    }
+    if args.len() > 1 && args[1] == "speedtest" {
+        return build_runtime(&config)?.block_on(speedtest::run(&args[2..]));
+    }
+
+    banner();
+    parse_arguments(args, &mut config)?;
+    build_runtime(&config)?.block_on(serve(config, true, tokio::signal::ctrl_c()))
+}
+
+/*************************************************
+ * banner
+ *************************************************/
+
+fn banner() {
+    println!("
+            ____  ____  _   _____  ______
+           / __ \\/ __ \\/ | / /   |/_  __/
+          / /_/ / / / /  |/ / /| | / /
+         / _, _/ /_/ / /|  / ___ |/ /
+        /_/ |_/_____/_/ |_/_/  |_/_/
+    ");
+}
+
+/*************************************************
+ * help
+ *************************************************/
+
+fn help() {
+    println!("Usage: rdnat [options] <username> <password>");
+    println!("       rdnat usage [-f <file>] [-u <user>] [--since <date>] [--until <date>]");
+    println!("       rdnat ctl [-s <socket>] <command>");
+    println!("       rdnat ca [--cert <path>] [--key <path>] init|rotate|export [--der] [-o <file>]");
+    println!("       rdnat bench [-x <proxy>] [--mode get|connect] [-c <n>] [-n <n> | -d <secs>] [-a <username> <password>] <url>");
+    println!("       rdnat check --proxy [https://]<host>:<port> --target <url> [--mode connect|get] [-a <username> <password>]");
+    println!("                   [--ca-bundle <path>] [--insecure] [-t <secs>]");
+    println!("       rdnat socks --via [http://]<host>:<port> [-l <addr>] [-a <username> <password>] [--login <username> <password>] [-v]");
+    println!("       rdnat speedtest --via <proxy> [--mode connect|get] [-n <samples>] [-d <secs>] [-a <username> <password>] [--no-direct] <url>");
+    println!();
+    println!("Options:");
+    println!("  -p <port>              Specify the port on which the proxy server will listen (default is 8000 if not provided)");
+    println!("  -a <username> <password>  Specify the username and password for proxy authentication");
+    println!("  -d, --debug            Enable debug logging to a log file (default log file is 'rdnat.log' in the current directory)");
+    println!("  --usage-file <path>    Record per-user traffic and persist it to <path> for export with 'rdnat usage'");
+    println!("  --usage-flush <secs>   Interval between usage flushes to disk (default is 60)");
+    println!("  --usage-quota <size>   Refuse a user's requests for the rest of the day once they have transferred <size>");
+    println!("  --ctl-socket <path>    Take 'rdnat ctl' commands on a Unix socket at <path>, for this user only (default is none)");
+    println!("  --webhook <url>        POST a JSON event to <url> on repeated auth failures, upstream outages, used-up quotas or dropped tunnels (repeatable)");
+    println!("  --webhook-template <path>  JSON payload template with {{{{event}}}}, {{{{message}}}} and {{{{time}}}} placeholders");
+    println!("  --webhook-auth-failures <n>  Failed logins from one client within a minute before notifying (default is 5)");
+    println!("  --webhook-upstream-failures <n>  Consecutive connect failures to a target before notifying (default is 3)");
+    println!("  --geoip-db <path>      MaxMind country (or city) database used to annotate logs and stats");
+    println!("  --geoip-asn-db <path>  MaxMind ASN database used to annotate logs and stats");
+    println!("  --resolve-clients      Include the reverse-DNS name of clients in access logs");
+    println!("  --rdns-timeout <ms>    Timeout for reverse-DNS lookups (default is 500)");
+    println!("  --log-level <level>    Initial log level: off, error, warn, info, debug or trace (default is 'debug' with -d, else 'error');");
+    println!("                         change it at runtime with 'rdnat ctl log-level <level>' or cycle it with SIGUSR1");
+    println!("  --log-suppress <host>  Omit access-log entries for <host> and its subdomains (repeatable)");
+    println!("  --log-redact <host>    Log requests to <host> and its subdomains without the destination (repeatable)");
+    println!("  --log-exclusions <path>  Read 'suppress <host>' / 'redact <host>' rules from a file");
+    println!("  --no-via               Do not add a Via header to forwarded requests and responses (also disables loop detection)");
+    println!("  --forwarded-for <mode> Pass the client address as 'xff' (X-Forwarded-For, default), 'forwarded', 'both' or 'off'");
+    println!("  --pool-max-idle <n>    Keep up to <n> idle connections per origin for later requests, 0 disables reuse (default is {})", DEFAULT_POOL_MAX_IDLE);
+    println!("  --pool-idle-timeout <secs>  Close pooled connections idle for <secs> (default is {})", DEFAULT_POOL_IDLE_TIMEOUT_SECS);
+    println!("  --origin-http2         Offer HTTP/2 to https origins, multiplexing requests over one connection");
+    println!("  --connect-timeout <secs>  Give up connecting to a target after <secs> and reply 504 (default is 5)");
+    println!("  --outbound-bind <ip>[,<ip>...]  Make connections to targets and upstream proxies from this local address,");
+    println!("                         or from each of several in turn");
+    println!("  --outbound-rotation <policy>  How connections take turns over --outbound-bind addresses: round-robin (default) or random");
+    println!("  --connect-attempt-delay <ms>  Head start of each connection attempt before the next address of a target is");
+    println!("                         raced against it, IPv6 and IPv4 in turn (default is {}, 0 tries them one by one)", outbound::DEFAULT_ATTEMPT_DELAY_MS);
+    println!("  --tcp-keepalive <secs>  Send TCP keepalive probes on client and outbound connections idle for <secs>");
+    println!("  --tcp-keepalive-interval <secs>  Time between keepalive probes (default is the system's)");
+    println!("  --tcp-keepalive-count <n>  Drop the connection after <n> unanswered probes (default is the system's)");
+    println!("  --tcp-user-timeout <secs>  Drop connections whose sent data stays unacknowledged for <secs> (TCP_USER_TIMEOUT, Linux)");
+    println!("  --tcp-nodelay          Send small writes on client and outbound connections at once (TCP_NODELAY),");
+    println!("                         for interactive traffic such as SSH over CONNECT");
+    println!("  --tcp-send-buffer <bytes>  Socket send buffer (SO_SNDBUF) of client and outbound connections");
+    println!("  --tcp-recv-buffer <bytes>  Socket receive buffer (SO_RCVBUF) of client and outbound connections");
+    println!("  --relay-buffer-size <bytes>  Size of each tunnel's relay buffers or splice pipes, one per direction: larger");
+    println!("                         for throughput, smaller for memory (default is {}, {} with splice or io_uring)", bufpool::DEFAULT_BUFFER_SIZE, relay::DEFAULT_PIPE_SIZE);
+    println!("  --relay-buffer-pool <n>  Keep up to <n> relay buffers for reuse by new tunnels (default is {}, 0 disables)", bufpool::DEFAULT_POOL_SIZE);
+    println!("  --cache-size <size>    Keep up to <size> of cacheable HTTP responses in memory (e.g. 256M) and answer repeated");
+    println!("                         requests from it, following Cache-Control, Expires and Vary and revalidating stale ones (default is off)");
+    println!("  --cache-max-object <size>  Largest response kept in the cache (default is {})", cache::DEFAULT_MAX_OBJECT);
+    println!("  --cache-dir <path>     Also keep cached responses on disk in <path>, where they survive restarts");
+    println!("  --cache-disk-size <size>  Most disk space used by --cache-dir (default is 1G)");
+    println!("  --cache-disk-max-object <size>  Largest response kept on disk (default is 512M)");
+    println!("  --compress             Compress text responses with brotli or gzip for clients that accept it, when the origin didn't");
+    println!("  --compress-min-size <size>  Smallest response compressed by --compress (default is {})", compress::DEFAULT_MIN_SIZE);
+    println!("  --throttle <profile>   Simulate a 2g, 3g, 4g or satellite link for every client: its bandwidth, latency,");
+    println!("                         jitter and loss (throttle rules pick one per destination)");
+    println!("  --user-throttle <user>=<profile>  Simulate a network link for one user");
+    println!("  --whoami               Answer http://{}{} with the client's user, source address, route and egress address", whoami::HOST, whoami::PATH);
+    println!("  --whoami-echo <url>    Find the egress address with an IP echo service answering with the address in plain text");
+    println!("                         (e.g. https://api.ipify.org), fetched the way the client's traffic goes; implies --whoami");
+    println!("  --memory-budget <size>  Cap the memory held by buffered bodies, tunnel buffers and captures (e.g. 512M);");
+    println!("                         requests and tunnels that would go over it get 503 (default is unlimited)");
+    println!("  --no-splice            Relay tunnels through userspace buffers instead of zero-copy splice() on Linux");
+    println!("  --io-uring             Accept clients and relay tunnels of plain TCP clients through io_uring (Linux, builds with the io-uring feature)");
+    println!("  --tcp-fastopen <n>     Accept TCP Fast Open on the listener, with up to <n> connections pending the handshake");
+    println!("  --tcp-fastopen-connect  Use TCP Fast Open on connections to targets and upstream proxies seen before (Linux)");
+    println!("  --outbound-mark <n>    Set this firewall mark (SO_MARK) on connections to targets and upstream proxies (Linux)");
+    println!("  --outbound-dscp <n>    Set this DSCP code point (0-63) on connections to targets and upstream proxies");
+    println!("  --dns-server <server>[,...]  Resolve target host names with these name servers instead of the system resolver:");
+    println!("                         <ip>[:<port>], tls://<host>[:<port>] (DNS over TLS) or https://<host>[:<port>]/dns-query (DNS over HTTPS)");
+    println!("  --dns-search <domain>[,...]  Search domains for target host names without a dot (default is none)");
+    println!("  --dns-timeout <secs>   Give up resolving a target host name after <secs> (default is 5)");
+    println!("  --hosts-file <path>    Resolve target host names listed in this /etc/hosts style file to its addresses (repeatable)");
+    println!("  --host-override <name>=<ip>[,<ip>...]  Resolve target host <name> to these addresses (repeatable)");
+    println!("  --dns-cache <n>        Cache up to <n> resolved target host names, 0 to disable (default is 10000)");
+    println!("  --dns-max-ttl <secs>   Cache resolved names at most <secs> whatever their TTL (default is 3600)");
+    println!("  --dns-negative-ttl <secs>  Cache names that failed to resolve for <secs> (default is 10)");
+    println!("  --dns-listen <addr>:<port>  Also serve DNS (UDP and TCP) here, relaying queries to the name servers and");
+    println!("                         answering names blocked by the domain and filter lists itself");
+    println!("  --dns-block-response <mode>  Answer blocked names with 'null' (0.0.0.0 / ::, default) or 'nxdomain'");
+    println!("  --doh                  Answer DNS over HTTPS queries at {} on the TLS listener", dnsproxy::DOH_PATH);
+    println!("  --max-header-bytes <n>  Reject request heads larger than <n> bytes with 431 (default is 65536)");
+    println!("  --max-headers <n>      Reject requests with more than <n> headers with 431 (default is 100)");
+    println!("  --header-timeout <secs>  Drop clients that take longer than <secs> to send a request head (default is 30)");
+    println!("  --header-min-rate <n>  Drop clients sending a request head slower than <n> bytes/s, 0 disables (default is 128)");
+    println!("  --idle-timeout <mins>  Close tunnels that relay nothing for <mins>, 0 disables (default is 15)");
+    println!("  --max-open-files <n|max>  Raise the open file limit to <n>, or to the hard limit with 'max'");
+    println!("  --worker-threads <n>   Run the proxy on <n> worker threads (default is one per core)");
+    println!("  --max-blocking-threads <n>  Allow up to <n> threads for blocking work such as reverse DNS (default is 512)");
+    println!("  --thread-name <name>   Name of the runtime threads, as shown by top -H (default is {})", DEFAULT_THREAD_NAME);
+    println!("  --acceptors <n|auto>   Accept connections in <n> loops on SO_REUSEPORT sockets, or one per core with 'auto'");
+    println!("  --decoy <404|url|path>  Answer direct web requests with a 404, a redirect to <url> or the page at <path> instead of revealing the proxy");
+    println!("  --anonymity <level>    transparent (forward the client address), anonymous (hide it) or elite (hide the proxy too); default is transparent");
+    println!("  --user-anonymity <user>=<level>  Anonymity level for one user (repeatable)");
+    println!("  --allow-private        Allow destinations on loopback, private and link-local networks or the proxy's own addresses");
+    println!("  --allow-ports <list>   Only allow CONNECT to these ports, e.g. 443,8443,10000-10100 (default is any port)");
+    println!("  --user-allow-ports <user>=<list>  CONNECT ports allowed for one user, overriding --allow-ports (repeatable)");
+    println!("  --allow-domains <path>  Only allow destinations listed in <path> ('example.com' exact, '.example.com' with subdomains)");
+    println!("  --block-domains <path|url>  Refuse destinations listed in <path> with 403; lists at a URL are refreshed periodically");
+    println!("  --block-page <path>    HTML page for requests refused by policy; {{{{url}}}}, {{{{reason}}}} and {{{{status}}}} are filled in");
+    println!("  --block-status <code>  Status code for requests refused by policy (default is 403)");
+    println!("  --pac <path>           Serve a proxy auto-config script at <path> (e.g. /proxy.pac); hosts with 'route direct' rules bypass the proxy");
+    println!("  --upstream [name=]<url>  Chain through an upstream proxy (http:// or socks5://[user:pass@]host:port); unnamed sets the default route, named ones are picked by 'route upstream <name>' rules (repeatable)");
+    println!("                         A comma-separated list of URLs makes a pool that connections are spread over;");
+    println!("                         append ';weight=<n>' (1-100, default 1) to a URL to give it a bigger or smaller share.");
+    println!("                         srv+<scheme>://[user:pass@]<name> or consul+<scheme>://[user:pass@]<host:port>/<service>");
+    println!("                         fill the pool from DNS SRV records or a Consul catalog instead");
+    println!("  --discovery-refresh <secs>  Time between lookups of discovered pools (default {})", discovery::DEFAULT_REFRESH_SECS);
+    println!("  --upstream-balance <policy>  How pools pick a proxy per connection: round-robin (default) or least-conn");
+    println!("  --upstream-sticky <key>  Keep each client-ip, user or cookie on the same proxy of a pool while it is healthy");
+    println!("  --upstream-session <secs>  Rotate each sticky client (by client-ip unless --upstream-sticky says otherwise)");
+    println!("                         to another proxy every <secs>");
+    println!("  --health-check <tcp|url>  Probe upstream proxies by connecting, or by fetching an http:// URL through them,");
+    println!("                         and take failing ones out of rotation until they recover");
+    println!("  --health-interval <secs>  Time between probes of each upstream proxy (default {})", upstream::DEFAULT_HEALTH_INTERVAL_SECS);
+    println!("  --health-fall <n>      Failed probes in a row that take a proxy out of rotation (default {})", upstream::DEFAULT_HEALTH_FALL);
+    println!("  --health-rise <n>      Good probes in a row that put it back (default {})", upstream::DEFAULT_HEALTH_RISE);
+    println!("  --retries <n>          Retry a failed connection or request up to <n> times, on another upstream proxy when");
+    println!("                         there is one (default 0); requests that may have reached the origin are only retried");
+    println!("                         for idempotent methods or with an Idempotency-Key header");
+    println!("  --retry-backoff <ms>   Wait before retrying the same target, doubled on every retry (default {})", upstream::DEFAULT_RETRY_BACKOFF_MS);
+    println!("  --breaker-failures <n>  Answer 503 at once for a target after <n> failures in a row, until it recovers");
+    println!("  --breaker-cooldown <secs>  How long a target is left alone before it is tried again (default {})", breaker::DEFAULT_COOLDOWN_SECS);
+    println!("  --icap-reqmod <url>    Send plain-HTTP requests to an ICAP REQMOD service (icap://host[:port]/service) before forwarding");
+    println!("  --icap-respmod <url>   Send plain-HTTP responses to an ICAP RESPMOD service before returning them");
+    println!("  --icap-bypass          Forward uninspected when the ICAP service fails (default is to answer 502)");
+    println!("  --icap-max-size <bytes>  Largest body handed to ICAP; bigger ones pass uninspected (default is 10485760)");
+    println!("  --clamd <path|host:port>  Scan plain-HTTP downloads with clamd and block infected ones with a 403");
+    println!("  --max-scan-size <bytes>  Largest download scanned; bigger ones pass unscanned (default is 26214400)");
+    println!("  --max-upload <size>    Largest plain-HTTP request body forwarded, e.g. 10M; 'cap upload' rules can lower it per user or site");
+    println!("  --max-download <size>  Largest plain-HTTP response body forwarded, e.g. 1G; 'cap download' rules can lower it per user or site");
+    println!("  --mitm-ca <cert.pem>   Intercept CONNECT tunnels, presenting leaf certificates signed by this CA (needs --mitm-key;");
+    println!("                         create one with 'rdnat ca init')");
+    println!("  --mitm-key <key.pem>   Private key of the --mitm-ca certificate");
+    println!("  --mitm-ports <list>    CONNECT ports intercepted by --mitm-ca (default 443); others stay blind tunnels,");
+    println!("                         as do hosts excluded with 'intercept off' rules");
+    println!("  --tls-ca-bundle <pem>  Verify origins rdnat connects to over TLS against these CAs instead of the system roots");
+    println!("  --tls-pin <host>=sha256/<base64>  Require a certificate in the chain of <host> to have this public key");
+    println!("                         (SPKI SHA-256, repeatable)");
+    println!("  --insecure-skip-verify Don't verify the certificates of TLS origins at all (testing only)");
+    println!("  --tls-cert <pem>       Serve the proxy over TLS (an HTTPS proxy) with this certificate chain (needs --tls-key)");
+    println!("  --tls-key <pem>        Private key of the --tls-cert certificate");
+    println!("  --acme-domain <name>   Obtain and renew the TLS listener certificate for <name> from an ACME CA such as");
+    println!("                         Let's Encrypt, instead of --tls-cert/--tls-key (repeatable)");
+    println!("  --acme-email <addr>    Contact address of the ACME account");
+    println!("  --acme-directory <url> ACME directory (default {})", acme::DEFAULT_ACME_DIRECTORY);
+    println!("  --acme-dir <path>      Where the account key and certificate are kept (default {})", acme::DEFAULT_ACME_DIR);
+    println!("  --acme-challenge <type>  tls-alpn-01 (default; answered by the listener, which must be reachable on port 443)");
+    println!("                         or http-01 (answered on --acme-http-port)");
+    println!("  --acme-http-port <port>  Port HTTP-01 validations are answered on (default {})", acme::DEFAULT_ACME_HTTP_PORT);
+    println!("  --tls-watch <secs>     How often the --tls-cert and --tls-key files are checked and reloaded when changed");
+    println!("                         (default {}, 0 disables; 'rdnat ctl reload-tls' reloads at once)", DEFAULT_TLS_WATCH_SECS);
+    println!("  --tls-min-version <v>  Oldest TLS version offered to clients by the listener and interception: 1.2 (default) or 1.3");
+    println!("  --tls-ciphers <list>   Cipher suites offered to clients, e.g. TLS13_AES_256_GCM_SHA384,TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384");
+    println!("                         (default: all the secure suites rustls supports)");
+    println!("  --tls-alpn <list>      ALPN protocols offered by the TLS listener, or 'none' (default http/1.1)");
+    println!("  --tls-keylog <path>    Append the secrets of intercepted and proxy-originated TLS sessions to <path> in the");
+    println!("                         NSS key log format, so Wireshark can decrypt captured traffic (debugging only)");
+    println!("  --har <path>           Record proxied plain-HTTP and intercepted transactions to a HAR file");
+    println!("  --har-max-body <size>  Bytes of each request and response body kept in the HAR file (default 1M)");
+    println!("  --har-max-file <size>  Start a new HAR file, keeping the full one as <path>.N, beyond this size (default 100M)");
+    println!("  --har-redact <list>    Headers whose values are redacted in the HAR file, or 'none'");
+    println!("                         (default authorization,proxy-authorization,cookie,set-cookie)");
+    println!("  --replay <har>         Serve the responses recorded in a HAR file, matched by method and URL, instead of");
+    println!("                         contacting origins (repeatable; HTTPS needs --mitm-ca)");
+    println!("  --replay-passthrough   Forward requests without a recorded response instead of answering 404");
+    println!("  --rules <path>         Evaluate allow/deny/route/limit rules from <path> against each destination");
+    println!("  --profile <name>=<path>  Define a routing profile with its own rules file (repeatable)");
+    println!("  --profile-for <user|subnet>=<name>  Use profile <name> instead of --rules for a user or client subnet such as 10.1.0.0/16 (repeatable)");
+    println!("  --timezone <zone>      Timezone for rule schedules: local, UTC, +HH:MM or a name like Europe/Berlin (default is local)");
+    println!("  --filter-list <path|url>  Block ad/tracker hosts from an EasyList-style or hosts-format filter file (repeatable)");
+    println!("  --list-refresh <mins>  Interval between updates of blocklists given as URLs (default is 60)");
+    println!("  -h, --help             Display this help message and exit");
+    println!();
+    println!("Arguments:");
+    println!("  <username>             The username for proxy authentication");
+    println!("  <password>             The password for proxy authentication (ignored if no username is provided)");
+    println!();
+    println!("Examples:");
+    println!("  ./rdnat                # Start the proxy with default settings: port 8000, no authentication");
+    println!("  ./rdnat -p 8001        # Start the proxy on port 8001, no authentication");
+    println!("  ./rdnat -a user passwd # Start the proxy with username 'user' and password 'passwd' on port 8000");
+    println!("  ./rdnat -p 8001 -a user passwd # Start the proxy on port 8001 with username 'user' and password 'passwd'");
+    println!("  ./rdnat -d             # Start the proxy with debug logging to 'rdnat.log'");
+    println!("  ./rdnat -d -a user passwd # Enable debug logging and start the proxy with authentication");
+    println!("  ./rdnat --usage-file rdnat-usage.csv # Record per-user traffic for billing");
+    println!("  ./rdnat usage --since 2024-01-01 # Export the recorded usage per user/day as CSV");
+    println!("  ./rdnat ctl top-destinations --window 10 # Show the busiest destinations and users of a running proxy");
+    println!("  ./rdnat ca init && ./rdnat --mitm-ca rdnat-ca.pem --mitm-key rdnat-ca.key # Intercept HTTPS with a new local CA");
+    println!("  ./rdnat -a user passwd --webhook http://alerts.local/hook # Notify on repeated auth failures");
+    println!("  ./rdnat bench -x 127.0.0.1:8000 -c 50 -n 10000 http://localhost:8080/ # Measure a running proxy");
+    println!("  ./rdnat check --proxy 127.0.0.1:8000 --target https://example.com # Time each step of a request through a proxy");
+    println!("  ./rdnat socks --via proxy.corp:3128 -a user passwd # SOCKS5 on 127.0.0.1:1080 through an HTTP proxy");
+    println!("  ./rdnat speedtest --via proxy.example:8000 http://origin.example/file # Tell a slow proxy from a slow origin");
+}
+
+/*************************************************
+ * parse_arguments
+ *************************************************/
+
+fn is_url(source: &str) -> bool {
+    source.starts_with("http://") || source.starts_with("https://")
+}
+
+fn arg_value(args: &[String], i: usize) -> Result<String, Box<dyn Error>> {
+    args.get(i + 1)
+        .cloned()
+        .ok_or_else(|| format!("Error: Missing argument for {}", args[i]).into())
+}
+
+fn network_profile(name: &str) -> Result<Link, Box<dyn Error>> {
+    Link::named(name).ok_or_else(|| format!("Error: Unknown network profile: {} (expected 2g, 3g, 4g or satellite)", name).into())
+}
+
+fn parse_arguments(args: &[String], config: &mut Config) -> Result<(), Box<dyn Error>> {
+    if args.len() > 1 && (args[1] == "-h" || args[1] == "--help") {
+        help();
+        return Ok(());
+    }
+
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "-p" | "--port" => {
+                if i + 1 < args.len() {
+                    config.port = args[i + 1].clone();
+                    i += 2;
+                } else {
+                    return Err("Error: Missing argument for -p or --port".into());
+                }
+            }
+            "-a" | "--auth" => {
+                if i + 2 < args.len() {
+                    config.username = args[i + 1].clone();
+                    config.password = args[i + 2].clone();
+                    i += 3;
+                } else {
+                    return Err("Error: Missing username or password for -auth or -a".into());
+                }
+            }
+            "-d" | "--debug" => {
+                config.log_path = Some(DEFAULT_LOGPATH.to_string());
+                i += 1;
+            }
+            "--log-level" => {
+                config.log_level = Some(arg_value(args, i)?.parse()?);
+                i += 2;
+            }
+            "--usage-file" => {
+                if i + 1 < args.len() {
+                    config.usage_file = Some(args[i + 1].clone());
+                    i += 2;
+                } else {
+                    return Err("Error: Missing argument for --usage-file".into());
+                }
+            }
+            "--ctl-socket" => {
+                if i + 1 < args.len() {
+                    config.ctl_socket = Some(args[i + 1].clone());
+                    i += 2;
+                } else {
+                    return Err("Error: Missing argument for --ctl-socket".into());
+                }
+            }
+            "--usage-quota" => {
+                config.usage_quota = Some(parse_size(&arg_value(args, i)?)?);
+                i += 2;
+            }
+            "--usage-flush" => {
+                if i + 1 < args.len() {
+                    config.usage_flush_secs = args[i + 1].parse()?;
+                    i += 2;
+                } else {
+                    return Err("Error: Missing argument for --usage-flush".into());
+                }
+            }
+            "--geoip-db" => {
+                config.geoip_db = Some(arg_value(args, i)?);
+                i += 2;
+            }
+            "--geoip-asn-db" => {
+                config.geoip_asn_db = Some(arg_value(args, i)?);
+                i += 2;
+            }
+            "--resolve-clients" => {
+                config.resolve_clients = true;
+                i += 1;
+            }
+            "--rdns-timeout" => {
+                config.rdns_timeout_ms = arg_value(args, i)?.parse()?;
+                i += 2;
+            }
+            "--log-suppress" => {
+                config.log_exclusions.add(LogAction::Suppress, &arg_value(args, i)?);
+                i += 2;
+            }
+            "--log-redact" => {
+                config.log_exclusions.add(LogAction::Redact, &arg_value(args, i)?);
+                i += 2;
+            }
+            "--log-exclusions" => {
+                config.log_exclusions.load(&arg_value(args, i)?)?;
+                i += 2;
+            }
+            "--allow-domains" => {
+                config.allow_domains.get_or_insert_with(DomainList::default).load(&arg_value(args, i)?)?;
+                i += 2;
+            }
+            "--block-domains" => {
+                let source = arg_value(args, i)?;
+                if is_url(&source) {
+                    config.remote_lists.push(Arc::new(RemoteList::new(&source, ListFormat::Domains)));
+                } else {
+                    config.block_domains.get_or_insert_with(DomainList::default).load(&source)?;
+                }
+                i += 2;
+            }
+            "--block-page" => {
+                let path = arg_value(args, i)?;
+                let page = std::fs::read_to_string(&path)
+                    .map_err(|e| format!("Error: Cannot read block page {}: {}", path, e))?;
+                config.block_page = Some(page);
+                i += 2;
+            }
+            "--block-status" => {
+                config.block_status = Some(StatusCode::from_bytes(arg_value(args, i)?.as_bytes())?);
+                i += 2;
+            }
+            "--pac" => {
+                config.pac_path = Some(arg_value(args, i)?);
+                i += 2;
+            }
+            "--upstream" => {
+                let value = arg_value(args, i)?;
+                // Only a name that isn't part of a URL (or a weight) names the pool.
+                match value.split_once('=').filter(|(name, _)| !name.contains("://") && !name.contains(';')) {
+                    Some((name, urls)) => config.upstreams.insert(name.to_string(), Pool::parse(urls)?),
+                    None => config.upstream.replace(Pool::parse(&value)?),
+                };
+                i += 2;
+            }
+            "--upstream-balance" => {
+                config.upstream_balance = Balance::parse(&arg_value(args, i)?)?;
+                i += 2;
+            }
+            "--upstream-sticky" => {
+                config.upstream_sticky = Some(Sticky::parse(&arg_value(args, i)?)?);
+                i += 2;
+            }
+            "--upstream-session" => {
+                config.upstream_session = Some(Duration::from_secs(arg_value(args, i)?.parse::<u64>()?.max(1)));
+                i += 2;
+            }
+            "--discovery-refresh" => {
+                config.discovery_refresh = Duration::from_secs(arg_value(args, i)?.parse::<u64>()?.max(1));
+                i += 2;
+            }
+            "--health-check" => {
+                config.health_check.probe = Some(Probe::parse(&arg_value(args, i)?)?);
+                i += 2;
+            }
+            "--health-interval" => {
+                config.health_check.interval = Duration::from_secs(arg_value(args, i)?.parse::<u64>()?.max(1));
+                i += 2;
+            }
+            "--health-fall" => {
+                config.health_check.fall = arg_value(args, i)?.parse::<u32>()?.max(1);
+                i += 2;
+            }
+            "--health-rise" => {
+                config.health_check.rise = arg_value(args, i)?.parse::<u32>()?.max(1);
+                i += 2;
+            }
+            "--retries" => {
+                config.retry.retries = arg_value(args, i)?.parse()?;
+                i += 2;
+            }
+            "--retry-backoff" => {
+                config.retry.backoff = Duration::from_millis(arg_value(args, i)?.parse()?);
+                i += 2;
+            }
+            "--breaker-failures" => {
+                config.breaker_failures = Some(arg_value(args, i)?.parse()?);
+                i += 2;
+            }
+            "--breaker-cooldown" => {
+                config.breaker_cooldown = Duration::from_secs(arg_value(args, i)?.parse()?);
+                i += 2;
+            }
+            "--profile" => {
+                let value = arg_value(args, i)?;
+                let (name, path) = value.split_once('=')
+                    .ok_or_else(|| format!("Error: Expected <name>=<rules path>, got: {}", value))?;
+                config.profiles.load(name, path)?;
+                i += 2;
+            }
+            "--profile-for" => {
+                let value = arg_value(args, i)?;
+                let (client, name) = value.split_once('=')
+                    .ok_or_else(|| format!("Error: Expected <user|subnet>=<profile>, got: {}", value))?;
+                config.profiles.assign(client, name)?;
+                i += 2;
+            }
+            "--icap-reqmod" => {
+                config.icap_reqmod = Some(IcapService::parse(&arg_value(args, i)?)?);
+                i += 2;
+            }
+            "--icap-respmod" => {
+                config.icap_respmod = Some(IcapService::parse(&arg_value(args, i)?)?);
+                i += 2;
+            }
+            "--icap-bypass" => {
+                config.icap_bypass = true;
+                i += 1;
+            }
+            "--icap-max-size" => {
+                config.icap_max_size = arg_value(args, i)?.parse()?;
+                i += 2;
+            }
+            "--clamd" => {
+                config.clamd = Some(Clamd::parse(&arg_value(args, i)?));
+                i += 2;
+            }
+            "--max-scan-size" => {
+                config.max_scan_size = arg_value(args, i)?.parse()?;
+                i += 2;
+            }
+            "--max-upload" => {
+                config.max_upload = Some(parse_size(&arg_value(args, i)?)?);
+                i += 2;
+            }
+            "--max-download" => {
+                config.max_download = Some(parse_size(&arg_value(args, i)?)?);
+                i += 2;
+            }
+            "--mitm-ca" => {
+                config.mitm_ca = Some(arg_value(args, i)?);
+                i += 2;
+            }
+            "--mitm-key" => {
+                config.mitm_key = Some(arg_value(args, i)?);
+                i += 2;
+            }
+            "--mitm-ports" => {
+                config.mitm_ports = Some(parse_ports(&arg_value(args, i)?)?);
+                i += 2;
+            }
+            "--replay" => {
+                config.replay.get_or_insert_with(Replay::default).load(&arg_value(args, i)?)?;
+                i += 2;
+            }
+            "--replay-passthrough" => {
+                config.replay_passthrough = true;
+                i += 1;
+            }
+            "--tls-ca-bundle" => {
+                config.origin_tls.set_ca_bundle(&arg_value(args, i)?);
+                i += 2;
+            }
+            "--tls-pin" => {
+                config.origin_tls.add_pin(&arg_value(args, i)?)?;
+                i += 2;
+            }
+            "--insecure-skip-verify" => {
+                config.origin_tls.set_insecure();
+                i += 1;
+            }
+            "--tls-cert" => {
+                config.tls_cert = Some(arg_value(args, i)?);
+                i += 2;
+            }
+            "--tls-key" => {
+                config.tls_key = Some(arg_value(args, i)?);
+                i += 2;
+            }
+            "--acme-domain" => {
+                config.acme.domains.push(arg_value(args, i)?);
+                i += 2;
+            }
+            "--acme-email" => {
+                config.acme.email = Some(arg_value(args, i)?);
+                i += 2;
+            }
+            "--acme-directory" => {
+                config.acme.directory = arg_value(args, i)?;
+                i += 2;
+            }
+            "--acme-dir" => {
+                config.acme.dir = arg_value(args, i)?;
+                i += 2;
+            }
+            "--acme-challenge" => {
+                config.acme.challenge = ChallengeType::parse(&arg_value(args, i)?)?;
+                i += 2;
+            }
+            "--acme-http-port" => {
+                config.acme.http_port = arg_value(args, i)?.parse()?;
+                i += 2;
+            }
+            "--tls-watch" => {
+                config.tls_watch = Duration::from_secs(arg_value(args, i)?.parse()?);
+                i += 2;
+            }
+            "--tls-min-version" => {
+                config.tls_policy.set_min_version(&arg_value(args, i)?)?;
+                i += 2;
+            }
+            "--tls-ciphers" => {
+                config.tls_policy.set_cipher_suites(&arg_value(args, i)?)?;
+                i += 2;
+            }
+            "--tls-alpn" => {
+                config.tls_policy.set_alpn(&arg_value(args, i)?);
+                i += 2;
+            }
+            "--tls-keylog" => {
+                config.tls_keylog = Some(arg_value(args, i)?);
+                i += 2;
+            }
+            "--har" => {
+                config.har_path = Some(arg_value(args, i)?);
+                i += 2;
+            }
+            "--har-max-body" => {
+                config.har_max_body = parse_size(&arg_value(args, i)?)? as usize;
+                i += 2;
+            }
+            "--har-max-file" => {
+                config.har_max_file_size = parse_size(&arg_value(args, i)?)?;
+                i += 2;
+            }
+            "--har-redact" => {
+                let value = arg_value(args, i)?;
+                config.har_redact = match value.as_str() {
+                    "none" => Vec::new(),
+                    list => list.split(',').map(|name| name.trim().to_ascii_lowercase()).collect(),
+                };
+                i += 2;
+            }
+            "--rules" => {
+                config.rules.load(&arg_value(args, i)?)?;
+                i += 2;
+            }
+            "--timezone" => {
+                config.rules.set_timezone(Timezone::parse(&arg_value(args, i)?)?);
+                i += 2;
+            }
+            "--filter-list" => {
+                let source = arg_value(args, i)?;
+                if is_url(&source) {
+                    config.remote_lists.push(Arc::new(RemoteList::new(&source, ListFormat::Filters)));
+                } else {
+                    config.filters.get_or_insert_with(FilterList::default).load(&source)?;
+                }
+                i += 2;
+            }
+            "--list-refresh" => {
+                config.list_refresh = Duration::from_secs(arg_value(args, i)?.parse::<u64>()? * 60);
+                i += 2;
+            }
+            "--no-via" => {
+                config.via = false;
+                i += 1;
+            }
+            "--forwarded-for" => {
+                config.forwarded_for = match arg_value(args, i)?.as_str() {
+                    "xff" => ClientAddrHeader::XForwardedFor,
+                    "forwarded" => ClientAddrHeader::Forwarded,
+                    "both" => ClientAddrHeader::Both,
+                    "off" => ClientAddrHeader::Off,
+                    mode => return Err(format!("Error: Invalid --forwarded-for mode: {}", mode).into()),
+                };
+                i += 2;
+            }
+            "--anonymity" => {
+                config.anonymity = arg_value(args, i)?.parse()?;
+                i += 2;
+            }
+            "--user-anonymity" => {
+                let value = arg_value(args, i)?;
+                let (user, level) = value.split_once('=')
+                    .ok_or_else(|| format!("Error: Expected <user>=<level>, got: {}", value))?;
+                config.user_anonymity.insert(user.to_string(), level.parse()?);
+                i += 2;
+            }
+            "--allow-private" => {
+                config.allow_private = true;
+                i += 1;
+            }
+            "--allow-ports" => {
+                config.allow_ports = Some(parse_ports(&arg_value(args, i)?)?);
+                i += 2;
+            }
+            "--user-allow-ports" => {
+                let value = arg_value(args, i)?;
+                let (user, ports) = value.split_once('=')
+                    .ok_or_else(|| format!("Error: Expected <user>=<ports>, got: {}", value))?;
+                config.user_allow_ports.insert(user.to_string(), parse_ports(ports)?);
+                i += 2;
+            }
+            "--outbound-bind" => {
+                let binds = arg_value(args, i)?
+                    .split(',')
+                    .map(|bind| bind.trim().parse().map_err(|_| format!("Error: Invalid outbound address: {}", bind)))
+                    .collect::<Result<_, _>>()?;
+                config.outbound.set_binds(binds);
+                i += 2;
+            }
+            "--outbound-rotation" => {
+                config.outbound.set_rotation(Rotation::parse(&arg_value(args, i)?)?);
+                i += 2;
+            }
+            "--connect-attempt-delay" => {
+                config.outbound.set_attempt_delay(Duration::from_millis(arg_value(args, i)?.parse()?));
+                i += 2;
+            }
+            "--tcp-keepalive" => {
+                let idle = Duration::from_secs(arg_value(args, i)?.parse()?);
+                config.socket_options.set_keepalive(Some(Keepalive { idle, interval: None, count: None }));
+                i += 2;
+            }
+            "--tcp-keepalive-interval" => {
+                config.keepalive_interval = Some(Duration::from_secs(arg_value(args, i)?.parse()?));
+                i += 2;
+            }
+            "--tcp-keepalive-count" => {
+                config.keepalive_count = Some(arg_value(args, i)?.parse()?);
+                i += 2;
+            }
+            "--tcp-user-timeout" => {
+                if !cfg!(target_os = "linux") {
+                    return Err("Error: --tcp-user-timeout (TCP_USER_TIMEOUT) is not supported on this platform, only on Linux".into());
+                }
+                config.socket_options.set_user_timeout(Some(Duration::from_secs(arg_value(args, i)?.parse()?)));
+                i += 2;
+            }
+            "--tcp-nodelay" => {
+                config.socket_options.set_nodelay(true);
+                i += 1;
+            }
+            "--tcp-send-buffer" => {
+                config.send_buffer = Some(arg_value(args, i)?.parse()?);
+                i += 2;
+            }
+            "--tcp-recv-buffer" => {
+                config.recv_buffer = Some(arg_value(args, i)?.parse()?);
+                i += 2;
+            }
+            "--relay-buffer-size" => {
+                config.relay_buffer_size = Some(arg_value(args, i)?.parse()?);
+                i += 2;
+            }
+            "--relay-buffer-pool" => {
+                config.relay_buffer_pool = arg_value(args, i)?.parse()?;
+                i += 2;
+            }
+            "--memory-budget" => {
+                config.memory_budget = Some(parse_size(&arg_value(args, i)?)? as usize);
+                i += 2;
+            }
+            "--cache-size" => {
+                config.cache_size = Some(parse_size(&arg_value(args, i)?)? as usize).filter(|&size| size > 0);
+                i += 2;
+            }
+            "--cache-max-object" => {
+                config.cache_max_object = parse_size(&arg_value(args, i)?)? as usize;
+                i += 2;
+            }
+            "--cache-dir" => {
+                config.cache_dir = Some(arg_value(args, i)?);
+                i += 2;
+            }
+            "--cache-disk-size" => {
+                config.cache_disk_size = parse_size(&arg_value(args, i)?)?;
+                i += 2;
+            }
+            "--cache-disk-max-object" => {
+                config.cache_disk_max_object = parse_size(&arg_value(args, i)?)?;
+                i += 2;
+            }
+            "--compress" => {
+                config.compress = true;
+                i += 1;
+            }
+            "--compress-min-size" => {
+                config.compress_min_size = parse_size(&arg_value(args, i)?)?;
+                i += 2;
+            }
+            "--throttle" => {
+                config.throttle = Some(network_profile(&arg_value(args, i)?)?);
+                i += 2;
+            }
+            "--user-throttle" => {
+                let value = arg_value(args, i)?;
+                let (user, profile) = value.split_once('=')
+                    .ok_or_else(|| format!("Error: Expected <user>=<profile>, got: {}", value))?;
+                config.user_throttle.insert(user.to_string(), network_profile(profile)?);
+                i += 2;
+            }
+            "--whoami" => {
+                config.whoami = true;
+                i += 1;
+            }
+            "--whoami-echo" => {
+                let url = arg_value(args, i)?;
+                let uri: hyper::Uri = url.parse().map_err(|_| format!("Error: Invalid URL: {}", url))?;
+                if uri.host().is_none() || !matches!(uri.scheme_str(), Some("http" | "https")) {
+                    return Err(format!("Error: Expected an http:// or https:// URL, got: {}", url).into());
+                }
+                config.whoami = true;
+                config.whoami_echo = Some(uri);
+                i += 2;
+            }
+            "--no-splice" => {
+                config.splice = false;
+                i += 1;
+            }
+            "--io-uring" => {
+                if !cfg!(all(target_os = "linux", feature = "io-uring")) {
+                    return Err("Error: --io-uring needs rdnat built on Linux with the io-uring feature".into());
+                }
+                config.io_uring = true;
+                i += 1;
+            }
+            "--tcp-fastopen" => {
+                config.fastopen_queue = Some(arg_value(args, i)?.parse()?);
+                i += 2;
+            }
+            "--tcp-fastopen-connect" => {
+                config.outbound.set_fastopen(true);
+                i += 1;
+            }
+            "--outbound-mark" => {
+                if !cfg!(target_os = "linux") {
+                    return Err("Error: --outbound-mark (SO_MARK) is not supported on this platform, only on Linux".into());
+                }
+                let mark = arg_value(args, i)?;
+                let parsed = match mark.strip_prefix("0x") {
+                    Some(hex) => u32::from_str_radix(hex, 16),
+                    None => mark.parse(),
+                };
+                config.outbound.set_mark(Some(parsed.map_err(|_| format!("Error: Invalid firewall mark: {}", mark))?));
+                i += 2;
+            }
+            "--outbound-dscp" => {
+                if !cfg!(unix) {
+                    return Err("Error: --outbound-dscp is only supported on Unix platforms".into());
+                }
+                let dscp = arg_value(args, i)?;
+                match dscp.parse::<u8>() {
+                    Ok(dscp @ 0..=63) => config.outbound.set_dscp(Some(dscp)),
+                    _ => return Err(format!("Error: DSCP must be from 0 to 63: {}", dscp).into()),
+                }
+                i += 2;
+            }
+            "--dns-server" => {
+                let servers = arg_value(args, i)?
+                    .split(',')
+                    .map(|server| Server::parse(server.trim()))
+                    .collect::<Result<_, _>>()?;
+                config.resolver.set_servers(servers);
+                i += 2;
+            }
+            "--dns-search" => {
+                config.resolver.set_search(arg_value(args, i)?.split(',').map(|domain| domain.trim().to_string()).filter(|domain| !domain.is_empty()).collect());
+                i += 2;
+            }
+            "--dns-timeout" => {
+                config.resolver.set_timeout(Duration::from_secs(arg_value(args, i)?.parse()?));
+                i += 2;
+            }
+            "--hosts-file" => {
+                config.hosts.load(&arg_value(args, i)?)?;
+                i += 2;
+            }
+            "--host-override" => {
+                config.hosts.add_entry(&arg_value(args, i)?)?;
+                i += 2;
+            }
+            "--dns-cache" => {
+                config.dns_cache_size = arg_value(args, i)?.parse()?;
+                i += 2;
+            }
+            "--dns-max-ttl" => {
+                config.dns_max_ttl = Duration::from_secs(arg_value(args, i)?.parse()?);
+                i += 2;
+            }
+            "--dns-negative-ttl" => {
+                config.dns_negative_ttl = Duration::from_secs(arg_value(args, i)?.parse()?);
+                i += 2;
+            }
+            "--dns-listen" => {
+                let addr = arg_value(args, i)?;
+                config.dns_listen = Some(addr.parse().map_err(|_| format!("Error: Invalid DNS listen address: {}", addr))?);
+                i += 2;
+            }
+            "--dns-block-response" => {
+                config.dns_block_response = BlockResponse::parse(&arg_value(args, i)?)?;
+                i += 2;
+            }
+            "--doh" => {
+                config.doh = true;
+                i += 1;
+            }
+            "--connect-timeout" => {
+                config.connect_timeout = Duration::from_secs(arg_value(args, i)?.parse()?);
+                i += 2;
+            }
+            "--max-header-bytes" => {
+                config.head_limits.max_bytes = arg_value(args, i)?.parse()?;
+                i += 2;
+            }
+            "--max-headers" => {
+                config.head_limits.max_headers = arg_value(args, i)?.parse()?;
+                i += 2;
+            }
+            "--header-timeout" => {
+                config.head_limits.timeout = Duration::from_secs(arg_value(args, i)?.parse()?);
+                i += 2;
+            }
+            "--header-min-rate" => {
+                config.head_limits.min_rate = arg_value(args, i)?.parse()?;
+                i += 2;
+            }
+            "--pool-max-idle" => {
+                config.pool_max_idle = arg_value(args, i)?.parse()?;
+                i += 2;
+            }
+            "--pool-idle-timeout" => {
+                config.pool_idle_timeout = Duration::from_secs(arg_value(args, i)?.parse()?);
+                i += 2;
+            }
+            "--origin-http2" => {
+                config.origin_http2 = true;
+                i += 1;
+            }
+            "--idle-timeout" => {
+                config.idle_timeout = Duration::from_secs(arg_value(args, i)?.parse::<u64>()? * 60);
+                i += 2;
+            }
+            "--max-open-files" => {
+                config.max_open_files = Some(match arg_value(args, i)?.as_str() {
+                    "max" => u64::MAX,
+                    limit => limit.parse()?,
+                });
+                i += 2;
+            }
+            "--worker-threads" => {
+                config.worker_threads = Some(arg_value(args, i)?.parse()?);
+                i += 2;
+            }
+            "--max-blocking-threads" => {
+                config.max_blocking_threads = Some(arg_value(args, i)?.parse()?);
+                i += 2;
+            }
+            "--thread-name" => {
+                config.thread_name = arg_value(args, i)?;
+                i += 2;
+            }
+            "--acceptors" => {
+                config.acceptors = match arg_value(args, i)?.as_str() {
+                    // Several acceptors need SO_REUSEPORT.
+                    "auto" if !cfg!(unix) => 1,
+                    "auto" => std::thread::available_parallelism()?.get(),
+                    count => count.parse()?,
+                };
+                i += 2;
+            }
+            "--decoy" => {
+                config.decoy = Some(Decoy::parse(&arg_value(args, i)?)?);
+                i += 2;
+            }
+            "--webhook" => {
+                config.webhooks.push(arg_value(args, i)?);
+                i += 2;
+            }
+            "--webhook-template" => {
+                config.webhook_template = Some(arg_value(args, i)?);
+                i += 2;
+            }
+            "--webhook-auth-failures" => {
+                config.webhook_auth_failures = arg_value(args, i)?.parse()?;
+                i += 2;
+            }
+            "--webhook-upstream-failures" => {
+                config.webhook_upstream_failures = arg_value(args, i)?.parse()?;
+                i += 2;
+            }
+            _ => {
+                eprintln!("Warning: Unknown argument: {}", args[i]);
+                i += 1;
+            }
+        }
+    }
+    config.finish()
+}
@@ -0,0 +1,576 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use brotli::{BrotliDecompressStream, BrotliResult, BrotliState, CompressorWriter, HeapAlloc, HuffmanCode};
+use flate2::write::{GzDecoder, GzEncoder};
+use flate2::Compression;
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{HeaderValue, ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, VARY};
+use hyper::{Body, HeaderMap, Response};
+use std::error::Error;
+use std::io::{self, Write};
+use crate::budget::{MemoryBudget, OverBudget};
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+/// Smallest response compressed unless configured; below it the encoding
+/// overhead eats most of the saving.
+pub const DEFAULT_MIN_SIZE: u64 = 1024;
+/// Content types other than `text/*` worth compressing.
+const COMPRESSIBLE_TYPES: [&str; 4] = ["json", "javascript", "xml", "wasm"];
+/// Brotli settings cheap enough to run on every response: quality 4 of 11
+/// and a 4 MiB window.
+const BROTLI_QUALITY: u32 = 4;
+const BROTLI_WINDOW: u32 = 22;
+/// Working buffer of the brotli encoder.
+const BROTLI_BUFFER: usize = 4096;
+/// Most decoded output produced at a time, so a small chunk that inflates
+/// enormously is handed on (and counted) a piece at a time.
+const DECODE_STEP: usize = 64 * 1024;
+
+/*************************************************
+ * Encoding
+ *************************************************/
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// The coding a response was sent in, if one that can be undone here.
+    fn of(headers: &HeaderMap) -> Option<Self> {
+        match headers.get(CONTENT_ENCODING)?.to_str().ok()?.trim().to_ascii_lowercase().as_str() {
+            "br" => Some(Encoding::Brotli),
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            _ => None,
+        }
+    }
+
+    fn token(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+/// The weights a client gave brotli and gzip in its Accept-Encoding.
+#[derive(Clone, Copy)]
+pub struct Accepted {
+    brotli: f32,
+    gzip: f32,
+}
+
+impl Accepted {
+    pub fn parse(headers: &HeaderMap) -> Self {
+        let weights: Vec<(String, f32)> = codings(headers)
+            .map(|(name, weight)| (name, weight.unwrap_or(0.0)))
+            .collect();
+        let weight = |names: &[&str]| {
+            weights.iter().find(|(name, _)| names.contains(&name.as_str()))
+                .or_else(|| weights.iter().find(|(name, _)| name == "*"))
+                .map_or(0.0, |(_, weight)| *weight)
+        };
+        Accepted { brotli: weight(&["br"]), gzip: weight(&["gzip", "x-gzip"]) }
+    }
+
+    /// What to compress with: brotli if the client takes it at least as
+    /// gladly as gzip, else gzip, else nothing.
+    pub fn preferred(&self) -> Option<Encoding> {
+        if self.brotli > 0.0 && self.brotli >= self.gzip {
+            Some(Encoding::Brotli)
+        } else if self.gzip > 0.0 {
+            Some(Encoding::Gzip)
+        } else {
+            None
+        }
+    }
+
+    pub fn accepts(&self, encoding: Encoding) -> bool {
+        match encoding {
+            Encoding::Brotli => self.brotli > 0.0,
+            Encoding::Gzip => self.gzip > 0.0,
+        }
+    }
+}
+
+/// The codings listed in Accept-Encoding, lowercased, with their weights;
+/// `None` for an unreadable weight, which refuses the coding rather than
+/// guess.
+fn codings(headers: &HeaderMap) -> impl Iterator<Item = (String, Option<f32>)> + '_ {
+    headers.get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter(|item| !item.trim().is_empty())
+        .map(|item| {
+            let mut params = item.split(';');
+            let name = params.next().unwrap_or_default().trim().to_ascii_lowercase();
+            let weight = params
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |weight| weight.trim().parse().ok());
+            (name, weight)
+        })
+}
+
+/// Narrow the Accept-Encoding of a request to the codings that can be
+/// undone here, so the response body can be looked into.
+pub fn restrict_accept_encoding(headers: &mut HeaderMap) {
+    let kept: Vec<String> = codings(headers)
+        .filter(|(name, _)| matches!(name.as_str(), "br" | "gzip" | "x-gzip" | "identity"))
+        .map(|(name, weight)| match weight {
+            Some(weight) if weight < 1.0 => format!("{};q={}", name, weight),
+            Some(_) => name,
+            None => format!("{};q=0", name),
+        })
+        .collect();
+    let value = if kept.is_empty() { String::from("identity") } else { kept.join(", ") };
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        headers.insert(ACCEPT_ENCODING, value);
+    }
+}
+
+/// Whether a response with `headers` is worth compressing and may be: text
+/// the origin didn't encode, of at least `min_size` bytes if the size is
+/// known, and without `no-transform`.
+pub fn is_compressible(headers: &HeaderMap, min_size: u64) -> bool {
+    let encoded = headers.get(CONTENT_ENCODING).is_some_and(|value| value != "identity");
+    let text = headers.get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_ascii_lowercase)
+        // Event streams are read as they arrive, which compression holds up.
+        .is_some_and(|value| !value.starts_with("text/event-stream")
+            && (value.starts_with("text/") || COMPRESSIBLE_TYPES.iter().any(|kind| value.contains(kind))));
+    let large = headers.get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<u64>().ok())
+        .is_none_or(|length| length >= min_size);
+    let no_transform = headers.get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"));
+    text && !encoded && large && !no_transform
+}
+
+/*************************************************
+ * decode
+ *************************************************/
+
+/// Undo the gzip or brotli coding of `response` as its body streams in,
+/// so it can be filtered and rewritten; returns the coding, to restore it
+/// afterwards. Responses in other codings are left alone. The body is cut
+/// off once more than `limit` bytes come out of the decoder, and each
+/// piece in flight is taken from `budget`.
+pub fn decode(response: &mut Response<Body>, limit: Option<u64>, budget: &MemoryBudget) -> Option<Encoding> {
+    let encoding = Encoding::of(response.headers())?;
+    response.headers_mut().remove(CONTENT_ENCODING);
+    response.headers_mut().remove(CONTENT_LENGTH);
+    let body = std::mem::take(response.body_mut());
+    let (mut sender, decoded) = Body::channel();
+    *response.body_mut() = decoded;
+    let budget = budget.clone();
+    tokio::spawn(async move {
+        if let Err(e) = decode_body(encoding, body, &mut sender, limit, budget).await {
+            log::debug!("Cannot decode response body: {}", e);
+            sender.abort();
+        }
+    });
+    Some(encoding)
+}
+
+async fn decode_body(
+    encoding: Encoding,
+    mut body: Body,
+    sender: &mut hyper::body::Sender,
+    limit: Option<u64>,
+    budget: MemoryBudget,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut decompressor = Decompressor::new(encoding);
+    let mut decoded = 0;
+    loop {
+        let chunk = body.data().await.transpose()?;
+        let mut input = chunk.as_deref().unwrap_or_default();
+        loop {
+            if chunk.is_some() && input.is_empty() {
+                break;
+            }
+            let _reservation = budget.try_reserve(DECODE_STEP).ok_or(OverBudget(DECODE_STEP))?;
+            let (piece, done) = match chunk {
+                Some(_) => (decompressor.step(&mut input)?, false),
+                None => decompressor.finish_step()?,
+            };
+            decoded += piece.len() as u64;
+            if let Some(limit) = limit.filter(|&limit| decoded > limit) {
+                return Err(format!("Decoded body exceeds {} bytes", limit).into());
+            }
+            // A send fails once the client is gone.
+            if !piece.is_empty() && sender.send_data(piece).await.is_err() {
+                return Ok(());
+            }
+            if done {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Decompresses a streamed body, the mirror image of `Compressor`, at
+/// most `DECODE_STEP` bytes at a time.
+struct Decompressor {
+    decoder: Decoder,
+}
+
+enum Decoder {
+    Brotli(Box<BrotliState<HeapAlloc<u8>, HeapAlloc<u32>, HeapAlloc<HuffmanCode>>>),
+    Gzip(Box<GzDecoder<StepSink>>),
+}
+
+/// Takes the gzip decoder's output until a step's worth is in, then
+/// refuses more; the decoder keeps the rest for the next step.
+#[derive(Default)]
+struct StepSink(Vec<u8>);
+
+impl Write for StepSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let room = DECODE_STEP.saturating_sub(self.0.len());
+        if room == 0 {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = room.min(buf.len());
+        self.0.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Decompressor {
+    fn new(encoding: Encoding) -> Self {
+        let decoder = match encoding {
+            // Strict: only RFC 7932 windows, at most 16 MiB.
+            Encoding::Brotli => Decoder::Brotli(Box::new(BrotliState::new_strict(
+                HeapAlloc::new(0),
+                HeapAlloc::new(0),
+                HeapAlloc::new(HuffmanCode::default()),
+            ))),
+            Encoding::Gzip => Decoder::Gzip(Box::new(GzDecoder::new(StepSink::default()))),
+        };
+        Decompressor { decoder }
+    }
+
+    /// Decode from `input` until it is used up or a step's worth of output
+    /// is in; `input` is left holding what wasn't taken yet.
+    fn step(&mut self, input: &mut &[u8]) -> io::Result<Bytes> {
+        match &mut self.decoder {
+            Decoder::Brotli(state) => {
+                let (result, output) = brotli_step(state, input);
+                match result {
+                    BrotliResult::ResultFailure => Err(io::Error::new(io::ErrorKind::InvalidData, "corrupt brotli stream")),
+                    BrotliResult::ResultSuccess if !input.is_empty() => Err(trailing_data()),
+                    _ => Ok(output),
+                }
+            }
+            Decoder::Gzip(decoder) => {
+                while !input.is_empty() {
+                    match decoder.write(input) {
+                        // Only once the stream has ended.
+                        Ok(0) => return Err(trailing_data()),
+                        Ok(n) => *input = &input[n..],
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(Bytes::from(std::mem::take(&mut decoder.get_mut().0)))
+            }
+        }
+    }
+
+    /// Once the body has ended, the next piece of what the decoder still
+    /// holds and whether that was the last; fails if the body was cut short.
+    fn finish_step(&mut self) -> io::Result<(Bytes, bool)> {
+        match &mut self.decoder {
+            Decoder::Brotli(state) => match brotli_step(state, &mut &[][..]) {
+                (BrotliResult::ResultSuccess, output) => Ok((output, true)),
+                (BrotliResult::NeedsMoreOutput, output) => Ok((output, false)),
+                (BrotliResult::NeedsMoreInput, _) => Err(io::ErrorKind::UnexpectedEof.into()),
+                (BrotliResult::ResultFailure, _) => Err(io::Error::new(io::ErrorKind::InvalidData, "corrupt brotli stream")),
+            },
+            Decoder::Gzip(decoder) => {
+                let done = match decoder.try_finish() {
+                    Ok(()) => true,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => false,
+                    Err(e) => return Err(e),
+                };
+                Ok((Bytes::from(std::mem::take(&mut decoder.get_mut().0)), done))
+            }
+        }
+    }
+}
+
+/// Run the brotli decoder over `input` into a step's worth of output.
+fn brotli_step(
+    state: &mut BrotliState<HeapAlloc<u8>, HeapAlloc<u32>, HeapAlloc<HuffmanCode>>,
+    input: &mut &[u8],
+) -> (BrotliResult, Bytes) {
+    let mut output = vec![0u8; DECODE_STEP];
+    let (mut available_in, mut input_offset) = (input.len(), 0);
+    let (mut available_out, mut output_offset, mut total_out) = (output.len(), 0, 0);
+    let result = BrotliDecompressStream(
+        &mut available_in,
+        &mut input_offset,
+        input,
+        &mut available_out,
+        &mut output_offset,
+        &mut output,
+        &mut total_out,
+        state,
+    );
+    *input = &input[input_offset..];
+    output.truncate(output_offset);
+    (result, Bytes::from(output))
+}
+
+fn trailing_data() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "data after the end of the compressed stream")
+}
+
+/*************************************************
+ * Compressor
+ *************************************************/
+
+/// Compresses a streamed body; what the encoder has produced so far is
+/// handed back with each chunk fed to it.
+pub struct Compressor {
+    encoding: Encoding,
+    encoder: Encoder,
+}
+
+enum Encoder {
+    Brotli(Box<CompressorWriter<Vec<u8>>>),
+    Gzip(GzEncoder<Vec<u8>>),
+}
+
+impl Compressor {
+    pub fn new(encoding: Encoding) -> Self {
+        let encoder = match encoding {
+            Encoding::Brotli => Encoder::Brotli(Box::new(CompressorWriter::new(Vec::new(), BROTLI_BUFFER, BROTLI_QUALITY, BROTLI_WINDOW))),
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), Compression::default())),
+        };
+        Compressor { encoding, encoder }
+    }
+
+    /// Make response `headers` describe the compressed body: its encoding,
+    /// no length, varying on Accept-Encoding, and a weak entity tag since
+    /// the bytes differ from the origin's.
+    pub fn prepare(&self, headers: &mut HeaderMap) {
+        headers.remove(CONTENT_LENGTH);
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static(self.encoding.token()));
+        let varies = headers.get_all(VARY)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|name| name.trim().eq_ignore_ascii_case("accept-encoding"));
+        if !varies {
+            headers.append(VARY, HeaderValue::from_static("Accept-Encoding"));
+        }
+        let weakened = headers.get(ETAG)
+            .filter(|etag| !etag.as_bytes().starts_with(b"W/"))
+            .and_then(|etag| HeaderValue::from_bytes(&[b"W/", etag.as_bytes()].concat()).ok());
+        if let Some(etag) = weakened {
+            headers.insert(ETAG, etag);
+        }
+    }
+
+    pub fn feed(&mut self, chunk: &[u8]) -> Bytes {
+        // Writing into a Vec can't fail.
+        let output = match &mut self.encoder {
+            Encoder::Brotli(encoder) => {
+                let _ = encoder.write_all(chunk);
+                encoder.get_mut()
+            }
+            Encoder::Gzip(encoder) => {
+                let _ = encoder.write_all(chunk);
+                encoder.get_mut()
+            }
+        };
+        Bytes::from(std::mem::take(output))
+    }
+
+    /// Compress the `last` of the body and end the stream.
+    pub fn finish(mut self, last: &[u8]) -> Bytes {
+        let mut output = self.feed(last).to_vec();
+        match self.encoder {
+            Encoder::Brotli(encoder) => output.extend(encoder.into_inner()),
+            Encoder::Gzip(encoder) => output.extend(encoder.finish().unwrap_or_default()),
+        }
+        Bytes::from(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        pairs.iter().map(|(name, value)| (name.parse().unwrap(), value.parse().unwrap())).collect()
+    }
+
+    fn preferred(accept_encoding: &str) -> Option<Encoding> {
+        Accepted::parse(&headers(&[("accept-encoding", accept_encoding)])).preferred()
+    }
+
+    fn compress(encoding: Encoding, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut compressor = Compressor::new(encoding);
+        let mut output = Vec::new();
+        for chunk in chunks {
+            output.extend_from_slice(&compressor.feed(chunk));
+        }
+        output.extend_from_slice(&compressor.finish(b""));
+        output
+    }
+
+    #[test]
+    fn negotiates_by_weight() {
+        assert!(preferred("gzip, deflate, br") == Some(Encoding::Brotli));
+        assert!(preferred("gzip;q=1, br;q=0.5") == Some(Encoding::Gzip));
+        assert!(preferred("br;q=0.8, gzip;q=0.8") == Some(Encoding::Brotli));
+        assert!(preferred("GZIP") == Some(Encoding::Gzip));
+        assert!(preferred("x-gzip") == Some(Encoding::Gzip));
+        assert!(preferred("*") == Some(Encoding::Brotli));
+        assert!(preferred("*;q=0.5, gzip") == Some(Encoding::Gzip));
+        assert!(preferred("br;q=0, *") == Some(Encoding::Gzip));
+        assert!(preferred("gzip;q=abc").is_none());
+        assert!(preferred("identity, deflate").is_none());
+        assert!(preferred("*;q=0").is_none());
+        assert!(Accepted::parse(&HeaderMap::new()).preferred().is_none());
+
+        let accepted = Accepted::parse(&headers(&[("accept-encoding", "gzip")]));
+        assert!(accepted.accepts(Encoding::Gzip) && !accepted.accepts(Encoding::Brotli));
+    }
+
+    #[test]
+    fn restricts_accept_encoding_to_decodable_codings() {
+        let restricted = |value: &str| {
+            let mut headers = headers(&[("accept-encoding", value)]);
+            restrict_accept_encoding(&mut headers);
+            headers[ACCEPT_ENCODING].to_str().unwrap().to_string()
+        };
+        assert_eq!(restricted("gzip, deflate, br;q=0.5, zstd"), "gzip, br;q=0.5");
+        assert_eq!(restricted("zstd, deflate"), "identity");
+        assert_eq!(restricted("gzip;q=x, identity"), "gzip;q=0, identity");
+    }
+
+    #[test]
+    fn compresses_only_sizable_unencoded_text() {
+        assert!(is_compressible(&headers(&[("content-type", "text/html"), ("content-length", "2048")]), 1024));
+        assert!(is_compressible(&headers(&[("content-type", "application/json")]), 1024));
+        assert!(!is_compressible(&headers(&[("content-type", "text/html"), ("content-length", "100")]), 1024));
+        assert!(!is_compressible(&headers(&[("content-type", "text/html"), ("content-encoding", "gzip")]), 1024));
+        assert!(!is_compressible(&headers(&[("content-type", "text/event-stream")]), 1024));
+        assert!(!is_compressible(&headers(&[("content-type", "image/jpeg")]), 1024));
+        assert!(!is_compressible(&headers(&[("content-type", "text/css"), ("cache-control", "public, No-Transform")]), 1024));
+    }
+
+    #[test]
+    fn prepares_headers_for_the_compressed_body() {
+        let mut response = headers(&[("content-length", "4096"), ("etag", "\"v1\""), ("vary", "Origin")]);
+        Compressor::new(Encoding::Brotli).prepare(&mut response);
+        assert_eq!(response[CONTENT_ENCODING], "br");
+        assert!(!response.contains_key(CONTENT_LENGTH));
+        assert_eq!(response[ETAG], "W/\"v1\"");
+        assert_eq!(response.get_all(VARY).iter().collect::<Vec<_>>(), ["Origin", "Accept-Encoding"]);
+
+        let mut again = response.clone();
+        Compressor::new(Encoding::Gzip).prepare(&mut again);
+        assert_eq!(again[ETAG], "W/\"v1\"");
+        assert_eq!(again.get_all(VARY).iter().count(), 2);
+    }
+
+    #[test]
+    fn round_trips_streamed_bodies() {
+        let body = "line of text to compress\n".repeat(500);
+        let chunks: Vec<&[u8]> = body.as_bytes().chunks(777).collect();
+        for encoding in [Encoding::Brotli, Encoding::Gzip] {
+            let compressed = compress(encoding, &chunks);
+            assert!(compressed.len() < body.len() / 10);
+            let mut decompressor = Decompressor::new(encoding);
+            let mut output = Vec::new();
+            for mut chunk in compressed.chunks(100) {
+                while !chunk.is_empty() {
+                    output.extend_from_slice(&decompressor.step(&mut chunk).unwrap());
+                }
+            }
+            let (last, done) = decompressor.finish_step().unwrap();
+            output.extend_from_slice(&last);
+            assert!(done && output == body.as_bytes());
+        }
+    }
+
+    #[test]
+    fn decodes_a_step_at_a_time() {
+        let body = vec![0u8; 8 << 20];
+        for encoding in [Encoding::Brotli, Encoding::Gzip] {
+            let compressed = compress(encoding, &[&body]);
+            let mut decompressor = Decompressor::new(encoding);
+            let mut input = &compressed[..];
+            let mut decoded = 0;
+            while !input.is_empty() {
+                let piece = decompressor.step(&mut input).unwrap();
+                assert!(piece.len() <= DECODE_STEP);
+                decoded += piece.len();
+            }
+            loop {
+                let (piece, done) = decompressor.finish_step().unwrap();
+                assert!(piece.len() <= DECODE_STEP);
+                decoded += piece.len();
+                if done {
+                    break;
+                }
+            }
+            assert_eq!(decoded, body.len());
+        }
+    }
+
+    #[tokio::test]
+    async fn cuts_off_bodies_that_decode_past_the_limit() {
+        let compressed = compress(Encoding::Gzip, &[&vec![0u8; 4 << 20]]);
+        let mut response = Response::new(Body::from(compressed));
+        response.headers_mut().insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        decode(&mut response, Some(1 << 20), &MemoryBudget::new(None));
+        assert!(hyper::body::to_bytes(response.into_body()).await.is_err());
+
+        let compressed = compress(Encoding::Brotli, &[b"within budget"]);
+        let mut response = Response::new(Body::from(compressed));
+        response.headers_mut().insert(CONTENT_ENCODING, HeaderValue::from_static("br"));
+        decode(&mut response, None, &MemoryBudget::new(Some(DECODE_STEP - 1)));
+        assert!(hyper::body::to_bytes(response.into_body()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn decodes_responses_in_known_codings() {
+        let compressed = compress(Encoding::Gzip, &[b"hello"]);
+        let mut response = Response::new(Body::from(compressed.clone()));
+        response.headers_mut().insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        response.headers_mut().insert(CONTENT_LENGTH, HeaderValue::from(compressed.len()));
+        assert!(decode(&mut response, None, &MemoryBudget::new(None)) == Some(Encoding::Gzip));
+        assert!(!response.headers().contains_key(CONTENT_ENCODING) && !response.headers().contains_key(CONTENT_LENGTH));
+        assert_eq!(hyper::body::to_bytes(response.into_body()).await.unwrap(), "hello");
+
+        let mut truncated = Response::new(Body::from(compressed[..compressed.len() - 4].to_vec()));
+        truncated.headers_mut().insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        decode(&mut truncated, None, &MemoryBudget::new(None));
+        assert!(hyper::body::to_bytes(truncated.into_body()).await.is_err());
+
+        let mut zstd = Response::new(Body::from("opaque"));
+        zstd.headers_mut().insert(CONTENT_ENCODING, HeaderValue::from_static("zstd"));
+        assert!(decode(&mut zstd, None, &MemoryBudget::new(None)).is_none());
+        assert_eq!(zstd.headers()[CONTENT_ENCODING], "zstd");
+    }
+}
@@ -0,0 +1,315 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::JoinHandle;
+use std::error::Error;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use log::{error, info};
+use crate::rules::url_pattern;
+use crate::{AcceptBackoff, Context};
+use crate::stats::{Counter, DEFAULT_TOP_LIMIT, DEFAULT_WINDOW_MINS};
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+/// Where `rdnat ctl` looks for the socket unless given one.
+pub const DEFAULT_CTL_SOCKET: &str = "rdnat.sock";
+/// Control commands change the proxy, so only its own user may connect:
+/// the socket is created with every permission bit but the owner's read
+/// and write masked off.
+const SOCKET_UMASK: libc::mode_t = 0o177;
+
+/*************************************************
+ * serve
+ *************************************************/
+
+/// Accept control connections; each one carries a single command line.
+/// A socket left at `path` by an earlier run is replaced, anything else
+/// there is left alone. Returns the accepting task.
+pub fn serve(path: &str, ctx: Arc<Context>) -> Result<JoinHandle<()>, Box<dyn Error>> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => return Err(format!("Error: Control socket path {} exists and is not a socket", path).into()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(format!("Error: Cannot use control socket path {}: {}", path, e).into()),
+    }
+    // Set before bind() rather than chmod'ed after, so there is no moment
+    // another user could connect. The umask is the process's, and is put
+    // back at once.
+    let umask = unsafe { libc::umask(SOCKET_UMASK) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+    let listener = listener.map_err(|e| format!("Error: Cannot listen on control socket {}: {}", path, e))?;
+    info!("Control socket listening on {}", path);
+
+    Ok(tokio::spawn(async move {
+        let mut backoff = AcceptBackoff::default();
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    backoff.failed(&e).await;
+                    continue;
+                }
+            };
+            backoff.reset();
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                if let Err(e) = ctl_worker(stream, ctx).await {
+                    error!("Control command failed: {}", e);
+                }
+            });
+        }
+    }))
+}
+
+async fn ctl_worker(stream: UnixStream, ctx: Arc<Context>) -> Result<(), Box<dyn Error>> {
+    let (reader, mut writer) = stream.into_split();
+    let mut line = String::new();
+    BufReader::new(reader).read_line(&mut line).await?;
+
+    let args: Vec<String> = line.split_whitespace().map(String::from).collect();
+    let response = execute(&ctx, &args).unwrap_or_else(|e| format!("{}\n", e));
+    writer.write_all(response.as_bytes()).await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/*************************************************
+ * execute
+ *************************************************/
+
+fn execute(ctx: &Context, args: &[String]) -> Result<String, Box<dyn Error>> {
+    match args.first().map(String::as_str) {
+        Some("top-destinations") => top_destinations(ctx, &args[1..]),
+        Some("log-level") => log_level(&args[1..]),
+        Some("reload-tls") => reload_tls(ctx),
+        Some("upstreams") => Ok(upstreams(ctx)),
+        Some("dns-cache") => dns_cache(ctx, &args[1..]),
+        Some("cache") => cache(ctx, &args[1..]),
+        Some("relay") => Ok(relay(ctx)),
+        Some("memory") => Ok(memory(ctx)),
+        Some("help") | None => Ok(ctl_help()),
+        Some(command) => Err(format!("Error: Unknown command: {}", command).into()),
+    }
+}
+
+fn ctl_help() -> String {
+    let mut help = String::from("Commands:\n");
+    help.push_str("  top-destinations [--window <mins>] [--limit <n>]  Busiest destinations and users (default: 5 minutes, top 10)\n");
+    help.push_str("  log-level [<level>]                               Show or change the log level (off, error, warn, info, debug, trace)\n");
+    help.push_str("  upstreams                                         Upstream proxies with their health and connections in use\n");
+    help.push_str("  dns-cache [flush]                                 DNS cache size and hit rate, or empty the cache\n");
+    help.push_str("  cache [purge <pattern> | clear]                   Response cache size and hit rate, drop the responses for URLs matching\n");
+    help.push_str("                                                    <pattern> (wildcards, or ~regex), or empty the cache\n");
+    help.push_str("  relay                                             Bytes held up in tunnels by slow receivers, per direction\n");
+    help.push_str("  memory                                            Buffered data held against the memory budget, and what was shed\n");
+    help.push_str("  reload-tls                                        Reload the certificate and key of the TLS listener\n");
+    help.push_str("  help                                              Display this help message\n");
+    help
+}
+
+/*************************************************
+ * top_destinations
+ *************************************************/
+
+fn top_destinations(ctx: &Context, args: &[String]) -> Result<String, Box<dyn Error>> {
+    let mut window = DEFAULT_WINDOW_MINS;
+    let mut limit = DEFAULT_TOP_LIMIT;
+
+    let mut i = 0;
+    while i < args.len() {
+        let value = args.get(i + 1).ok_or(format!("Error: Missing argument for {}", args[i]))?;
+        match args[i].as_str() {
+            "--window" => window = value.parse()?,
+            "--limit" => limit = value.parse()?,
+            arg => return Err(format!("Error: Unknown argument: {}", arg).into()),
+        }
+        i += 2;
+    }
+
+    let (destinations, users) = ctx.stats.top(window, limit);
+    let mut report = format!("Top destinations (last {} minutes):\n", window);
+    report.push_str(&format_table("DESTINATION", &destinations));
+    report.push_str(&format!("\nTop users (last {} minutes):\n", window));
+    report.push_str(&format_table("USER", &users));
+    Ok(report)
+}
+
+fn format_table(title: &str, rows: &[(String, Counter)]) -> String {
+    let mut table = format!("  {:<40} {:>12} {:>16}\n", title, "CONNECTIONS", "BYTES");
+    for (name, counter) in rows {
+        table.push_str(&format!("  {:<40} {:>12} {:>16}\n", name, counter.connections, counter.bytes));
+    }
+    table
+}
+
+/*************************************************
+ * log_level
+ *************************************************/
+
+fn log_level(args: &[String]) -> Result<String, Box<dyn Error>> {
+    if let Some(level) = args.first() {
+        let level: log::LevelFilter = level.parse()
+            .map_err(|_| format!("Error: Invalid log level: {}", level))?;
+        log::set_max_level(level);
+        log::warn!("Log level changed to {}", level);
+    }
+    Ok(format!("Log level: {}\n", log::max_level()))
+}
+
+/*************************************************
+ * upstreams
+ *************************************************/
+
+fn upstreams(ctx: &Context) -> String {
+    let pools = ctx.config.upstream.iter()
+        .map(|pool| ("(default)", pool))
+        .chain(ctx.config.upstreams.iter().map(|(name, pool)| (name.as_str(), pool)));
+    let mut report = format!("  {:<16} {:<40} {:>6} {:<6} {:>12}\n", "ROUTE", "UPSTREAM", "WEIGHT", "STATE", "CONNECTIONS");
+    for (name, pool) in pools {
+        for (address, weight, up, active) in pool.status() {
+            let state = if up { "up" } else { "down" };
+            report.push_str(&format!("  {:<16} {:<40} {:>6} {:<6} {:>12}\n", name, address, weight, state, active));
+        }
+    }
+    report
+}
+
+/*************************************************
+ * dns_cache
+ *************************************************/
+
+fn dns_cache(ctx: &Context, args: &[String]) -> Result<String, Box<dyn Error>> {
+    let cache = ctx.guard.resolver().cache().ok_or("Error: The DNS cache is disabled")?;
+    match args.first().map(String::as_str) {
+        Some("flush") => {
+            let flushed = cache.flush();
+            info!("Flushed {} DNS cache entries", flushed);
+            Ok(format!("Flushed {} entries\n", flushed))
+        }
+        Some(arg) => Err(format!("Error: Unknown argument: {}", arg).into()),
+        None => {
+            let stats = cache.stats();
+            let lookups = stats.hits + stats.negative_hits + stats.misses;
+            let hit_rate = if lookups == 0 { 0.0 } else { (stats.hits + stats.negative_hits) as f64 * 100.0 / lookups as f64 };
+            let mut report = format!("  {:<16} {:>12}\n", "Entries", stats.entries);
+            report.push_str(&format!("  {:<16} {:>12}\n", "Negative", stats.negative_entries));
+            report.push_str(&format!("  {:<16} {:>12}\n", "Hits", stats.hits));
+            report.push_str(&format!("  {:<16} {:>12}\n", "Negative hits", stats.negative_hits));
+            report.push_str(&format!("  {:<16} {:>12}\n", "Misses", stats.misses));
+            report.push_str(&format!("  {:<16} {:>11.1}%\n", "Hit rate", hit_rate));
+            Ok(report)
+        }
+    }
+}
+
+/*************************************************
+ * cache
+ *************************************************/
+
+fn cache(ctx: &Context, args: &[String]) -> Result<String, Box<dyn Error>> {
+    let cache = ctx.cache.as_ref().ok_or("Error: The response cache is disabled")?;
+    let (purged, what) = match args.first().map(String::as_str) {
+        Some("purge") => {
+            let pattern = args.get(1).ok_or("Error: Missing URL pattern")?;
+            let regex = url_pattern(pattern).map_err(|e| format!("Error: Invalid URL pattern {}: {}", pattern, e))?;
+            (cache.purge(|url| regex.is_match(url)), format!("matching {}", pattern))
+        }
+        Some("clear") => (cache.purge(|_| true), String::from("all")),
+        Some(arg) => return Err(format!("Error: Unknown argument: {}", arg).into()),
+        None => {
+            let stats = cache.stats();
+            let lookups = stats.hits + stats.stale + stats.misses;
+            let hit_rate = if lookups == 0 { 0.0 } else { (stats.hits + stats.revalidated) as f64 * 100.0 / lookups as f64 };
+            let mut report = format!("  {:<16} {:>12}\n", "Entries", stats.entries);
+            report.push_str(&format!("  {:<16} {:>12}\n", "Size", stats.size));
+            report.push_str(&format!("  {:<16} {:>12}\n", "Capacity", stats.capacity));
+            if let Some((entries, size, capacity)) = stats.disk {
+                report.push_str(&format!("  {:<16} {:>12}\n", "Disk entries", entries));
+                report.push_str(&format!("  {:<16} {:>12}\n", "Disk size", size));
+                report.push_str(&format!("  {:<16} {:>12}\n", "Disk capacity", capacity));
+            }
+            report.push_str(&format!("  {:<16} {:>12}\n", "Hits", stats.hits));
+            report.push_str(&format!("  {:<16} {:>12}\n", "Stale", stats.stale));
+            report.push_str(&format!("  {:<16} {:>12}\n", "Revalidated", stats.revalidated));
+            report.push_str(&format!("  {:<16} {:>12}\n", "Misses", stats.misses));
+            report.push_str(&format!("  {:<16} {:>11.1}%\n", "Hit rate", hit_rate));
+            return Ok(report);
+        }
+    };
+    let (from_memory, from_disk) = purged;
+    info!("Purged {} cached responses from memory and {} from disk ({})", from_memory, from_disk, what);
+    Ok(format!("Purged {} responses from memory and {} from disk\n", from_memory, from_disk))
+}
+
+/*************************************************
+ * relay
+ *************************************************/
+
+fn relay(ctx: &Context) -> String {
+    let mut report = format!("  {:<10} {:>14} {:>10}\n", "Direction", "In flight", "Stalls");
+    for (name, flow) in [("Upload", &ctx.flows.upload), ("Download", &ctx.flows.download)] {
+        report.push_str(&format!(
+            "  {:<10} {:>14} {:>10}\n",
+            name,
+            flow.in_flight.load(Ordering::Relaxed),
+            flow.stalls.load(Ordering::Relaxed)
+        ));
+    }
+    report
+}
+
+/*************************************************
+ * reload_tls
+ *************************************************/
+
+fn reload_tls(ctx: &Context) -> Result<String, Box<dyn Error>> {
+    let listener = ctx.tls_listener.as_ref().ok_or("Error: The proxy has no TLS listener")?;
+    listener.reload()?;
+    info!("Reloaded TLS certificate {}", listener.cert_path());
+    Ok(format!("Reloaded TLS certificate {}\n", listener.cert_path()))
+}
+
+/*************************************************
+ * run
+ *************************************************/
+
+/// Handle `rdnat ctl [-s <socket>] <command>`: send a command to a running proxy.
+pub async fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut path = String::from(DEFAULT_CTL_SOCKET);
+    let mut command = args;
+    if args.len() > 1 && (args[0] == "-s" || args[0] == "--socket") {
+        path = args[1].clone();
+        command = &args[2..];
+    }
+
+    let mut stream = UnixStream::connect(&path).await
+        .map_err(|e| format!("Error: Cannot connect to control socket {}: {}", path, e))?;
+    stream.write_all(format!("{}\n", command.join(" ")).as_bytes()).await?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    print!("{}", response);
+    Ok(())
+}
+
+/*************************************************
+ * memory
+ *************************************************/
+
+fn memory(ctx: &Context) -> String {
+    let stats = ctx.budget.stats();
+    let limit = stats.limit.map_or_else(|| String::from("unlimited"), |limit| limit.to_string());
+    let mut report = format!("  {:<10} {:>14}\n", "Budget", limit);
+    report.push_str(&format!("  {:<10} {:>14}\n", "In use", stats.used));
+    report.push_str(&format!("  {:<10} {:>14}\n", "Shed", stats.shed));
+    report
+}
@@ -0,0 +1,56 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use std::error::Error;
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+const NOT_FOUND_PAGE: &str = "<html>\r\n<head><title>404 Not Found</title></head>\r\n<body>\r\n<center><h1>404 Not Found</h1></center>\r\n<hr><center>nginx</center>\r\n</body>\r\n</html>\r\n";
+
+/*************************************************
+ * Decoy
+ *************************************************/
+
+/// What direct requests for a path get instead of a 400, so a port scan
+/// sees an ordinary web server.
+pub enum Decoy {
+    NotFound,
+    Redirect(String),
+    Page(String),
+}
+
+impl Decoy {
+    /// `404`, an http(s):// URL to redirect to, or the path of an HTML page.
+    pub fn parse(value: &str) -> Result<Self, Box<dyn Error>> {
+        if value == "404" {
+            Ok(Decoy::NotFound)
+        } else if value.starts_with("http://") || value.starts_with("https://") {
+            Ok(Decoy::Redirect(value.to_string()))
+        } else {
+            let page = std::fs::read_to_string(value)
+                .map_err(|e| format!("Error: Cannot read decoy page {}: {}", value, e))?;
+            Ok(Decoy::Page(page))
+        }
+    }
+
+    pub async fn respond<W: AsyncWrite + Unpin>(&self, stream: &mut W) -> Result<(), Box<dyn Error>> {
+        let (status, extra, body) = match self {
+            Decoy::NotFound => ("404 Not Found", String::new(), NOT_FOUND_PAGE),
+            Decoy::Redirect(url) => ("301 Moved Permanently", format!("Location: {}\r\n", url), ""),
+            Decoy::Page(page) => ("200 OK", String::new(), page.as_str()),
+        };
+        let response = format!(
+            "HTTP/1.1 {}\r\nServer: nginx\r\nContent-Type: text/html\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            extra,
+            body
+        );
+        stream.write_all(response.as_bytes()).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use hyper::{Body, Client, Request};
+use serde_json::Value;
+use std::error::Error;
+use std::time::Duration;
+use crate::dns;
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+pub const DEFAULT_REFRESH_SECS: u64 = 30;
+
+/// Sent as `X-Consul-Token` when set.
+const CONSUL_TOKEN_ENV: &str = "CONSUL_HTTP_TOKEN";
+
+/*************************************************
+ * Discovery
+ *************************************************/
+
+/// Where the members of a pool are looked up, given instead of a list of
+/// URLs as `srv+<scheme>://[user:pass@]<name>` for DNS SRV records or
+/// `consul+<scheme>://[user:pass@]<host:port>/<service>[?tag=<tag>]` for
+/// the passing instances of a Consul service. `<scheme>` (http or socks5)
+/// and the credentials apply to every proxy found.
+pub struct Discovery {
+    scheme: String,
+    credentials: Option<String>,
+    source: Source,
+}
+
+enum Source {
+    Srv(String),
+    Consul { address: String, service: String, tag: Option<String> },
+}
+
+impl Discovery {
+    /// `None` when `url` is a plain proxy URL.
+    pub fn parse(url: &str) -> Result<Option<Self>, Box<dyn Error>> {
+        let Some((kind, rest)) = url.split_once('+').filter(|(kind, _)| matches!(*kind, "srv" | "consul")) else {
+            return Ok(None);
+        };
+        let invalid = || format!("Error: Invalid upstream discovery URL: {}", url);
+        let (scheme, rest) = rest.split_once("://").ok_or_else(invalid)?;
+        if !matches!(scheme, "http" | "socks5" | "socks5h") {
+            return Err(format!("Error: Discovered upstream proxies must be http or socks5: {}", url).into());
+        }
+        let (credentials, rest) = match rest.rsplit_once('@') {
+            Some((credentials, rest)) => (Some(credentials.to_string()), rest),
+            None => (None, rest),
+        };
+        let source = if kind == "srv" {
+            let name = rest.trim_end_matches('/');
+            if name.is_empty() || name.contains('/') {
+                return Err(invalid().into());
+            }
+            Source::Srv(name.to_string())
+        } else {
+            let (address, path) = rest.split_once('/').ok_or_else(invalid)?;
+            let (service, query) = path.split_once('?').unwrap_or((path, ""));
+            let tag = query.split('&').find_map(|pair| pair.strip_prefix("tag=")).map(str::to_string);
+            if address.is_empty() || service.is_empty() {
+                return Err(invalid().into());
+            }
+            Source::Consul { address: address.to_string(), service: service.to_string(), tag }
+        };
+        Ok(Some(Discovery { scheme: scheme.to_string(), credentials, source }))
+    }
+
+    /// URL and weight of every proxy found.
+    pub async fn resolve(&self, timeout: Duration) -> Result<Vec<(String, u32)>, Box<dyn Error + Send + Sync>> {
+        let found = match &self.source {
+            Source::Srv(name) => resolve_srv(name, timeout).await?,
+            Source::Consul { address, service, tag } => {
+                tokio::time::timeout(timeout, resolve_consul(address, service, tag.as_deref()))
+                    .await
+                    .map_err(|_| format!("Consul at {} timed out", address))??
+            }
+        };
+        let credentials = self.credentials.as_ref().map(|credentials| format!("{}@", credentials)).unwrap_or_default();
+        Ok(found.into_iter()
+            .map(|(address, weight)| (format!("{}://{}{}", self.scheme, credentials, address), weight))
+            .collect())
+    }
+
+    /// The name or service looked up, for logs.
+    pub fn describe(&self) -> String {
+        match &self.source {
+            Source::Srv(name) => format!("SRV {}", name),
+            Source::Consul { address, service, .. } => format!("Consul service {} at {}", service, address),
+        }
+    }
+}
+
+/// The targets of the records with the lowest priority (the others are
+/// backups), with their weights.
+async fn resolve_srv(name: &str, timeout: Duration) -> Result<Vec<(String, u32)>, Box<dyn Error + Send + Sync>> {
+    let records: Vec<_> = dns::srv(name, timeout).await?
+        .into_iter()
+        // A target of "." means the service isn't offered.
+        .filter(|record| !record.target().is_root())
+        .map(|record| (record.priority(), record.weight(), record.port(), record.target().to_utf8().trim_end_matches('.').to_string()))
+        .collect();
+    let Some(best) = records.iter().map(|(priority, ..)| *priority).min() else {
+        return Ok(Vec::new());
+    };
+    Ok(records.into_iter()
+        .filter(|(priority, ..)| *priority == best)
+        .map(|(_, weight, port, target)| (format!("{}:{}", target, port), weight as u32))
+        .collect())
+}
+
+/// The instances of `service` passing their health checks.
+async fn resolve_consul(address: &str, service: &str, tag: Option<&str>) -> Result<Vec<(String, u32)>, Box<dyn Error + Send + Sync>> {
+    let mut url = format!("http://{}/v1/health/service/{}?passing=true", address, service);
+    if let Some(tag) = tag {
+        url.push_str(&format!("&tag={}", tag));
+    }
+    let mut request = Request::get(url.as_str());
+    if let Ok(token) = std::env::var(CONSUL_TOKEN_ENV) {
+        request = request.header("X-Consul-Token", token);
+    }
+    let response = Client::new().request(request.body(Body::empty())?).await?;
+    if !response.status().is_success() {
+        return Err(format!("Consul at {} answered {}", address, response.status()).into());
+    }
+    let entries: Value = serde_json::from_slice(&hyper::body::to_bytes(response.into_body()).await?)?;
+    Ok(entries.as_array().into_iter().flatten()
+        .filter_map(|entry| {
+            let service = &entry["Service"];
+            let host = service["Address"].as_str().filter(|host| !host.is_empty())
+                .or_else(|| entry["Node"]["Address"].as_str())?;
+            let port = service["Port"].as_u64()?;
+            let weight = service["Weights"]["Passing"].as_u64().unwrap_or(1) as u32;
+            let host = if host.contains(':') { format!("[{}]", host) } else { host.to_string() };
+            Some((format!("{}:{}", host, port), weight))
+        })
+        .collect())
+}
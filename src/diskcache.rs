@@ -0,0 +1,414 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use hyper::body::Bytes;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Body, HeaderMap, Response, StatusCode};
+use ring::digest::{Context as Digest, SHA256};
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::cache::{vary_names, CacheRequest, Index, Meta};
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+pub const DEFAULT_DISK_SIZE: u64 = 1 << 30;
+/// Largest response kept on disk unless configured.
+pub const DEFAULT_DISK_MAX_OBJECT: u64 = 512 * 1024 * 1024;
+const READ_CHUNK: usize = 64 * 1024;
+/// Prefix of files still being written; left over ones are removed on
+/// startup.
+const TMP_PREFIX: &str = ".tmp-";
+
+/*************************************************
+ * DiskStore
+ *************************************************/
+
+/// The disk tier of the response cache: each response is a `<name>.body`
+/// file with a `<name>.meta` file next to it holding the head, freshness
+/// and SHA-256 of the body. Bodies are checked against it as they are
+/// read back, so a file damaged by a crash or a full disk is dropped
+/// instead of served. The index is rebuilt from the meta files on
+/// startup, so what was cached survives restarts.
+pub struct DiskStore {
+    dir: PathBuf,
+    capacity: u64,
+    max_object: u64,
+    index: Mutex<Index<DiskEntry>>,
+    next_id: AtomicU64,
+}
+
+struct DiskEntry {
+    key: String,
+    name: String,
+    meta: Meta,
+    size: u64,
+    checksum: String,
+}
+
+/// A body on its way to disk.
+pub struct DiskWriter {
+    file: tokio::fs::File,
+    tmp: PathBuf,
+    name: String,
+    size: u64,
+    max_object: u64,
+    digest: Digest,
+    finished: bool,
+}
+
+impl DiskStore {
+    /// Open the store in `dir`, creating it if needed, and index what it
+    /// holds; the least recently written responses over `capacity` are
+    /// removed.
+    pub fn open(dir: &str, capacity: u64, max_object: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let store = DiskStore {
+            dir: PathBuf::from(dir),
+            capacity,
+            max_object,
+            index: Mutex::new(Index::default()),
+            next_id: AtomicU64::new(0),
+        };
+
+        let mut found = Vec::new();
+        for file in fs::read_dir(dir)? {
+            let path = file?.path();
+            let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if file_name.starts_with(TMP_PREFIX) {
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+            let Some(name) = file_name.strip_suffix(".meta") else {
+                continue;
+            };
+            let loaded = fs::read(&path).ok()
+                .and_then(|meta| serde_json::from_slice::<Value>(&meta).ok())
+                .and_then(|meta| store.parse_meta(name, &meta));
+            match loaded {
+                Some((entry, request_headers)) => {
+                    let written = fs::metadata(&path).and_then(|metadata| metadata.modified()).unwrap_or(UNIX_EPOCH);
+                    found.push((written, entry, request_headers));
+                }
+                None => {
+                    log::warn!("Removing unreadable cache entry {}", path.display());
+                    store.remove_files(name);
+                }
+            }
+        }
+        // Oldest first, so the newest end up the most recently used.
+        found.sort_by_key(|(written, _, _)| *written);
+        let mut index = store.index.lock().unwrap();
+        for (_, entry, request_headers) in found {
+            let (key, size, vary) = (entry.key.clone(), entry.size, vary_names(&entry.meta.headers));
+            for replaced in index.insert(&key, &request_headers, vary, entry, size) {
+                store.remove_files(&replaced.name);
+            }
+        }
+        for evicted in index.make_room(0, capacity) {
+            store.remove_files(&evicted.name);
+        }
+        drop(index);
+        Ok(store)
+    }
+
+    /// Whether `response`, of `length` bytes if known, can be written to
+    /// disk: within the size limit and with a head the meta file can hold.
+    pub fn accepts(&self, response: &Response<Body>, length: Option<u64>) -> bool {
+        length.is_none_or(|length| length <= self.max_object.min(self.capacity))
+            && response.headers().values().all(|value| value.to_str().is_ok())
+    }
+
+    /// The stored response to `request`, with its body ready to be
+    /// streamed from disk.
+    pub fn lookup(self: &Arc<Self>, request: &CacheRequest) -> Option<(Meta, DiskBody)> {
+        let mut index = self.index.lock().unwrap();
+        let entry = index.get(&request.key, &request.headers)?;
+        let (name, size, checksum, meta) = (entry.name.clone(), entry.size, entry.checksum.clone(), entry.meta.clone());
+        drop(index);
+        // Opened now, so the body can still be read if the entry is
+        // replaced before it is used.
+        let file = match fs::File::open(self.body_path(&name)) {
+            Ok(file) => tokio::fs::File::from_std(file),
+            Err(e) => {
+                log::warn!("Cannot read cache entry {}: {}", name, e);
+                self.invalidate(&request.key);
+                return None;
+            }
+        };
+        Some((meta, DiskBody { store: self.clone(), key: request.key.clone(), name, file, size, checksum }))
+    }
+
+    /// Start writing a body to a temporary file.
+    pub async fn writer(&self) -> io::Result<DiskWriter> {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+        let name = format!("{:016x}{:04x}", nanos, self.next_id.fetch_add(1, Ordering::Relaxed) & 0xffff);
+        let tmp = self.dir.join(format!("{}{}.body", TMP_PREFIX, name));
+        let file = tokio::fs::File::create(&tmp).await?;
+        Ok(DiskWriter { file, tmp, name, size: 0, max_object: self.max_object, digest: Digest::new(&SHA256), finished: false })
+    }
+
+    /// Put a completely written body in place as the response to
+    /// `request`, making room for it.
+    pub async fn finish(&self, mut writer: DiskWriter, request: &CacheRequest, meta: &Meta) -> io::Result<()> {
+        writer.file.flush().await?;
+        let size = writer.size;
+        if size > self.capacity {
+            return Ok(());
+        }
+        let checksum = hex(writer.digest.clone().finish().as_ref());
+        let vary = vary_names(&meta.headers);
+        let request_headers = varied_headers(&vary, &request.headers);
+        tokio::fs::rename(&writer.tmp, self.body_path(&writer.name)).await?;
+        writer.finished = true;
+        if let Err(e) = self.write_meta(&writer.name, &request.key, &request_headers, meta, size, &checksum).await {
+            self.remove_files(&writer.name);
+            return Err(e);
+        }
+
+        let entry = DiskEntry { key: request.key.clone(), name: writer.name.clone(), meta: meta.clone(), size, checksum };
+        let mut index = self.index.lock().unwrap();
+        let mut removed = index.make_room(size, self.capacity);
+        removed.extend(index.insert(&request.key, &request_headers, vary, entry, size));
+        drop(index);
+        for entry in removed {
+            self.remove_files(&entry.name);
+        }
+        log::debug!("Cached {} on disk ({} bytes)", request.key, size);
+        Ok(())
+    }
+
+    /// Replace the `old` meta of the response to `request` with `meta`, if
+    /// it is still stored.
+    pub async fn update(&self, request: &CacheRequest, old: &Meta, meta: &Meta) -> io::Result<()> {
+        let stored = {
+            let mut index = self.index.lock().unwrap();
+            index.get(&request.key, &request.headers).filter(|entry| entry.meta.response_time == old.response_time).map(|entry| {
+                entry.meta = meta.clone();
+                (entry.name.clone(), entry.size, entry.checksum.clone())
+            })
+        };
+        let Some((name, size, checksum)) = stored else {
+            return Ok(());
+        };
+        let request_headers = varied_headers(&vary_names(&meta.headers), &request.headers);
+        self.write_meta(&name, &request.key, &request_headers, meta, size, &checksum).await
+    }
+
+    /// Forget what is stored under `key`, removing the files.
+    pub fn invalidate(&self, key: &str) {
+        let removed = self.index.lock().unwrap().remove(key);
+        for entry in removed {
+            self.remove_files(&entry.name);
+        }
+    }
+
+    /// Forget what is stored under every key that `matches`, removing the
+    /// files; returns how many responses that was.
+    pub fn purge(&self, matches: impl Fn(&str) -> bool) -> usize {
+        let removed = self.index.lock().unwrap().remove_matching(matches);
+        for entry in &removed {
+            self.remove_files(&entry.name);
+        }
+        removed.len()
+    }
+
+    /// Entries, bytes and capacity.
+    pub fn stats(&self) -> (usize, u64, u64) {
+        let index = self.index.lock().unwrap();
+        (index.entries(), index.size(), self.capacity)
+    }
+
+    /// Write the meta file of body `name`, replacing any there was.
+    async fn write_meta(&self, name: &str, key: &str, request_headers: &HeaderMap, meta: &Meta, size: u64, checksum: &str) -> io::Result<()> {
+        let meta_json = json!({
+            "key": key,
+            "status": meta.status.as_u16(),
+            "headers": header_pairs(&meta.headers),
+            "request_headers": header_pairs(request_headers),
+            "response_time": meta.response_time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+            "initial_age": meta.initial_age.as_secs(),
+            "freshness": meta.freshness.as_secs(),
+            "must_revalidate": meta.must_revalidate,
+            "size": size,
+            "sha256": checksum,
+        });
+        let meta_tmp = self.dir.join(format!("{}{}.meta", TMP_PREFIX, name));
+        let written = async {
+            tokio::fs::write(&meta_tmp, meta_json.to_string()).await?;
+            tokio::fs::rename(&meta_tmp, self.meta_path(name)).await
+        };
+        let written = written.await;
+        if written.is_err() {
+            let _ = tokio::fs::remove_file(&meta_tmp).await;
+        }
+        written
+    }
+
+    fn parse_meta(&self, name: &str, meta: &Value) -> Option<(DiskEntry, HeaderMap)> {
+        let headers = parse_headers(meta.get("headers")?)?;
+        let request_headers = parse_headers(meta.get("request_headers")?)?;
+        let size = meta.get("size")?.as_u64()?;
+        // A body of another size than recorded was never finished.
+        if fs::metadata(self.body_path(name)).ok()?.len() != size {
+            return None;
+        }
+        let entry = DiskEntry {
+            key: meta.get("key")?.as_str()?.to_string(),
+            name: name.to_string(),
+            meta: Meta {
+                status: StatusCode::from_u16(meta.get("status")?.as_u64()? as u16).ok()?,
+                headers,
+                response_time: UNIX_EPOCH + Duration::from_millis(meta.get("response_time")?.as_u64()?),
+                initial_age: Duration::from_secs(meta.get("initial_age")?.as_u64()?),
+                freshness: Duration::from_secs(meta.get("freshness")?.as_u64()?),
+                must_revalidate: meta.get("must_revalidate")?.as_bool()?,
+            },
+            size,
+            checksum: meta.get("sha256")?.as_str()?.to_string(),
+        };
+        Some((entry, request_headers))
+    }
+
+    fn body_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.body", name))
+    }
+
+    fn meta_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.meta", name))
+    }
+
+    fn remove_files(&self, name: &str) {
+        for path in [self.meta_path(name), self.body_path(name)] {
+            remove_quietly(&path);
+        }
+    }
+}
+
+/// A stored body, streamed from its file.
+pub struct DiskBody {
+    store: Arc<DiskStore>,
+    key: String,
+    name: String,
+    file: tokio::fs::File,
+    size: u64,
+    checksum: String,
+}
+
+impl DiskBody {
+    /// The body, checked against its checksum as it is read.
+    pub fn into_body(self) -> Body {
+        let DiskBody { store, key, name, mut file, size, checksum } = self;
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            let mut digest = Digest::new(&SHA256);
+            let mut read = 0;
+            let mut buf = vec![0u8; READ_CHUNK];
+            // The last chunk is held back until the body checks out, so a
+            // corrupt one never reaches the client whole.
+            let mut held: Option<Bytes> = None;
+            loop {
+                let n = match file.read(&mut buf).await {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(e) => {
+                        log::warn!("Cannot read cache entry {}: {}", name, e);
+                        sender.abort();
+                        return;
+                    }
+                };
+                digest.update(&buf[..n]);
+                read += n as u64;
+                if let Some(chunk) = held.replace(Bytes::copy_from_slice(&buf[..n])) {
+                    if sender.send_data(chunk).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            if read != size || hex(digest.finish().as_ref()) != checksum {
+                // Too late to answer otherwise; cutting the body short
+                // tells the client it didn't get the response.
+                log::warn!("Cache entry {} for {} is corrupt, removing it", name, key);
+                sender.abort();
+                store.invalidate(&key);
+                return;
+            }
+            if let Some(chunk) = held {
+                let _ = sender.send_data(chunk).await;
+            }
+        });
+        body
+    }
+}
+
+impl DiskWriter {
+    /// Append a chunk of the body; fails once the body outgrows the size
+    /// limit.
+    pub async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        if self.size + chunk.len() as u64 > self.max_object {
+            return Err(io::Error::other("Response too large for the disk cache"));
+        }
+        self.file.write_all(chunk).await?;
+        self.digest.update(chunk);
+        self.size += chunk.len() as u64;
+        Ok(())
+    }
+}
+
+impl Drop for DiskWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            remove_quietly(&self.tmp);
+        }
+    }
+}
+
+/// What a request with `headers` sent for each header in `vary`, to index
+/// the variant again on startup.
+fn varied_headers(vary: &[HeaderName], headers: &HeaderMap) -> HeaderMap {
+    let mut varied = HeaderMap::new();
+    for name in vary {
+        for value in headers.get_all(name).iter().filter(|value| value.to_str().is_ok()) {
+            varied.append(name.clone(), value.clone());
+        }
+    }
+    varied
+}
+
+fn header_pairs(headers: &HeaderMap) -> Vec<Value> {
+    headers.iter()
+        .filter_map(|(name, value)| Some(json!([name.as_str(), value.to_str().ok()?])))
+        .collect()
+}
+
+fn parse_headers(headers: &Value) -> Option<HeaderMap> {
+    let mut map = HeaderMap::new();
+    for header in headers.as_array()? {
+        let name = HeaderName::from_bytes(header.get(0)?.as_str()?.as_bytes()).ok()?;
+        let value = HeaderValue::from_str(header.get(1)?.as_str()?).ok()?;
+        map.append(name, value);
+    }
+    Some(map)
+}
+
+fn remove_quietly(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            log::warn!("Cannot remove {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
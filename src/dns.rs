@@ -0,0 +1,377 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use hickory_resolver::config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts, ServerOrderingStrategy, TlsClientConfig};
+use hickory_resolver::config::LookupIpStrategy;
+use hickory_resolver::error::{ResolveError, ResolveErrorKind};
+use hickory_resolver::lookup::Lookup;
+use hickory_resolver::proto::rr::rdata::SRV;
+use hickory_resolver::proto::rr::RecordType;
+use hickory_resolver::{Name, TokioAsyncResolver};
+use hyper::Uri;
+use tokio::sync::OnceCell;
+use tokio_rustls::rustls::ClientConfig;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::dnscache::DnsCache;
+use crate::hosts::HostOverrides;
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+pub const DEFAULT_TIMEOUT_SECS: u64 = 5;
+
+const DNS_PORT: u16 = 53;
+const DOT_PORT: u16 = 853;
+const DOH_PORT: u16 = 443;
+/// The only path hickory sends DNS over HTTPS queries to.
+const DOH_PATH: &str = "/dns-query";
+/// How long system resolver answers are cached, as `getaddrinfo` doesn't
+/// tell their TTL.
+const SYSTEM_TTL: Duration = Duration::from_secs(60);
+
+/*************************************************
+ * Server
+ *************************************************/
+
+/// A name server and how to reach it.
+#[derive(Clone)]
+pub enum Server {
+    /// Plain DNS over UDP, with TCP for truncated answers.
+    Udp(SocketAddr),
+    /// DNS over TLS (RFC 7858): `tls://<host>[:<port>]`, the certificate
+    /// checked against `<host>`.
+    Tls { host: String, port: u16 },
+    /// DNS over HTTPS (RFC 8484): `https://<host>[:<port>]/dns-query`.
+    Https(Uri),
+}
+
+impl Server {
+    pub fn parse(value: &str) -> Result<Self, Box<dyn Error>> {
+        let invalid = || format!("Error: Invalid name server: {}", value);
+        if value.starts_with("https://") {
+            let uri: Uri = value.parse().map_err(|_| invalid())?;
+            if uri.host().is_none_or(str::is_empty) {
+                return Err(invalid().into());
+            }
+            if uri.path_and_query().map(|path| path.as_str()) != Some(DOH_PATH) {
+                return Err(format!("Error: DNS over HTTPS is only spoken at {}: {}", DOH_PATH, value).into());
+            }
+            return Ok(Server::Https(uri));
+        }
+        if let Some(address) = value.strip_prefix("tls://") {
+            let address = address.trim_end_matches('/');
+            let (host, port) = match address.rsplit_once(':').filter(|(host, _)| !host.contains(':') || host.ends_with(']')) {
+                Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+                None => (address, DOT_PORT),
+            };
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            if host.is_empty() {
+                return Err(invalid().into());
+            }
+            return Ok(Server::Tls { host: host.to_string(), port });
+        }
+        value.parse::<SocketAddr>()
+            .or_else(|_| value.trim_start_matches('[').trim_end_matches(']').parse().map(|ip| SocketAddr::new(ip, DNS_PORT)))
+            .map(Server::Udp)
+            .map_err(|_| invalid().into())
+    }
+
+    fn is_encrypted(&self) -> bool {
+        !matches!(self, Server::Udp(_))
+    }
+
+    /// How hickory reaches this server: over UDP and TCP, or at every
+    /// address of an encrypted server's host.
+    async fn configs(&self, tls: Option<&TlsClientConfig>) -> io::Result<Vec<NameServerConfig>> {
+        let (host, port, protocol) = match self {
+            Server::Udp(addr) => return Ok(vec![NameServerConfig::new(*addr, Protocol::Udp), NameServerConfig::new(*addr, Protocol::Tcp)]),
+            Server::Tls { host, port } => (host.as_str(), *port, Protocol::Tls),
+            Server::Https(uri) => (uri.host().unwrap_or_default(), uri.port_u16().unwrap_or(DOH_PORT), Protocol::Https),
+        };
+        let tls = tls.ok_or_else(|| io::Error::other(format!("{}: TLS is not set up", self)))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let addrs: Vec<SocketAddr> = match host.parse::<IpAddr>() {
+            Ok(ip) => vec![SocketAddr::new(ip, port)],
+            Err(_) => tokio::net::lookup_host((host, port)).await
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", self, e)))?
+                .collect(),
+        };
+        Ok(addrs.into_iter()
+            .map(|addr| NameServerConfig {
+                tls_dns_name: Some(host.to_string()),
+                tls_config: Some(tls.clone()),
+                ..NameServerConfig::new(addr, protocol)
+            })
+            .collect())
+    }
+}
+
+impl fmt::Display for Server {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Server::Udp(addr) => write!(f, "{}", addr),
+            Server::Tls { host, port } => write!(f, "tls://{}:{}", host, port),
+            Server::Https(uri) => write!(f, "{}", uri),
+        }
+    }
+}
+
+/*************************************************
+ * Resolver
+ *************************************************/
+
+/// How destination host names are resolved. Left unconfigured, this is
+/// the system resolver (`getaddrinfo`, so `/etc/hosts` and NSS apply);
+/// with name servers or search domains set, rdnat asks the name servers
+/// itself through hickory (those of `/etc/resolv.conf` when none are
+/// given). Name servers can be reached over TLS or HTTPS so lookups don't
+/// leave the host in plaintext.
+#[derive(Clone)]
+pub struct Resolver {
+    servers: Vec<Server>,
+    search: Vec<String>,
+    timeout: Duration,
+    tls: Option<TlsClientConfig>,
+    cache: Option<Arc<DnsCache>>,
+    hosts: Arc<HostOverrides>,
+    /// The hickory resolver, set up on first use as reaching encrypted
+    /// servers may need a lookup of their own; reset by every setter.
+    client: Arc<OnceCell<TokioAsyncResolver>>,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Resolver {
+            servers: Vec::new(),
+            search: Vec::new(),
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+            tls: None,
+            cache: None,
+            hosts: Arc::default(),
+            client: Arc::default(),
+        }
+    }
+}
+
+impl Resolver {
+    pub fn set_servers(&mut self, servers: Vec<Server>) {
+        self.servers = servers;
+        self.client = Arc::default();
+    }
+
+    /// The TLS settings encrypted name servers are verified with. Names of
+    /// encrypted servers are themselves looked up with the system resolver,
+    /// so give IP addresses to keep every lookup private.
+    pub fn set_tls_config(&mut self, mut config: ClientConfig) {
+        if !self.servers.iter().any(Server::is_encrypted) {
+            return;
+        }
+        // DNS over TLS negotiates no protocol and hickory asks for h2 for
+        // DNS over HTTPS when none is set.
+        config.alpn_protocols.clear();
+        self.tls = Some(TlsClientConfig(Arc::new(config)));
+        self.client = Arc::default();
+    }
+
+    pub fn set_search(&mut self, search: Vec<String>) {
+        self.search = search;
+        self.client = Arc::default();
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+        self.client = Arc::default();
+    }
+
+    pub fn set_cache(&mut self, cache: Option<Arc<DnsCache>>) {
+        self.cache = cache;
+    }
+
+    /// Addresses that take precedence over DNS and the cache.
+    pub fn set_hosts(&mut self, hosts: HostOverrides) {
+        self.hosts = Arc::new(hosts);
+    }
+
+    pub fn cache(&self) -> Option<&DnsCache> {
+        self.cache.as_deref()
+    }
+
+    fn is_system(&self) -> bool {
+        self.servers.is_empty() && self.search.is_empty()
+    }
+
+    /// The addresses of `host` (a name or an IP literal), with `port`.
+    pub async fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        if let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        let host = host.to_ascii_lowercase();
+        if let Some(ips) = self.hosts.get(&host) {
+            return Ok(ips.iter().map(|&ip| SocketAddr::new(ip, port)).collect());
+        }
+        let ips = match self.cache.as_ref().and_then(|cache| cache.get(&host)) {
+            Some(ips) if ips.is_empty() => return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} did not resolve (cached)", host))),
+            Some(ips) => ips,
+            None => {
+                let resolved = self.resolve(&host).await;
+                if let Some(cache) = &self.cache {
+                    // A timeout says nothing about the name; anything else
+                    // is likely to fail the same way again soon.
+                    match &resolved {
+                        Ok((ips, ttl)) => cache.insert(&host, ips.clone(), *ttl),
+                        Err(e) if e.kind() != io::ErrorKind::TimedOut => cache.insert(&host, Vec::new(), Duration::ZERO),
+                        Err(_) => {}
+                    }
+                }
+                resolved?.0
+            }
+        };
+        Ok(ips.into_iter().map(|ip| SocketAddr::new(ip, port)).collect())
+    }
+
+    /// The addresses of `host` and how long they may be cached.
+    async fn resolve(&self, host: &str) -> io::Result<(Vec<IpAddr>, Duration)> {
+        if self.is_system() {
+            let addrs = tokio::time::timeout(self.timeout, tokio::net::lookup_host((host, 0)))
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("resolving {} timed out", host)))??;
+            let ips: Vec<IpAddr> = addrs.map(|addr| addr.ip()).collect();
+            if ips.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("no addresses for {}", host)));
+            }
+            return Ok((ips, SYSTEM_TTL));
+        }
+        let found = self.client().await?.lookup_ip(host).await
+            .map_err(|e| into_io_error(e, host))?;
+        let ttl = found.valid_until().saturating_duration_since(Instant::now());
+        Ok((found.iter().collect(), ttl))
+    }
+
+    /// Ask the name servers for the `record_type` records of `name`, as is:
+    /// search domains only apply to names not ending in a dot.
+    pub async fn query(&self, name: Name, record_type: RecordType) -> Result<Lookup, ResolveError> {
+        self.client().await?.lookup(name, record_type).await
+    }
+
+    /// The hickory resolver, set up on first use.
+    async fn client(&self) -> io::Result<&TokioAsyncResolver> {
+        self.client.get_or_try_init(|| async {
+            let mut servers = Vec::new();
+            for server in &self.servers {
+                servers.extend(server.configs(self.tls.as_ref()).await?);
+            }
+            if servers.is_empty() {
+                servers = system_servers();
+            }
+            let search = self.search.iter()
+                .map(|domain| Name::from_utf8(domain).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("search domain {}: {}", domain, e))))
+                .collect::<io::Result<Vec<Name>>>()?;
+            let mut opts = ResolverOpts::default();
+            opts.timeout = self.timeout;
+            // Like the C resolver with `ndots:1`: a name with a dot is tried
+            // as is first, a bare one with each search domain first.
+            opts.ndots = 1;
+            opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+            opts.server_ordering_strategy = ServerOrderingStrategy::UserProvidedOrder;
+            // Overrides and caching are rdnat's own, and relayed answers
+            // keep their CNAME chains.
+            opts.use_hosts_file = false;
+            opts.cache_size = 0;
+            opts.preserve_intermediates = true;
+            Ok(TokioAsyncResolver::tokio(ResolverConfig::from_parts(None, search, servers), opts))
+        })
+        .await
+    }
+}
+
+/*************************************************
+ * srv
+ *************************************************/
+
+/// Ask the name servers in `/etc/resolv.conf` for the SRV records of
+/// `name`. A name that doesn't exist has no records.
+pub async fn srv(name: &str, timeout: Duration) -> Result<Vec<SRV>, Box<dyn Error + Send + Sync>> {
+    let mut resolver = Resolver::default();
+    resolver.set_timeout(timeout);
+    match resolver.client().await?.srv_lookup(name).await {
+        Ok(found) => Ok(found.iter().cloned().collect()),
+        Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The name servers of `/etc/resolv.conf`, or the local resolver when
+/// there are none.
+fn system_servers() -> Vec<NameServerConfig> {
+    match hickory_resolver::system_conf::read_system_conf() {
+        Ok((config, _)) if !config.name_servers().is_empty() => config.name_servers().to_vec(),
+        _ => {
+            let local = SocketAddr::from(([127, 0, 0, 1], DNS_PORT));
+            vec![NameServerConfig::new(local, Protocol::Udp), NameServerConfig::new(local, Protocol::Tcp)]
+        }
+    }
+}
+
+fn into_io_error(e: ResolveError, host: &str) -> io::Error {
+    let kind = match e.kind() {
+        ResolveErrorKind::NoRecordsFound { .. } => io::ErrorKind::NotFound,
+        ResolveErrorKind::Timeout => io::ErrorKind::TimedOut,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, format!("resolving {}: {}", host, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_plain_servers_with_default_port() {
+        assert!(matches!(Server::parse("10.0.0.53").unwrap(), Server::Udp(addr) if addr == "10.0.0.53:53".parse().unwrap()));
+        assert!(matches!(Server::parse("[2001:db8::1]").unwrap(), Server::Udp(addr) if addr == "[2001:db8::1]:53".parse().unwrap()));
+        assert!(matches!(Server::parse("10.0.0.53:5353").unwrap(), Server::Udp(addr) if addr.port() == 5353));
+        assert!(Server::parse("dns.example").is_err());
+    }
+
+    #[test]
+    fn parses_tls_servers() {
+        assert!(matches!(Server::parse("tls://1.1.1.1").unwrap(), Server::Tls { host, port: DOT_PORT } if host == "1.1.1.1"));
+        assert!(matches!(Server::parse("tls://dns.example:8853/").unwrap(), Server::Tls { host, port: 8853 } if host == "dns.example"));
+        assert!(matches!(Server::parse("tls://[2001:db8::1]").unwrap(), Server::Tls { host, port: DOT_PORT } if host == "2001:db8::1"));
+        assert!(Server::parse("tls://").is_err());
+    }
+
+    #[test]
+    fn https_servers_need_the_standard_path() {
+        assert!(matches!(Server::parse("https://dns.google/dns-query").unwrap(), Server::Https(_)));
+        assert!(Server::parse("https://dns.google/resolve").is_err());
+        assert!(Server::parse("https://dns.google/dns-query?ct").is_err());
+    }
+
+    #[tokio::test]
+    async fn encrypted_servers_need_tls() {
+        let server = Server::parse("tls://1.1.1.1").unwrap();
+        assert!(server.configs(None).await.is_err());
+        let udp = Server::parse("10.0.0.53").unwrap().configs(None).await.unwrap();
+        assert_eq!(udp.iter().map(|config| config.protocol).collect::<Vec<_>>(), [Protocol::Udp, Protocol::Tcp]);
+    }
+
+    #[tokio::test]
+    async fn overrides_and_literals_skip_dns() {
+        let mut resolver = Resolver::default();
+        resolver.set_servers(vec![Server::Udp("192.0.2.1:53".parse().unwrap())]);
+        let mut hosts = HostOverrides::default();
+        hosts.add("Intranet.Example", "10.1.2.3".parse().unwrap());
+        resolver.set_hosts(hosts);
+        assert_eq!(resolver.lookup("intranet.example", 8080).await.unwrap(), ["10.1.2.3:8080".parse().unwrap()]);
+        assert_eq!(resolver.lookup("[2001:db8::1]", 443).await.unwrap(), ["[2001:db8::1]:443".parse().unwrap()]);
+        assert_eq!(resolver.lookup("198.51.100.7", 80).await.unwrap(), ["198.51.100.7:80".parse().unwrap()]);
+    }
+}
@@ -0,0 +1,119 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+pub const DEFAULT_CAPACITY: usize = 10_000;
+pub const DEFAULT_NEGATIVE_TTL_SECS: u64 = 10;
+pub const DEFAULT_MAX_TTL_SECS: u64 = 3600;
+
+/*************************************************
+ * DnsCache
+ *************************************************/
+
+/// Resolved host names, each kept for the TTL of its records (at most
+/// `max_ttl`). Failed lookups are kept for `negative_ttl` so a name that
+/// doesn't resolve costs one lookup per TTL too.
+pub struct DnsCache {
+    capacity: usize,
+    negative_ttl: Duration,
+    max_ttl: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+    hits: AtomicU64,
+    negative_hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Entry {
+    /// Empty for a failed lookup.
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+}
+
+/// A snapshot of the cache counters.
+pub struct CacheStats {
+    pub entries: usize,
+    pub negative_entries: usize,
+    pub hits: u64,
+    pub negative_hits: u64,
+    pub misses: u64,
+}
+
+impl DnsCache {
+    pub fn new(capacity: usize, negative_ttl: Duration, max_ttl: Duration) -> Self {
+        DnsCache {
+            capacity,
+            negative_ttl,
+            max_ttl,
+            entries: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            negative_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// The cached addresses of `host`: `Some(empty)` for a cached failure,
+    /// `None` when it has to be looked up.
+    pub fn get(&self, host: &str) -> Option<Vec<IpAddr>> {
+        let entries = self.entries.lock().unwrap();
+        match entries.get(host).filter(|entry| entry.expires > Instant::now()) {
+            Some(entry) => {
+                let counter = if entry.addrs.is_empty() { &self.negative_hits } else { &self.hits };
+                counter.fetch_add(1, Ordering::Relaxed);
+                Some(entry.addrs.clone())
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Remember the addresses `host` resolved to for `ttl`, or that it
+    /// failed to resolve (`addrs` empty).
+    pub fn insert(&self, host: &str, addrs: Vec<IpAddr>, ttl: Duration) {
+        let ttl = if addrs.is_empty() { self.negative_ttl } else { ttl.min(self.max_ttl) };
+        if ttl.is_zero() {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity && !entries.contains_key(host) {
+            let now = Instant::now();
+            entries.retain(|_, entry| entry.expires > now);
+            if entries.len() >= self.capacity {
+                entries.clear();
+            }
+        }
+        entries.insert(host.to_string(), Entry { addrs, expires: Instant::now() + ttl });
+    }
+
+    /// Forget every entry; returns how many there were.
+    pub fn flush(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let flushed = entries.len();
+        entries.clear();
+        flushed
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        let live: Vec<&Entry> = entries.values().filter(|entry| entry.expires > now).collect();
+        CacheStats {
+            entries: live.len(),
+            negative_entries: live.iter().filter(|entry| entry.addrs.is_empty()).count(),
+            hits: self.hits.load(Ordering::Relaxed),
+            negative_hits: self.negative_hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
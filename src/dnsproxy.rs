@@ -0,0 +1,362 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use hickory_resolver::error::ResolveErrorKind;
+use hickory_resolver::proto::op::{Edns, Message, MessageType, ResponseCode};
+use hickory_resolver::proto::rr::rdata::{A, AAAA};
+use hickory_resolver::proto::rr::{RData, Record, RecordType};
+use hyper::{Method, StatusCode};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::task::JoinHandle;
+use std::error::Error;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use log::{debug, error, info, warn};
+use crate::request::{ClientConn, RequestHead};
+use crate::{AcceptBackoff, Context};
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+/// TTL of the answers for blocked names, short so unblocking takes
+/// effect soon.
+const BLOCKED_TTL: u32 = 60;
+/// Largest query accepted over UDP, and the payload size offered to
+/// EDNS clients.
+const MAX_QUERY: usize = 4096;
+/// Largest UDP answer for a client that doesn't use EDNS (RFC 1035).
+const MIN_UDP_ANSWER: usize = 512;
+/// How long a TCP client may sit between queries.
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest DNS message, over TCP or HTTPS.
+const MAX_MESSAGE: u64 = 65535;
+
+pub const DOH_PATH: &str = "/dns-query";
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/*************************************************
+ * BlockResponse
+ *************************************************/
+
+/// What a blocked name is answered with.
+#[derive(Clone, Copy, Default)]
+pub enum BlockResponse {
+    /// `0.0.0.0` / `::` for address queries, no records otherwise.
+    #[default]
+    Null,
+    Nxdomain,
+}
+
+impl BlockResponse {
+    pub fn parse(name: &str) -> Result<Self, Box<dyn Error>> {
+        match name {
+            "null" => Ok(BlockResponse::Null),
+            "nxdomain" => Ok(BlockResponse::Nxdomain),
+            name => Err(format!("Error: Unknown DNS block response: {} (expected null or nxdomain)", name).into()),
+        }
+    }
+}
+
+/*************************************************
+ * serve
+ *************************************************/
+
+/// Answer DNS queries on `addr` over UDP and TCP: names blocked by the
+/// domain and filter lists get a blocked answer, everything else is
+/// relayed to the resolver's name servers. Returns the UDP and TCP tasks.
+pub async fn serve(addr: SocketAddr, ctx: Arc<Context>) -> Result<Vec<JoinHandle<()>>, Box<dyn Error>> {
+    let socket = UdpSocket::bind(addr).await
+        .map_err(|e| format!("Error: Cannot listen for DNS on {}: {}", addr, e))?;
+    let listener = TcpListener::bind(addr).await
+        .map_err(|e| format!("Error: Cannot listen for DNS on {}: {}", addr, e))?;
+    info!("DNS listening on {}", addr);
+
+    let socket = Arc::new(socket);
+    let udp_ctx = ctx.clone();
+    let udp = tokio::spawn(async move {
+        let mut buf = vec![0u8; MAX_QUERY];
+        loop {
+            let (len, client) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    error!("DNS receive failed: {}", e);
+                    continue;
+                }
+            };
+            let (socket, ctx, query) = (socket.clone(), udp_ctx.clone(), buf[..len].to_vec());
+            tokio::spawn(async move {
+                if let Some(answer) = answer(&ctx, &query, client, true).await {
+                    if let Err(e) = socket.send_to(&answer, client).await {
+                        debug!("DNS answer to {} failed: {}", client, e);
+                    }
+                }
+            });
+        }
+    });
+
+    let tcp = tokio::spawn(async move {
+        let mut backoff = AcceptBackoff::default();
+        loop {
+            let (stream, client) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    backoff.failed(&e).await;
+                    continue;
+                }
+            };
+            backoff.reset();
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve_tcp(stream, client, ctx).await {
+                    debug!("DNS connection from {} ended: {}", client, e);
+                }
+            });
+        }
+    });
+    Ok(vec![udp, tcp])
+}
+
+/// Answer length-prefixed queries until the client closes or goes idle.
+async fn serve_tcp(mut stream: TcpStream, client: SocketAddr, ctx: Arc<Context>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    loop {
+        let length = match tokio::time::timeout(TCP_IDLE_TIMEOUT, stream.read_u16()).await {
+            Ok(length) => length? as usize,
+            Err(_) => return Ok(()),
+        };
+        let mut query = vec![0u8; length];
+        stream.read_exact(&mut query).await?;
+        let Some(answer) = answer(&ctx, &query, client, false).await else {
+            return Ok(());
+        };
+        let mut message = Vec::with_capacity(2 + answer.len());
+        message.extend_from_slice(&(answer.len() as u16).to_be_bytes());
+        message.extend_from_slice(&answer);
+        stream.write_all(&message).await?;
+    }
+}
+
+/*************************************************
+ * serve_doh
+ *************************************************/
+
+/// Answer a DNS over HTTPS request (RFC 8484): the query comes base64url
+/// encoded in the `dns` parameter of a GET, or as the body of a POST.
+/// Returns whether the connection stays open for another request.
+pub async fn serve_doh<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut ClientConn<S>,
+    head: &RequestHead,
+    ctx: &Context,
+    client: SocketAddr,
+) -> Result<bool, Box<dyn Error>> {
+    let query = match head.method {
+        Method::GET => head.target.split_once('?')
+            .and_then(|(_, query)| query.split('&').find_map(|pair| pair.strip_prefix("dns=")))
+            .and_then(|encoded| base64::decode_config(encoded, base64::URL_SAFE_NO_PAD).ok()),
+        Method::POST => {
+            let content_type = head.headers.get(hyper::header::CONTENT_TYPE).and_then(|value| value.to_str().ok());
+            if content_type != Some(DNS_MESSAGE) {
+                return reply(conn, head, StatusCode::UNSUPPORTED_MEDIA_TYPE, &[]).await;
+            }
+            let length = head.body_length()?;
+            Some(conn.read_body(length, MAX_MESSAGE, &ctx.config.head_limits).await?.to_vec())
+        }
+        _ => return reply(conn, head, StatusCode::METHOD_NOT_ALLOWED, &[]).await,
+    };
+    match query {
+        Some(query) => match answer(ctx, &query, client, false).await {
+            Some(answer) => reply(conn, head, StatusCode::OK, &answer).await,
+            None => reply(conn, head, StatusCode::BAD_REQUEST, &[]).await,
+        },
+        None => reply(conn, head, StatusCode::BAD_REQUEST, &[]).await,
+    }
+}
+
+async fn reply<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut ClientConn<S>,
+    head: &RequestHead,
+    status: StatusCode,
+    body: &[u8],
+) -> Result<bool, Box<dyn Error>> {
+    let keep_alive = head.keep_alive();
+    let mut response = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\n", status, body.len());
+    if status == StatusCode::OK {
+        response.push_str(&format!("Content-Type: {}\r\n", DNS_MESSAGE));
+    }
+    response.push_str(if keep_alive { "\r\n" } else { "Connection: close\r\n\r\n" });
+    let mut message = response.into_bytes();
+    message.extend_from_slice(body);
+    conn.stream.write_all(&message).await?;
+    Ok(keep_alive && status == StatusCode::OK)
+}
+
+/// The answer to `query`, or `None` for something that isn't a query.
+/// Over `udp` an answer too large for the client is truncated, so it
+/// asks again over TCP.
+async fn answer(ctx: &Context, query: &[u8], client: SocketAddr, udp: bool) -> Option<Vec<u8>> {
+    let request = Message::from_vec(query).ok()?;
+    let [question] = request.queries() else {
+        return None;
+    };
+    if request.message_type() != MessageType::Query {
+        return None;
+    }
+    let name = question.name().to_utf8();
+    let name = name.trim_end_matches('.');
+    let response = if let Some(reason) = ctx.domain_block(name) {
+        info!("DNS {} -> {} blocked by {}", client.ip(), name, reason);
+        blocked(&request, ctx.config.dns_block_response)
+    } else {
+        debug!("DNS {} -> {}", client.ip(), name);
+        match ctx.guard.resolver().query(question.name().clone(), question.query_type()).await {
+            Ok(found) => {
+                let mut response = response(&request, ResponseCode::NoError);
+                response.add_answers(found.records().iter().cloned());
+                response
+            }
+            Err(e) => match e.kind() {
+                ResolveErrorKind::NoRecordsFound { response_code, soa, .. } => {
+                    let mut response = response(&request, *response_code);
+                    if let Some(soa) = soa {
+                        response.add_name_server(soa.as_ref().clone().into_record_of_rdata());
+                    }
+                    response
+                }
+                _ => {
+                    warn!("DNS query for {} from {} failed: {}", name, client.ip(), e);
+                    response(&request, ResponseCode::ServFail)
+                }
+            },
+        }
+    };
+    let limit = match (udp, request.extensions()) {
+        (false, _) => MAX_MESSAGE as usize,
+        (true, Some(edns)) => (edns.max_payload() as usize).clamp(MIN_UDP_ANSWER, MAX_QUERY),
+        (true, None) => MIN_UDP_ANSWER,
+    };
+    encode(response, limit)
+}
+
+/// An answer to `request` with `rcode` and no records yet.
+fn response(request: &Message, rcode: ResponseCode) -> Message {
+    let mut response = Message::new();
+    response.set_id(request.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request.op_code())
+        .set_recursion_desired(request.recursion_desired())
+        .set_recursion_available(true)
+        .set_response_code(rcode)
+        .add_queries(request.queries().iter().cloned());
+    if request.extensions().is_some() {
+        let mut edns = Edns::new();
+        edns.set_max_payload(MAX_QUERY as u16);
+        response.set_edns(edns);
+    }
+    response
+}
+
+/// The answer for a blocked name.
+fn blocked(request: &Message, block: BlockResponse) -> Message {
+    let question = &request.queries()[0];
+    let rdata = match (block, question.query_type()) {
+        (BlockResponse::Nxdomain, _) => return response(request, ResponseCode::NXDomain),
+        (BlockResponse::Null, RecordType::A) => RData::A(A(Ipv4Addr::UNSPECIFIED)),
+        (BlockResponse::Null, RecordType::AAAA) => RData::AAAA(AAAA(Ipv6Addr::UNSPECIFIED)),
+        (BlockResponse::Null, _) => return response(request, ResponseCode::NoError),
+    };
+    let mut response = response(request, ResponseCode::NoError);
+    response.add_answer(Record::from_rdata(question.name().clone(), BLOCKED_TTL, rdata));
+    response
+}
+
+/// `response` in wire format, its records left out and flagged as
+/// truncated if it's longer than `limit`.
+fn encode(mut response: Message, limit: usize) -> Option<Vec<u8>> {
+    let message = response.to_vec().ok()?;
+    if message.len() <= limit {
+        return Some(message);
+    }
+    response.take_answers();
+    response.take_name_servers();
+    response.take_additionals();
+    response.set_truncated(true);
+    response.to_vec().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_resolver::proto::op::{OpCode, Query};
+    use hickory_resolver::Name;
+
+    fn request(name: &str, query_type: RecordType) -> Message {
+        let mut request = Message::new();
+        request.set_id(0x1234)
+            .set_recursion_desired(true)
+            .add_query(Query::query(Name::from_ascii(name).unwrap(), query_type));
+        request
+    }
+
+    #[test]
+    fn responses_echo_the_request() {
+        let response = response(&request("example.com.", RecordType::MX), ResponseCode::ServFail);
+        assert_eq!(response.id(), 0x1234);
+        assert_eq!(response.message_type(), MessageType::Response);
+        assert_eq!(response.op_code(), OpCode::Query);
+        assert!(response.recursion_desired() && response.recursion_available());
+        assert_eq!(response.response_code(), ResponseCode::ServFail);
+        assert_eq!(response.queries(), request("example.com.", RecordType::MX).queries());
+        assert!(response.extensions().is_none());
+    }
+
+    #[test]
+    fn blocked_addresses_are_null() {
+        let answer = blocked(&request("ads.example.", RecordType::A), BlockResponse::Null);
+        assert_eq!(answer.response_code(), ResponseCode::NoError);
+        assert_eq!(answer.answers()[0].data(), Some(&RData::A(A(Ipv4Addr::UNSPECIFIED))));
+        assert_eq!(answer.answers()[0].ttl(), BLOCKED_TTL);
+
+        let answer = blocked(&request("ads.example.", RecordType::AAAA), BlockResponse::Null);
+        assert_eq!(answer.answers()[0].data(), Some(&RData::AAAA(AAAA(Ipv6Addr::UNSPECIFIED))));
+
+        let answer = blocked(&request("ads.example.", RecordType::TXT), BlockResponse::Null);
+        assert_eq!(answer.response_code(), ResponseCode::NoError);
+        assert!(answer.answers().is_empty());
+    }
+
+    #[test]
+    fn blocked_names_can_not_exist() {
+        let answer = blocked(&request("ads.example.", RecordType::A), BlockResponse::Nxdomain);
+        assert_eq!(answer.response_code(), ResponseCode::NXDomain);
+        assert!(answer.answers().is_empty());
+    }
+
+    #[test]
+    fn oversized_answers_are_truncated() {
+        let request = request("many.example.", RecordType::A);
+        let mut answer = response(&request, ResponseCode::NoError);
+        for i in 0..64 {
+            answer.add_answer(Record::from_rdata(Name::from_ascii("many.example.").unwrap(), 60, RData::A(A(Ipv4Addr::new(10, 0, 0, i)))));
+        }
+        let full = Message::from_vec(&encode(answer.clone(), MAX_MESSAGE as usize).unwrap()).unwrap();
+        assert_eq!(full.answers().len(), 64);
+        assert!(!full.truncated());
+
+        let cut = Message::from_vec(&encode(answer, MIN_UDP_ANSWER).unwrap()).unwrap();
+        assert!(cut.truncated());
+        assert!(cut.answers().is_empty());
+        assert_eq!(cut.id(), 0x1234);
+        assert_eq!(cut.queries(), request.queries());
+    }
+
+    #[test]
+    fn edns_requests_get_edns_answers() {
+        let mut request = request("example.com.", RecordType::A);
+        request.set_edns(Edns::new());
+        let response = response(&request, ResponseCode::NoError);
+        assert_eq!(response.extensions().as_ref().map(Edns::max_payload), Some(MAX_QUERY as u16));
+    }
+}
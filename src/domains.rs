@@ -0,0 +1,63 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use std::collections::HashSet;
+use std::error::Error;
+
+/*************************************************
+ * DomainList
+ *************************************************/
+
+/// Domains loaded from a list file, one per line. `example.com` matches
+/// only that host; `.example.com` (or `*.example.com`) matches it and all
+/// of its subdomains.
+#[derive(Default)]
+pub struct DomainList {
+    exact: HashSet<String>,
+    suffixes: HashSet<String>,
+}
+
+impl DomainList {
+    pub fn add(&mut self, pattern: &str) {
+        let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+        match pattern.strip_prefix("*.").or_else(|| pattern.strip_prefix('.')) {
+            Some(suffix) => self.suffixes.insert(suffix.to_string()),
+            None => self.exact.insert(pattern),
+        };
+    }
+
+    /// Load a list file; `#` starts a comment.
+    pub fn load(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Error: Cannot read domain list {}: {}", path, e))?;
+        self.parse(&content);
+        Ok(())
+    }
+
+    pub fn parse(&mut self, content: &str) {
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if !line.is_empty() {
+                self.add(line);
+            }
+        }
+    }
+
+    pub fn contains(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        if self.exact.contains(&host) {
+            return true;
+        }
+        let mut domain = host.as_str();
+        loop {
+            if self.suffixes.contains(domain) {
+                return true;
+            }
+            match domain.split_once('.') {
+                Some((_, parent)) => domain = parent,
+                None => return false,
+            }
+        }
+    }
+}
@@ -0,0 +1,70 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use maxminddb::{geoip2, Reader};
+use std::error::Error;
+use std::fmt;
+use std::net::IpAddr;
+
+/*************************************************
+ * GeoInfo
+ *************************************************/
+
+#[derive(Default)]
+pub struct GeoInfo {
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+    pub organization: Option<String>,
+}
+
+impl fmt::Display for GeoInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.country.as_deref().unwrap_or("??"))?;
+        if let Some(asn) = self.asn {
+            write!(f, " AS{}", asn)?;
+        }
+        if let Some(organization) = &self.organization {
+            write!(f, " {}", organization)?;
+        }
+        Ok(())
+    }
+}
+
+/*************************************************
+ * GeoIp
+ *************************************************/
+
+/// MaxMind country and ASN databases, either of which may be absent.
+pub struct GeoIp {
+    country_db: Option<Reader<Vec<u8>>>,
+    asn_db: Option<Reader<Vec<u8>>>,
+}
+
+impl GeoIp {
+    pub fn open(country_path: Option<&str>, asn_path: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        let open = |path: &str| {
+            Reader::open_readfile(path).map_err(|e| format!("Error: Cannot open GeoIP database {}: {}", path, e))
+        };
+        Ok(GeoIp {
+            country_db: country_path.map(open).transpose()?,
+            asn_db: asn_path.map(open).transpose()?,
+        })
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> GeoInfo {
+        let mut info = GeoInfo::default();
+        if let Some(db) = &self.country_db {
+            if let Ok(Some(record)) = db.lookup(ip).and_then(|result| result.decode::<geoip2::Country>()) {
+                info.country = record.country.iso_code.map(String::from);
+            }
+        }
+        if let Some(db) = &self.asn_db {
+            if let Ok(Some(record)) = db.lookup(ip).and_then(|result| result.decode::<geoip2::Asn>()) {
+                info.asn = record.autonomous_system_number;
+                info.organization = record.autonomous_system_organization.map(String::from);
+            }
+        }
+        info
+    }
+}
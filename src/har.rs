@@ -0,0 +1,276 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use hyper::header::{HeaderName, CONTENT_TYPE};
+use hyper::{HeaderMap, Request, Response, StatusCode, Version};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::budget::{MemoryBudget, Reservation};
+use crate::logfilter::REDACTED;
+use crate::webhook::escape_json;
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+/// Default bytes of each request and response body kept in the capture.
+pub const DEFAULT_MAX_BODY: usize = 1024 * 1024;
+/// Default size at which a capture file is closed and a new one started.
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 100 * 1024 * 1024;
+/// Headers whose values are replaced unless `--har-redact` says otherwise.
+pub const DEFAULT_REDACTED: [&str; 4] = ["authorization", "proxy-authorization", "cookie", "set-cookie"];
+
+const HAR_HEAD: &str = "{\"log\":{\"version\":\"1.2\",\"creator\":{\"name\":\"rdnat\",\"version\":\"0.1.0\"},\"entries\":[\n";
+const HAR_TAIL: &str = "\n]}}\n";
+
+/*************************************************
+ * BodyCapture
+ *************************************************/
+
+/// The first `limit` bytes of a body, and its full size. The captured
+/// bytes count against the memory budget; the capture is cut short when
+/// the budget runs out rather than the request being turned away.
+pub struct BodyCapture {
+    data: Vec<u8>,
+    limit: usize,
+    size: u64,
+    reservation: Reservation,
+}
+
+impl BodyCapture {
+    pub fn new(limit: usize, budget: &MemoryBudget) -> Self {
+        BodyCapture { data: Vec::new(), limit, size: 0, reservation: budget.reservation() }
+    }
+
+    pub fn record(&mut self, chunk: &[u8]) {
+        self.size += chunk.len() as u64;
+        let room = self.limit.saturating_sub(self.data.len()).min(chunk.len());
+        if room > 0 && self.reservation.grow(room).is_ok() {
+            self.data.extend_from_slice(&chunk[..room]);
+        } else {
+            // Nothing more is kept once a chunk was missed.
+            self.limit = self.data.len();
+        }
+    }
+
+    fn truncated(&self) -> bool {
+        (self.data.len() as u64) < self.size
+    }
+
+    /// `"text":...` (base64 unless the captured bytes are UTF-8), with a
+    /// comment when the body was cut short.
+    fn json_text(&self) -> String {
+        let mut json = match std::str::from_utf8(&self.data) {
+            Ok(text) => format!("\"text\":\"{}\"", escape_json(text)),
+            Err(_) => format!("\"text\":\"{}\",\"encoding\":\"base64\"", base64::encode(&self.data)),
+        };
+        if self.truncated() {
+            json.push_str(&format!(",\"comment\":\"truncated to {} of {} bytes\"", self.data.len(), self.size));
+        }
+        json
+    }
+}
+
+/*************************************************
+ * Exchange
+ *************************************************/
+
+/// One proxied HTTP transaction on its way into the capture.
+pub struct Exchange {
+    started: OffsetDateTime,
+    start: Instant,
+    method: String,
+    url: String,
+    version: Version,
+    request_headers: HeaderMap,
+    pub request_body: BodyCapture,
+    waited: Duration,
+    status: StatusCode,
+    response_headers: HeaderMap,
+    pub response_body: BodyCapture,
+}
+
+impl Exchange {
+    /// Note the response as it goes to the client.
+    pub fn respond<T>(&mut self, response: &Response<T>) {
+        self.waited = self.start.elapsed();
+        self.status = response.status();
+        self.response_headers = response.headers().clone();
+    }
+}
+
+/*************************************************
+ * HarWriter
+ *************************************************/
+
+/// Appends transactions to a HAR file, which stays a valid document after
+/// every entry; once it outgrows its size limit it is set aside as
+/// `<path>.1`, `<path>.2`... and a new one is started.
+pub struct HarWriter {
+    path: String,
+    max_body: usize,
+    max_file_size: u64,
+    redact: Vec<HeaderName>,
+    file: Mutex<HarFile>,
+    budget: MemoryBudget,
+}
+
+struct HarFile {
+    file: File,
+    size: u64,
+    entries: usize,
+}
+
+impl HarWriter {
+    pub fn create(path: &str, max_body: usize, max_file_size: u64, redact: &[String], budget: MemoryBudget) -> Result<Self, Box<dyn Error>> {
+        let redact = redact.iter()
+            .map(|name| HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("Error: Invalid header name: {}", name)))
+            .collect::<Result<_, _>>()?;
+        let file = HarFile::create(path).map_err(|e| format!("Error: Cannot create HAR file {}: {}", path, e))?;
+        Ok(HarWriter {
+            path: path.to_string(),
+            max_body,
+            max_file_size,
+            redact,
+            file: Mutex::new(file),
+            budget,
+        })
+    }
+
+    /// Start capturing a request about to be forwarded.
+    pub fn begin<T>(&self, request: &Request<T>) -> Exchange {
+        Exchange {
+            started: OffsetDateTime::now_utc(),
+            start: Instant::now(),
+            method: request.method().to_string(),
+            url: request.uri().to_string(),
+            version: request.version(),
+            request_headers: request.headers().clone(),
+            request_body: BodyCapture::new(self.max_body, &self.budget),
+            waited: Duration::ZERO,
+            status: StatusCode::OK,
+            response_headers: HeaderMap::new(),
+            response_body: BodyCapture::new(self.max_body, &self.budget),
+        }
+    }
+
+    /// Write a finished transaction to the capture.
+    pub fn record(&self, exchange: Exchange) {
+        let entry = self.entry_json(&exchange);
+        let mut file = self.file.lock().unwrap();
+        let written = file.rotate_if_full(&self.path, self.max_file_size, entry.len())
+            .and_then(|_| file.append(&entry));
+        if let Err(e) = written {
+            log::error!("Cannot write HAR file {}: {}", self.path, e);
+        }
+    }
+
+    fn entry_json(&self, exchange: &Exchange) -> String {
+        let total = exchange.start.elapsed();
+        let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        let version = format!("{:?}", exchange.version);
+        let query = exchange.url.split_once('?').map_or("", |(_, query)| query);
+        let query = query.split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                format!("{{\"name\":\"{}\",\"value\":\"{}\"}}", escape_json(name), escape_json(value))
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let post_data = match exchange.request_body.size {
+            0 => String::new(),
+            _ => format!(
+                ",\"postData\":{{\"mimeType\":\"{}\",{}}}",
+                escape_json(&mime_type(&exchange.request_headers)),
+                exchange.request_body.json_text()
+            ),
+        };
+        let request = format!(
+            "{{\"method\":\"{}\",\"url\":\"{}\",\"httpVersion\":\"{}\",\"cookies\":[],\"headers\":[{}],\"queryString\":[{}]{},\"headersSize\":-1,\"bodySize\":{}}}",
+            escape_json(&exchange.method),
+            escape_json(&exchange.url),
+            version,
+            self.headers_json(&exchange.request_headers),
+            query,
+            post_data,
+            exchange.request_body.size
+        );
+        let response = format!(
+            "{{\"status\":{},\"statusText\":\"{}\",\"httpVersion\":\"{}\",\"cookies\":[],\"headers\":[{}],\"content\":{{\"size\":{},\"mimeType\":\"{}\",{}}},\"redirectURL\":\"{}\",\"headersSize\":-1,\"bodySize\":{}}}",
+            exchange.status.as_u16(),
+            exchange.status.canonical_reason().unwrap_or_default(),
+            version,
+            self.headers_json(&exchange.response_headers),
+            exchange.response_body.size,
+            escape_json(&mime_type(&exchange.response_headers)),
+            exchange.response_body.json_text(),
+            escape_json(exchange.response_headers.get(hyper::header::LOCATION).and_then(|value| value.to_str().ok()).unwrap_or_default()),
+            exchange.response_body.size
+        );
+        format!(
+            "{{\"startedDateTime\":\"{}\",\"time\":{:.3},\"request\":{},\"response\":{},\"cache\":{{}},\"timings\":{{\"send\":0,\"wait\":{:.3},\"receive\":{:.3}}}}}",
+            exchange.started.format(&Rfc3339).unwrap_or_default(),
+            millis(total),
+            request,
+            response,
+            millis(exchange.waited),
+            millis(total.saturating_sub(exchange.waited))
+        )
+    }
+
+    fn headers_json(&self, headers: &HeaderMap) -> String {
+        headers.iter()
+            .map(|(name, value)| {
+                let value = if self.redact.contains(name) { REDACTED.into() } else { String::from_utf8_lossy(value.as_bytes()) };
+                format!("{{\"name\":\"{}\",\"value\":\"{}\"}}", name, escape_json(&value))
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+fn mime_type(headers: &HeaderMap) -> String {
+    headers.get(CONTENT_TYPE)
+        .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+        .unwrap_or_default()
+}
+
+impl HarFile {
+    fn create(path: &str) -> std::io::Result<Self> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        file.write_all(HAR_HEAD.as_bytes())?;
+        file.write_all(HAR_TAIL.as_bytes())?;
+        Ok(HarFile { file, size: (HAR_HEAD.len() + HAR_TAIL.len()) as u64, entries: 0 })
+    }
+
+    /// Write the entry over the closing brackets, then close them again.
+    fn append(&mut self, entry: &str) -> std::io::Result<()> {
+        let separator = if self.entries == 0 { "" } else { ",\n" };
+        self.file.seek(SeekFrom::Start(self.size - HAR_TAIL.len() as u64))?;
+        self.file.write_all(format!("{}{}{}", separator, entry, HAR_TAIL).as_bytes())?;
+        self.size += (separator.len() + entry.len()) as u64;
+        self.entries += 1;
+        Ok(())
+    }
+
+    fn rotate_if_full(&mut self, path: &str, max_size: u64, entry_len: usize) -> std::io::Result<()> {
+        if self.entries == 0 || self.size + entry_len as u64 <= max_size {
+            return Ok(());
+        }
+        let rotated = (1..)
+            .map(|n| format!("{}.{}", path, n))
+            .find(|rotated| !Path::new(rotated).exists())
+            .unwrap_or_default();
+        std::fs::rename(path, rotated)?;
+        *self = HarFile::create(path)?;
+        Ok(())
+    }
+}
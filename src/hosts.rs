@@ -0,0 +1,68 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::IpAddr;
+
+/*************************************************
+ * HostOverrides
+ *************************************************/
+
+/// Fixed addresses for host names, consulted before DNS: `/etc/hosts`
+/// style files and single `name=ip` entries. A name given several
+/// addresses resolves to all of them, in the order given.
+#[derive(Default)]
+pub struct HostOverrides {
+    hosts: HashMap<String, Vec<IpAddr>>,
+}
+
+impl HostOverrides {
+    pub fn add(&mut self, name: &str, ip: IpAddr) {
+        let addrs = self.hosts.entry(name.trim_end_matches('.').to_ascii_lowercase()).or_default();
+        if !addrs.contains(&ip) {
+            addrs.push(ip);
+        }
+    }
+
+    /// Add `<name>=<ip>[,<ip>...]`.
+    pub fn add_entry(&mut self, entry: &str) -> Result<(), Box<dyn Error>> {
+        let invalid = || format!("Error: Invalid host override, expected <name>=<ip>: {}", entry);
+        let (name, ips) = entry.split_once('=').ok_or_else(invalid)?;
+        if name.is_empty() {
+            return Err(invalid().into());
+        }
+        for ip in ips.split(',') {
+            self.add(name, ip.trim().parse().map_err(|_| invalid())?);
+        }
+        Ok(())
+    }
+
+    /// Load `<ip> <name> [<alias>...]` lines; `#` starts a comment.
+    pub fn load(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Error: Cannot read hosts file {}: {}", path, e))?;
+        for (lineno, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let ip = fields.next().and_then(|ip| ip.split('%').next()?.parse().ok());
+            let names: Vec<&str> = fields.collect();
+            let (Some(ip), false) = (ip, names.is_empty()) else {
+                return Err(format!("Error: Invalid hosts entry at {}:{}: {}", path, lineno + 1, line).into());
+            };
+            for name in names {
+                self.add(name, ip);
+            }
+        }
+        Ok(())
+    }
+
+    /// The addresses `host` (lowercase) is pinned to.
+    pub fn get(&self, host: &str) -> Option<&[IpAddr]> {
+        self.hosts.get(host.trim_end_matches('.')).map(Vec::as_slice)
+    }
+}
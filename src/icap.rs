@@ -0,0 +1,248 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use hyper::body::Bytes;
+use hyper::header::{HeaderName, HeaderValue, CONTENT_LENGTH, TRANSFER_ENCODING};
+use hyper::{Body, HeaderMap, Request, Response, StatusCode};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use std::error::Error;
+use std::time::Duration;
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+const DEFAULT_ICAP_PORT: u16 = 1344;
+/// Default largest body handed to the ICAP server; bigger ones pass
+/// uninspected.
+pub const DEFAULT_MAX_SIZE: usize = 10 * 1024 * 1024;
+/// Bound on a whole ICAP exchange, scanning included.
+const ICAP_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_HEADERS: usize = 100;
+
+type IcapError = Box<dyn Error + Send + Sync>;
+
+/*************************************************
+ * Modified
+ *************************************************/
+
+/// What the ICAP server made of a message it did not leave alone.
+pub enum Modified {
+    /// A rewritten request to send instead (REQMOD only).
+    Request(Request<Body>),
+    /// A response to hand to the client instead, such as a block page.
+    Response(Response<Body>),
+}
+
+/*************************************************
+ * IcapService
+ *************************************************/
+
+/// An ICAP service such as `icap://dlp.example:1344/reqmod`.
+pub struct IcapService {
+    address: String,
+    uri: String,
+}
+
+impl IcapService {
+    pub fn parse(url: &str) -> Result<Self, Box<dyn Error>> {
+        let rest = url.strip_prefix("icap://")
+            .ok_or_else(|| format!("Error: ICAP service must be an icap:// URL: {}", url))?;
+        let authority = rest.split('/').next().unwrap_or_default();
+        if authority.is_empty() {
+            return Err(format!("Error: Invalid ICAP service: {}", url).into());
+        }
+        let address = if authority.rsplit_once(':').is_some_and(|(_, port)| !port.ends_with(']')) {
+            authority.to_string()
+        } else {
+            format!("{}:{}", authority, DEFAULT_ICAP_PORT)
+        };
+        Ok(IcapService { address, uri: url.to_string() })
+    }
+
+    /// Hand a request (head from `request_head`, fully read body) to the
+    /// service. `None` means it is fine as it is.
+    pub async fn reqmod(&self, head: &[u8], body: &Bytes) -> Result<Option<Modified>, IcapError> {
+        tokio::time::timeout(ICAP_TIMEOUT, self.exchange("REQMOD", &[("req-hdr", head)], "req-body", body))
+            .await
+            .map_err(|_| format!("ICAP service {} timed out", self.uri))?
+    }
+
+    /// Hand a response, and the request it answers, to the service.
+    pub async fn respmod(&self, request_head: &[u8], head: &[u8], body: &Bytes) -> Result<Option<Modified>, IcapError> {
+        let sections = [("req-hdr", request_head), ("res-hdr", head)];
+        tokio::time::timeout(ICAP_TIMEOUT, self.exchange("RESPMOD", &sections, "res-body", body))
+            .await
+            .map_err(|_| format!("ICAP service {} timed out", self.uri))?
+    }
+
+    async fn exchange(&self, method: &str, sections: &[(&str, &[u8])], body_name: &str, body: &Bytes) -> Result<Option<Modified>, IcapError> {
+        let mut encapsulated = Vec::new();
+        let mut payload = Vec::new();
+        for (name, section) in sections {
+            encapsulated.push(format!("{}={}", name, payload.len()));
+            payload.extend_from_slice(section);
+        }
+        let body_name = if body.is_empty() { "null-body" } else { body_name };
+        encapsulated.push(format!("{}={}", body_name, payload.len()));
+        if !body.is_empty() {
+            payload.extend_from_slice(format!("{:x}\r\n", body.len()).as_bytes());
+            payload.extend_from_slice(body);
+            payload.extend_from_slice(b"\r\n0\r\n\r\n");
+        }
+        let host = self.address.rsplit_once(':').map_or(self.address.as_str(), |(host, _)| host);
+        let head = format!(
+            "{} {} ICAP/1.0\r\nHost: {}\r\nAllow: 204\r\nEncapsulated: {}\r\n\r\n",
+            method,
+            self.uri,
+            host,
+            encapsulated.join(", ")
+        );
+
+        let mut stream = BufReader::new(TcpStream::connect(&self.address).await?);
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(&payload).await?;
+
+        let mut status_line = String::new();
+        stream.read_line(&mut status_line).await?;
+        let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+        let mut encapsulated = String::new();
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).await? == 0 {
+                return Err("ICAP server closed the connection".into());
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("encapsulated") {
+                    encapsulated = value.trim().to_string();
+                }
+            }
+        }
+        match status {
+            "204" => return Ok(None),
+            "200" => {}
+            _ => return Err(format!("ICAP service {} answered: {}", self.uri, status_line.trim_end()).into()),
+        }
+
+        // Header sections run up to the next offset; the body comes last.
+        let offsets: Vec<(&str, usize)> = encapsulated.split(',')
+            .filter_map(|entry| entry.trim().split_once('='))
+            .map(|(name, offset)| Ok((name, offset.parse()?)))
+            .collect::<Result<_, IcapError>>()?;
+        let mut request_head = None;
+        let mut response_head = None;
+        for (index, (name, offset)) in offsets.iter().enumerate() {
+            let Some((_, next)) = offsets.get(index + 1) else {
+                break;
+            };
+            let mut section = vec![0; next.checked_sub(*offset).ok_or("ICAP offsets out of order")?];
+            stream.read_exact(&mut section).await?;
+            match *name {
+                "req-hdr" => request_head = Some(section),
+                "res-hdr" => response_head = Some(section),
+                _ => {}
+            }
+        }
+        let body = match offsets.last() {
+            Some((name, _)) if name.ends_with("-body") && *name != "null-body" => read_chunked(&mut stream).await?,
+            _ => Bytes::new(),
+        };
+        match (response_head, request_head) {
+            (Some(head), _) => Ok(Some(Modified::Response(parse_response(&head, body)?))),
+            (None, Some(head)) => Ok(Some(Modified::Request(parse_request(&head, body)?))),
+            (None, None) => Err("ICAP response carries no HTTP message".into()),
+        }
+    }
+}
+
+async fn read_chunked(stream: &mut BufReader<TcpStream>) -> Result<Bytes, IcapError> {
+    let mut body = Vec::new();
+    loop {
+        let mut line = String::new();
+        stream.read_line(&mut line).await?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| format!("Invalid ICAP chunk size: {}", size))?;
+        if size == 0 {
+            // Trailers, then the final empty line.
+            loop {
+                line.clear();
+                if stream.read_line(&mut line).await? == 0 || line.trim_end().is_empty() {
+                    return Ok(Bytes::from(body));
+                }
+            }
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        stream.read_exact(&mut body[start..]).await?;
+        line.clear();
+        stream.read_line(&mut line).await?;
+    }
+}
+
+/*************************************************
+ * HTTP messages
+ *************************************************/
+
+/// The head of `request` as ICAP servers expect it, with an absolute URI.
+pub fn request_head<T>(request: &Request<T>) -> Vec<u8> {
+    let start = format!("{} {} HTTP/1.1\r\n", request.method(), request.uri());
+    encode_head(start, request.headers())
+}
+
+pub fn response_head<T>(response: &Response<T>) -> Vec<u8> {
+    let start = format!("HTTP/1.1 {}\r\n", response.status());
+    encode_head(start, response.headers())
+}
+
+fn encode_head(start: String, headers: &HeaderMap) -> Vec<u8> {
+    let mut head = start.into_bytes();
+    for (name, value) in headers {
+        head.extend_from_slice(name.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+    head
+}
+
+fn parse_request(head: &[u8], body: Bytes) -> Result<Request<Body>, IcapError> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut parsed = httparse::Request::new(&mut headers);
+    parsed.parse(head)?;
+    let mut request = Request::builder()
+        .method(parsed.method.ok_or("ICAP request without a method")?)
+        .uri(parsed.path.ok_or("ICAP request without a URI")?)
+        .body(Body::empty())?;
+    copy_headers(parsed.headers, request.headers_mut(), body.len())?;
+    *request.body_mut() = Body::from(body);
+    Ok(request)
+}
+
+fn parse_response(head: &[u8], body: Bytes) -> Result<Response<Body>, IcapError> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut parsed = httparse::Response::new(&mut headers);
+    parsed.parse(head)?;
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::from_u16(parsed.code.ok_or("ICAP response without a status")?)?;
+    copy_headers(parsed.headers, response.headers_mut(), body.len())?;
+    *response.body_mut() = Body::from(body);
+    Ok(response)
+}
+
+/// Copy parsed headers, replacing the framing with the length of the
+/// de-chunked body.
+fn copy_headers(parsed: &[httparse::Header], headers: &mut HeaderMap, length: usize) -> Result<(), IcapError> {
+    for header in parsed {
+        headers.append(HeaderName::from_bytes(header.name.as_bytes())?, HeaderValue::from_bytes(header.value)?);
+    }
+    headers.remove(TRANSFER_ENCODING);
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(length));
+    Ok(())
+}
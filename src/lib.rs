@@ -0,0 +1,2569 @@
+//! rdnat, an HTTP(S) proxy server, as a library for programs that embed
+//! the proxy rather than run the `rdnat` binary.
+//!
+//! ```no_run
+//! use rdnat::ProxyServer;
+//!
+//! fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     ProxyServer::builder()
+//!         .port(8080)
+//!         .auth("user", "passwd")
+//!         .rules("rules.txt")
+//!         .build()?
+//!         .run()
+//! }
+//! ```
+
+// The stats, purge and reload methods only serve the control socket,
+// which needs a Unix socket.
+#![cfg_attr(not(unix), allow(dead_code))]
+
+/*************************************************
+ * Mod
+ *************************************************/
+
+mod accounting;
+mod acme;
+mod adblock;
+mod bench;
+mod breaker;
+mod budget;
+mod bufpool;
+mod cache;
+mod ca;
+mod check;
+mod clamav;
+mod cli;
+mod compress;
+#[cfg(unix)]
+mod ctl;
+mod decoy;
+mod discovery;
+mod diskcache;
+mod dns;
+mod dnscache;
+mod dnsproxy;
+mod domains;
+mod geoip;
+mod har;
+mod hosts;
+mod icap;
+mod logfilter;
+mod mitm;
+mod outbound;
+mod probe;
+mod profiles;
+mod rdns;
+mod relay;
+mod remote;
+mod replay;
+mod request;
+mod rewrite;
+mod rules;
+mod server;
+mod sockopt;
+mod socks;
+mod speedtest;
+#[cfg(target_os = "linux")]
+mod splice;
+mod ssrf;
+mod stats;
+mod throttle;
+mod tls;
+mod upstream;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+mod webhook;
+mod whoami;
+
+/*************************************************
+ * Use
+ *************************************************/
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use hyper::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, EXPECT, FORWARDED, MAX_FORWARDS, PROXY_AUTHORIZATION, SET_COOKIE, VIA};
+use hyper::{Body, Client, HeaderMap, Method, Request, Response, StatusCode, Version};
+use hyper::body::{Bytes, HttpBody as _};
+use hyper_rustls::HttpsConnector;
+use std::str;
+use base64::decode;
+use log::{info, error, warn, LevelFilter};
+use accounting::{Accounting, ANONYMOUS_USER};
+use acme::{Acme, AcmeConfig};
+use adblock::FilterList;
+use breaker::Breaker;
+use budget::{MemoryBudget, OverBudget, Reservation};
+use bufpool::BufferPool;
+use cache::{CacheRequest, Lookup, ResponseCache};
+use compress::{Accepted, Compressor, Encoding};
+use diskcache::DiskStore;
+use clamav::Clamd;
+use decoy::Decoy;
+use dns::Resolver;
+use dnscache::DnsCache;
+use dnsproxy::BlockResponse;
+use domains::DomainList;
+use geoip::GeoIp;
+use har::{BodyCapture, HarWriter};
+use hosts::HostOverrides;
+use icap::{IcapService, Modified};
+use logfilter::{LogAction, LogExclusions, REDACTED};
+use mitm::{Interceptor, KeyLogFile, Rewound};
+use outbound::{DirectConnector, Outbound};
+use profiles::Profiles;
+use request::{Authority, BodyLength, BodyTooLarge, ClientConn, HeadError, HeadLimits, RequestHead};
+use rdns::ReverseDns;
+use relay::{Activity, Flows};
+use rewrite::BodyRewriter;
+use remote::RemoteList;
+use replay::Replay;
+use rules::{normalize_url, Fault, PortList, Rules, Verdict};
+use sockopt::SocketOptions;
+use ssrf::{AddressGuard, Blocked};
+use stats::Stats;
+use throttle::{Link, Throttled};
+use tls::{ClientStream, ListenerPolicy, OriginTls, TlsListener};
+use upstream::{Attempts, Balance, HealthCheck, Pool, Retry, Sticky, Upstream, UpstreamConnector};
+use webhook::Webhooks;
+
+pub use server::{ProxyServer, ProxyServerBuilder};
+#[doc(hidden)]
+pub use cli::run as run_cli;
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+const DEFAULT_PORT: &str = "8000";
+const DEFAULT_PASSWD: &str = "anonymous";
+const DEFAULT_LOGPATH: &str = "rdnat.log";
+const DEFAULT_THREAD_NAME: &str = "rdnat-worker";
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 5;
+/// CONNECT port intercepted when `--mitm-ports` is not given.
+const DEFAULT_MITM_PORT: u16 = 443;
+const DEFAULT_IDLE_TIMEOUT_MINS: u64 = 15;
+/// Idle connections kept open per origin for later requests.
+const DEFAULT_POOL_MAX_IDLE: usize = 32;
+const DEFAULT_POOL_IDLE_TIMEOUT_SECS: u64 = 90;
+const DEFAULT_TLS_WATCH_SECS: u64 = 60;
+/// Largest request body kept in memory so the request can be retried.
+const MAX_RETRY_BODY: u64 = 1024 * 1024;
+/// Pending connection queue of each SO_REUSEPORT listener.
+#[cfg(unix)]
+const LISTEN_BACKLOG: u32 = 1024;
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+/// Body size a truncate fault cuts into when the response doesn't give one.
+const TRUNCATE_UNSIZED: u64 = 64 * 1024;
+const TRUNCATED: &str = "Fault injected: response body truncated";
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+/// Request headers that can identify the client or an earlier proxy hop.
+const CLIENT_ID_HEADERS: [&str; 5] = ["x-forwarded-for", "forwarded", "x-real-ip", "client-ip", "x-client-ip"];
+
+/*************************************************
+ * Config
+ *************************************************/
+
+struct Config {
+    port: String,
+    username: String,
+    password: String,
+    log_path: Option<String>,
+    log_level: Option<LevelFilter>,
+    usage_file: Option<String>,
+    usage_flush_secs: u64,
+    /// Bytes each user may transfer per day; unlimited when `None`.
+    usage_quota: Option<u64>,
+    /// Path of the control socket; none when `None`.
+    ctl_socket: Option<String>,
+    webhooks: Vec<String>,
+    webhook_template: Option<String>,
+    webhook_auth_failures: u32,
+    webhook_upstream_failures: u32,
+    geoip_db: Option<String>,
+    geoip_asn_db: Option<String>,
+    resolve_clients: bool,
+    rdns_timeout_ms: u64,
+    log_exclusions: LogExclusions,
+    via: bool,
+    forwarded_for: ClientAddrHeader,
+    connect_timeout: Duration,
+    outbound: Outbound,
+    socket_options: SocketOptions,
+    keepalive_interval: Option<Duration>,
+    keepalive_count: Option<u32>,
+    fastopen_queue: Option<u32>,
+    /// `None` for each relay's own default.
+    relay_buffer_size: Option<usize>,
+    relay_buffer_pool: usize,
+    /// Most bytes of buffered data held at once; unlimited when `None`.
+    memory_budget: Option<usize>,
+    /// Bytes of responses kept in the cache; off when `None`.
+    cache_size: Option<usize>,
+    cache_max_object: usize,
+    /// Directory of the disk tier of the cache; none when `None`.
+    cache_dir: Option<String>,
+    cache_disk_size: u64,
+    cache_disk_max_object: u64,
+    /// Compress text responses for clients that take it.
+    compress: bool,
+    compress_min_size: u64,
+    /// Network link simulated for every client, and for some users.
+    throttle: Option<Link>,
+    user_throttle: HashMap<String, Link>,
+    /// Answer `http://rdnat.internal/whoami`, finding the egress address
+    /// through the IP echo service if there is one.
+    whoami: bool,
+    whoami_echo: Option<hyper::Uri>,
+    splice: bool,
+    io_uring: bool,
+    send_buffer: Option<u32>,
+    recv_buffer: Option<u32>,
+    resolver: Resolver,
+    hosts: HostOverrides,
+    dns_cache_size: usize,
+    dns_listen: Option<SocketAddr>,
+    dns_block_response: BlockResponse,
+    doh: bool,
+    dns_negative_ttl: Duration,
+    dns_max_ttl: Duration,
+    head_limits: HeadLimits,
+    idle_timeout: Duration,
+    pool_max_idle: usize,
+    pool_idle_timeout: Duration,
+    origin_http2: bool,
+    max_open_files: Option<u64>,
+    acceptors: usize,
+    worker_threads: Option<usize>,
+    max_blocking_threads: Option<usize>,
+    thread_name: String,
+    decoy: Option<Decoy>,
+    anonymity: Anonymity,
+    user_anonymity: HashMap<String, Anonymity>,
+    allow_private: bool,
+    allow_ports: Option<PortList>,
+    user_allow_ports: HashMap<String, PortList>,
+    allow_domains: Option<DomainList>,
+    block_domains: Option<DomainList>,
+    rules: Rules,
+    filters: Option<FilterList>,
+    remote_lists: Vec<Arc<RemoteList>>,
+    list_refresh: Duration,
+    block_page: Option<String>,
+    block_status: Option<StatusCode>,
+    pac_path: Option<String>,
+    upstream: Option<Pool>,
+    upstreams: HashMap<String, Pool>,
+    upstream_balance: Balance,
+    upstream_sticky: Option<Sticky>,
+    upstream_session: Option<Duration>,
+    discovery_refresh: Duration,
+    health_check: HealthCheck,
+    retry: Retry,
+    breaker_failures: Option<u32>,
+    breaker_cooldown: Duration,
+    profiles: Profiles,
+    icap_reqmod: Option<IcapService>,
+    icap_respmod: Option<IcapService>,
+    icap_bypass: bool,
+    icap_max_size: usize,
+    clamd: Option<Clamd>,
+    max_scan_size: usize,
+    max_upload: Option<u64>,
+    max_download: Option<u64>,
+    mitm_ca: Option<String>,
+    mitm_key: Option<String>,
+    mitm_ports: Option<PortList>,
+    har_path: Option<String>,
+    har_max_body: usize,
+    har_max_file_size: u64,
+    har_redact: Vec<String>,
+    replay: Option<Replay>,
+    replay_passthrough: bool,
+    tls_keylog: Option<String>,
+    origin_tls: OriginTls,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    tls_policy: ListenerPolicy,
+    tls_watch: Duration,
+    acme: AcmeConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            port: String::from(DEFAULT_PORT),
+            username: String::new(),
+            password: String::new(),
+            log_path: None,
+            log_level: None,
+            usage_file: None,
+            usage_flush_secs: accounting::DEFAULT_FLUSH_SECS,
+            usage_quota: None,
+            ctl_socket: None,
+            webhooks: Vec::new(),
+            webhook_template: None,
+            webhook_auth_failures: webhook::DEFAULT_AUTH_FAILURES,
+            webhook_upstream_failures: webhook::DEFAULT_UPSTREAM_FAILURES,
+            geoip_db: None,
+            geoip_asn_db: None,
+            resolve_clients: false,
+            rdns_timeout_ms: rdns::DEFAULT_RDNS_TIMEOUT_MS,
+            log_exclusions: LogExclusions::default(),
+            via: true,
+            forwarded_for: ClientAddrHeader::XForwardedFor,
+            connect_timeout: Duration::from_secs(DEFAULT_CONNECT_TIMEOUT_SECS),
+            outbound: Outbound::default(),
+            socket_options: SocketOptions::default(),
+            keepalive_interval: None,
+            keepalive_count: None,
+            fastopen_queue: None,
+            relay_buffer_size: None,
+            relay_buffer_pool: bufpool::DEFAULT_POOL_SIZE,
+            memory_budget: None,
+            cache_size: None,
+            cache_max_object: cache::DEFAULT_MAX_OBJECT,
+            cache_dir: None,
+            cache_disk_size: diskcache::DEFAULT_DISK_SIZE,
+            cache_disk_max_object: diskcache::DEFAULT_DISK_MAX_OBJECT,
+            compress: false,
+            compress_min_size: compress::DEFAULT_MIN_SIZE,
+            throttle: None,
+            user_throttle: HashMap::new(),
+            whoami: false,
+            whoami_echo: None,
+            splice: true,
+            io_uring: false,
+            send_buffer: None,
+            recv_buffer: None,
+            resolver: Resolver::default(),
+            hosts: HostOverrides::default(),
+            dns_cache_size: dnscache::DEFAULT_CAPACITY,
+            dns_listen: None,
+            dns_block_response: BlockResponse::default(),
+            doh: false,
+            dns_negative_ttl: Duration::from_secs(dnscache::DEFAULT_NEGATIVE_TTL_SECS),
+            dns_max_ttl: Duration::from_secs(dnscache::DEFAULT_MAX_TTL_SECS),
+            head_limits: HeadLimits::default(),
+            idle_timeout: Duration::from_secs(DEFAULT_IDLE_TIMEOUT_MINS * 60),
+            pool_max_idle: DEFAULT_POOL_MAX_IDLE,
+            pool_idle_timeout: Duration::from_secs(DEFAULT_POOL_IDLE_TIMEOUT_SECS),
+            origin_http2: false,
+            max_open_files: None,
+            acceptors: 1,
+            worker_threads: None,
+            max_blocking_threads: None,
+            thread_name: String::from(DEFAULT_THREAD_NAME),
+            decoy: None,
+            anonymity: Anonymity::Transparent,
+            user_anonymity: HashMap::new(),
+            allow_private: false,
+            allow_ports: None,
+            user_allow_ports: HashMap::new(),
+            allow_domains: None,
+            block_domains: None,
+            rules: Rules::default(),
+            filters: None,
+            remote_lists: Vec::new(),
+            list_refresh: Duration::from_secs(remote::DEFAULT_REFRESH_MINS * 60),
+            block_page: None,
+            block_status: None,
+            pac_path: None,
+            upstream: None,
+            upstreams: HashMap::new(),
+            upstream_balance: Balance::RoundRobin,
+            upstream_sticky: None,
+            upstream_session: None,
+            discovery_refresh: Duration::from_secs(discovery::DEFAULT_REFRESH_SECS),
+            health_check: HealthCheck::default(),
+            retry: Retry::default(),
+            breaker_failures: None,
+            breaker_cooldown: Duration::from_secs(breaker::DEFAULT_COOLDOWN_SECS),
+            profiles: Profiles::default(),
+            icap_reqmod: None,
+            icap_respmod: None,
+            icap_bypass: false,
+            icap_max_size: icap::DEFAULT_MAX_SIZE,
+            clamd: None,
+            max_scan_size: clamav::DEFAULT_MAX_SCAN_SIZE,
+            max_upload: None,
+            max_download: None,
+            mitm_ca: None,
+            mitm_key: None,
+            mitm_ports: None,
+            har_path: None,
+            har_max_body: har::DEFAULT_MAX_BODY,
+            har_max_file_size: har::DEFAULT_MAX_FILE_SIZE,
+            har_redact: har::DEFAULT_REDACTED.iter().map(|name| name.to_string()).collect(),
+            replay: None,
+            replay_passthrough: false,
+            tls_keylog: None,
+            origin_tls: OriginTls::default(),
+            tls_cert: None,
+            tls_key: None,
+            tls_policy: ListenerPolicy::default(),
+            tls_watch: Duration::from_secs(DEFAULT_TLS_WATCH_SECS),
+            acme: AcmeConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Check the settings against each other and fill in those that follow
+    /// from others, once all are given.
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        self.profiles.finish(self.rules.timezone())?;
+        let rule_sets = || std::iter::once(&self.rules).chain(self.profiles.all());
+        if self.geoip_db.is_none() && rule_sets().any(Rules::needs_country) {
+            return Err("Error: Rules match on country, which needs --geoip-db".into());
+        }
+        if self.geoip_asn_db.is_none() && rule_sets().any(Rules::needs_asn) {
+            return Err("Error: Rules match on asn, which needs --geoip-asn-db".into());
+        }
+        if let Some(name) = rule_sets().find_map(|rules| rules.upstream_names().find(|name| !self.upstreams.contains_key(*name))) {
+            return Err(format!("Error: Rules route through undefined upstream: {}", name).into());
+        }
+        if self.usage_quota.is_some() && self.usage_file.is_none() {
+            return Err("Error: --usage-quota counts the usage recorded with --usage-file, which is missing".into());
+        }
+        if !cfg!(unix) && self.ctl_socket.is_some() {
+            return Err("Error: The control socket is only supported on Unix platforms".into());
+        }
+        if self.mitm_ca.is_some() != self.mitm_key.is_some() {
+            return Err("Error: --mitm-ca and --mitm-key go together".into());
+        }
+        if !self.acme.domains.is_empty() {
+            if self.tls_cert.is_some() || self.tls_key.is_some() {
+                return Err("Error: --acme-domain obtains the certificate itself; drop --tls-cert and --tls-key".into());
+            }
+            self.tls_cert = Some(self.acme.cert_path());
+            self.tls_key = Some(self.acme.key_path());
+        }
+        for pool in self.upstream.iter_mut().chain(self.upstreams.values_mut()) {
+            pool.set_balance(self.upstream_balance);
+            // Sessions rotate per client unless told otherwise.
+            pool.set_sticky(self.upstream_sticky.or(self.upstream_session.map(|_| Sticky::ClientIp)));
+            pool.set_session(self.upstream_session);
+        }
+        if self.keepalive_interval.is_some() || self.keepalive_count.is_some() {
+            let Some(mut keepalive) = self.socket_options.keepalive() else {
+                return Err("Error: --tcp-keepalive-interval and --tcp-keepalive-count need --tcp-keepalive".into());
+            };
+            keepalive.interval = self.keepalive_interval;
+            keepalive.count = self.keepalive_count;
+            self.socket_options.set_keepalive(Some(keepalive));
+        }
+        self.socket_options.set_buffers(self.send_buffer, self.recv_buffer);
+        self.outbound.set_socket_options(self.socket_options.clone());
+        let hosts = std::mem::take(&mut self.hosts);
+        self.resolver.set_hosts(hosts);
+        for &bind in self.outbound.binds() {
+            // Catch an address this host doesn't have now rather than on every
+            // connection.
+            std::net::TcpListener::bind((bind, 0)).map_err(|e| format!("Error: Cannot use outbound address {}: {}", bind, e))?;
+        }
+        self.outbound.socket(true).map_err(|e| format!("Error: Cannot mark outbound connections: {}", e))?;
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return Err("Error: --tls-cert and --tls-key go together".into());
+        }
+        if self.worker_threads == Some(0) || self.max_blocking_threads == Some(0) {
+            return Err("Error: --worker-threads and --max-blocking-threads must be at least 1".into());
+        }
+        if self.acceptors == 0 {
+            return Err("Error: --acceptors must be at least 1".into());
+        }
+        if !cfg!(unix) && self.acceptors > 1 {
+            return Err("Error: More than one acceptor needs SO_REUSEPORT, which only Unix platforms have".into());
+        }
+        if self.relay_buffer_size == Some(0) {
+            return Err("Error: --relay-buffer-size must be at least 1".into());
+        }
+        if self.doh && self.tls_cert.is_none() {
+            return Err("Error: --doh needs the TLS listener (--tls-cert and --tls-key, or --acme-domain)".into());
+        }
+        if self.username.is_empty() {
+            self.password.clear();
+        } else if self.password.is_empty() {
+            self.password = String::from(DEFAULT_PASSWD);
+        }
+        Ok(())
+    }
+}
+
+/// Which header carries the client address to the origin.
+#[derive(Clone, Copy, PartialEq)]
+enum ClientAddrHeader {
+    Off,
+    XForwardedFor,
+    Forwarded,
+    Both,
+}
+
+/// How much of the client the origin gets to see: `Transparent` forwards
+/// the client address, `Anonymous` hides it but admits to being a proxy,
+/// `Elite` looks like a direct connection.
+#[derive(Clone, Copy, PartialEq)]
+enum Anonymity {
+    Transparent,
+    Anonymous,
+    Elite,
+}
+
+impl std::str::FromStr for Anonymity {
+    type Err = String;
+
+    fn from_str(level: &str) -> Result<Self, Self::Err> {
+        match level {
+            "transparent" => Ok(Anonymity::Transparent),
+            "anonymous" => Ok(Anonymity::Anonymous),
+            "elite" => Ok(Anonymity::Elite),
+            level => Err(format!("Error: Invalid anonymity level: {}", level)),
+        }
+    }
+}
+
+/*************************************************
+ * Context
+ *************************************************/
+
+struct Context {
+    username: Option<String>,
+    password: Option<String>,
+    accounting: Option<Arc<Accounting>>,
+    stats: Stats,
+    webhooks: Option<Webhooks>,
+    breaker: Option<Breaker>,
+    geoip: Option<GeoIp>,
+    rdns: Option<ReverseDns>,
+    guard: Arc<AddressGuard>,
+    interceptor: Option<Interceptor>,
+    har: Option<HarWriter>,
+    /// TLS settings for `https` requests to origins.
+    tls_client: tokio_rustls::rustls::ClientConfig,
+    /// Terminates TLS on accepted connections when the listener is HTTPS.
+    tls_listener: Option<Arc<TlsListener>>,
+    /// Via pseudonym, unique per process so loops can be told apart from
+    /// chains of several rdnat instances.
+    via_name: String,
+    /// Pooled clients for plain HTTP requests: straight to origins, and
+    /// one per upstream proxy URL.
+    origin_client: Client<HttpsConnector<DirectConnector>>,
+    upstream_clients: Mutex<HashMap<String, Client<HttpsConnector<UpstreamConnector>>>>,
+    /// Buffers for relaying tunnels.
+    buffers: BufferPool,
+    /// Bytes held up in the relay by slow receivers.
+    flows: Flows,
+    /// Memory for buffered bodies, relay buffers and captures.
+    budget: MemoryBudget,
+    cache: Option<Arc<ResponseCache>>,
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    uring: Option<uring::Ring>,
+    config: Config,
+}
+
+impl Context {
+    /// The pooled client for requests through `upstream`.
+    fn upstream_client(&self, upstream: &Arc<Upstream>) -> Client<HttpsConnector<UpstreamConnector>> {
+        let mut clients = self.upstream_clients.lock().unwrap();
+        clients.entry(upstream.url.clone())
+            .or_insert_with(|| {
+                let connector = UpstreamConnector(upstream.clone(), self.config.connect_timeout, self.config.outbound.clone());
+                origin_client(&self.config, &self.tls_client, connector)
+            })
+            .clone()
+    }
+
+    /// What a tunnel for `stream` relays through: a buffer or a pipe per
+    /// direction, as `copy_io` picks them.
+    fn relay_memory(&self, stream: &ClientStream, throttled: bool) -> usize {
+        let size = match stream {
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            ClientStream::Plain(_) if self.uring.is_some() && !throttled => uring::DEFAULT_BUFFER_SIZE,
+            #[cfg(target_os = "linux")]
+            ClientStream::Plain(_) if self.config.splice && !throttled => splice::DEFAULT_PIPE_SIZE,
+            _ => bufpool::DEFAULT_BUFFER_SIZE,
+        };
+        2 * self.config.relay_buffer_size.unwrap_or(size)
+    }
+
+    /// The network link simulated for a request or tunnel (`url` is
+    /// `None`): a `throttle` rule's, else the user's, else the default.
+    fn link(&self, rules: &Rules, host: &str, port: u16, user: &str, url: Option<&str>) -> Option<Link> {
+        rules.throttle(host, port, user, url)
+            .or_else(|| self.config.user_throttle.get(user).copied())
+            .or(self.config.throttle)
+    }
+
+    fn record_transfer(&self, user: &str, destination: &str, addr: Option<IpAddr>, sent: u64, received: u64) {
+        if let Some(quota) = self.accounting.as_ref().and_then(|accounting| accounting.record(user, sent, received)) {
+            info!("User {} used up the daily transfer quota of {} bytes", user, quota);
+            if let Some(webhooks) = &self.webhooks {
+                webhooks.quota_exhausted(user, quota);
+            }
+        }
+        let label = addr.and_then(|addr| self.geo_label(addr));
+        self.stats.record(user, destination, label, sent + received);
+    }
+
+    /// Country/ASN annotation for `addr`, when a GeoIP database is loaded.
+    fn geo_label(&self, addr: IpAddr) -> Option<String> {
+        self.geoip.as_ref().map(|geoip| geoip.lookup(addr).to_string())
+    }
+
+    fn describe_addr(&self, addr: SocketAddr) -> String {
+        match self.geo_label(addr.ip()) {
+            Some(label) => format!("{} [{}]", addr, label),
+            None => addr.to_string(),
+        }
+    }
+
+    /// The rules of the client's profile, or the global rules.
+    fn rules_for(&self, user: &str, ip: IpAddr) -> &Rules {
+        self.config.profiles.select(user, ip).unwrap_or(&self.config.rules)
+    }
+
+    /// What blocks `host` among the domain lists, filter lists and remote
+    /// lists, if anything does.
+    fn domain_block(&self, host: &str) -> Option<String> {
+        let config = &self.config;
+        let listed = config.allow_domains.as_ref().is_none_or(|list| list.contains(host))
+            && !config.block_domains.as_ref().is_some_and(|list| list.contains(host));
+        if !listed {
+            return Some(String::from("policy"));
+        }
+        if config.filters.as_ref().is_some_and(|filters| filters.blocks(host)) {
+            return Some(String::from("a filter list"));
+        }
+        config.remote_lists.iter().find(|list| list.blocks(host)).map(|list| list.url.clone())
+    }
+
+    /// Apply the domain lists and `rules` to a destination (and URL, for
+    /// plain HTTP); the error is the status and message to refuse the
+    /// request with, or `302 Found` and the location to redirect to. On
+    /// success, the pool of upstream proxies to go through (`None` to
+    /// connect directly).
+    async fn check_destination(
+        &self,
+        rules: &Rules,
+        host: &str,
+        port: u16,
+        user: &str,
+        url: Option<&str>,
+    ) -> Result<Option<&Pool>, (StatusCode, String)> {
+        let config = &self.config;
+        if self.accounting.as_ref().is_some_and(|accounting| accounting.exhausted(user)) {
+            return Err((StatusCode::FORBIDDEN, format!("The daily transfer quota of {} is used up", user)));
+        }
+        if let Some(reason) = self.domain_block(host) {
+            return Err((StatusCode::FORBIDDEN, format!("Access to {} is blocked by {}", host, reason)));
+        }
+        let geo = match (&self.geoip, rules.needs_country() || rules.needs_asn()) {
+            (Some(geoip), true) => destination_addr(self.guard.resolver(), host, port).await.map(|ip| geoip.lookup(ip)),
+            _ => None,
+        };
+        match rules.evaluate(host, port, user, url, geo.as_ref()) {
+            Verdict::Allow => Ok(config.upstream.as_ref()),
+            Verdict::Route(None) => Ok(None),
+            Verdict::Route(Some(name)) => Ok(config.upstreams.get(&name)),
+            Verdict::Redirect(location) if url.is_some() => Err((StatusCode::FOUND, location)),
+            Verdict::Redirect(_) => {
+                Err((StatusCode::FORBIDDEN, format!("Access to {} is redirected by policy, which tunnels can't follow", host)))
+            }
+            Verdict::Deny(line) => {
+                Err((StatusCode::FORBIDDEN, format!("Access to {} is blocked by policy (rule on line {})", host, line)))
+            }
+            Verdict::RateLimited(line) => {
+                Err((StatusCode::TOO_MANY_REQUESTS, format!("Rate limit exceeded (rule on line {})", line)))
+            }
+        }
+    }
+
+    fn via_value(&self, version: Version) -> HeaderValue {
+        let protocol = if version == Version::HTTP_10 { "1.0" } else { "1.1" };
+        HeaderValue::from_str(&format!("{} {}", protocol, self.via_name)).unwrap_or(HeaderValue::from_static("1.1 rdnat"))
+    }
+
+    /// Like `describe_addr`, with the client's PTR name when lookups are enabled.
+    async fn describe_client(&self, addr: SocketAddr) -> String {
+        let description = self.describe_addr(addr);
+        let name = match &self.rdns {
+            Some(rdns) => rdns.lookup(addr.ip()).await,
+            None => None,
+        };
+        match name {
+            Some(name) => format!("{} ({})", description, name),
+            None => description,
+        }
+    }
+}
+
+/*************************************************
+ * copy_io
+ *************************************************/
+
+/// Relay both directions until they finish, or until neither has moved a
+/// byte for the idle timeout (zero disables the check). Unless turned
+/// off, a plain TCP client is relayed with splice(), in the kernel.
+/// Returns the bytes sent and received, and the error that ended the
+/// tunnel if it didn't close cleanly.
+async fn copy_io(stream1: ClientStream, mut stream2: TcpStream, link: Option<Link>, ctx: &Context) -> (u64, u64, Option<String>) {
+    let (idle_timeout, buffers, flows) = (ctx.config.idle_timeout, &ctx.buffers, &ctx.flows);
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    let stream1 = match (stream1, &ctx.uring, link) {
+        (ClientStream::Plain(stream1), Some(ring), None) => return uring_io(ring, stream1, stream2, ctx).await,
+        (stream1, _, _) => stream1,
+    };
+    let peer_addr = stream1.peer_addr().map_or_else(|_| String::from("-"), |addr| addr.to_string());
+    let activity = Activity::new();
+    let sent = AtomicU64::new(0);
+    let received = AtomicU64::new(0);
+
+    let relay = async {
+        match stream1 {
+            #[cfg(target_os = "linux")]
+            ClientStream::Plain(mut stream1) if ctx.config.splice && link.is_none() => {
+                let pipe_size = ctx.config.relay_buffer_size.unwrap_or(splice::DEFAULT_PIPE_SIZE);
+                let (r1, mut w1) = stream1.split();
+                let (r2, mut w2) = stream2.split();
+                tokio::join!(
+                    splice_half(&r1, &mut w2, pipe_size, &sent, &activity, &flows.upload),
+                    splice_half(&r2, &mut w1, pipe_size, &received, &activity, &flows.download)
+                )
+            }
+            mut stream1 => {
+                let forward = relay::Half::new(buffers.get(), &sent, &activity, &flows.upload);
+                let backward = relay::Half::new(buffers.get(), &received, &activity, &flows.download);
+                match link {
+                    Some(link) => relay::relay(&mut Throttled::new(stream1, link), &mut stream2, forward, backward).await,
+                    None => relay::relay(&mut stream1, &mut stream2, forward, backward).await,
+                }
+            }
+        }
+    };
+    let mut dropped = None;
+    tokio::select! {
+        (res1, res2) = relay => {
+            if let Err(e) = &res1 {
+                log::error!("Error copying from stream1 to stream2: {}", e);
+            }
+            if let Err(e) = &res2 {
+                log::error!("Error copying from stream2 to stream1: {}", e);
+            }
+            dropped = res1.err().or(res2.err()).map(|e| e.to_string());
+        }
+        _ = activity.idle(idle_timeout) => {
+            info!("Closing tunnel from {} after {} seconds idle", peer_addr, idle_timeout.as_secs());
+        }
+    }
+
+    (sent.into_inner(), received.into_inner(), dropped)
+}
+
+/// `copy_io` through io_uring, for plain TCP clients when the ring is on.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+async fn uring_io(ring: &uring::Ring, stream1: TcpStream, stream2: TcpStream, ctx: &Context) -> (u64, u64, Option<String>) {
+    let idle_timeout = ctx.config.idle_timeout;
+    let buffer_size = ctx.config.relay_buffer_size.unwrap_or(uring::DEFAULT_BUFFER_SIZE);
+    let peer_addr = stream1.peer_addr().map_or_else(|_| String::from("-"), |addr| addr.to_string());
+    let activity = Activity::new();
+    let sent = AtomicU64::new(0);
+    let received = AtomicU64::new(0);
+    // The ring does the waiting, so the sockets leave the runtime and
+    // go back to blocking.
+    let streams = stream1.into_std().and_then(|stream1| Ok((stream1, stream2.into_std()?)));
+    let (stream1, stream2) = match streams.and_then(|(stream1, stream2)| {
+        stream1.set_nonblocking(false)?;
+        stream2.set_nonblocking(false)?;
+        Ok((stream1, stream2))
+    }) {
+        Ok(streams) => streams,
+        Err(e) => {
+            log::error!("Cannot relay {} through io_uring: {}", peer_addr, e);
+            return (0, 0, Some(e.to_string()));
+        }
+    };
+
+    let mut dropped = None;
+    {
+        let relay = async {
+            tokio::join!(
+                uring_half(ring, &stream1, &stream2, buffer_size, &sent, &activity, &ctx.flows.upload),
+                uring_half(ring, &stream2, &stream1, buffer_size, &received, &activity, &ctx.flows.download)
+            )
+        };
+        tokio::pin!(relay);
+        tokio::select! {
+            (res1, res2) = &mut relay => {
+                if let Err(e) = &res1 {
+                    log::error!("Error copying from stream1 to stream2: {}", e);
+                }
+                if let Err(e) = &res2 {
+                    log::error!("Error copying from stream2 to stream1: {}", e);
+                }
+                dropped = res1.err().or(res2.err()).map(|e| e.to_string());
+            }
+            _ = activity.idle(idle_timeout) => {
+                info!("Closing tunnel from {} after {} seconds idle", peer_addr, idle_timeout.as_secs());
+                // Operations in flight hold the sockets open; shutting them
+                // down completes them.
+                let _ = stream1.shutdown(std::net::Shutdown::Both);
+                let _ = stream2.shutdown(std::net::Shutdown::Both);
+                let _ = relay.await;
+            }
+        }
+    }
+
+    (sent.into_inner(), received.into_inner(), dropped)
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+async fn uring_half(
+    ring: &uring::Ring,
+    reader: &std::net::TcpStream,
+    writer: &std::net::TcpStream,
+    buffer_size: usize,
+    copied: &AtomicU64,
+    activity: &Activity,
+    flow: &relay::Flow,
+) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let mut buf = vec![0u8; buffer_size];
+    loop {
+        let (n, returned) = ring.recv(reader.as_raw_fd(), buf).await;
+        buf = returned;
+        let n = n?;
+        if n == 0 {
+            break;
+        }
+        // The ring waits for a slow receiver itself, so stalls don't show.
+        let mut chunk = relay::Chunk::new(flow, n);
+        while chunk.left() > 0 {
+            let (w, returned) = ring.send(writer.as_raw_fd(), buf, n - chunk.left()..n).await;
+            buf = returned;
+            chunk.written(w?);
+        }
+        copied.fetch_add(n as u64, Ordering::Relaxed);
+        activity.touch();
+    }
+    writer.shutdown(std::net::Shutdown::Write)
+}
+
+/// One direction of a tunnel through a pipe with splice(), so the data
+/// stays in the kernel. The EOF is passed on with a write shutdown.
+#[cfg(target_os = "linux")]
+async fn splice_half(
+    reader: &tokio::net::tcp::ReadHalf<'_>,
+    writer: &mut tokio::net::tcp::WriteHalf<'_>,
+    pipe_size: usize,
+    copied: &AtomicU64,
+    activity: &Activity,
+    flow: &relay::Flow,
+) -> std::io::Result<()> {
+    let pipe = splice::Pipe::new(pipe_size)?;
+    loop {
+        let n = pipe.transfer(reader.as_ref(), writer.as_ref(), flow).await?;
+        if n == 0 {
+            break;
+        }
+        copied.fetch_add(n as u64, Ordering::Relaxed);
+        activity.touch();
+    }
+    writer.shutdown().await
+}
+
+/*************************************************
+ * handle_tunneling
+ *************************************************/
+
+async fn handle_tunneling(
+    mut stream: ClientStream,
+    target: &str,
+    headers: &HeaderMap,
+    early_data: &[u8],
+    ctx: Arc<Context>,
+    user: String,
+    rules: &Rules,
+) -> Result<(), Box<dyn Error>> {
+    let authority = match Authority::parse(target).map_err(|e| e.to_string()) {
+        Ok(authority) => authority,
+        Err(detail) => {
+            send_error(&mut stream, StatusCode::BAD_REQUEST, &detail).await;
+            return Err(detail.into());
+        }
+    };
+    let target_addr = &authority.to_string();
+    let pool = match ctx.check_destination(rules, &authority.host, authority.port, &user, None).await {
+        Ok(pool) => pool,
+        Err((status, detail)) => {
+            send_denied(&mut stream, &ctx.config, status, target_addr, &detail).await;
+            return Err(detail.into());
+        }
+    };
+    let allowed_ports = ctx.config.user_allow_ports.get(&user).or(ctx.config.allow_ports.as_ref());
+    if allowed_ports.is_some_and(|ports| !ports.iter().any(|range| range.contains(&authority.port))) {
+        let detail = format!("CONNECT to port {} is not allowed", authority.port);
+        send_error(&mut stream, StatusCode::FORBIDDEN, &detail).await;
+        return Err(detail.into());
+    }
+    let intercepted = ctx.config.mitm_ports.as_ref()
+        .map_or(authority.port == DEFAULT_MITM_PORT, |ports| ports.iter().any(|range| range.contains(&authority.port)))
+        && rules.intercepts(&authority.host, authority.port, &user);
+    if let (Some(interceptor), true) = (&ctx.interceptor, intercepted) {
+        let client_addr = stream.peer_addr()?;
+        match ctx.config.log_exclusions.action(&authority.host) {
+            LogAction::Log => info!("CONNECT {} -> {} (intercepted)", ctx.describe_client(client_addr).await, target_addr),
+            LogAction::Redact => info!("CONNECT {} -> {} (intercepted)", ctx.describe_client(client_addr).await, REDACTED),
+            LogAction::Suppress => {}
+        }
+        stream.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await?;
+        let stream = Rewound::new(early_data.to_vec(), stream);
+        return intercept(stream, client_addr, &authority, interceptor, &ctx, &user, rules).await;
+    }
+    // A blind tunnel has no response to truncate; the other faults apply
+    // to it before it is connected.
+    inject_faults(&mut stream, &rules.faults(&authority.host, authority.port, &user, None), target_addr).await?;
+    let link = ctx.link(rules, &authority.host, authority.port, &user, None);
+    if let Some(Err(wait)) = ctx.breaker.as_ref().map(|breaker| breaker.check(target_addr)) {
+        let detail = circuit_open(target_addr, wait);
+        send_error(&mut stream, StatusCode::SERVICE_UNAVAILABLE, &detail).await;
+        return Err(detail.into());
+    }
+    let relay_memory = ctx.relay_memory(&stream, link.is_some());
+    let Some(relay_buffers) = ctx.budget.try_reserve(relay_memory) else {
+        let detail = OverBudget(relay_memory).to_string();
+        send_error(&mut stream, StatusCode::SERVICE_UNAVAILABLE, &detail).await;
+        return Err(detail.into());
+    };
+    // Nothing has gone through the tunnel before it is connected, so any
+    // failure can be retried.
+    let mut attempts = Attempts::new(pool, ctx.config.retry, stream.peer_addr()?.ip(), &user);
+    let mut upstream = attempts.first();
+    let hop_headers = tunnel_headers(&ctx, headers, &user);
+    let mut target_stream = loop {
+        let connect = async {
+            match &upstream {
+                Some(upstream) => upstream.connect(target_addr, &hop_headers, &ctx.config.outbound).await,
+                None => connect_target(&ctx.guard, &ctx.config.outbound, &authority).await,
+            }
+        };
+        let connect = tokio::time::timeout(ctx.config.connect_timeout, connect)
+            .await
+            .unwrap_or_else(|_| Err(Box::new(std::io::Error::from(std::io::ErrorKind::TimedOut))));
+        match connect {
+            Ok(target_stream) => break target_stream,
+            Err(e) if attempts.can_retry() && upstream_status(&*e) != StatusCode::FORBIDDEN => {
+                warn!("Attempt {} to connect to {} failed, retrying: {}", attempts.made(), target_addr, e);
+                upstream = attempts.next(upstream).await;
+            }
+            Err(e) => {
+                let status = upstream_status(&*e);
+                if let (Some(webhooks), false) = (&ctx.webhooks, status == StatusCode::FORBIDDEN) {
+                    webhooks.upstream_failed(target_addr);
+                }
+                if let (Some(breaker), false) = (&ctx.breaker, status == StatusCode::FORBIDDEN) {
+                    breaker.failed(target_addr);
+                }
+                send_error(&mut stream, status, &format!("Cannot connect to {}: {}", target_addr, e)).await;
+                return Err(e);
+            }
+        }
+    };
+    if let Some(webhooks) = &ctx.webhooks {
+        webhooks.upstream_succeeded(target_addr);
+    }
+    if let Some(breaker) = &ctx.breaker {
+        breaker.succeeded(target_addr);
+    }
+    let client_addr = stream.peer_addr()?;
+    let remote_addr = target_stream.peer_addr()?;
+    let host = authority.host;
+    match ctx.config.log_exclusions.action(&host) {
+        LogAction::Log => info!(
+            "CONNECT {} -> {} ({})",
+            ctx.describe_client(client_addr).await,
+            target_addr,
+            ctx.describe_addr(remote_addr)
+        ),
+        LogAction::Redact => info!("CONNECT {} -> {}", ctx.describe_client(client_addr).await, REDACTED),
+        LogAction::Suppress => {}
+    }
+    if let Some(link) = link {
+        // Connecting takes a round trip over the link.
+        tokio::time::sleep(link.round_trip()).await;
+    }
+    stream.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await?;
+
+    // Clients may pipeline the first bytes of the tunnel (e.g. a TLS
+    // ClientHello) right behind the CONNECT head.
+    target_stream.write_all(early_data).await?;
+    let early_len = early_data.len() as u64;
+    // The tunnel is relayed in the connection's own task; the upstream
+    // counts as in use for as long as it is open.
+    let (sent, received, dropped) = copy_io(stream, target_stream, link, &ctx).await;
+    drop(relay_buffers);
+    drop(upstream);
+    if let (Some(webhooks), Some(error)) = (&ctx.webhooks, dropped) {
+        webhooks.tunnel_dropped(client_addr.ip(), target_addr, &error);
+    }
+    ctx.record_transfer(&user, &host, Some(remote_addr.ip()), sent + early_len, received);
+    Ok(())
+}
+
+/// Resolve through the address guard and connect to the first reachable
+/// address.
+async fn connect_target(
+    guard: &AddressGuard,
+    outbound: &Outbound,
+    authority: &Authority,
+) -> Result<TcpStream, Box<dyn Error + Send + Sync>> {
+    let addrs = guard.resolve(&authority.host, authority.port).await?;
+    Ok(outbound.connect(&addrs).await?)
+}
+
+/// The address GeoIP rules judge a destination by: the host itself when
+/// it is an IP literal, otherwise the first address it resolves to.
+async fn destination_addr(resolver: &Resolver, host: &str, port: u16) -> Option<IpAddr> {
+    resolver.lookup(host, port).await.ok()?.first().map(|addr| addr.ip())
+}
+
+/*************************************************
+ * intercept
+ *************************************************/
+
+/// Terminate TLS on an established tunnel with a certificate for its host
+/// and handle the requests inside as ones for `https://` URLs, so the
+/// plain-HTTP policy, logging and inspection apply to them.
+async fn intercept(
+    stream: Rewound<ClientStream>,
+    client_addr: SocketAddr,
+    authority: &Authority,
+    interceptor: &Interceptor,
+    ctx: &Context,
+    user: &str,
+    rules: &Rules,
+) -> Result<(), Box<dyn Error>> {
+    let acceptor = interceptor.acceptor(&authority.host).await?;
+    let stream = tokio::time::timeout(ctx.config.head_limits.timeout, acceptor.accept(stream))
+        .await
+        .map_err(|_| format!("TLS handshake with {} timed out", client_addr))?
+        .map_err(|e| format!("TLS handshake with {} failed: {}", client_addr, e))?;
+    let origin = match authority.port {
+        DEFAULT_MITM_PORT if authority.host.contains(':') => format!("[{}]", authority.host),
+        DEFAULT_MITM_PORT => authority.host.clone(),
+        _ => authority.to_string(),
+    };
+    let mut conn = ClientConn::new(stream);
+    loop {
+        let Some((mut head, head_len)) = next_request(&mut conn, ctx).await? else {
+            break;
+        };
+        if !head.target.starts_with('/') {
+            let detail = format!("Expected an origin-form request inside the tunnel, got: {}", head.target);
+            send_error(&mut conn.stream, StatusCode::BAD_REQUEST, &detail).await;
+            return Err(detail.into());
+        }
+        head.target = format!("https://{}{}", origin, head.target);
+        if !handle_http_request(&mut conn, head, head_len, ctx, user, rules, client_addr).await? {
+            break;
+        }
+    }
+    let _ = conn.stream.shutdown().await;
+    Ok(())
+}
+
+/*************************************************
+ * handle_http_request
+ *************************************************/
+
+async fn handle_http_request<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    conn: &mut ClientConn<S>,
+    head: RequestHead,
+    head_len: usize,
+    ctx: &Context,
+    user: &str,
+    rules: &Rules,
+    client_addr: SocketAddr,
+) -> Result<bool, Box<dyn Error>> {
+    let body_length = match head.body_length().map_err(|e| e.to_string()) {
+        Ok(body_length) => body_length,
+        Err(detail) => {
+            send_error(&mut conn.stream, StatusCode::BAD_REQUEST, &detail).await;
+            return Err(detail.into());
+        }
+    };
+    let keep_alive = head.keep_alive();
+    let method = head.method.clone();
+    let version = head.version;
+    let uri = head.target;
+
+    let (host, port) = match uri.parse::<hyper::Uri>() {
+        Ok(parsed) if parsed.host().is_some() && matches!(parsed.scheme_str(), Some("http" | "https")) => {
+            let default_port = if parsed.scheme_str() == Some("https") { 443 } else { 80 };
+            (parsed.host().unwrap_or_default().to_string(), parsed.port_u16().unwrap_or(default_port))
+        }
+        _ => {
+            let detail = format!("Expected an absolute http:// or https:// URI, got: {}", uri);
+            send_error(&mut conn.stream, StatusCode::BAD_REQUEST, &detail).await;
+            return Err(detail.into());
+        }
+    };
+    // What URL rules match against.
+    let url = normalize_url(&uri);
+    if ctx.config.whoami && whoami::is_whoami(&host, &uri) {
+        // A request body isn't read, so the connection can't be reused.
+        let keep_alive = keep_alive && matches!(body_length, BodyLength::Empty);
+        return whoami::serve(conn, &method, keep_alive, ctx, user, rules, client_addr).await;
+    }
+    let pool = match ctx.check_destination(rules, &host, port, user, Some(&url)).await {
+        Ok(pool) => pool,
+        Err((StatusCode::FOUND, location)) => {
+            let response = format!("HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", location);
+            conn.stream.write_all(response.as_bytes()).await?;
+            return Ok(false);
+        }
+        Err((status, detail)) => {
+            send_denied(&mut conn.stream, &ctx.config, status, &uri, &detail).await;
+            return Err(detail.into());
+        }
+    };
+    // IP literals never reach the resolver, so check them here.
+    if let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        if ctx.guard.is_blocked(ip) {
+            let detail = Blocked(host).to_string();
+            send_error(&mut conn.stream, StatusCode::FORBIDDEN, &detail).await;
+            return Err(detail.into());
+        }
+        if let Err(e) = ctx.config.outbound.usable(vec![SocketAddr::new(ip, port)]) {
+            let detail = format!("Cannot connect to {}: {}", host, e);
+            send_error(&mut conn.stream, StatusCode::BAD_GATEWAY, &detail).await;
+            return Err(detail.into());
+        }
+    }
+    match ctx.config.log_exclusions.action(&host) {
+        LogAction::Log => info!("HTTP {} -> {}", ctx.describe_client(client_addr).await, uri),
+        LogAction::Redact => info!("HTTP {} -> {}", ctx.describe_client(client_addr).await, REDACTED),
+        LogAction::Suppress => {}
+    }
+    if let Some(replay) = &ctx.config.replay {
+        match replay.response(&method, &uri) {
+            Some(response) => {
+                // Read the request body off the connection; nobody needs it.
+                let (body_sender, body) = Body::channel();
+                let (_, body_sent) = tokio::join!(
+                    hyper::body::to_bytes(body),
+                    async { conn.forward_body(body_length, body_sender, None, &ctx.config.head_limits, None).await.map_err(|e| e.to_string()) }
+                );
+                let body_sent = match body_sent {
+                    Ok(body_sent) => body_sent,
+                    Err(detail) => {
+                        send_error(&mut conn.stream, StatusCode::BAD_REQUEST, &detail).await;
+                        return Err(detail.into());
+                    }
+                };
+                let (received, keep_alive) = write_response(&mut conn.stream, response, &method, version, keep_alive, BodyHandling::default()).await?;
+                ctx.record_transfer(user, &host, None, head_len as u64 + body_sent, received);
+                return Ok(keep_alive);
+            }
+            None if ctx.config.replay_passthrough => {}
+            None => {
+                let detail = format!("No recorded response for {} {}", method, uri);
+                send_error(&mut conn.stream, StatusCode::NOT_FOUND, &detail).await;
+                return Err(detail.into());
+            }
+        }
+    }
+    // Keyed as a CONNECT target is, so both paths share failure counts.
+    let destination = format!("{}:{}", host.to_ascii_lowercase(), port);
+    if let Some(Err(wait)) = ctx.breaker.as_ref().map(|breaker| breaker.check(&destination)) {
+        let detail = circuit_open(&destination, wait);
+        send_error(&mut conn.stream, StatusCode::SERVICE_UNAVAILABLE, &detail).await;
+        return Err(detail.into());
+    }
+    let faults = rules.faults(&host, port, user, Some(&url));
+    inject_faults(&mut conn.stream, &faults, &uri).await?;
+    let link = ctx.link(rules, &host, port, user, Some(&url));
+    if let Some(link) = link {
+        // The request and the start of its answer cross the link.
+        tokio::time::sleep(link.round_trip()).await;
+    }
+    let request_edits = rules.header_edits(&host, port, user, &url, false);
+    let response_edits = rules.header_edits(&host, port, user, &url, true);
+    let rewrites = rules.body_rewrites(&host, port, user, &url);
+    let (max_upload, max_download) = rules.body_caps(&host, port, user, &url);
+    let max_upload = max_upload.into_iter().chain(ctx.config.max_upload).min();
+    let max_download = max_download.into_iter().chain(ctx.config.max_download).min();
+    if let (BodyLength::Fixed(length), Some(limit)) = (&body_length, max_upload) {
+        if *length > limit {
+            let detail = BodyTooLarge(limit).to_string();
+            send_error(&mut conn.stream, StatusCode::PAYLOAD_TOO_LARGE, &detail).await;
+            return Err(detail.into());
+        }
+    }
+    let (body_sender, body) = Body::channel();
+    let body = match link {
+        Some(link) if !matches!(body_length, BodyLength::Empty) => link.pace_upload(body),
+        _ => body,
+    };
+    let mut request = Request::builder()
+        .method(head.method)
+        .uri(uri.as_str())
+        .body(body)?;
+    *request.headers_mut() = head.headers;
+    request::strip_hop_by_hop(request.headers_mut());
+    // What the client takes, before the origin is asked for something else.
+    let accepted = Accepted::parse(request.headers());
+    if let BodyLength::Fixed(length) = body_length {
+        // Collapse repeated (identical) Content-Length values.
+        request.headers_mut().insert(CONTENT_LENGTH, HeaderValue::from(length));
+    }
+    add_forwarding_headers(ctx, request.headers_mut(), head.version, client_addr.ip(), user);
+    for edit in request_edits {
+        edit.apply(request.headers_mut());
+    }
+    // Bodies that are filtered or rewritten are decoded first, so only
+    // codings that can be are asked for.
+    let inspected = !rewrites.is_empty() || ctx.config.icap_respmod.is_some() || ctx.config.clamd.is_some();
+    if inspected {
+        compress::restrict_accept_encoding(request.headers_mut());
+    }
+    // Only requests without a body are answered from the cache.
+    let cache_request = ctx.cache.as_ref()
+        .filter(|_| matches!(body_length, BodyLength::Empty))
+        .and_then(|_| CacheRequest::new(&request));
+    let lookup = match (&ctx.cache, &cache_request) {
+        (Some(cache), Some(cache_request)) => cache.lookup(cache_request),
+        _ => Lookup::Miss,
+    };
+    // A stale response is only used once the origin confirms it, so the
+    // request goes out conditional on it.
+    let (cached, revalidation, stale) = match lookup {
+        Lookup::Hit(response, revalidation) => (Some(response), revalidation, None),
+        Lookup::Stale(stale) => {
+            stale.make_conditional(request.headers_mut());
+            (None, None, Some(stale))
+        }
+        Lookup::Miss => (None, None, None),
+    };
+    let hit = cached.is_some();
+    if hit {
+        info!("Answered {} {} from the cache", method, uri);
+    } else if cache_request.as_ref().is_some_and(CacheRequest::only_if_cached) {
+        let detail = format!("No cached response for {} {}", method, uri);
+        send_error(&mut conn.stream, StatusCode::GATEWAY_TIMEOUT, &detail).await;
+        return Err(detail.into());
+    }
+
+    // Only a body of a known, bounded size is kept around to send again.
+    let replayable = ctx.config.retry.retries > 0 && match body_length {
+        BodyLength::Empty => true,
+        BodyLength::Fixed(length) => length <= MAX_RETRY_BODY,
+        BodyLength::Chunked => false,
+    };
+    // Only bodies of a known, bounded size are held back for REQMOD.
+    let reqmod = ctx.config.icap_reqmod.as_ref().filter(|_| match body_length {
+        BodyLength::Empty => true,
+        BodyLength::Fixed(length) => length <= ctx.config.icap_max_size as u64,
+        BodyLength::Chunked => false,
+    });
+    // Memory for what this exchange buffers, held until it is relayed;
+    // a held-back request body is reserved before it is read.
+    let mut buffered = ctx.budget.reservation();
+    if let (BodyLength::Fixed(length), true) = (&body_length, replayable || reqmod.is_some()) {
+        if let Err(e) = buffered.grow(*length as usize) {
+            let detail = e.to_string();
+            send_error(&mut conn.stream, StatusCode::SERVICE_UNAVAILABLE, &detail).await;
+            return Err(detail.into());
+        }
+    }
+
+    // hyper can't relay the origin's interim response, so answer the
+    // expectation here instead of leaving the client to wait it out.
+    if let Some(expect) = request.headers_mut().remove(EXPECT) {
+        if !expect.as_bytes().eq_ignore_ascii_case(b"100-continue") {
+            let detail = format!("Unsupported expectation: {}", String::from_utf8_lossy(expect.as_bytes()));
+            send_error(&mut conn.stream, StatusCode::EXPECTATION_FAILED, &detail).await;
+            return Err(detail.into());
+        }
+        if version != Version::HTTP_10 && !matches!(body_length, BodyLength::Empty) {
+            conn.stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+        }
+    }
+
+    let mut exchange = ctx.har.as_ref().map(|har| har.begin(&request));
+    let pinned = pool.filter(|pool| pool.sticks_by_cookie()).and_then(|_| upstream::take_sticky_cookie(request.headers_mut()));
+    let mut attempts = Attempts::new(pool, ctx.config.retry, client_addr.ip(), user).pinned(pinned.clone());
+    // Held until the response is relayed, so the member counts as in use.
+    let mut upstream = attempts.first();
+    if let (Some(cache), Some(revalidation)) = (&ctx.cache, revalidation) {
+        let response = forward_request(ctx, upstream.as_ref().map(|lease| &lease.upstream), revalidation.request());
+        tokio::spawn(cache.clone().revalidate(*revalidation, response));
+    }
+    let lease = &mut upstream;
+    let send = |request: Request<Body>| async move {
+        if !replayable {
+            return forward_request(ctx, lease.as_ref().map(|lease| &lease.upstream), request).await;
+        }
+        let idempotent = upstream::is_idempotent(request.method(), request.headers());
+        let (parts, body) = request.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        loop {
+            let mut request = Request::builder()
+                .method(parts.method.clone())
+                .uri(parts.uri.clone())
+                .version(parts.version)
+                .body(Body::from(body.clone()))?;
+            *request.headers_mut() = parts.headers.clone();
+            match forward_request(ctx, lease.as_ref().map(|lease| &lease.upstream), request).await {
+                // Anything else may have reached the origin, which only
+                // idempotent requests can stand twice.
+                Err(e) if attempts.can_retry() && upstream_status(&*e) != StatusCode::FORBIDDEN
+                    && (idempotent || e.downcast_ref::<hyper::Error>().is_some_and(hyper::Error::is_connect)) =>
+                {
+                    warn!("Attempt {} at {} {} failed, retrying: {}", attempts.made(), parts.method, parts.uri, e);
+                    *lease = attempts.next(lease.take()).await;
+                }
+                response => return response,
+            }
+        }
+    };
+    let request_head = ctx.config.icap_respmod.as_ref().map(|_| icap::request_head(&request));
+    // `Box<dyn Error>` is not `Send`, so stringify the body error before it
+    // is parked inside the join.
+    let forward_body = async {
+        let capture = exchange.as_mut().map(|exchange| &mut exchange.request_body);
+        conn.forward_body(body_length, body_sender, max_upload, &ctx.config.head_limits, capture).await.map_err(|e| {
+            let status = if e.is::<BodyTooLarge>() { StatusCode::PAYLOAD_TOO_LARGE } else { StatusCode::BAD_REQUEST };
+            (status, e.to_string())
+        })
+    };
+    let (response, body_sent) = match (cached, reqmod) {
+        (Some(cached), _) => {
+            drop(forward_body);
+            (Ok(cached), Ok(0))
+        }
+        (None, Some(icap)) => {
+            let (body, body_sent) = tokio::join!(hyper::body::to_bytes(std::mem::take(request.body_mut())), forward_body);
+            let response = match body {
+                Ok(body) => match icap.reqmod(&icap::request_head(&request), &body).await {
+                    Ok(Some(Modified::Response(response))) => Ok(response),
+                    Ok(Some(Modified::Request(modified))) => send(modified).await,
+                    Ok(None) => {
+                        *request.body_mut() = Body::from(body);
+                        send(request).await
+                    }
+                    Err(e) if ctx.config.icap_bypass => {
+                        warn!("Forwarding {} uninspected: {}", host, e);
+                        *request.body_mut() = Body::from(body);
+                        send(request).await
+                    }
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e.into()),
+            };
+            (response, body_sent)
+        }
+        (None, None) => tokio::join!(send(request), forward_body),
+    };
+    // A body we couldn't frame is the client's fault; the origin saw it
+    // aborted rather than complete.
+    let body_sent = match body_sent {
+        Ok(body_sent) => body_sent,
+        Err((status, detail)) => {
+            send_error(&mut conn.stream, status, &detail).await;
+            return Err(detail.into());
+        }
+    };
+    let mut response = match response {
+        Ok(response) => response,
+        Err(e) => {
+            let status = upstream_status(&*e);
+            if let (Some(webhooks), false) = (&ctx.webhooks, status == StatusCode::FORBIDDEN) {
+                webhooks.upstream_failed(&destination);
+            }
+            if let (Some(breaker), false) = (&ctx.breaker, status == StatusCode::FORBIDDEN) {
+                breaker.failed(&destination);
+            }
+            send_error(&mut conn.stream, status, &format!("Upstream request to {} failed: {}", host, e)).await;
+            return Err(e);
+        }
+    };
+    if !hit {
+        let mut revalidated = false;
+        if let Some(webhooks) = &ctx.webhooks {
+            webhooks.upstream_succeeded(&destination);
+        }
+        if let Some(breaker) = &ctx.breaker {
+            breaker.succeeded(&destination);
+        }
+        if let Some(cache) = &ctx.cache {
+            match (cache_request, stale) {
+                (Some(cache_request), Some(stale)) if response.status() == StatusCode::NOT_MODIFIED => {
+                    info!("Revalidated {} {} with the origin", method, uri);
+                    response = cache.refresh(&cache_request, stale, &response).await;
+                    revalidated = true;
+                }
+                (Some(cache_request), _) => response = cache.fill(cache_request, response),
+                (None, _) if !method.is_safe() && !response.status().is_server_error() && !response.status().is_client_error() => cache.invalidate(&uri),
+                (None, _) => {}
+            }
+        }
+        // Only once the response is cached (a Set-Cookie would keep it out
+        // of the cache), and never on one served from the cache: the
+        // cookie is this client's alone.
+        let cookie = pool.zip(upstream.as_ref()).and_then(|(pool, lease)| pool.sticky_cookie(lease, pinned.as_deref()));
+        if let (Some(cookie), false) = (cookie, revalidated) {
+            response.headers_mut().append(SET_COOKIE, cookie);
+        }
+    }
+    let bodyless = method == Method::HEAD
+        || matches!(response.status(), StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED | StatusCode::PARTIAL_CONTENT);
+    let decoded = if inspected && !bodyless { compress::decode(&mut response, max_download, &ctx.budget) } else { None };
+    let blocked_type = response.headers().get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|content_type| {
+            let line = rules.evaluate_response(&host, port, user, &url, content_type)?;
+            Some(format!("Content type {} is blocked by policy (rule on line {})", content_type, line))
+        });
+    if let Some(detail) = blocked_type {
+        send_denied(&mut conn.stream, &ctx.config, StatusCode::FORBIDDEN, &uri, &detail).await;
+        return Err(detail.into());
+    }
+    if let (Some(icap), Some(request_head), false) = (&ctx.config.icap_respmod, &request_head, method == Method::HEAD) {
+        response = match inspect_response(ctx, icap, request_head, response, &mut buffered).await {
+            Ok(response) => response,
+            Err(e) => {
+                let status = if e.is::<OverBudget>() { StatusCode::SERVICE_UNAVAILABLE } else { StatusCode::BAD_GATEWAY };
+                let detail = format!("Content inspection of {} failed: {}", host, e);
+                send_error(&mut conn.stream, status, &detail).await;
+                return Err(detail.into());
+            }
+        };
+    }
+    if let (Some(clamd), false) = (&ctx.config.clamd, method == Method::HEAD) {
+        response = match scan_response(ctx, clamd, response, &mut buffered).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(signature)) => {
+                let detail = format!("Download blocked: {} found", signature);
+                send_denied(&mut conn.stream, &ctx.config, StatusCode::FORBIDDEN, &uri, &detail).await;
+                return Err(detail.into());
+            }
+            Err(e) => {
+                let status = if e.is::<OverBudget>() { StatusCode::SERVICE_UNAVAILABLE } else { StatusCode::BAD_GATEWAY };
+                let detail = format!("Virus scan of {} failed: {}", host, e);
+                send_error(&mut conn.stream, status, &detail).await;
+                return Err(detail.into());
+            }
+        };
+    }
+    if ctx.config.via {
+        response.headers_mut().append(VIA, ctx.via_value(Version::HTTP_11));
+    }
+    for edit in response_edits {
+        edit.apply(response.headers_mut());
+    }
+    let length = response.headers().get(CONTENT_LENGTH).and_then(|value| value.to_str().ok()?.parse::<u64>().ok());
+    if let (Some(length), Some(limit), false) = (length, max_download, method == Method::HEAD) {
+        if length > limit {
+            let detail = format!("Download of {} bytes exceeds the {} byte limit", length, limit);
+            send_denied(&mut conn.stream, &ctx.config, StatusCode::FORBIDDEN, &uri, &detail).await;
+            return Err(detail.into());
+        }
+    }
+    if let Some(exchange) = &mut exchange {
+        exchange.respond(&response);
+    }
+    let body = BodyHandling {
+        rewriter: (!rewrites.is_empty()).then(|| BodyRewriter::new(rewrites)),
+        // A decoded body goes back in its coding, when the client takes it.
+        compression: decoded.filter(|&encoding| accepted.accepts(encoding)).or_else(|| {
+            accepted.preferred().filter(|_| ctx.config.compress && compress::is_compressible(response.headers(), ctx.config.compress_min_size))
+        }),
+        max_size: max_download,
+        truncate: faults.iter().find_map(|fault| match fault {
+            Fault::Truncate(share) => Some(*share),
+            _ => None,
+        }),
+        capture: exchange.as_mut().map(|exchange| &mut exchange.response_body),
+    };
+    // The response crosses the link as it is sent, compressed or not.
+    let (received, keep_alive) = match link {
+        Some(link) => write_response(&mut Throttled::new(&mut conn.stream, link), response, &method, version, keep_alive, body).await?,
+        None => write_response(&mut conn.stream, response, &method, version, keep_alive, body).await?,
+    };
+    drop(buffered);
+    ctx.record_transfer(user, &host, None, head_len as u64 + body_sent, received);
+    if let (Some(har), Some(exchange)) = (&ctx.har, exchange) {
+        har.record(exchange);
+    }
+    Ok(keep_alive)
+}
+
+/*************************************************
+ * forward_request
+ *************************************************/
+
+/// Send `request` to its origin, through `upstream` if given. The returned
+/// future doesn't borrow `ctx`, so it may outlive the exchange.
+fn forward_request(
+    ctx: &Context,
+    upstream: Option<&Arc<Upstream>>,
+    mut request: Request<Body>,
+) -> impl Future<Output = Result<Response<Body>, Box<dyn Error + Send + Sync>>> + Send + 'static {
+    let authorization = upstream
+        .and_then(|upstream| upstream.authorization.as_deref())
+        .map(HeaderValue::from_str)
+        .transpose();
+    let response = authorization.map(|authorization| {
+        if let Some(authorization) = authorization {
+            request.headers_mut().insert(PROXY_AUTHORIZATION, authorization);
+        }
+        match upstream {
+            Some(upstream) => ctx.upstream_client(upstream).request(request),
+            None => ctx.origin_client.request(request),
+        }
+    });
+    async move { Ok(response?.await?) }
+}
+
+/// A client over `connector` that keeps connections to origins open for
+/// reuse, within the pool limits.
+fn origin_client<C>(config: &Config, tls: &tokio_rustls::rustls::ClientConfig, connector: C) -> Client<HttpsConnector<C>>
+where
+    C: hyper::service::Service<hyper::Uri> + Clone + Send + Sync + 'static,
+    C::Response: hyper::client::connect::Connection + AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    C::Future: Send + 'static,
+    C::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    let https = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_tls_config(tls.clone())
+        .https_or_http()
+        .enable_http1();
+    let https = if config.origin_http2 { https.enable_http2().wrap_connector(connector) } else { https.wrap_connector(connector) };
+    Client::builder()
+        .pool_idle_timeout(config.pool_idle_timeout)
+        .pool_max_idle_per_host(config.pool_max_idle)
+        .build(https)
+}
+
+/*************************************************
+ * inspect_response
+ *************************************************/
+
+/// Pass a response through ICAP RESPMOD. Bodies over the size limit are
+/// forwarded uninspected.
+async fn inspect_response(
+    ctx: &Context,
+    icap: &IcapService,
+    request_head: &[u8],
+    response: Response<Body>,
+    buffered: &mut Reservation,
+) -> Result<Response<Body>, Box<dyn Error + Send + Sync>> {
+    let (parts, body) = response.into_parts();
+    let body = match buffer_body(body, ctx.config.icap_max_size, buffered).await? {
+        Ok(body) => body,
+        Err(replay) => return Ok(Response::from_parts(parts, replay)),
+    };
+    let response = Response::from_parts(parts, Body::empty());
+    match icap.respmod(request_head, &icap::response_head(&response), &body).await {
+        Ok(Some(Modified::Response(modified))) => Ok(modified),
+        Ok(Some(Modified::Request(_))) => Err("ICAP server answered RESPMOD with a request".into()),
+        Ok(None) => Ok(response.map(|_| Body::from(body))),
+        Err(e) if ctx.config.icap_bypass => {
+            warn!("Forwarding response uninspected: {}", e);
+            Ok(response.map(|_| Body::from(body)))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/*************************************************
+ * scan_response
+ *************************************************/
+
+/// Scan a response body with clamd; the signature name when it is
+/// infected. Bodies over the scan size limit are forwarded unscanned.
+async fn scan_response(
+    ctx: &Context,
+    clamd: &Clamd,
+    response: Response<Body>,
+    buffered: &mut Reservation,
+) -> Result<Result<Response<Body>, String>, Box<dyn Error + Send + Sync>> {
+    let (parts, body) = response.into_parts();
+    let body = match buffer_body(body, ctx.config.max_scan_size, buffered).await? {
+        Ok(body) => body,
+        Err(replay) => return Ok(Ok(Response::from_parts(parts, replay))),
+    };
+    match clamd.scan(&body).await? {
+        Some(signature) => Ok(Err(signature)),
+        None => Ok(Ok(Response::from_parts(parts, Body::from(body)))),
+    }
+}
+
+/*************************************************
+ * buffer_body
+ *************************************************/
+
+/// Read `body` into memory if it fits in `limit` bytes, taking what it
+/// reads from `reservation`. A longer body is handed back as a new body
+/// that replays what was already read.
+async fn buffer_body(mut body: Body, limit: usize, reservation: &mut Reservation) -> Result<Result<Bytes, Body>, Box<dyn Error + Send + Sync>> {
+    let mut buffered = Vec::new();
+    if body.size_hint().lower() > limit as u64 {
+        return Ok(Err(body));
+    }
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        reservation.grow(chunk.len())?;
+        buffered.extend_from_slice(&chunk);
+        if buffered.len() > limit {
+            let (mut sender, replay) = Body::channel();
+            tokio::spawn(async move {
+                if sender.send_data(Bytes::from(buffered)).await.is_err() {
+                    return;
+                }
+                while let Some(chunk) = body.data().await {
+                    let sent = match chunk {
+                        Ok(chunk) => sender.send_data(chunk).await.is_ok(),
+                        Err(_) => false,
+                    };
+                    if !sent {
+                        sender.abort();
+                        return;
+                    }
+                }
+            });
+            return Ok(Err(replay));
+        }
+    }
+    Ok(Ok(Bytes::from(buffered)))
+}
+
+/*************************************************
+ * upstream_status
+ *************************************************/
+
+/// Status to answer a failed upstream connection or request with: 403 for
+/// blocked destinations, 504 for timeouts, 502 otherwise. hyper reports
+/// connect failures somewhere down the source chain.
+fn upstream_status(e: &(dyn Error + 'static)) -> StatusCode {
+    let mut source = Some(e);
+    while let Some(err) = source {
+        if err.is::<Blocked>() {
+            return StatusCode::FORBIDDEN;
+        }
+        let timed_out = err.downcast_ref::<std::io::Error>().is_some_and(|err| err.kind() == std::io::ErrorKind::TimedOut)
+            || err.downcast_ref::<hyper::Error>().is_some_and(hyper::Error::is_timeout);
+        if timed_out {
+            return StatusCode::GATEWAY_TIMEOUT;
+        }
+        source = err.source();
+    }
+    StatusCode::BAD_GATEWAY
+}
+
+/// Why a destination whose circuit is open gets a 503; `wait` is what is
+/// left of its cooldown.
+fn circuit_open(destination: &str, wait: Duration) -> String {
+    format!("{} is failing; not trying it again for {} seconds", destination, wait.as_secs().max(1))
+}
+
+/*************************************************
+ * send_error
+ *************************************************/
+
+/// Best-effort plain-text error response; the connection is closed after it.
+async fn send_error<W: AsyncWrite + Unpin>(stream: &mut W, status: StatusCode, detail: &str) {
+    let body = format!("{}\n{}\n", status, detail);
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+}
+
+/*************************************************
+ * inject_faults
+ *************************************************/
+
+/// Apply the faults of `fault` rules that act before a request is
+/// forwarded: sleep through delays, then fail with a reset (closing the
+/// connection unanswered) or an error status, whichever comes first.
+async fn inject_faults<W: AsyncWrite + Unpin>(stream: &mut W, faults: &[Fault], target: &str) -> Result<(), Box<dyn Error>> {
+    for fault in faults {
+        match *fault {
+            Fault::Delay(delay) => {
+                log::debug!("Fault injected: delaying {} by {}ms", target, delay.as_millis());
+                tokio::time::sleep(delay).await;
+            }
+            Fault::Reset => return Err(format!("Fault injected: connection for {} reset", target).into()),
+            Fault::Status(status) => {
+                let detail = format!("Fault injected: {} answered with {}", target, status.as_u16());
+                send_error(stream, status, &detail).await;
+                return Err(detail.into());
+            }
+            Fault::Truncate(_) => {}
+        }
+    }
+    Ok(())
+}
+
+/*************************************************
+ * send_denied
+ *************************************************/
+
+/// Refuse a request on policy grounds, with the configured block page and
+/// status when there are any. The page may use {{url}}, {{reason}} and
+/// {{status}}.
+async fn send_denied<W: AsyncWrite + Unpin>(stream: &mut W, config: &Config, status: StatusCode, url: &str, reason: &str) {
+    let status = match config.block_status {
+        Some(block_status) if status == StatusCode::FORBIDDEN => block_status,
+        _ => status,
+    };
+    let page = match &config.block_page {
+        Some(page) => page,
+        None => return send_error(stream, status, reason).await,
+    };
+    let body = page
+        .replace("{{url}}", &escape_html(url))
+        .replace("{{reason}}", &escape_html(reason))
+        .replace("{{status}}", status.as_str());
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/*************************************************
+ * add_forwarding_headers
+ *************************************************/
+
+fn add_forwarding_headers(ctx: &Context, headers: &mut HeaderMap, version: Version, client_ip: IpAddr, user: &str) {
+    let config = &ctx.config;
+    let anonymity = config.user_anonymity.get(user).copied().unwrap_or(config.anonymity);
+    if anonymity != Anonymity::Transparent {
+        for name in CLIENT_ID_HEADERS {
+            headers.remove(name);
+        }
+    }
+    if anonymity == Anonymity::Elite {
+        headers.remove(VIA);
+        return;
+    }
+
+    if config.via {
+        headers.append(VIA, ctx.via_value(version));
+    }
+    if anonymity == Anonymity::Anonymous {
+        return;
+    }
+
+    if matches!(config.forwarded_for, ClientAddrHeader::XForwardedFor | ClientAddrHeader::Both) {
+        let value = match headers.get(X_FORWARDED_FOR).and_then(|value| value.to_str().ok()) {
+            Some(previous) => format!("{}, {}", previous, client_ip),
+            None => client_ip.to_string(),
+        };
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(X_FORWARDED_FOR, value);
+        }
+    }
+    if matches!(config.forwarded_for, ClientAddrHeader::Forwarded | ClientAddrHeader::Both) {
+        let node = match client_ip {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+        };
+        if let Ok(value) = HeaderValue::from_str(&format!("for={}", node)) {
+            headers.append(FORWARDED, value);
+        }
+    }
+}
+
+/// The headers a CONNECT through an upstream proxy carries: the Via
+/// entries of the client's CONNECT plus this proxy's, and its
+/// Max-Forwards, so the hops further on can tell a loop too.
+fn tunnel_headers(ctx: &Context, headers: &HeaderMap, user: &str) -> HeaderMap {
+    let mut hop = HeaderMap::new();
+    let anonymity = ctx.config.user_anonymity.get(user).copied().unwrap_or(ctx.config.anonymity);
+    if anonymity != Anonymity::Elite {
+        for via in headers.get_all(VIA) {
+            hop.append(VIA, via.clone());
+        }
+        if ctx.config.via {
+            hop.append(VIA, ctx.via_value(Version::HTTP_11));
+        }
+    }
+    if let Some(max_forwards) = headers.get(MAX_FORWARDS) {
+        hop.insert(MAX_FORWARDS, max_forwards.clone());
+    }
+    hop
+}
+
+/*************************************************
+ * check_loop
+ *************************************************/
+
+/// Reject requests that already carry this instance's Via entry, and
+/// TRACE and OPTIONS requests that have run out of Max-Forwards hops;
+/// otherwise count their hop down. Other methods ignore Max-Forwards
+/// (RFC 9110, section 7.6.2).
+fn check_loop(ctx: &Context, method: &Method, headers: &mut HeaderMap) -> Result<(), String> {
+    let looped = headers.get_all(VIA)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|hop| hop.split_whitespace().nth(1) == Some(ctx.via_name.as_str()));
+    if looped {
+        return Err(format!("Request already passed through {}", ctx.via_name));
+    }
+    if *method != Method::TRACE && *method != Method::OPTIONS {
+        return Ok(());
+    }
+
+    let max_forwards = headers.get(MAX_FORWARDS)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u32>().ok());
+    match max_forwards {
+        Some(0) => Err(String::from("Max-Forwards exhausted")),
+        Some(hops) => {
+            headers.insert(MAX_FORWARDS, HeaderValue::from(hops - 1));
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+/*************************************************
+ * write_response
+ *************************************************/
+
+/// What happens to a response body on its way to the client.
+#[derive(Default)]
+struct BodyHandling<'a> {
+    rewriter: Option<BodyRewriter>,
+    /// Compresses the body, after any rewriting.
+    compression: Option<Encoding>,
+    max_size: Option<u64>,
+    /// Share of the body to send before cutting it off, for `fault truncate`.
+    truncate: Option<f64>,
+    /// Copy of the body as the client gets it, for the HAR capture.
+    capture: Option<&'a mut BodyCapture>,
+}
+
+/// Write `response` to the client. hyper has already decoded the upstream
+/// framing, so bodies without a Content-Length are sent chunked, or
+/// delimited by closing the connection for HTTP/1.0 clients. A text body
+/// passed through a rewriter loses its Content-Length to the same framing.
+/// So does a compressed body. A body growing past its maximum size is cut
+/// off mid-transfer, as is one with a truncate fault: after its share of
+/// the Content-Length, or of `TRUNCATE_UNSIZED` without one, and never
+/// before losing at least the end of the chunked framing. Returns
+/// the body size and whether the connection can carry another request.
+async fn write_response<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    mut response: Response<Body>,
+    method: &Method,
+    version: Version,
+    mut keep_alive: bool,
+    body: BodyHandling<'_>,
+) -> Result<(u64, bool), Box<dyn Error>> {
+    let BodyHandling { rewriter, compression, max_size, truncate, mut capture } = body;
+    let status = response.status();
+    request::strip_hop_by_hop(response.headers_mut());
+    let bodyless = method == Method::HEAD
+        || status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED;
+    let mut rewriter = rewriter.filter(|_| {
+        !bodyless && status != StatusCode::PARTIAL_CONTENT && rewrite::is_rewritable(response.headers())
+    });
+    if rewriter.is_some() {
+        response.headers_mut().remove(CONTENT_LENGTH);
+    }
+    let mut compressor = compression.filter(|_| !bodyless && status != StatusCode::PARTIAL_CONTENT).map(Compressor::new);
+    if let Some(compressor) = &compressor {
+        compressor.prepare(response.headers_mut());
+    }
+    let unsized_body = !bodyless && !response.headers().contains_key(CONTENT_LENGTH);
+    let truncate_at = truncate.filter(|_| !bodyless).map(|share| {
+        let length = response.headers().get(CONTENT_LENGTH).and_then(|value| value.to_str().ok()?.parse::<u64>().ok());
+        (length.unwrap_or(TRUNCATE_UNSIZED) as f64 * share) as u64
+    });
+    // HTTP/1.0 clients don't understand chunked framing; delimit the body
+    // by closing the connection instead.
+    let http10 = version == Version::HTTP_10;
+    let chunked = unsized_body && !http10;
+    if unsized_body && http10 {
+        keep_alive = false;
+    }
+
+    let status_line = if http10 { "HTTP/1.0" } else { "HTTP/1.1" };
+    let mut head = format!("{} {}\r\n", status_line, status).into_bytes();
+    for (key, value) in response.headers() {
+        head.extend_from_slice(key.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    if chunked {
+        head.extend_from_slice(b"Transfer-Encoding: chunked\r\n");
+    }
+    if !keep_alive {
+        head.extend_from_slice(b"Connection: close\r\n");
+    } else if http10 {
+        head.extend_from_slice(b"Connection: keep-alive\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+    stream.write_all(&head).await?;
+
+    let mut written = 0;
+    while let Some(chunk) = response.body_mut().data().await {
+        let chunk = match &mut rewriter {
+            Some(rewriter) => rewriter.feed(&chunk?),
+            None => chunk?,
+        };
+        let chunk = match &mut compressor {
+            Some(compressor) => compressor.feed(&chunk),
+            None => chunk,
+        };
+        if let Some(limit) = max_size.filter(|limit| written + chunk.len() as u64 > *limit) {
+            return Err(format!("Error: Response body exceeds {} bytes", limit).into());
+        }
+        if let Some(cut) = truncate_at.filter(|cut| written + chunk.len() as u64 > *cut) {
+            write_body_chunk(stream, &chunk[..(cut - written) as usize], chunked).await?;
+            stream.flush().await?;
+            return Err(TRUNCATED.into());
+        }
+        // The compressor may hold the whole chunk back.
+        if let (Some(capture), false) = (&mut capture, chunk.is_empty()) {
+            capture.record(&chunk);
+        }
+        written += write_body_chunk(stream, &chunk, chunked).await?;
+    }
+    let last = rewriter.map(BodyRewriter::finish).unwrap_or_default();
+    let last = match compressor {
+        Some(compressor) => compressor.finish(&last),
+        None => last,
+    };
+    if let Some(cut) = truncate_at {
+        let end = last.len().min((cut.saturating_sub(written)) as usize);
+        write_body_chunk(stream, &last[..end], chunked).await?;
+        stream.flush().await?;
+        return Err(TRUNCATED.into());
+    }
+    if let (Some(capture), false) = (&mut capture, last.is_empty()) {
+        capture.record(&last);
+    }
+    written += write_body_chunk(stream, &last, chunked).await?;
+    if chunked {
+        stream.write_all(b"0\r\n\r\n").await?;
+    }
+    stream.flush().await?;
+    Ok((written, keep_alive))
+}
+
+async fn write_body_chunk<W: AsyncWrite + Unpin>(stream: &mut W, chunk: &[u8], chunked: bool) -> std::io::Result<u64> {
+    if chunk.is_empty() {
+        return Ok(0);
+    }
+    if chunked {
+        stream.write_all(format!("{:x}\r\n", chunk.len()).as_bytes()).await?;
+        stream.write_all(chunk).await?;
+        stream.write_all(b"\r\n").await?;
+    } else {
+        stream.write_all(chunk).await?;
+    }
+    Ok(chunk.len() as u64)
+}
+
+/*************************************************
+ * proxy_worker
+ *************************************************/
+
+async fn proxy_worker(
+    stream: TcpStream,
+    ctx: Arc<Context>,
+) -> Result<(), Box<dyn Error>> {
+    let peer_addr = stream.peer_addr()?;
+    info!("HTTP connection from: {}", ctx.describe_client(peer_addr).await);
+    let stream = match &ctx.tls_listener {
+        Some(listener) => {
+            let stream = tokio::time::timeout(ctx.config.head_limits.timeout, listener.accept(stream))
+                .await
+                .map_err(|_| format!("TLS handshake with {} timed out", peer_addr))?
+                .map_err(|e| format!("TLS handshake with {} failed: {}", peer_addr, e))?;
+            ClientStream::Tls(Box::new(stream))
+        }
+        None => ClientStream::Plain(stream),
+    };
+    let mut conn = ClientConn::new(stream);
+    loop {
+        let Some((mut head, head_len)) = next_request(&mut conn, &ctx).await? else {
+            return Ok(());
+        };
+
+        // DoH clients can't send proxy credentials either.
+        if ctx.config.doh && head.target.split('?').next() == Some(dnsproxy::DOH_PATH) {
+            if !dnsproxy::serve_doh(&mut conn, &head, &ctx, peer_addr).await? {
+                return Ok(());
+            }
+            continue;
+        }
+
+        // Browsers fetch the PAC file without proxy credentials.
+        if head.method == Method::GET && ctx.config.pac_path.as_ref().is_some_and(|path| *path == head.target) {
+            return serve_pac(&mut conn.stream, &ctx, &head).await;
+        }
+
+        // Direct requests for a path are what scanners send to a web port;
+        // don't reveal the proxy to them.
+        if let Some(decoy) = &ctx.config.decoy {
+            if head.method != Method::CONNECT && head.target.starts_with('/') {
+                return decoy.respond(&mut conn.stream).await;
+            }
+        }
+
+        let user = match check_auth(&ctx, &head.headers) {
+            Ok(user) => user,
+            Err(attempted) => {
+                if let (Some(webhooks), true) = (&ctx.webhooks, attempted) {
+                    webhooks.auth_failed(peer_addr.ip());
+                }
+                let response = "HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"Proxy\"\r\nConnection: close\r\n\r\n";
+                conn.stream.write_all(response.as_bytes()).await?;
+                return Ok(());
+            }
+        };
+
+        if let Err(detail) = check_loop(&ctx, &head.method, &mut head.headers) {
+            send_error(&mut conn.stream, StatusCode::LOOP_DETECTED, &detail).await;
+            return Err(detail.into());
+        }
+
+        let profile = ctx.clone();
+        let rules = profile.rules_for(&user, peer_addr.ip());
+        if head.method == Method::CONNECT {
+            let early_data = conn.take_buffered();
+            return handle_tunneling(conn.stream, &head.target, &head.headers, &early_data, ctx, user, rules).await;
+        }
+        if !handle_http_request(&mut conn, head, head_len, &ctx, &user, rules, peer_addr).await? {
+            return Ok(());
+        }
+    }
+}
+
+/// Read the next request head, answering a malformed one with an error;
+/// `None` once the client has closed the connection.
+async fn next_request<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut ClientConn<S>,
+    ctx: &Context,
+) -> Result<Option<(RequestHead, usize)>, Box<dyn Error>> {
+    // Only malformed requests get an error response; I/O errors mean the
+    // client is gone.
+    let head = conn.read_head(&ctx.config.head_limits).await.map_err(|e| {
+        let status = if e.is::<std::io::Error>() {
+            None
+        } else if let Some(e) = e.downcast_ref::<HeadError>() {
+            Some(e.status())
+        } else {
+            Some(StatusCode::BAD_REQUEST)
+        };
+        (status, e.to_string())
+    });
+    match head {
+        Ok(head) => Ok(head),
+        Err((None, detail)) => Err(detail.into()),
+        Err((Some(status), detail)) => {
+            send_error(&mut conn.stream, status, &detail).await;
+            Err(detail.into())
+        }
+    }
+}
+
+/*************************************************
+ * serve_pac
+ *************************************************/
+
+/// Answer with a PAC script pointing clients back at the address they
+/// fetched it from.
+async fn serve_pac(stream: &mut ClientStream, ctx: &Context, head: &RequestHead) -> Result<(), Box<dyn Error>> {
+    let host = match head.headers.get(hyper::header::HOST).and_then(|value| value.to_str().ok()) {
+        Some(host) if host.rsplit_once(':').is_some_and(|(_, port)| !port.ends_with(']')) => host.to_string(),
+        Some(host) => format!("{}:{}", host, ctx.config.port),
+        None => stream.local_addr()?.to_string(),
+    };
+    let script = ctx.rules_for(ANONYMOUS_USER, stream.peer_addr()?.ip()).pac(&host);
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/x-ns-proxy-autoconfig\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        script.len(),
+        script
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+/*************************************************
+ * check_auth
+ *************************************************/
+
+/// Validate `Proxy-Authorization` against the configured credentials.
+/// Returns the user to account the request to, or whether wrong
+/// credentials (as opposed to none at all) were supplied.
+fn check_auth(ctx: &Context, headers: &HeaderMap) -> Result<String, bool> {
+    let (Some(username), Some(password)) = (&ctx.username, &ctx.password) else {
+        return Ok(String::from(ANONYMOUS_USER));
+    };
+    let Some(value) = headers.get(PROXY_AUTHORIZATION) else {
+        return Err(false);
+    };
+
+    let credentials = value.to_str()
+        .ok()
+        .and_then(|value| value.trim().split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("basic"))
+        .and_then(|(_, encoded)| decode(encoded.trim()).ok());
+    if credentials.is_some_and(|credentials| same_secret(&credentials, format!("{}:{}", username, password).as_bytes())) {
+        Ok(username.clone())
+    } else {
+        Err(true)
+    }
+}
+
+/// Whether `given` matches the `expected` secret, compared in time that
+/// doesn't depend on where they differ.
+fn same_secret(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len() && given.iter().zip(expected).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/*************************************************
+ * init_logging
+ *************************************************/
+
+/// Log to `log_path` (`rdnat.log` when `None`); the path logged to.
+fn init_logging(log_path: Option<String>, log_level: Option<LevelFilter>) -> Result<String, Box<dyn Error>> {
+    let level = log_level
+        .or_else(|| std::env::var("RUST_LOG").ok().and_then(|level| level.parse().ok()))
+        .unwrap_or(if log_path.is_some() { LevelFilter::Debug } else { LevelFilter::Error });
+    let log_file_path = log_path.unwrap_or_else(|| String::from(DEFAULT_LOGPATH));
+    let file = std::fs::File::create(&log_file_path)?;
+
+    // The logger itself accepts everything; `log::max_level` is the knob
+    // that the control socket and SIGUSR1 turn at runtime.
+    env_logger::builder()
+        .target(env_logger::Target::Pipe(Box::new(file)))
+        .filter_level(LevelFilter::Trace)
+        .try_init()
+        .map_err(|_| "Error: Another logger is installed; drop the log file option to use it")?;
+    log::set_max_level(level);
+    Ok(log_file_path)
+}
+
+/*************************************************
+ * spawn_log_level_signal
+ *************************************************/
+
+/// Rotate through error -> warn -> info -> debug -> trace on each SIGUSR1.
+#[cfg(unix)]
+fn spawn_log_level_signal() -> Result<(), Box<dyn Error>> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut usr1 = signal(SignalKind::user_defined1())?;
+    tokio::spawn(async move {
+        while usr1.recv().await.is_some() {
+            let level = match log::max_level() {
+                LevelFilter::Off | LevelFilter::Error => LevelFilter::Warn,
+                LevelFilter::Warn => LevelFilter::Info,
+                LevelFilter::Info => LevelFilter::Debug,
+                LevelFilter::Debug => LevelFilter::Trace,
+                LevelFilter::Trace => LevelFilter::Error,
+            };
+            log::set_max_level(level);
+            log::warn!("Log level changed to {}", level);
+        }
+    });
+    Ok(())
+}
+
+/// There is no SIGUSR1 outside Unix; the level stays as configured.
+#[cfg(not(unix))]
+fn spawn_log_level_signal() -> Result<(), Box<dyn Error>> {
+    Ok(())
+}
+
+/*************************************************
+ * raise_nofile_limit
+ *************************************************/
+
+/// Raise the soft RLIMIT_NOFILE to `limit`, capped at the hard limit.
+/// Returns the limit in effect.
+#[cfg(unix)]
+fn raise_nofile_limit(limit: u64) -> Result<u64, Box<dyn Error>> {
+    let mut rlim = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) } != 0 {
+        return Err(format!("Error: getrlimit failed: {}", std::io::Error::last_os_error()).into());
+    }
+    let target = limit.min(rlim.rlim_max);
+    if target > rlim.rlim_cur {
+        rlim.rlim_cur = target;
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &rlim) } != 0 {
+            return Err(format!("Error: setrlimit failed: {}", std::io::Error::last_os_error()).into());
+        }
+    }
+    Ok(rlim.rlim_cur)
+}
+
+#[cfg(not(unix))]
+fn raise_nofile_limit(_limit: u64) -> Result<u64, Box<dyn Error>> {
+    Err("Error: --max-open-files is only supported on Unix platforms".into())
+}
+
+/*************************************************
+ * bind_listeners
+ *************************************************/
+
+/// The proxy listener, or with several acceptors one SO_REUSEPORT socket
+/// per accept loop, so the kernel spreads new connections over them.
+async fn bind_listeners(config: &Config) -> Result<Vec<TcpListener>, Box<dyn Error>> {
+    let addr: SocketAddr = format!("0.0.0.0:{}", config.port).parse().map_err(|_| format!("Error: Invalid port: {}", config.port))?;
+    let mut listeners = Vec::with_capacity(config.acceptors);
+    if config.acceptors == 1 {
+        listeners.push(TcpListener::bind(addr).await?);
+    } else {
+        #[cfg(unix)]
+        for _ in 0..config.acceptors {
+            let socket = tokio::net::TcpSocket::new_v4()?;
+            socket.set_reuseaddr(true)?;
+            socket.set_reuseport(true)?;
+            socket.bind(addr)?;
+            listeners.push(socket.listen(LISTEN_BACKLOG)?);
+        }
+    }
+    for listener in &listeners {
+        config.socket_options.apply_listener(listener).map_err(|e| format!("Error: Cannot set socket buffers: {}", e))?;
+        if let Some(queue) = config.fastopen_queue {
+            sockopt::set_fastopen(listener, queue).map_err(|e| format!("Error: Cannot enable TCP Fast Open: {}", e))?;
+        }
+    }
+    Ok(listeners)
+}
+
+/*************************************************
+ * AcceptBackoff
+ *************************************************/
+
+/// The wait after a failed accept, doubling while accepts keep failing.
+/// Accept errors are mostly transient (EMFILE, ECONNABORTED), so listeners
+/// back off and keep serving rather than spin or give up.
+struct AcceptBackoff(Duration);
+
+impl Default for AcceptBackoff {
+    fn default() -> Self {
+        AcceptBackoff(ACCEPT_BACKOFF_MIN)
+    }
+}
+
+impl AcceptBackoff {
+    async fn failed(&mut self, e: &std::io::Error) {
+        error!("Accept failed, retrying in {:?}: {}", self.0, e);
+        tokio::time::sleep(self.0).await;
+        self.0 = (self.0 * 2).min(ACCEPT_BACKOFF_MAX);
+    }
+
+    fn reset(&mut self) {
+        self.0 = ACCEPT_BACKOFF_MIN;
+    }
+}
+
+/*************************************************
+ * accept_loop
+ *************************************************/
+
+async fn accept_loop(listener: TcpListener, ctx: Arc<Context>) {
+    let mut backoff = AcceptBackoff::default();
+    loop {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        let accepted = match &ctx.uring {
+            Some(ring) => ring.accept(&listener).await,
+            None => listener.accept().await.map(|(stream, _)| stream),
+        };
+        #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+        let accepted = listener.accept().await.map(|(stream, _)| stream);
+        let stream = match accepted {
+            Ok(stream) => stream,
+            Err(e) => {
+                backoff.failed(&e).await;
+                continue;
+            }
+        };
+        backoff.reset();
+        if let Err(e) = ctx.config.socket_options.apply(&stream) {
+            warn!("Cannot set socket options on a client connection: {}", e);
+        }
+        let ctx = ctx.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = proxy_worker(stream, ctx).await {
+                error!("[x] error: {}", e);
+            }
+        });
+    }
+}
+
+/// The runtime sized by the runtime flags, tokio's defaults otherwise.
+fn build_runtime(config: &Config) -> Result<tokio::runtime::Runtime, Box<dyn Error>> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all().thread_name(config.thread_name.clone());
+    if let Some(threads) = config.worker_threads {
+        builder.worker_threads(threads);
+    }
+    if let Some(threads) = config.max_blocking_threads {
+        builder.max_blocking_threads(threads);
+    }
+    Ok(builder.build()?)
+}
+
+/*************************************************
+ * serve
+ *************************************************/
+
+/// Run the proxy until `shutdown` completes. `standalone` is for rdnat
+/// running as its own program: it announces where it listens and what it
+/// writes, always logs to a file and takes SIGUSR1. Embedded, it leaves
+/// stdout and signals to the host program, logs only when asked to and
+/// reports through the `log` facade.
+async fn serve(
+    config: Config,
+    standalone: bool,
+    shutdown: impl Future<Output = std::io::Result<()>>,
+) -> Result<(), Box<dyn Error>> {
+    let announce = |message: String| {
+        if standalone {
+            println!("{}", message);
+        }
+        info!("{}", message);
+    };
+    let listeners = bind_listeners(&config).await?;
+    if standalone {
+        match config.tls_cert {
+            Some(_) => println!("Proxy listening on port: {} (TLS)", config.port),
+            None => println!("Proxy listening on port: {}", config.port),
+        }
+        if !config.username.is_empty() {
+            println!("Username: {}", config.username);
+            println!("Password: {}", config.password);
+        }
+    }
+
+    if standalone || config.log_path.is_some() {
+        let log_file_path = init_logging(config.log_path.clone(), config.log_level)?;
+        if standalone {
+            println!("Log file created at: {}", log_file_path);
+        }
+    }
+    if standalone {
+        spawn_log_level_signal()?;
+    }
+    if config.fastopen_queue.is_some() && sockopt::fastopen_enabled(sockopt::FASTOPEN_SERVER) == Some(false) {
+        warn!("TCP Fast Open is off for listeners; set bit 2 of net.ipv4.tcp_fastopen");
+    }
+    if config.outbound.fastopen() && sockopt::fastopen_enabled(sockopt::FASTOPEN_CLIENT) == Some(false) {
+        warn!("TCP Fast Open is off for outgoing connections; set bit 1 of net.ipv4.tcp_fastopen");
+    }
+    if config.outbound.fastopen() && !cfg!(target_os = "linux") {
+        warn!("TCP Fast Open for outgoing connections is only supported on Linux; ignoring --tcp-fastopen-connect");
+    }
+    if let Some(filters) = &config.filters {
+        info!("Loaded {} filter list rules", filters.rule_count());
+    }
+    if let Some(replay) = &config.replay {
+        info!("Loaded recorded responses for {} requests", replay.len());
+    }
+    for list in &config.remote_lists {
+        list.clone().spawn_refresher(config.list_refresh);
+    }
+    for pool in config.upstream.iter().chain(config.upstreams.values()) {
+        pool.discover(config.connect_timeout).await?;
+        pool.spawn_discovery(config.discovery_refresh, config.connect_timeout);
+        pool.spawn_health_checks(&config.health_check, config.connect_timeout, &config.outbound);
+    }
+    if let Some(limit) = config.max_open_files {
+        let limit = raise_nofile_limit(limit)?;
+        info!("Open file limit set to {}", limit);
+    }
+
+    let accounting = config.usage_file.as_ref().map(|path| Arc::new(Accounting::new(path, config.usage_quota)));
+    if let Some(accounting) = &accounting {
+        accounting.clone().spawn_flusher(config.usage_flush_secs);
+    }
+
+    let breaker = config.breaker_failures.map(|failures| Breaker::new(failures, config.breaker_cooldown));
+
+    let geoip = if config.geoip_db.is_some() || config.geoip_asn_db.is_some() {
+        Some(GeoIp::open(config.geoip_db.as_deref(), config.geoip_asn_db.as_deref())?)
+    } else {
+        None
+    };
+
+    let key_log = match &config.tls_keylog {
+        Some(path) => {
+            announce(format!("TLS session keys are written to: {}", path));
+            Some(Arc::new(KeyLogFile::open(path)?))
+        }
+        None => None,
+    };
+    let mut interceptor = match (&config.mitm_ca, &config.mitm_key) {
+        (Some(cert), Some(key)) => Some(Interceptor::load(cert, key, config.tls_policy.clone())?),
+        _ => None,
+    };
+    if let (Some(interceptor), Some(key_log)) = (&mut interceptor, &key_log) {
+        interceptor.set_key_log(key_log.clone());
+    }
+    let tls_client = config.origin_tls.client_config(key_log.map(|key_log| key_log as Arc<dyn tokio_rustls::rustls::KeyLog>))?;
+    if config.origin_tls.is_insecure() {
+        let warning = "TLS certificates of origins are NOT verified (--insecure-skip-verify); connections can be intercepted";
+        if standalone {
+            println!("WARNING: {}", warning);
+        }
+        warn!("{}", warning);
+    }
+
+    let webhooks = if config.webhooks.is_empty() {
+        None
+    } else {
+        Some(Webhooks::new(
+            config.webhooks.clone(),
+            config.webhook_template.as_deref(),
+            config.webhook_auth_failures,
+            config.webhook_upstream_failures,
+            tls_client.clone(),
+        )?)
+    };
+
+    if !config.acme.domains.is_empty() {
+        config.acme.prepare()?;
+    }
+    let tls_listener = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(Arc::new(TlsListener::load(config.tls_policy.clone(), cert, key)?)),
+        _ => None,
+    };
+    if let (Some(listener), false) = (&tls_listener, config.tls_watch.is_zero()) {
+        listener.clone().spawn_watcher(config.tls_watch);
+    }
+    if let (Some(listener), false) = (&tls_listener, config.acme.domains.is_empty()) {
+        announce(format!("Certificates for {} are obtained from: {}", config.acme.domains.join(", "), config.acme.directory));
+        let acme = Arc::new(Acme::new(config.acme.clone(), tls_client.clone()));
+        acme.serve_http_challenges().await?;
+        acme.spawn_renewer(listener.clone());
+    }
+
+    let budget = MemoryBudget::new(config.memory_budget);
+    let disk_cache = match &config.cache_dir {
+        Some(dir) => Some(Arc::new(
+            DiskStore::open(dir, config.cache_disk_size, config.cache_disk_max_object)
+                .map_err(|e| format!("Error: Cannot open cache directory {}: {}", dir, e))?,
+        )),
+        None => None,
+    };
+    let cache = (config.cache_size.is_some() || disk_cache.is_some())
+        .then(|| Arc::new(ResponseCache::new(config.cache_size.unwrap_or(0), config.cache_max_object, budget.clone(), disk_cache)));
+    let har = match &config.har_path {
+        Some(path) => Some(HarWriter::create(path, config.har_max_body, config.har_max_file_size, &config.har_redact, budget.clone())?),
+        None => None,
+    };
+
+    let mut resolver = config.resolver.clone();
+    resolver.set_tls_config(tls_client.clone());
+    if config.dns_cache_size > 0 {
+        resolver.set_cache(Some(Arc::new(DnsCache::new(config.dns_cache_size, config.dns_negative_ttl, config.dns_max_ttl))));
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    let uring = if config.io_uring {
+        Some(uring::Ring::new().map_err(|e| format!("Error: Cannot set up io_uring: {}", e))?)
+    } else {
+        None
+    };
+    let guard = Arc::new(AddressGuard::new(config.allow_private, resolver));
+    let direct = DirectConnector(guard.clone(), config.outbound.clone(), config.connect_timeout);
+    let origin_client = origin_client(&config, &tls_client, direct);
+    let ctx = Arc::new(Context {
+        username: if config.username.is_empty() { None } else { Some(config.username.clone()) },
+        password: if config.password.is_empty() { None } else { Some(config.password.clone()) },
+        accounting,
+        stats: Stats::default(),
+        webhooks,
+        breaker,
+        geoip,
+        rdns: config.resolve_clients.then(|| ReverseDns::new(config.rdns_timeout_ms)),
+        guard,
+        interceptor,
+        har,
+        tls_client,
+        tls_listener,
+        via_name: format!("rdnat-{:08x}", RandomState::new().build_hasher().finish() as u32),
+        origin_client,
+        upstream_clients: Mutex::new(HashMap::new()),
+        buffers: BufferPool::new(config.relay_buffer_size.unwrap_or(bufpool::DEFAULT_BUFFER_SIZE), config.relay_buffer_pool),
+        flows: Flows::default(),
+        cache,
+        budget,
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        uring,
+        config,
+    });
+    let mut tasks = Vec::new();
+    #[cfg(unix)]
+    if let Some(path) = &ctx.config.ctl_socket {
+        tasks.push(ctl::serve(path, ctx.clone())?);
+    }
+    if let Some(addr) = ctx.config.dns_listen {
+        tasks.extend(dnsproxy::serve(addr, ctx.clone()).await?);
+    }
+
+    tasks.extend(listeners.into_iter().map(|listener| tokio::spawn(accept_loop(listener, ctx.clone()))));
+    let stopped = shutdown.await;
+    // Free the ports, and the context the tasks hold, even when the
+    // runtime outlives the proxy.
+    for task in tasks {
+        task.abort();
+    }
+    stopped?;
+
+    if let Some(path) = &ctx.config.ctl_socket {
+        let _ = std::fs::remove_file(path);
+    }
+    if let Some(accounting) = &ctx.accounting {
+        accounting.flush()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,74 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use std::error::Error;
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+pub const REDACTED: &str = "[redacted]";
+
+/*************************************************
+ * LogAction
+ *************************************************/
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LogAction {
+    Log,
+    Redact,
+    Suppress,
+}
+
+/*************************************************
+ * LogExclusions
+ *************************************************/
+
+/// Hosts whose access-log entries are dropped or redacted. A pattern
+/// matches the host itself and all of its subdomains.
+#[derive(Default)]
+pub struct LogExclusions {
+    rules: Vec<(String, LogAction)>,
+}
+
+impl LogExclusions {
+    pub fn add(&mut self, action: LogAction, pattern: &str) {
+        let pattern = pattern.trim_start_matches("*.").trim_start_matches('.');
+        self.rules.push((pattern.to_ascii_lowercase(), action));
+    }
+
+    /// Load `suppress <host>` / `redact <host>` lines; `#` starts a comment.
+    pub fn load(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        let content = std::fs::read_to_string(path)?;
+        for (lineno, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+                ["suppress", pattern] => self.add(LogAction::Suppress, pattern),
+                ["redact", pattern] => self.add(LogAction::Redact, pattern),
+                _ => return Err(format!("Error: Invalid log exclusion at {}:{}: {}", path, lineno + 1, line).into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// The strictest action of all patterns matching `host`.
+    pub fn action(&self, host: &str) -> LogAction {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let mut action = LogAction::Log;
+        for (pattern, rule_action) in &self.rules {
+            let matches = host == *pattern
+                || host.strip_suffix(pattern.as_str()).is_some_and(|prefix| prefix.ends_with('.'));
+            if matches && *rule_action == LogAction::Suppress {
+                return LogAction::Suppress;
+            }
+            if matches {
+                action = LogAction::Redact;
+            }
+        }
+        action
+    }
+}
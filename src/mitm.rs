@@ -0,0 +1,264 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use rcgen::{Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, KeyPair, SanType};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::rustls::{self, KeyLog, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use time::OffsetDateTime;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use crate::tls::ListenerPolicy;
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+/// Leaf certificates stay under the 398 days clients accept.
+const LEAF_VALIDITY_DAYS: i64 = 365;
+/// Leaf configurations kept; past this the least recently used goes.
+const MAX_CACHED_LEAVES: usize = 1024;
+
+/*************************************************
+ * Interceptor
+ *************************************************/
+
+/// Terminates intercepted tunnels with per-host leaf certificates signed
+/// by a local CA, which clients must trust.
+pub struct Interceptor {
+    signer: Arc<Signer>,
+    leaves: Mutex<Leaves<Arc<ServerConfig>>>,
+    key_log: Option<Arc<KeyLogFile>>,
+}
+
+/// What leaf certificates are made with, shared with the blocking tasks
+/// that make them.
+struct Signer {
+    ca: Certificate,
+    ca_der: Vec<u8>,
+    policy: ListenerPolicy,
+}
+
+impl Interceptor {
+    /// Load the CA certificate and private key (PEM). Clients are offered
+    /// the versions and cipher suites of `policy`.
+    pub fn load(cert_path: &str, key_path: &str, policy: ListenerPolicy) -> Result<Self, Box<dyn Error>> {
+        let read = |path: &str| {
+            std::fs::read_to_string(path).map_err(|e| format!("Error: Cannot read {}: {}", path, e))
+        };
+        let (cert_pem, key_pem) = (read(cert_path)?, read(key_path)?);
+        let ca_der = rustls_pemfile::certs(&mut cert_pem.as_bytes())?
+            .into_iter()
+            .next()
+            .ok_or_else(|| format!("Error: No certificate in {}", cert_path))?;
+        let key = KeyPair::from_pem(&key_pem)
+            .map_err(|e| format!("Error: Invalid CA key {}: {}", key_path, e))?;
+        let params = CertificateParams::from_ca_cert_der(&ca_der, key)
+            .map_err(|e| format!("Error: Invalid CA certificate {}: {}", cert_path, e))?;
+        Ok(Interceptor {
+            signer: Arc::new(Signer { ca: Certificate::from_params(params)?, ca_der, policy }),
+            leaves: Mutex::new(Leaves::new(MAX_CACHED_LEAVES)),
+            key_log: None,
+        })
+    }
+
+    /// Log the secrets of the sessions with clients to `key_log`.
+    pub fn set_key_log(&mut self, key_log: Arc<KeyLogFile>) {
+        self.key_log = Some(key_log);
+    }
+
+    /// TLS acceptor presenting a certificate for `host`. A new leaf is
+    /// made on a blocking thread, without holding up the handshakes of
+    /// hosts already cached.
+    pub async fn acceptor(&self, host: &str) -> Result<TlsAcceptor, Box<dyn Error>> {
+        if let Some(config) = self.leaves.lock().unwrap().get(host) {
+            return Ok(TlsAcceptor::from(config));
+        }
+        let (signer, name) = (self.signer.clone(), host.to_string());
+        let mut config = tokio::task::spawn_blocking(move || signer.leaf_config(&name).map_err(|e| e.to_string())).await??;
+        if let Some(key_log) = &self.key_log {
+            config.key_log = key_log.clone();
+        }
+        let config = Arc::new(config);
+        self.leaves.lock().unwrap().insert(host, config.clone());
+        Ok(TlsAcceptor::from(config))
+    }
+}
+
+impl Signer {
+    fn leaf_config(&self, host: &str) -> Result<ServerConfig, Box<dyn Error>> {
+        let mut params = CertificateParams::new(vec![host.to_string()]);
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            params.subject_alt_names = vec![SanType::IpAddress(ip)];
+        }
+        params.distinguished_name.push(DnType::CommonName, host);
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        let now = OffsetDateTime::now_utc();
+        params.not_before = now - time::Duration::days(1);
+        params.not_after = now + time::Duration::days(LEAF_VALIDITY_DAYS);
+        let leaf = Certificate::from_params(params)?;
+        let chain = vec![
+            rustls::Certificate(leaf.serialize_der_with_signer(&self.ca)?),
+            rustls::Certificate(self.ca_der.clone()),
+        ];
+        let mut config = self.policy.builder()?
+            .with_single_cert(chain, rustls::PrivateKey(leaf.serialize_private_key_der()))?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(config)
+    }
+}
+
+/*************************************************
+ * Leaves
+ *************************************************/
+
+/// Cached leaf configurations by host, with the order they were last
+/// used in.
+struct Leaves<C> {
+    configs: HashMap<String, (C, u64)>,
+    /// Hosts by last use, oldest first.
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    capacity: usize,
+}
+
+impl<C: Clone> Leaves<C> {
+    fn new(capacity: usize) -> Self {
+        Leaves { configs: HashMap::new(), recency: BTreeMap::new(), tick: 0, capacity }
+    }
+
+    /// The configuration for `host`; counts as a use.
+    fn get(&mut self, host: &str) -> Option<C> {
+        let (config, last_used) = self.configs.get_mut(host)?;
+        self.recency.remove(last_used);
+        self.tick += 1;
+        *last_used = self.tick;
+        self.recency.insert(self.tick, host.to_string());
+        Some(config.clone())
+    }
+
+    /// Add the configuration for `host` as the most recently used,
+    /// dropping the least recently used if the cache is full.
+    fn insert(&mut self, host: &str, config: C) {
+        if let Some((_, last_used)) = self.configs.remove(host) {
+            self.recency.remove(&last_used);
+        }
+        while self.configs.len() >= self.capacity {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            self.configs.remove(&oldest);
+        }
+        self.tick += 1;
+        self.configs.insert(host.to_string(), (config, self.tick));
+        self.recency.insert(self.tick, host.to_string());
+    }
+}
+
+/*************************************************
+ * KeyLogFile
+ *************************************************/
+
+/// Appends TLS session secrets in the NSS key log format, which Wireshark
+/// uses to decrypt captured traffic. Whoever can read the file can decrypt
+/// the sessions, so it is created readable by its owner only.
+#[derive(Debug)]
+pub struct KeyLogFile {
+    file: Mutex<File>,
+}
+
+impl KeyLogFile {
+    pub fn open(path: &str) -> Result<Self, Box<dyn Error>> {
+        let mut options = OpenOptions::new();
+        options.append(true).create(true);
+        // Elsewhere the file gets the directory's default permissions.
+        #[cfg(unix)]
+        options.mode(0o600);
+        let file = options.open(path).map_err(|e| format!("Error: Cannot open key log {}: {}", path, e))?;
+        Ok(KeyLogFile { file: Mutex::new(file) })
+    }
+}
+
+impl KeyLog for KeyLogFile {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        let line = format!("{} {} {}\n", label, hex(client_random), hex(secret));
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            log::error!("Cannot write key log: {}", e);
+        }
+    }
+}
+
+/*************************************************
+ * Rewound
+ *************************************************/
+
+/// A stream that first replays bytes already read from it, such as a TLS
+/// ClientHello pipelined behind the CONNECT head.
+pub struct Rewound<S> {
+    prefix: Vec<u8>,
+    inner: S,
+}
+
+impl<S> Rewound<S> {
+    pub fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Rewound { prefix, inner }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewound<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if !self.prefix.is_empty() {
+            let n = self.prefix.len().min(buf.remaining());
+            buf.put_slice(&self.prefix[..n]);
+            self.prefix.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewound<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_evict_the_least_recently_used_one_at_a_time() {
+        let mut leaves = Leaves::new(3);
+        for host in ["a.example", "b.example", "c.example"] {
+            leaves.insert(host, host.len());
+        }
+        assert!(leaves.get("a.example").is_some());
+        leaves.insert("d.example", 9);
+        assert!(leaves.get("b.example").is_none());
+        for host in ["a.example", "c.example", "d.example"] {
+            assert!(leaves.get(host).is_some(), "{}", host);
+        }
+        leaves.insert("d.example", 10);
+        assert_eq!(leaves.get("d.example"), Some(10));
+        assert_eq!((leaves.configs.len(), leaves.recency.len()), (3, 3));
+    }
+}
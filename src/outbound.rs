@@ -0,0 +1,300 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use hyper::service::Service;
+use hyper::Uri;
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::task::JoinSet;
+use std::collections::hash_map::RandomState;
+use std::error::Error;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+#[cfg(unix)]
+use std::os::fd::AsRawFd;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+#[cfg(unix)]
+use crate::sockopt::set_option;
+use crate::sockopt::SocketOptions;
+use crate::ssrf::AddressGuard;
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+/// How long a connection attempt gets before the next address is tried
+/// alongside it (RFC 8305's Connection Attempt Delay).
+pub const DEFAULT_ATTEMPT_DELAY_MS: u64 = 250;
+
+/*************************************************
+ * Rotation
+ *************************************************/
+
+/// How each connection picks its source address when there are several.
+#[derive(Clone, Copy, Default)]
+pub enum Rotation {
+    #[default]
+    RoundRobin,
+    Random,
+}
+
+impl Rotation {
+    pub fn parse(name: &str) -> Result<Self, Box<dyn Error>> {
+        match name {
+            "round-robin" => Ok(Rotation::RoundRobin),
+            "random" => Ok(Rotation::Random),
+            name => Err(format!("Error: Unknown source rotation: {} (expected round-robin or random)", name).into()),
+        }
+    }
+}
+
+/*************************************************
+ * Outbound
+ *************************************************/
+
+/// How connections to targets and upstream proxies are made.
+#[derive(Clone, Default)]
+pub struct Outbound {
+    /// Local addresses connections are made from, one per connection;
+    /// only destinations of their families are reachable then.
+    binds: Vec<IpAddr>,
+    rotation: Rotation,
+    next: Arc<AtomicUsize>,
+    /// Firewall mark (SO_MARK) for policy routing.
+    mark: Option<u32>,
+    /// DSCP code point for the IP header, 0 to 63.
+    dscp: Option<u8>,
+    /// Head start of each connection attempt over the next; zero tries
+    /// the addresses one after another. `None` for the default.
+    attempt_delay: Option<Duration>,
+    options: SocketOptions,
+    /// Send the first data in the SYN to destinations we hold a Fast Open
+    /// cookie for. Linux only; ignored elsewhere.
+    fastopen: bool,
+}
+
+impl Outbound {
+    pub fn set_binds(&mut self, binds: Vec<IpAddr>) {
+        self.binds = binds;
+    }
+
+    pub fn set_rotation(&mut self, rotation: Rotation) {
+        self.rotation = rotation;
+    }
+
+    pub fn set_mark(&mut self, mark: Option<u32>) {
+        self.mark = mark;
+    }
+
+    pub fn set_dscp(&mut self, dscp: Option<u8>) {
+        self.dscp = dscp;
+    }
+
+    pub fn set_attempt_delay(&mut self, delay: Duration) {
+        self.attempt_delay = Some(delay);
+    }
+
+    pub fn set_socket_options(&mut self, options: SocketOptions) {
+        self.options = options;
+    }
+
+    pub fn set_fastopen(&mut self, fastopen: bool) {
+        self.fastopen = fastopen;
+    }
+
+    pub fn fastopen(&self) -> bool {
+        self.fastopen
+    }
+
+    pub fn binds(&self) -> &[IpAddr] {
+        &self.binds
+    }
+
+    /// The source addresses for the next connection, one per family.
+    pub fn local_addresses(&self) -> (Option<Ipv4Addr>, Option<Ipv6Addr>) {
+        let v4 = match self.pick(true) {
+            Some(IpAddr::V4(ip)) => Some(ip),
+            _ => None,
+        };
+        let v6 = match self.pick(false) {
+            Some(IpAddr::V6(ip)) => Some(ip),
+            _ => None,
+        };
+        (v4, v6)
+    }
+
+    fn pick(&self, ipv4: bool) -> Option<IpAddr> {
+        let candidates: Vec<&IpAddr> = self.binds.iter().filter(|bind| bind.is_ipv4() == ipv4).collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        let turn = match self.rotation {
+            Rotation::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed),
+            Rotation::Random => RandomState::new().build_hasher().finish() as usize,
+        };
+        Some(*candidates[turn % candidates.len()])
+    }
+
+    /// The addresses of `addrs` that can be reached from the bind addresses.
+    pub fn usable(&self, addrs: Vec<SocketAddr>) -> io::Result<Vec<SocketAddr>> {
+        if self.binds.is_empty() {
+            return Ok(addrs);
+        }
+        let usable: Vec<SocketAddr> = addrs.into_iter()
+            .filter(|addr| self.binds.iter().any(|bind| bind.is_ipv4() == addr.is_ipv4()))
+            .collect();
+        if usable.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "no address of the outbound address family to connect to"));
+        }
+        Ok(usable)
+    }
+
+    /// Connect to the first reachable address of `addrs`, Happy Eyeballs
+    /// style (RFC 8305): IPv6 and IPv4 addresses take turns, and each
+    /// attempt gets a head start of the attempt delay (or until it fails)
+    /// before the next one is raced against it. The first to connect wins.
+    pub async fn connect(&self, addrs: &[SocketAddr]) -> io::Result<TcpStream> {
+        let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to");
+        let (v4, v6) = self.local_addresses();
+        let delay = self.attempt_delay.unwrap_or(Duration::from_millis(DEFAULT_ATTEMPT_DELAY_MS));
+        let mut pending = interleave(self.usable(addrs.to_vec())?).into_iter();
+        // Dropping the set aborts the attempts still running.
+        let mut attempts = JoinSet::new();
+        loop {
+            if let Some(addr) = pending.next() {
+                let bind = if addr.is_ipv4() { v4.map(IpAddr::V4) } else { v6.map(IpAddr::V6) };
+                let outbound = self.clone();
+                attempts.spawn(async move { outbound.connect_from(bind, addr).await });
+            }
+            let more = pending.len() > 0;
+            loop {
+                let stagger = async {
+                    match (more, delay.is_zero()) {
+                        (true, false) => tokio::time::sleep(delay).await,
+                        _ => std::future::pending().await,
+                    }
+                };
+                tokio::select! {
+                    joined = attempts.join_next() => match joined {
+                        Some(Ok(Ok(stream))) => return Ok(stream),
+                        Some(Ok(Err(e))) => last_error = e,
+                        Some(Err(e)) => last_error = io::Error::other(e),
+                        None if !more => return Err(last_error),
+                        None => {}
+                    },
+                    _ = stagger => {}
+                }
+                // A failure or the end of the head start lets the next
+                // address go; with none left, wait for the others.
+                if more {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Resolve `address` (`host:port`) and connect to it.
+    pub async fn connect_host(&self, address: &str) -> io::Result<TcpStream> {
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host(address).await?.collect();
+        self.connect(&addrs).await
+    }
+
+    /// A socket with the mark, DSCP, socket options and Fast Open applied.
+    /// Setting a mark takes CAP_NET_ADMIN, so this is also tried once at
+    /// startup.
+    pub fn socket(&self, ipv4: bool) -> io::Result<TcpSocket> {
+        let socket = if ipv4 { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+        if let Some(mark) = self.mark {
+            #[cfg(target_os = "linux")]
+            set_option(socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_MARK, mark as libc::c_int)?;
+            #[cfg(not(target_os = "linux"))]
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("firewall mark {} (SO_MARK) is not supported on this platform", mark)));
+        }
+        if let Some(dscp) = self.dscp {
+            // DSCP is the top six bits of the TOS / traffic class byte.
+            #[cfg(unix)]
+            {
+                let (level, name) = if ipv4 { (libc::IPPROTO_IP, libc::IP_TOS) } else { (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) };
+                set_option(socket.as_raw_fd(), level, name, (dscp as libc::c_int) << 2)?;
+            }
+            #[cfg(not(unix))]
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("DSCP marking ({}) is not supported on this platform", dscp)));
+        }
+        self.options.apply(&socket)?;
+        #[cfg(target_os = "linux")]
+        if self.fastopen {
+            // With a cached cookie connect() returns at once and the SYN
+            // leaves with the first write.
+            set_option(socket.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_FASTOPEN_CONNECT, 1)?;
+        }
+        Ok(socket)
+    }
+
+    async fn connect_from(&self, bind: Option<IpAddr>, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = self.socket(addr.is_ipv4())?;
+        if let Some(bind) = bind {
+            socket.bind(SocketAddr::new(bind, 0))?;
+        }
+        socket.connect(addr).await
+    }
+}
+
+/// `addrs` with IPv6 and IPv4 addresses alternating, IPv6 first, each
+/// family in the order given.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    let mut interleaved = Vec::with_capacity(v6.len() + v4.len());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return interleaved,
+            (first, second) => interleaved.extend(first.into_iter().chain(second)),
+        }
+    }
+}
+
+/*************************************************
+ * DirectConnector
+ *************************************************/
+
+/// hyper connector for requests that go straight to the origin: resolves
+/// through the `AddressGuard` and connects with the outbound settings,
+/// within the timeout.
+#[derive(Clone)]
+pub struct DirectConnector(pub Arc<AddressGuard>, pub Outbound, pub Duration);
+
+type ConnectFuture = Pin<Box<dyn Future<Output = Result<TcpStream, Box<dyn Error + Send + Sync>>> + Send>>;
+
+impl Service<Uri> for DirectConnector {
+    type Response = TcpStream;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = ConnectFuture;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let (guard, outbound, timeout) = (self.0.clone(), self.1.clone(), self.2);
+        Box::pin(async move {
+            let host = uri.host().ok_or("URI without a host")?;
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            let port = uri.port_u16().unwrap_or(if uri.scheme_str() == Some("https") { 443 } else { 80 });
+            let connect = async {
+                let addrs = guard.resolve(host, port).await?;
+                let stream = outbound.connect(&addrs).await?;
+                stream.set_nodelay(true)?;
+                Ok::<_, Box<dyn Error + Send + Sync>>(stream)
+            };
+            tokio::time::timeout(timeout, connect)
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+        })
+    }
+}
@@ -0,0 +1,127 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+use std::error::Error;
+use std::io::ErrorKind;
+use std::time::{Duration, Instant};
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+/// Largest response head read through the proxy.
+const MAX_HEAD: usize = 64 * 1024;
+const READ_BUFFER: usize = 64 * 1024;
+
+/*************************************************
+ * Response
+ *************************************************/
+
+// What `bench`, `check` and `speedtest` share: they send a request by hand
+// and read back its response, timing it as it arrives.
+
+/// Read a response head; its status code and whatever followed it.
+pub async fn read_head<S: AsyncRead + Unpin>(stream: &mut S) -> Result<(u16, Vec<u8>), Box<dyn Error + Send + Sync>> {
+    let (head, rest) = read_head_text(stream).await?;
+    Ok((status(&head).ok_or("Error: Invalid response head")?, rest))
+}
+
+/// Read a response head; its text and whatever followed it.
+pub async fn read_head_text<S: AsyncRead + Unpin>(stream: &mut S) -> Result<(String, Vec<u8>), Box<dyn Error + Send + Sync>> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 4096];
+    let end = loop {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err("Error: Connection closed before the response head".into());
+        }
+        head.extend_from_slice(&buf[..n]);
+        if let Some(end) = head.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end + 4;
+        }
+        if head.len() > MAX_HEAD {
+            return Err("Error: Response head too large".into());
+        }
+    };
+    let rest = head.split_off(end);
+    let head = String::from_utf8(head).map_err(|_| "Error: Invalid response head")?;
+    Ok((head, rest))
+}
+
+/// The status code of a response head.
+pub fn status(head: &str) -> Option<u16> {
+    head.split(' ').nth(1)?.parse().ok()
+}
+
+/// Read the rest of a body, `read` bytes of which came with the head, and
+/// the total read. The requests all ask to close the connection, so the
+/// body ends at EOF; reading also stops once `limit` bytes are in or at
+/// `deadline`, whichever comes first.
+pub async fn read_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    mut read: u64,
+    limit: u64,
+    deadline: Option<Instant>,
+) -> std::io::Result<u64> {
+    let mut buf = vec![0u8; READ_BUFFER];
+    while read < limit {
+        let chunk = match deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                match tokio::time::timeout(left, stream.read(&mut buf)).await {
+                    Ok(chunk) => chunk,
+                    Err(_) => break,
+                }
+            }
+            None => stream.read(&mut buf).await,
+        };
+        match chunk {
+            Ok(0) => break,
+            // Many servers close TLS without a close_notify.
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Ok(n) => read += n as u64,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+/*************************************************
+ * Timing
+ *************************************************/
+
+pub fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_the_head_and_what_followed_it() {
+        let mut response = &b"HTTP/1.1 204 No Content\r\nServer: x\r\n\r\nbody"[..];
+        let (status, rest) = read_head(&mut response).await.unwrap();
+        assert_eq!(status, 204);
+        assert_eq!(rest, b"body");
+        let mut truncated = &b"HTTP/1.1 200 OK\r\n"[..];
+        assert!(read_head(&mut truncated).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_the_body_until_eof_or_the_limit() {
+        let body = vec![b'x'; 3 * READ_BUFFER];
+        assert_eq!(read_body(&mut &body[..], 10, u64::MAX, None).await.unwrap(), 10 + body.len() as u64);
+        assert_eq!(read_body(&mut &body[..], 0, 1, None).await.unwrap(), READ_BUFFER as u64);
+        assert_eq!(read_body(&mut &body[..], 5, 5, None).await.unwrap(), 5);
+    }
+
+    #[tokio::test]
+    async fn stops_reading_the_body_at_the_deadline() {
+        let (mut client, _server) = tokio::io::duplex(64);
+        let deadline = Instant::now() + Duration::from_millis(20);
+        assert_eq!(read_body(&mut client, 3, u64::MAX, Some(deadline)).await.unwrap(), 3);
+    }
+}
@@ -0,0 +1,108 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::IpAddr;
+use crate::rules::{Rules, Timezone};
+
+/*************************************************
+ * Subnet
+ *************************************************/
+
+/// An address block such as `10.1.0.0/16` or `fd00::/8`; a bare address
+/// is a block of one.
+pub struct Subnet {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl Subnet {
+    pub fn parse(value: &str) -> Result<Self, Box<dyn Error>> {
+        let (address, prefix) = value.split_once('/').unwrap_or((value, ""));
+        let network: IpAddr = address.parse()
+            .map_err(|_| format!("Error: Invalid subnet: {}", value))?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = if prefix.is_empty() { bits } else { prefix.parse()? };
+        if prefix > bits {
+            return Err(format!("Error: Invalid subnet: {}", value).into());
+        }
+        Ok(Subnet { network, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/*************************************************
+ * Profiles
+ *************************************************/
+
+/// Named rule sets assigned to users or client subnets. A user assignment
+/// wins over a subnet; among subnets the first assigned wins. Clients
+/// without a profile use the global rules.
+#[derive(Default)]
+pub struct Profiles {
+    rules: HashMap<String, Rules>,
+    users: HashMap<String, String>,
+    subnets: Vec<(Subnet, String)>,
+}
+
+impl Profiles {
+    pub fn load(&mut self, name: &str, path: &str) -> Result<(), Box<dyn Error>> {
+        let mut rules = Rules::default();
+        rules.load(path)?;
+        self.rules.insert(name.to_string(), rules);
+        Ok(())
+    }
+
+    /// Assign `name` to a subnet (anything that parses as an address) or
+    /// to a user.
+    pub fn assign(&mut self, client: &str, name: &str) -> Result<(), Box<dyn Error>> {
+        if client.contains('/') || client.parse::<IpAddr>().is_ok() {
+            self.subnets.push((Subnet::parse(client)?, name.to_string()));
+        } else {
+            self.users.insert(client.to_string(), name.to_string());
+        }
+        Ok(())
+    }
+
+    /// Check that every assignment names a loaded profile and apply the
+    /// schedule timezone.
+    pub fn finish(&mut self, timezone: Timezone) -> Result<(), Box<dyn Error>> {
+        let mut assigned = self.users.values().chain(self.subnets.iter().map(|(_, name)| name));
+        if let Some(name) = assigned.find(|name| !self.rules.contains_key(*name)) {
+            return Err(format!("Error: Unknown profile: {}", name).into());
+        }
+        for rules in self.rules.values_mut() {
+            rules.set_timezone(timezone);
+        }
+        Ok(())
+    }
+
+    pub fn select(&self, user: &str, ip: IpAddr) -> Option<&Rules> {
+        let name = self.users.get(user)
+            .or_else(|| self.subnets.iter().find(|(subnet, _)| subnet.contains(ip)).map(|(_, name)| name))?;
+        self.rules.get(name)
+    }
+
+    pub fn all(&self) -> impl Iterator<Item = &Rules> {
+        self.rules.values()
+    }
+}
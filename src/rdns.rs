@@ -0,0 +1,117 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use std::collections::HashMap;
+#[cfg(unix)]
+use std::ffi::CStr;
+use std::net::IpAddr;
+#[cfg(unix)]
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+pub const DEFAULT_RDNS_TIMEOUT_MS: u64 = 500;
+
+const CACHE_TTL: Duration = Duration::from_secs(3600);
+const CACHE_CAPACITY: usize = 4096;
+
+/*************************************************
+ * ReverseDns
+ *************************************************/
+
+/// Cached PTR lookups for client addresses. Failures and timeouts are
+/// cached as well so an unresolvable client costs one lookup per TTL.
+pub struct ReverseDns {
+    timeout: Duration,
+    cache: Mutex<HashMap<IpAddr, (Option<String>, Instant)>>,
+}
+
+impl ReverseDns {
+    pub fn new(timeout_ms: u64) -> Self {
+        ReverseDns {
+            timeout: Duration::from_millis(timeout_ms),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn lookup(&self, ip: IpAddr) -> Option<String> {
+        if let Some((name, resolved_at)) = self.cache.lock().unwrap().get(&ip) {
+            if resolved_at.elapsed() < CACHE_TTL {
+                return name.clone();
+            }
+        }
+
+        let name = tokio::time::timeout(self.timeout, tokio::task::spawn_blocking(move || getnameinfo(ip)))
+            .await
+            .ok()
+            .and_then(Result::ok)
+            .flatten();
+
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= CACHE_CAPACITY {
+            cache.retain(|_, (_, resolved_at)| resolved_at.elapsed() < CACHE_TTL);
+            if cache.len() >= CACHE_CAPACITY {
+                cache.clear();
+            }
+        }
+        cache.insert(ip, (name.clone(), Instant::now()));
+        name
+    }
+}
+
+/*************************************************
+ * getnameinfo
+ *************************************************/
+
+#[cfg(unix)]
+fn getnameinfo(ip: IpAddr) -> Option<String> {
+    let addr = to_sockaddr(SocketAddr::new(ip, 0));
+    let mut host = [0 as libc::c_char; libc::NI_MAXHOST as usize];
+    let ret = unsafe {
+        libc::getnameinfo(
+            &addr.0 as *const libc::sockaddr_storage as *const libc::sockaddr,
+            addr.1,
+            host.as_mut_ptr(),
+            host.len() as libc::socklen_t,
+            std::ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        )
+    };
+    if ret != 0 {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(host.as_ptr()) };
+    name.to_str().ok().map(String::from)
+}
+
+/// Clients are shown by address alone where there is no getnameinfo.
+#[cfg(not(unix))]
+fn getnameinfo(_ip: IpAddr) -> Option<String> {
+    None
+}
+
+#[cfg(unix)]
+fn to_sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+            std::mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_addr.s6_addr = addr.ip().octets();
+            std::mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
@@ -0,0 +1,243 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use std::future::poll_fn;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use crate::bufpool::PooledBuffer;
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+/// Capacity of a Linux pipe unless resized, what a spliced tunnel moves
+/// per transfer.
+pub const DEFAULT_PIPE_SIZE: usize = 65536;
+
+/*************************************************
+ * relay
+ *************************************************/
+
+/// Copy `a` to `b` and `b` to `a` until both directions are done, in the
+/// calling task: one future polls both directions, so a tunnel costs one
+/// task and one waker rather than a task and a join of two copies.
+/// Returns how each direction ended.
+pub async fn relay<A, B>(a: &mut A, b: &mut B, mut a_to_b: Half<'_>, mut b_to_a: Half<'_>) -> (io::Result<()>, io::Result<()>)
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let (mut forward, mut backward) = (None, None);
+    poll_fn(|cx| {
+        if forward.is_none() {
+            if let Poll::Ready(result) = a_to_b.poll_copy(cx, a, b) {
+                forward = Some(result);
+            }
+        }
+        if backward.is_none() {
+            if let Poll::Ready(result) = b_to_a.poll_copy(cx, b, a) {
+                backward = Some(result);
+            }
+        }
+        match (&forward, &backward) {
+            (Some(_), Some(_)) => Poll::Ready(()),
+            _ => Poll::Pending,
+        }
+    })
+    .await;
+    (forward.unwrap(), backward.unwrap())
+}
+
+/*************************************************
+ * Half
+ *************************************************/
+
+/// One direction of a relay. The EOF is passed on with a write shutdown,
+/// so the peer sees the half-close while the other direction keeps
+/// flowing. Nothing more is read until the buffer is written out, so a
+/// slow receiver holds the sender back by at most one buffer.
+pub struct Half<'a> {
+    buf: PooledBuffer<'a>,
+    pos: usize,
+    cap: usize,
+    eof: bool,
+    /// Whether something was written since the writer was last flushed.
+    unflushed: bool,
+    /// Whether the writer has already held up the current buffer.
+    stalled: bool,
+    copied: &'a AtomicU64,
+    activity: &'a Activity,
+    flow: &'a Flow,
+}
+
+impl<'a> Half<'a> {
+    /// A direction relaying through `buf`, adding what it copies to
+    /// `copied` and what it holds to `flow`.
+    pub fn new(buf: PooledBuffer<'a>, copied: &'a AtomicU64, activity: &'a Activity, flow: &'a Flow) -> Self {
+        Half { buf, pos: 0, cap: 0, eof: false, unflushed: false, stalled: false, copied, activity, flow }
+    }
+
+    fn poll_copy<R, W>(&mut self, cx: &mut Context<'_>, reader: &mut R, writer: &mut W) -> Poll<io::Result<()>>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        loop {
+            if self.pos == self.cap && !self.eof {
+                let mut read_buf = ReadBuf::new(&mut self.buf);
+                match Pin::new(&mut *reader).poll_read(cx, &mut read_buf) {
+                    Poll::Ready(result) => result?,
+                    Poll::Pending => {
+                        // Nothing more to send for now: push out what a
+                        // buffering writer (TLS) still holds.
+                        if self.unflushed {
+                            ready!(Pin::new(&mut *writer).poll_flush(cx))?;
+                            self.unflushed = false;
+                        }
+                        return Poll::Pending;
+                    }
+                }
+                let n = read_buf.filled().len();
+                if n == 0 {
+                    self.eof = true;
+                } else {
+                    self.pos = 0;
+                    self.cap = n;
+                    self.stalled = false;
+                    self.flow.in_flight.fetch_add(n as u64, Ordering::Relaxed);
+                }
+            }
+            while self.pos < self.cap {
+                let n = match Pin::new(&mut *writer).poll_write(cx, &self.buf[self.pos..self.cap]) {
+                    Poll::Ready(result) => result?,
+                    Poll::Pending => {
+                        if !self.stalled {
+                            self.stalled = true;
+                            self.flow.stalls.fetch_add(1, Ordering::Relaxed);
+                        }
+                        return Poll::Pending;
+                    }
+                };
+                if n == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                }
+                self.pos += n;
+                self.unflushed = true;
+                self.flow.in_flight.fetch_sub(n as u64, Ordering::Relaxed);
+                if self.pos == self.cap {
+                    self.copied.fetch_add(self.cap as u64, Ordering::Relaxed);
+                    self.activity.touch();
+                }
+            }
+            if self.eof {
+                return Pin::new(&mut *writer).poll_shutdown(cx);
+            }
+        }
+    }
+}
+
+impl Drop for Half<'_> {
+    fn drop(&mut self) {
+        // A tunnel closed midway leaves its unwritten bytes behind.
+        self.flow.in_flight.fetch_sub((self.cap - self.pos) as u64, Ordering::Relaxed);
+    }
+}
+
+/*************************************************
+ * Flow
+ *************************************************/
+
+/// Backpressure across all tunnels in one direction: the bytes read from
+/// the sender that the receiver hasn't taken yet, and how often a
+/// receiver has held a buffer up.
+#[derive(Default)]
+pub struct Flow {
+    pub in_flight: AtomicU64,
+    pub stalls: AtomicU64,
+}
+
+/// A chunk read from the sender, counted in flight until it is written
+/// or given up.
+pub struct Chunk<'a> {
+    flow: &'a Flow,
+    left: usize,
+}
+
+impl<'a> Chunk<'a> {
+    pub fn new(flow: &'a Flow, len: usize) -> Self {
+        flow.in_flight.fetch_add(len as u64, Ordering::Relaxed);
+        Chunk { flow, left: len }
+    }
+
+    pub fn left(&self) -> usize {
+        self.left
+    }
+
+    pub fn written(&mut self, n: usize) {
+        self.left -= n;
+        self.flow.in_flight.fetch_sub(n as u64, Ordering::Relaxed);
+    }
+
+    /// The receiver can't take more for now.
+    pub fn stalled(&self) {
+        self.flow.stalls.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Chunk<'_> {
+    fn drop(&mut self) {
+        self.flow.in_flight.fetch_sub(self.left as u64, Ordering::Relaxed);
+    }
+}
+
+/// Both directions of the relay.
+#[derive(Default)]
+pub struct Flows {
+    /// From clients to targets.
+    pub upload: Flow,
+    /// From targets to clients.
+    pub download: Flow,
+}
+
+/*************************************************
+ * Activity
+ *************************************************/
+
+/// When a tunnel last moved data, shared by both relay directions.
+pub struct Activity {
+    started: Instant,
+    last_ms: AtomicU64,
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Activity {
+            started: Instant::now(),
+            last_ms: AtomicU64::new(0),
+        }
+    }
+
+    pub fn touch(&self) {
+        self.last_ms.store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Resolves once nothing has been relayed for `timeout`.
+    pub async fn idle(&self, timeout: Duration) {
+        if timeout.is_zero() {
+            return std::future::pending().await;
+        }
+        loop {
+            let last = self.started + Duration::from_millis(self.last_ms.load(Ordering::Relaxed));
+            let deadline = last + timeout;
+            if Instant::now() >= deadline {
+                return;
+            }
+            tokio::time::sleep_until(deadline.into()).await;
+        }
+    }
+}
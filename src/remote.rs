@@ -0,0 +1,134 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use hyper::header::{ETAG, IF_NONE_MATCH};
+use hyper::{Body, Client, Request, StatusCode};
+use std::error::Error;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use log::{error, info};
+use crate::adblock::FilterList;
+use crate::domains::DomainList;
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+pub const DEFAULT_REFRESH_MINS: u64 = 60;
+
+/*************************************************
+ * ListFormat
+ *************************************************/
+
+#[derive(Clone, Copy)]
+pub enum ListFormat {
+    Domains,
+    Filters,
+}
+
+enum Compiled {
+    Domains(DomainList),
+    Filters(FilterList),
+}
+
+impl Compiled {
+    fn new(format: ListFormat, content: &str) -> Self {
+        match format {
+            ListFormat::Domains => {
+                let mut list = DomainList::default();
+                list.parse(content);
+                Compiled::Domains(list)
+            }
+            ListFormat::Filters => {
+                let mut list = FilterList::default();
+                list.parse(content);
+                Compiled::Filters(list)
+            }
+        }
+    }
+
+    fn blocks(&self, host: &str) -> bool {
+        match self {
+            Compiled::Domains(list) => list.contains(host),
+            Compiled::Filters(list) => list.blocks(host),
+        }
+    }
+}
+
+/*************************************************
+ * RemoteList
+ *************************************************/
+
+/// A blocklist fetched from a URL and refreshed in the background. Updates
+/// are conditional on the last ETag and replace the compiled list in one
+/// swap, so lookups never see a half-loaded list.
+pub struct RemoteList {
+    pub url: String,
+    format: ListFormat,
+    compiled: RwLock<Arc<Compiled>>,
+    etag: Mutex<Option<String>>,
+}
+
+impl RemoteList {
+    pub fn new(url: &str, format: ListFormat) -> Self {
+        RemoteList {
+            url: url.to_string(),
+            format,
+            compiled: RwLock::new(Arc::new(Compiled::new(format, ""))),
+            etag: Mutex::new(None),
+        }
+    }
+
+    pub fn blocks(&self, host: &str) -> bool {
+        let compiled = self.compiled.read().unwrap().clone();
+        compiled.blocks(host)
+    }
+
+    /// Fetch the list unless unchanged; returns whether it was replaced.
+    async fn refresh(&self) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        let mut request = Request::get(&self.url);
+        if let Some(etag) = self.etag.lock().unwrap().clone() {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        let response = Client::builder()
+            .build::<_, Body>(connector)
+            .request(request.body(Body::empty())?)
+            .await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(false);
+        }
+        if !response.status().is_success() {
+            return Err(format!("unexpected status {}", response.status()).into());
+        }
+
+        let etag = response.headers()
+            .get(ETAG)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        let compiled = Compiled::new(self.format, &String::from_utf8_lossy(&body));
+        *self.compiled.write().unwrap() = Arc::new(compiled);
+        *self.etag.lock().unwrap() = etag;
+        Ok(true)
+    }
+
+    pub fn spawn_refresher(self: Arc<Self>, interval: Duration) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval.max(Duration::from_secs(60)));
+            loop {
+                ticker.tick().await;
+                match self.refresh().await {
+                    Ok(true) => info!("Blocklist {} updated", self.url),
+                    Ok(false) => info!("Blocklist {} unchanged", self.url),
+                    Err(e) => error!("Failed to update blocklist {}: {}", self.url, e),
+                }
+            }
+        });
+    }
+}
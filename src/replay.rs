@@ -0,0 +1,109 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use hyper::body::Bytes;
+use hyper::header::{HeaderName, HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, TRANSFER_ENCODING};
+use hyper::{Body, Method, Response, StatusCode};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/*************************************************
+ * Replay
+ *************************************************/
+
+/// Responses recorded in HAR files, served instead of contacting origins.
+/// Requests match on method and URL; a request recorded several times gets
+/// the recorded responses in order, and the last one from then on.
+#[derive(Default)]
+pub struct Replay {
+    recordings: HashMap<(String, String), Recording>,
+}
+
+struct Recording {
+    responses: Vec<Recorded>,
+    next: AtomicUsize,
+}
+
+struct Recorded {
+    status: StatusCode,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    body: Bytes,
+}
+
+impl Replay {
+    pub fn load(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        let invalid = |detail: &str| format!("Error: Invalid HAR file {}: {}", path, detail);
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Error: Cannot read HAR file {}: {}", path, e))?;
+        let har: Value = serde_json::from_str(&content).map_err(|e| invalid(&e.to_string()))?;
+        let entries = har["log"]["entries"].as_array().ok_or_else(|| invalid("no log.entries"))?;
+        for (index, entry) in entries.iter().enumerate() {
+            let request = &entry["request"];
+            let (Some(method), Some(url)) = (request["method"].as_str(), request["url"].as_str()) else {
+                return Err(invalid(&format!("entry {} has no request method or URL", index)).into());
+            };
+            let recorded = parse_response(&entry["response"])
+                .map_err(|e| invalid(&format!("entry {}: {}", index, e)))?;
+            let key = (method.to_ascii_uppercase(), without_fragment(url).to_string());
+            self.recordings
+                .entry(key)
+                .or_insert_with(|| Recording { responses: Vec::new(), next: AtomicUsize::new(0) })
+                .responses
+                .push(recorded);
+        }
+        Ok(())
+    }
+
+    /// Number of distinct requests with a recorded response.
+    pub fn len(&self) -> usize {
+        self.recordings.len()
+    }
+
+    pub fn response(&self, method: &Method, url: &str) -> Option<Response<Body>> {
+        let recording = self.recordings.get(&(method.to_string(), without_fragment(url).to_string()))?;
+        let index = recording.next.fetch_add(1, Ordering::Relaxed).min(recording.responses.len() - 1);
+        let recorded = &recording.responses[index];
+        let mut response = Response::new(Body::from(recorded.body.clone()));
+        *response.status_mut() = recorded.status;
+        for (name, value) in &recorded.headers {
+            response.headers_mut().append(name, value.clone());
+        }
+        response.headers_mut().insert(CONTENT_LENGTH, HeaderValue::from(recorded.body.len()));
+        Some(response)
+    }
+}
+
+fn without_fragment(url: &str) -> &str {
+    url.split('#').next().unwrap_or_default()
+}
+
+/// A HAR response. Its content is stored decoded, so the headers that
+/// describe the original encoding and framing are dropped.
+fn parse_response(response: &Value) -> Result<Recorded, Box<dyn Error>> {
+    let status = response["status"].as_u64().ok_or("response has no status")?;
+    let status = StatusCode::from_u16(status as u16)?;
+    let mut headers = Vec::new();
+    for header in response["headers"].as_array().into_iter().flatten() {
+        let (Some(name), Some(value)) = (header["name"].as_str(), header["value"].as_str()) else {
+            continue;
+        };
+        // HTTP/2 recordings carry pseudo-headers such as `:status`.
+        let Ok(name) = HeaderName::from_bytes(name.as_bytes()) else {
+            continue;
+        };
+        if name == CONTENT_ENCODING || name == CONTENT_LENGTH || name == TRANSFER_ENCODING {
+            continue;
+        }
+        headers.push((name, HeaderValue::from_str(value)?));
+    }
+    let content = &response["content"];
+    let text = content["text"].as_str().unwrap_or_default();
+    let body = match content["encoding"].as_str() {
+        Some("base64") => base64::decode(text)?,
+        _ => text.as_bytes().to_vec(),
+    };
+    Ok(Recorded { status, headers, body: Bytes::from(body) })
+}
@@ -0,0 +1,677 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+use hyper::body::{Bytes, Sender};
+use hyper::header::{HeaderName, HeaderValue, CONNECTION, CONTENT_LENGTH, TRANSFER_ENCODING};
+use hyper::{HeaderMap, Method, StatusCode, Version};
+use std::error::Error;
+use std::fmt;
+use std::net::Ipv6Addr;
+use std::time::{Duration, Instant};
+use crate::har::BodyCapture;
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+pub const DEFAULT_MAX_HEAD_BYTES: usize = 64 * 1024;
+pub const DEFAULT_MAX_HEADERS: usize = 100;
+pub const DEFAULT_HEAD_TIMEOUT_SECS: u64 = 30;
+pub const DEFAULT_MIN_HEAD_RATE: u64 = 128;
+
+/// How long a client may trickle its head before the rate is checked.
+const MIN_RATE_GRACE: Duration = Duration::from_secs(5);
+
+const HOP_BY_HOP: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+const DEFAULT_CONNECT_PORT: u16 = 443;
+const READ_CHUNK: usize = 8192;
+const MAX_CHUNK_LINE: usize = 4096;
+
+/*************************************************
+ * HeadLimits
+ *************************************************/
+
+/// Bounds on what a client may send, and how slowly, before its request
+/// head is complete. `min_rate` is in bytes per second, 0 disables it.
+#[derive(Clone, Copy)]
+pub struct HeadLimits {
+    pub max_bytes: usize,
+    pub max_headers: usize,
+    pub timeout: Duration,
+    pub min_rate: u64,
+}
+
+impl Default for HeadLimits {
+    fn default() -> Self {
+        HeadLimits {
+            max_bytes: DEFAULT_MAX_HEAD_BYTES,
+            max_headers: DEFAULT_MAX_HEADERS,
+            timeout: Duration::from_secs(DEFAULT_HEAD_TIMEOUT_SECS),
+            min_rate: DEFAULT_MIN_HEAD_RATE,
+        }
+    }
+}
+
+/// The request head broke `HeadLimits`.
+#[derive(Debug)]
+pub enum HeadError {
+    TooLarge(String),
+    TooSlow(String),
+}
+
+impl HeadError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            HeadError::TooLarge(_) => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            HeadError::TooSlow(_) => StatusCode::REQUEST_TIMEOUT,
+        }
+    }
+}
+
+impl fmt::Display for HeadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeadError::TooLarge(detail) | HeadError::TooSlow(detail) => f.write_str(detail),
+        }
+    }
+}
+
+impl Error for HeadError {}
+
+/// The request body is larger than the upload limit.
+#[derive(Debug)]
+pub struct BodyTooLarge(pub u64);
+
+impl fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Error: Request body exceeds {} bytes", self.0)
+    }
+}
+
+impl Error for BodyTooLarge {}
+
+/*************************************************
+ * RequestHead
+ *************************************************/
+
+pub struct RequestHead {
+    pub method: Method,
+    pub target: String,
+    pub version: Version,
+    pub headers: HeaderMap,
+}
+
+/*************************************************
+ * BodyLength
+ *************************************************/
+
+pub enum BodyLength {
+    Empty,
+    Fixed(u64),
+    Chunked,
+}
+
+impl RequestHead {
+    /// Request body framing per RFC 7230 section 3.3.3. Anything an origin
+    /// could read differently from us (the basis of request smuggling) is
+    /// rejected rather than repaired.
+    pub fn body_length(&self) -> Result<BodyLength, Box<dyn Error>> {
+        let has_length = self.headers.contains_key(CONTENT_LENGTH);
+        if self.headers.contains_key(TRANSFER_ENCODING) {
+            if self.version == Version::HTTP_10 {
+                return Err("Error: Transfer-Encoding is not supported in HTTP/1.0 requests".into());
+            }
+            if has_length {
+                return Err("Error: Request has both Content-Length and Transfer-Encoding".into());
+            }
+            let codings: Vec<&str> = self.headers.get_all(TRANSFER_ENCODING)
+                .iter()
+                .map(|value| value.to_str())
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter()
+                .flat_map(|value| value.split(','))
+                .map(str::trim)
+                .collect();
+            if !matches!(codings.as_slice(), [coding] if coding.eq_ignore_ascii_case("chunked")) {
+                return Err("Error: Unsupported Transfer-Encoding".into());
+            }
+            return Ok(BodyLength::Chunked);
+        }
+        if !has_length {
+            return Ok(BodyLength::Empty);
+        }
+
+        // Repeated Content-Length values are only tolerated when identical.
+        let mut length = None;
+        for value in self.headers.get_all(CONTENT_LENGTH) {
+            for value in value.to_str()?.split(',') {
+                let value = value.trim();
+                if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(format!("Error: Invalid Content-Length: {}", value).into());
+                }
+                let value: u64 = value.parse()?;
+                if length.is_some_and(|length| length != value) {
+                    return Err("Error: Conflicting Content-Length values".into());
+                }
+                length = Some(value);
+            }
+        }
+        match length {
+            Some(0) | None => Ok(BodyLength::Empty),
+            Some(length) => Ok(BodyLength::Fixed(length)),
+        }
+    }
+
+    /// Whether the client wants to send further requests on this
+    /// connection, from `Connection` (or the legacy `Proxy-Connection`
+    /// sent by browsers) and the protocol version's default.
+    pub fn keep_alive(&self) -> bool {
+        let tokens: Vec<String> = self.headers.get_all(CONNECTION)
+            .iter()
+            .chain(self.headers.get_all("proxy-connection").iter())
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|token| token.trim().to_ascii_lowercase())
+            .collect();
+        if tokens.iter().any(|token| token == "close") {
+            return false;
+        }
+        self.version != Version::HTTP_10 || tokens.iter().any(|token| token == "keep-alive")
+    }
+}
+
+/*************************************************
+ * ClientConn
+ *************************************************/
+
+/// A client connection together with the bytes read ahead of the request
+/// currently being processed.
+pub struct ClientConn<S> {
+    pub stream: S,
+    buffer: Vec<u8>,
+}
+
+impl<S: AsyncRead + Unpin> ClientConn<S> {
+    pub fn new(stream: S) -> Self {
+        ClientConn {
+            stream,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Bytes received but not consumed yet.
+    pub fn take_buffered(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buffer)
+    }
+
+    async fn fill(&mut self) -> Result<usize, Box<dyn Error>> {
+        self.buffer.reserve(READ_CHUNK);
+        Ok(self.stream.read_buf(&mut self.buffer).await?)
+    }
+
+    /// Read and parse the next request head. Returns the head and its size
+    /// on the wire, or `None` if the client closed the connection (or let
+    /// it idle past the timeout) before sending anything.
+    pub async fn read_head(&mut self, limits: &HeadLimits) -> Result<Option<(RequestHead, usize)>, Box<dyn Error>> {
+        let started = Instant::now();
+        let mut first_byte = (!self.buffer.is_empty()).then_some(started);
+        let mut scanned = 0;
+        loop {
+            if let Some(end) = find_head_end(&self.buffer, scanned) {
+                if end > limits.max_bytes {
+                    break;
+                }
+                let head = parse_head(&self.buffer[..end], limits.max_headers)?;
+                self.buffer.drain(..end);
+                return Ok(Some((head, end)));
+            }
+            if self.buffer.len() >= limits.max_bytes {
+                break;
+            }
+            scanned = self.buffer.len().saturating_sub(3);
+
+            let remaining = limits.timeout.saturating_sub(started.elapsed());
+            let read = match tokio::time::timeout(remaining, self.fill()).await {
+                Ok(read) => read?,
+                Err(_) if self.buffer.is_empty() => return Ok(None),
+                Err(_) => {
+                    return Err(HeadError::TooSlow(format!(
+                        "Error: Request head not received within {} seconds",
+                        limits.timeout.as_secs()
+                    )).into());
+                }
+            };
+            if read == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                return Err("Error: Connection closed before the request head was complete".into());
+            }
+
+            // Rate is measured from the first byte so idle keep-alive
+            // connections are only subject to the timeout.
+            let first_byte = *first_byte.get_or_insert_with(Instant::now);
+            let elapsed = first_byte.elapsed();
+            if limits.min_rate > 0
+                && elapsed > MIN_RATE_GRACE
+                && (self.buffer.len() as f64 / elapsed.as_secs_f64()) < limits.min_rate as f64
+            {
+                return Err(HeadError::TooSlow(format!(
+                    "Error: Request head sent slower than {} bytes/s",
+                    limits.min_rate
+                )).into());
+            }
+        }
+        Err(HeadError::TooLarge(format!("Error: Request head exceeds {} bytes", limits.max_bytes)).into())
+    }
+
+    /// Up to `max` body bytes, from the read-ahead buffer first.
+    async fn read_some(&mut self, max: u64) -> Result<Bytes, Box<dyn Error>> {
+        if self.buffer.is_empty() && self.fill().await? == 0 {
+            return Err("Error: Connection closed in the middle of the request body".into());
+        }
+        let n = self.buffer.len().min(max.try_into().unwrap_or(usize::MAX));
+        Ok(Bytes::from(self.buffer.drain(..n).collect::<Vec<u8>>()))
+    }
+
+    async fn read_line(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        loop {
+            if let Some(pos) = self.buffer.windows(2).position(|window| window == b"\r\n") {
+                let line = self.buffer.drain(..pos + 2).take(pos).collect();
+                return Ok(line);
+            }
+            if self.buffer.len() >= MAX_CHUNK_LINE {
+                return Err("Error: Chunk header line too long".into());
+            }
+            if self.fill().await? == 0 {
+                return Err("Error: Connection closed in the middle of the request body".into());
+            }
+        }
+    }
+
+    /// Stream the request body into `sender`, decoding chunked framing (the
+    /// upstream request is re-framed by hyper). Returns the body size. A
+    /// body that fails before its end (over `limit`, badly framed or cut
+    /// off) is aborted, so the upstream never sees it complete.
+    /// The body is also copied into `capture`, when given. Trailers are
+    /// skipped, as many and as large as `limits` allows headers to be.
+    pub async fn forward_body(
+        &mut self,
+        length: BodyLength,
+        sender: Sender,
+        limit: Option<u64>,
+        limits: &HeadLimits,
+        mut capture: Option<&mut BodyCapture>,
+    ) -> Result<u64, Box<dyn Error>> {
+        let mut sender = BodySender(Some(sender));
+        let mut forwarded = 0;
+        let limit = limit.unwrap_or(u64::MAX);
+        match length {
+            BodyLength::Empty => {}
+            BodyLength::Fixed(length) => {
+                if length > limit {
+                    return Err(Box::new(BodyTooLarge(limit)));
+                }
+                while forwarded < length {
+                    let data = self.read_some(length - forwarded).await?;
+                    forwarded += data.len() as u64;
+                    if let Some(capture) = &mut capture {
+                        capture.record(&data);
+                    }
+                    sender.send_data(data).await?;
+                }
+            }
+            BodyLength::Chunked => loop {
+                let line = self.read_line().await?;
+                let size = std::str::from_utf8(&line)?
+                    .split(';')
+                    .next()
+                    .unwrap_or_default()
+                    .trim_end_matches([' ', '\t']);
+                // from_str_radix alone would accept a sign or overlong sizes.
+                let valid = !size.is_empty() && size.len() <= 16 && size.bytes().all(|b| b.is_ascii_hexdigit());
+                let size = valid.then(|| u64::from_str_radix(size, 16).ok()).flatten()
+                    .ok_or_else(|| format!("Error: Invalid chunk size: {}", size))?;
+                if size == 0 {
+                    let (mut fields, mut bytes) = (0, 0);
+                    loop {
+                        let line = self.read_line().await?;
+                        if line.is_empty() {
+                            break;
+                        }
+                        fields += 1;
+                        bytes += line.len() + 2;
+                        if fields > limits.max_headers || bytes > limits.max_bytes {
+                            return Err(format!("Error: Trailers exceed {} fields or {} bytes", limits.max_headers, limits.max_bytes).into());
+                        }
+                    }
+                    break;
+                }
+
+                let mut remaining = size;
+                while remaining > 0 {
+                    let data = self.read_some(remaining).await?;
+                    remaining -= data.len() as u64;
+                    forwarded += data.len() as u64;
+                    if forwarded > limit {
+                        return Err(Box::new(BodyTooLarge(limit)));
+                    }
+                    if let Some(capture) = &mut capture {
+                        capture.record(&data);
+                    }
+                    sender.send_data(data).await?;
+                }
+                if !self.read_line().await?.is_empty() {
+                    return Err("Error: Missing CRLF after chunk data".into());
+                }
+            },
+        }
+        sender.finish();
+        Ok(forwarded)
+    }
+
+    /// Read a whole request body of at most `limit` bytes into memory.
+    pub async fn read_body(&mut self, length: BodyLength, limit: u64, limits: &HeadLimits) -> Result<Bytes, Box<dyn Error>> {
+        let (sender, body) = hyper::Body::channel();
+        // The error is turned into a string while the body is still being
+        // collected, so the future stays Send.
+        let forward = async { self.forward_body(length, sender, Some(limit), limits, None).await.map_err(|e| e.to_string()) };
+        let (forwarded, body) = tokio::join!(forward, hyper::body::to_bytes(body));
+        forwarded?;
+        Ok(body?)
+    }
+}
+
+fn find_head_end(buffer: &[u8], from: usize) -> Option<usize> {
+    buffer[from..]
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|pos| from + pos + 4)
+}
+
+/*************************************************
+ * BodySender
+ *************************************************/
+
+/// The sending end of a forwarded body, aborted when dropped before
+/// `finish`: hyper takes a dropped sender for the end of the body, which
+/// would pass a body cut short off as a complete, shorter one.
+struct BodySender(Option<Sender>);
+
+impl BodySender {
+    async fn send_data(&mut self, data: Bytes) -> Result<(), hyper::Error> {
+        match &mut self.0 {
+            Some(sender) => sender.send_data(data).await,
+            None => Ok(()),
+        }
+    }
+
+    /// The body was read to its end.
+    fn finish(mut self) {
+        self.0.take();
+    }
+}
+
+impl Drop for BodySender {
+    fn drop(&mut self) {
+        if let Some(sender) = self.0.take() {
+            sender.abort();
+        }
+    }
+}
+
+/*************************************************
+ * parse_head
+ *************************************************/
+
+fn parse_head(head: &[u8], max_headers: usize) -> Result<RequestHead, Box<dyn Error>> {
+    let mut headers = vec![httparse::EMPTY_HEADER; max_headers];
+    let mut request = httparse::Request::new(&mut headers);
+    match request.parse(head) {
+        Ok(status) if status.is_partial() => return Err("Error: Incomplete request head".into()),
+        Ok(_) => {}
+        Err(httparse::Error::TooManyHeaders) => {
+            return Err(HeadError::TooLarge(format!("Error: Request has more than {} headers", max_headers)).into());
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    let method = Method::from_bytes(request.method.unwrap_or_default().as_bytes())?;
+    let target = request.path.unwrap_or_default().to_string();
+    let version = match request.version {
+        Some(0) => Version::HTTP_10,
+        _ => Version::HTTP_11,
+    };
+
+    let mut header_map = HeaderMap::with_capacity(request.headers.len());
+    for header in request.headers.iter() {
+        header_map.append(
+            HeaderName::from_bytes(header.name.as_bytes())?,
+            HeaderValue::from_bytes(header.value)?,
+        );
+    }
+
+    Ok(RequestHead {
+        method,
+        target,
+        version,
+        headers: header_map,
+    })
+}
+
+/*************************************************
+ * strip_hop_by_hop
+ *************************************************/
+
+/// Remove headers that only apply to a single connection (RFC 7230
+/// section 6.1), including any listed in `Connection`.
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers.get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|token| HeaderName::from_bytes(token.trim().as_bytes()).ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(name);
+    }
+}
+
+/*************************************************
+ * Authority
+ *************************************************/
+
+/// A validated `host:port` CONNECT target. IPv6 hosts are kept without
+/// their brackets.
+pub struct Authority {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Authority {
+    /// Parse `host[:port]` or `[ipv6][:port]`; the port defaults to 443.
+    pub fn parse(target: &str) -> Result<Self, Box<dyn Error>> {
+        let invalid = || format!("Error: Invalid CONNECT target: {}", target);
+        let (host, port) = if let Some(rest) = target.strip_prefix('[') {
+            let (host, rest) = rest.split_once(']').ok_or_else(invalid)?;
+            host.parse::<Ipv6Addr>().map_err(|_| invalid())?;
+            match rest {
+                "" => (host, None),
+                _ => (host, Some(rest.strip_prefix(':').ok_or_else(invalid)?)),
+            }
+        } else {
+            match target.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (target, None),
+            }
+        };
+
+        let valid_host = !host.is_empty()
+            && (host.contains(':')
+                || host.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_')));
+        if !valid_host {
+            return Err(invalid().into());
+        }
+        let port = match port {
+            Some(port) => match port.parse::<u16>() {
+                Ok(port) if port != 0 => port,
+                _ => return Err(invalid().into()),
+            },
+            None => DEFAULT_CONNECT_PORT,
+        };
+        Ok(Authority {
+            host: host.to_ascii_lowercase(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for Authority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Framing of the request head `raw`; a head that doesn't parse is
+    /// rejected as well.
+    fn length(raw: &str) -> Result<BodyLength, Box<dyn Error>> {
+        parse_head(raw.as_bytes(), DEFAULT_MAX_HEADERS)?.body_length()
+    }
+
+    async fn body(raw: &[u8], limit: u64) -> Result<Bytes, Box<dyn Error>> {
+        ClientConn::new(raw).read_body(BodyLength::Chunked, limit, &HeadLimits::default()).await
+    }
+
+    #[test]
+    fn accepts_repeated_identical_content_length() {
+        assert!(matches!(length("POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\n"), Ok(BodyLength::Fixed(5))));
+        assert!(matches!(length("POST / HTTP/1.1\r\nContent-Length: 5, 5\r\n\r\n"), Ok(BodyLength::Fixed(5))));
+        assert!(matches!(length("POST / HTTP/1.1\r\nContent-Length: 0\r\n\r\n"), Ok(BodyLength::Empty)));
+        assert!(matches!(length("GET / HTTP/1.1\r\n\r\n"), Ok(BodyLength::Empty)));
+    }
+
+    #[test]
+    fn rejects_conflicting_or_invalid_content_length() {
+        assert!(length("POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n").is_err());
+        assert!(length("POST / HTTP/1.1\r\nContent-Length: 5, 6\r\n\r\n").is_err());
+        assert!(length("POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\n").is_err());
+        assert!(length("POST / HTTP/1.1\r\nContent-Length: 0x5\r\n\r\n").is_err());
+        assert!(length("POST / HTTP/1.1\r\nContent-Length: 5,\r\n\r\n").is_err());
+        assert!(length("POST / HTTP/1.1\r\nContent-Length: 99999999999999999999\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn rejects_transfer_encoding_with_content_length() {
+        assert!(length("POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n").is_err());
+        assert!(length("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 0\r\n\r\n").is_err());
+        assert!(length("POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn accepts_only_plain_chunked_transfer_encoding() {
+        assert!(matches!(length("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n"), Ok(BodyLength::Chunked)));
+        assert!(matches!(length("POST / HTTP/1.1\r\nTransfer-Encoding: Chunked \r\n\r\n"), Ok(BodyLength::Chunked)));
+        for coding in ["chunked, chunked", "gzip, chunked", "chunked, identity", "xchunked", "chunked;q=1", "chunk", ""] {
+            let raw = format!("POST / HTTP/1.1\r\nTransfer-Encoding: {}\r\n\r\n", coding);
+            assert!(length(&raw).is_err(), "accepted {:?}", coding);
+        }
+        assert!(length("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n\r\n").is_err());
+        assert!(length("POST / HTTP/1.1\r\nTransfer-Encoding: \x0bchunked\r\n\r\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn decodes_chunked_body_and_skips_trailers() {
+        let raw = b"5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\nExpires: never\r\nX-Trailer: 1\r\n\r\nGET";
+        let mut conn = ClientConn::new(&raw[..]);
+        let body = conn.read_body(BodyLength::Chunked, 1024, &HeadLimits::default()).await.unwrap();
+        assert_eq!(&body[..], b"hello world");
+        // The next request starts right after the trailer section.
+        assert_eq!(conn.take_buffered(), b"GET");
+    }
+
+    #[tokio::test]
+    async fn caps_trailers_like_headers() {
+        let limits = HeadLimits { max_headers: 2, max_bytes: 64, ..HeadLimits::default() };
+        let read = |raw: &'static [u8]| async move { ClientConn::new(raw).read_body(BodyLength::Chunked, 1024, &limits).await };
+        assert!(read(b"0\r\nA: 1\r\nB: 2\r\n\r\n").await.is_ok());
+        let error = read(b"0\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n").await.unwrap_err();
+        assert!(error.to_string().contains("Trailers exceed 2 fields or 64 bytes"), "{}", error);
+        let long = format!("0\r\nA: {}\r\n\r\n", "x".repeat(64));
+        assert!(ClientConn::new(long.as_bytes()).read_body(BodyLength::Chunked, 1024, &limits).await.is_err());
+    }
+
+    /// Forward `raw` as a body of `length`, returning how the forwarding
+    /// and the receiving end each came out.
+    async fn forwarded(raw: &[u8], length: BodyLength) -> (Result<u64, String>, Result<Bytes, hyper::Error>) {
+        let (sender, body) = hyper::Body::channel();
+        let mut conn = ClientConn::new(raw);
+        let forward = async { conn.forward_body(length, sender, None, &HeadLimits::default(), None).await.map_err(|e| e.to_string()) };
+        tokio::join!(forward, hyper::body::to_bytes(body))
+    }
+
+    #[tokio::test]
+    async fn aborts_chunked_bodies_that_fail_midway() {
+        for raw in [&b"5\r\nhello\r\nzz\r\n"[..], b"5\r\nhello\r\n3\r\nab", b"5\r\nhello world\r\n0\r\n\r\n", b"5\r\nhello\r\n"] {
+            let (forward, body) = forwarded(raw, BodyLength::Chunked).await;
+            assert!(forward.is_err() && body.is_err(), "{:?} ended cleanly", String::from_utf8_lossy(raw));
+        }
+        let (forward, body) = forwarded(b"5\r\nhello\r\n0\r\n\r\n", BodyLength::Chunked).await;
+        assert_eq!((forward.unwrap(), &body.unwrap()[..]), (5, &b"hello"[..]));
+    }
+
+    #[tokio::test]
+    async fn aborts_bodies_shorter_than_their_content_length() {
+        let (forward, body) = forwarded(b"hel", BodyLength::Fixed(5)).await;
+        assert!(forward.unwrap_err().contains("Connection closed in the middle of the request body"));
+        assert!(body.is_err());
+        let (forward, body) = forwarded(b"hello", BodyLength::Fixed(5)).await;
+        assert_eq!((forward.unwrap(), &body.unwrap()[..]), (5, &b"hello"[..]));
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_chunk_sizes() {
+        for size in ["", "-5", "+5", "0x5", " 5", "g", "10000000000000000", "00000000000000005"] {
+            let raw = format!("{}\r\nhello\r\n0\r\n\r\n", size);
+            assert!(body(raw.as_bytes(), u64::MAX).await.is_err(), "accepted {:?}", size);
+        }
+        assert!(body(b"ffffffffffffffff\r\nhello", u64::MAX).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_chunk_data_without_crlf() {
+        assert!(body(b"5\r\nhello world\r\n0\r\n\r\n", u64::MAX).await.is_err());
+        assert!(body(b"5\r\nhel", u64::MAX).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn aborts_bodies_over_the_limit() {
+        let error = body(b"5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n", 8).await.unwrap_err();
+        assert!(error.to_string().contains("exceeds 8 bytes"));
+        let error = ClientConn::new(&b"hello"[..]).read_body(BodyLength::Fixed(5), 4, &HeadLimits::default()).await.unwrap_err();
+        assert!(error.to_string().contains("exceeds 4 bytes"));
+    }
+}
@@ -0,0 +1,172 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use hyper::body::Bytes;
+use hyper::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use hyper::HeaderMap;
+use regex::bytes::{NoExpand, Regex};
+use std::error::Error;
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+/// Longest line held back waiting for its end; a longer one is rewritten
+/// as is, so a match spanning the cut is missed.
+const MAX_PENDING: usize = 64 * 1024;
+
+/// Content types other than `text/*` that are worth rewriting.
+const TEXT_TYPES: [&str; 3] = ["json", "javascript", "xml"];
+
+/*************************************************
+ * Rewrite
+ *************************************************/
+
+/// One find/replace on response bodies: a literal string, or a regex
+/// (after `~`) whose replacement may refer to captures as `$1`.
+#[derive(Clone)]
+pub struct Rewrite {
+    pattern: Regex,
+    replacement: String,
+    expand: bool,
+}
+
+impl Rewrite {
+    pub fn parse(find: &str, replacement: &str) -> Result<Self, Box<dyn Error>> {
+        let (pattern, expand) = match find.strip_prefix('~') {
+            Some(regex) => (Regex::new(regex)?, true),
+            None => (Regex::new(&regex::escape(find))?, false),
+        };
+        Ok(Rewrite { pattern, replacement: replacement.to_string(), expand })
+    }
+
+    fn apply(&self, text: &[u8]) -> Vec<u8> {
+        let replacement = self.replacement.as_bytes();
+        if self.expand {
+            self.pattern.replace_all(text, replacement).into_owned()
+        } else {
+            self.pattern.replace_all(text, NoExpand(replacement)).into_owned()
+        }
+    }
+}
+
+/// Whether a response body is uncompressed text.
+pub fn is_rewritable(headers: &HeaderMap) -> bool {
+    let encoded = headers.get(CONTENT_ENCODING).is_some_and(|value| value != "identity");
+    let text = headers.get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_ascii_lowercase)
+        .is_some_and(|value| value.starts_with("text/") || TEXT_TYPES.iter().any(|kind| value.contains(kind)));
+    text && !encoded
+}
+
+/*************************************************
+ * BodyRewriter
+ *************************************************/
+
+/// Applies rewrites to a streamed body a line at a time, holding back the
+/// unfinished last line of each chunk.
+pub struct BodyRewriter {
+    rewrites: Vec<Rewrite>,
+    pending: Vec<u8>,
+}
+
+impl BodyRewriter {
+    pub fn new(rewrites: Vec<Rewrite>) -> Self {
+        BodyRewriter { rewrites, pending: Vec::new() }
+    }
+
+    pub fn feed(&mut self, chunk: &[u8]) -> Bytes {
+        self.pending.extend_from_slice(chunk);
+        let end = match self.pending.iter().rposition(|&b| b == b'\n') {
+            Some(newline) => newline + 1,
+            None if self.pending.len() > MAX_PENDING => self.pending.len(),
+            None => return Bytes::new(),
+        };
+        let lines: Vec<u8> = self.pending.drain(..end).collect();
+        self.rewrite(lines)
+    }
+
+    pub fn finish(mut self) -> Bytes {
+        let rest = std::mem::take(&mut self.pending);
+        self.rewrite(rest)
+    }
+
+    fn rewrite(&self, text: Vec<u8>) -> Bytes {
+        let text = self.rewrites.iter().fold(text, |text, rewrite| rewrite.apply(&text));
+        Bytes::from(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewriter(rules: &[(&str, &str)]) -> BodyRewriter {
+        BodyRewriter::new(rules.iter().map(|(find, replacement)| Rewrite::parse(find, replacement).unwrap()).collect())
+    }
+
+    fn stream(mut rewriter: BodyRewriter, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut output = Vec::new();
+        for chunk in chunks {
+            output.extend_from_slice(&rewriter.feed(chunk));
+        }
+        output.extend_from_slice(&rewriter.finish());
+        output
+    }
+
+    #[test]
+    fn matches_across_chunk_boundaries() {
+        let body = b"<a href=\"http://internal:8080/x\">\nsee http://internal:8080/y\nend";
+        let expected = b"<a href=\"https://public.example/x\">\nsee https://public.example/y\nend";
+        for cut in 0..=body.len() {
+            let output = stream(rewriter(&[("http://internal:8080/", "https://public.example/")]), &[&body[..cut], &body[cut..]]);
+            assert_eq!(output, expected, "cut at {}", cut);
+        }
+        let bytewise: Vec<&[u8]> = body.chunks(1).collect();
+        assert_eq!(stream(rewriter(&[("http://internal:8080/", "https://public.example/")]), &bytewise), expected);
+    }
+
+    #[test]
+    fn holds_back_the_unfinished_line() {
+        let mut rewriter = rewriter(&[("cat", "dog")]);
+        assert_eq!(rewriter.feed(b"a c"), "");
+        assert_eq!(rewriter.feed(b"at\nb c"), "a dog\n");
+        assert_eq!(rewriter.feed(b"a"), "");
+        assert_eq!(rewriter.finish(), "b ca");
+    }
+
+    #[test]
+    fn applies_rewrites_in_order_with_captures() {
+        let output = stream(rewriter(&[("~<title>(\\w+)</title>", "<title>[$1]</title>"), ("[", "(")]), &[b"<title>Home</title>\n"]);
+        assert_eq!(output, b"<title>(Home]</title>\n");
+        // Literal replacements are taken as they are.
+        assert_eq!(stream(rewriter(&[("price", "$1")]), &[b"price: 3\n"]), b"$1: 3\n");
+        assert!(Rewrite::parse("~(unclosed", "x").is_err());
+    }
+
+    #[test]
+    fn flushes_overlong_lines() {
+        let mut rewriter = rewriter(&[("x", "y")]);
+        let line = vec![b'x'; MAX_PENDING];
+        assert_eq!(rewriter.feed(&line), "");
+        let flushed = rewriter.feed(b"x");
+        assert_eq!(flushed.len(), MAX_PENDING + 1);
+        assert!(flushed.iter().all(|&b| b == b'y'));
+        assert_eq!(rewriter.finish(), "");
+    }
+
+    #[test]
+    fn rewrites_only_uncompressed_text() {
+        let headers = |pairs: &[(&'static str, &'static str)]| -> HeaderMap {
+            pairs.iter().map(|(name, value)| (name.parse().unwrap(), value.parse().unwrap())).collect()
+        };
+        assert!(is_rewritable(&headers(&[("content-type", "text/html; charset=utf-8")])));
+        assert!(is_rewritable(&headers(&[("content-type", "application/json")])));
+        assert!(is_rewritable(&headers(&[("content-type", "Application/JavaScript"), ("content-encoding", "identity")])));
+        assert!(!is_rewritable(&headers(&[("content-type", "text/html"), ("content-encoding", "gzip")])));
+        assert!(!is_rewritable(&headers(&[("content-type", "image/png")])));
+        assert!(!is_rewritable(&headers(&[])));
+    }
+}
@@ -0,0 +1,1090 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{HeaderMap, StatusCode};
+use regex::Regex;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::error::Error;
+use std::hash::{BuildHasher, Hasher};
+use std::ops::RangeInclusive;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::geoip::GeoInfo;
+use crate::rewrite::Rewrite;
+use crate::throttle::Link;
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+pub type PortList = Vec<RangeInclusive<u16>>;
+
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+extern "C" {
+    fn tzset();
+}
+
+/*************************************************
+ * Action
+ *************************************************/
+
+enum Action {
+    Allow,
+    Deny,
+    /// `route direct` (`None`) or `route upstream <name>`.
+    Route(Option<String>),
+    /// At most `count` matching requests per `window` and user.
+    Limit { count: u32, window: Duration },
+    /// Send plain-HTTP clients elsewhere; `$1`... refer to `url` captures.
+    Redirect(String),
+    /// Edit the headers of forwarded plain-HTTP requests or responses.
+    Header { response: bool, edit: HeaderEdit },
+    /// Find/replace in plain-HTTP text responses.
+    Rewrite(Rewrite),
+    /// Largest plain-HTTP request (upload) or response (download) body.
+    Cap { upload: bool, bytes: u64 },
+    /// Whether CONNECT tunnels are intercepted (`intercept on|off`).
+    Intercept(bool),
+    /// Inject `fault` into this share of matching requests, from 0 to 1.
+    Fault { fault: FaultKind, probability: f64 },
+    /// Simulate a network link (`throttle 3g`).
+    Throttle(Link),
+}
+
+/*************************************************
+ * HeaderEdit
+ *************************************************/
+
+pub enum HeaderEdit {
+    Add(HeaderName, HeaderValue),
+    /// Set the header, dropping any values it had.
+    Replace(HeaderName, HeaderValue),
+    Remove(HeaderName),
+}
+
+impl HeaderEdit {
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        match self {
+            HeaderEdit::Add(name, value) => {
+                headers.append(name, value.clone());
+            }
+            HeaderEdit::Replace(name, value) => {
+                headers.insert(name, value.clone());
+            }
+            HeaderEdit::Remove(name) => {
+                headers.remove(name);
+            }
+        }
+    }
+}
+
+/*************************************************
+ * Fault
+ *************************************************/
+
+/// A failure a `fault` rule injects, for testing how clients cope with a
+/// flaky network.
+#[derive(Clone, Copy)]
+enum FaultKind {
+    /// Hold the request for a random time in the range.
+    Delay(Duration, Duration),
+    Reset,
+    Truncate,
+    Status(StatusCode),
+}
+
+/// A fault that fired for a request, its randomness settled.
+#[derive(Clone, Copy)]
+pub enum Fault {
+    /// Hold the request this long before forwarding it.
+    Delay(Duration),
+    /// Drop the client connection without an answer.
+    Reset,
+    /// Cut the response body off after this share of it.
+    Truncate(f64),
+    /// Answer with this status instead of forwarding.
+    Status(StatusCode),
+}
+
+impl FaultKind {
+    fn settle(self) -> Fault {
+        match self {
+            FaultKind::Delay(min, max) => Fault::Delay(min + (max - min).mul_f64(random())),
+            FaultKind::Reset => Fault::Reset,
+            FaultKind::Truncate => Fault::Truncate(random()),
+            FaultKind::Status(status) => Fault::Status(status),
+        }
+    }
+}
+
+/// A random number in `[0, 1)`.
+pub fn random() -> f64 {
+    (RandomState::new().build_hasher().finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/*************************************************
+ * Verdict
+ *************************************************/
+
+pub enum Verdict {
+    Allow,
+    /// Connect directly (`None`) or through the named upstream proxy.
+    Route(Option<String>),
+    Deny(usize),
+    RateLimited(usize),
+    Redirect(String),
+}
+
+/*************************************************
+ * Timezone
+ *************************************************/
+
+/// Zone that rule schedules are evaluated in.
+#[derive(Clone, Copy)]
+pub enum Timezone {
+    /// The system zone, or the zone named by `TZ`.
+    Local,
+    /// A fixed offset from UTC in seconds.
+    Fixed(i64),
+}
+
+impl Timezone {
+    /// `local`, `UTC`, an offset like `+05:30`, or a zoneinfo name such as
+    /// `Europe/Berlin` (which becomes the process's local zone).
+    pub fn parse(value: &str) -> Result<Self, Box<dyn Error>> {
+        if value == "local" {
+            return Ok(Timezone::Local);
+        }
+        if value.eq_ignore_ascii_case("utc") {
+            return Ok(Timezone::Fixed(0));
+        }
+        if let Some(offset) = value.strip_prefix(['+', '-']) {
+            let sign = if value.starts_with('-') { -1 } else { 1 };
+            let (hours, minutes) = offset.split_once(':').unwrap_or((offset, "0"));
+            let offset: i64 = hours.parse::<i64>()? * 3600 + minutes.parse::<i64>()? * 60;
+            return Ok(Timezone::Fixed(sign * offset));
+        }
+        if !std::path::Path::new("/usr/share/zoneinfo").join(value).is_file() {
+            return Err(format!("Error: Unknown timezone: {}", value).into());
+        }
+        std::env::set_var("TZ", value);
+        unsafe { tzset() };
+        Ok(Timezone::Local)
+    }
+
+    /// Current weekday (0 is Monday) and minute of the day.
+    fn now(&self) -> (u32, u32) {
+        match self {
+            Timezone::Local => {
+                let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+                let time = unsafe { libc::time(std::ptr::null_mut()) };
+                #[cfg(unix)]
+                unsafe { libc::localtime_r(&time, &mut tm) };
+                #[cfg(windows)]
+                unsafe { libc::localtime_s(&mut tm, &time) };
+                (((tm.tm_wday + 6) % 7) as u32, (tm.tm_hour * 60 + tm.tm_min) as u32)
+            }
+            Timezone::Fixed(offset) => {
+                let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64 + offset;
+                // 1970-01-01 was a Thursday.
+                let weekday = (secs.div_euclid(86400) + 3).rem_euclid(7);
+                (weekday as u32, (secs.rem_euclid(86400) / 60) as u32)
+            }
+        }
+    }
+}
+
+/*************************************************
+ * Schedule
+ *************************************************/
+
+/// Days of the week (bit 0 is Monday) and a daily window in minutes; the
+/// window wraps past midnight when it ends before it starts.
+struct Schedule {
+    days: u8,
+    start: u32,
+    end: u32,
+}
+
+impl Schedule {
+    fn matches(&self, (weekday, minute): (u32, u32)) -> bool {
+        let in_window = if self.start < self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        };
+        // After midnight, a wrapping window belongs to the previous day.
+        let day = if self.start >= self.end && minute < self.end { (weekday + 6) % 7 } else { weekday };
+        in_window && self.days & (1 << day) != 0
+    }
+}
+
+/// `mon-fri`, `sat,sun`, `mon,wed-fri`.
+fn parse_days(value: &str) -> Result<u8, Box<dyn Error>> {
+    let day = |name: &str| {
+        DAY_NAMES.iter()
+            .position(|day| name.eq_ignore_ascii_case(day))
+            .ok_or_else(|| format!("unknown day: {}", name))
+    };
+    let mut days = 0;
+    for item in value.split(',') {
+        let (first, last) = item.split_once('-').unwrap_or((item, item));
+        let (first, last) = (day(first)?, day(last)?);
+        let mut current = first;
+        loop {
+            days |= 1 << current;
+            if current == last {
+                break;
+            }
+            current = (current + 1) % 7;
+        }
+    }
+    Ok(days)
+}
+
+/// `09:00-17:00`.
+fn parse_window(value: &str) -> Result<(u32, u32), Box<dyn Error>> {
+    let minutes = |time: &str| -> Result<u32, Box<dyn Error>> {
+        let (hours, minutes) = time.split_once(':').ok_or_else(|| format!("expected HH:MM, got {}", time))?;
+        let (hours, minutes): (u32, u32) = (hours.parse()?, minutes.parse()?);
+        if hours > 24 || minutes > 59 || hours * 60 + minutes > 24 * 60 {
+            return Err(format!("invalid time: {}", time).into());
+        }
+        Ok(hours * 60 + minutes)
+    };
+    let (start, end) = value.split_once('-').ok_or("expected HH:MM-HH:MM")?;
+    Ok((minutes(start)?, minutes(end)?))
+}
+
+/*************************************************
+ * Rule
+ *************************************************/
+
+struct Rule {
+    line: usize,
+    action: Action,
+    host: Option<Regex>,
+    host_source: Option<String>,
+    ports: Option<PortList>,
+    user: Option<String>,
+    schedule: Option<Schedule>,
+    url: Option<Regex>,
+    countries: Option<Vec<String>>,
+    asns: Option<Vec<u32>>,
+    content_type: Option<Regex>,
+}
+
+impl Rule {
+    /// `url` is only known for plain-HTTP requests; rules with a URL
+    /// matcher never match tunnels. Likewise `country` and `asn` never match
+    /// a destination that couldn't be resolved or located. Rules with a
+    /// `type` matcher only take part in `evaluate_response`.
+    fn matches(&self, host: &str, port: u16, user: &str, url: Option<&str>, geo: Option<&GeoInfo>, now: (u32, u32)) -> bool {
+        self.content_type.is_none() && self.matches_request(host, port, user, url, geo, now)
+    }
+
+    fn matches_request(&self, host: &str, port: u16, user: &str, url: Option<&str>, geo: Option<&GeoInfo>, now: (u32, u32)) -> bool {
+        self.url.as_ref().is_none_or(|pattern| url.is_some_and(|url| pattern.is_match(url)))
+            && self.countries.as_ref().is_none_or(|countries| {
+                geo.and_then(|geo| geo.country.as_ref()).is_some_and(|country| countries.contains(country))
+            })
+            && self.asns.as_ref().is_none_or(|asns| geo.and_then(|geo| geo.asn).is_some_and(|asn| asns.contains(&asn)))
+            && self.host.as_ref().is_none_or(|pattern| pattern.is_match(host))
+            && self.ports.as_ref().is_none_or(|ports| ports.iter().any(|range| range.contains(&port)))
+            && self.user.as_ref().is_none_or(|name| name == user)
+            && self.schedule.as_ref().is_none_or(|schedule| schedule.matches(now))
+    }
+
+    fn needs_geo(&self) -> bool {
+        self.countries.is_some() || self.asns.is_some()
+    }
+}
+
+/*************************************************
+ * Rules
+ *************************************************/
+
+/// Destination policy read from a rules file and evaluated top to bottom.
+/// Each line is an action followed by optional matchers:
+///
+/// ```text
+/// deny  host ~^ads?\d*\.          # regex after `~`
+/// limit 60/min user guest         # not terminal, 429 once exceeded
+/// allow host *.example.com port 443,8443
+/// deny  port 25
+/// deny  host *.social.example days mon-fri time 09:00-17:00
+/// deny  user kid time 21:00-07:00
+/// deny  url */ads/*
+/// redirect https://example.com/$1 url ~^http://old\.example\.com/(.*)
+/// route direct host *.corp.example
+/// route upstream vpn host *.streaming.example
+/// route block host *.tracker.example   # same as deny
+/// deny  country KP,IR                  # destination address, via GeoIP
+/// route upstream eu asn AS3320
+/// header request remove X-Api-Key
+/// header request add X-Corp "Acme Inc" host *.partner.example
+/// header response replace Server rdnat
+/// rewrite http://internal:8080/ https://public.example/ host internal
+/// rewrite ~<body> "<body><p>Monitored</p>" url */portal/*
+/// deny  type application/x-msdownload user guest
+/// cap   download 100M user guest
+/// cap   upload 5M host *.paste.example
+/// intercept off host *.bank.example    # pinned or sensitive: blind tunnel
+/// fault delay 200ms-2s host api.staging.example
+/// fault status 503 10% url */checkout/*
+/// fault reset 5% user tester
+/// fault truncate 20% host cdn.staging.example
+/// throttle 3g user mobile-qa             # 2g, 3g, 4g or satellite
+/// ```
+///
+/// The first `allow`, `deny` or `route` rule that matches decides; when
+/// none does the request is allowed (and takes the default route).
+/// Every matching `header` and `rewrite` rule applies to plain-HTTP
+/// traffic, in order; of the matching `cap` rules the smallest wins. The
+/// first matching `intercept` rule decides whether a tunnel is intercepted
+/// under `--mitm-ca`. Every matching `fault` rule fires with its
+/// probability (always without one), and the first matching `throttle`
+/// rule picks the simulated link. Schedules are evaluated in `timezone`.
+pub struct Rules {
+    rules: Vec<Rule>,
+    hits: Mutex<HashMap<(usize, String), (Instant, u32)>>,
+    timezone: Timezone,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Rules {
+            rules: Vec::new(),
+            hits: Mutex::new(HashMap::new()),
+            timezone: Timezone::Local,
+        }
+    }
+}
+
+impl Rules {
+    pub fn set_timezone(&mut self, timezone: Timezone) {
+        self.timezone = timezone;
+    }
+
+    pub fn timezone(&self) -> Timezone {
+        self.timezone
+    }
+
+    pub fn load(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Error: Cannot read rules {}: {}", path, e))?;
+        for (lineno, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let rule = parse_rule(lineno + 1, line)
+                .map_err(|e| format!("Error: Invalid rule at {}:{}: {}", path, lineno + 1, e))?;
+            self.rules.push(rule);
+        }
+        Ok(())
+    }
+
+    /// `geo` describes the destination address; it is only needed when
+    /// rules match on it (`needs_country`, `needs_asn`).
+    pub fn evaluate(&self, host: &str, port: u16, user: &str, url: Option<&str>, geo: Option<&GeoInfo>) -> Verdict {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let now = self.timezone.now();
+        for (index, rule) in self.rules.iter().enumerate() {
+            if !rule.matches(&host, port, user, url, geo, now) {
+                continue;
+            }
+            match &rule.action {
+                Action::Allow => return Verdict::Allow,
+                Action::Route(upstream) => return Verdict::Route(upstream.clone()),
+                Action::Deny => return Verdict::Deny(rule.line),
+                Action::Redirect(target) => {
+                    let location = match (&rule.url, url) {
+                        (Some(pattern), Some(url)) => pattern.replace(url, target.as_str()).into_owned(),
+                        _ => target.clone(),
+                    };
+                    return Verdict::Redirect(location);
+                }
+                Action::Limit { count, window } => {
+                    if !self.take(index, user, *count, *window) {
+                        return Verdict::RateLimited(rule.line);
+                    }
+                }
+                Action::Header { .. } | Action::Rewrite(_) | Action::Cap { .. } | Action::Intercept(_) | Action::Fault { .. }
+                | Action::Throttle(_) => {}
+            }
+        }
+        Verdict::Allow
+    }
+
+    /// Judge a plain-HTTP response by its Content-Type: the first `allow` or
+    /// `deny` rule with a matching `type` decides. Returns the line of the
+    /// denying rule.
+    pub fn evaluate_response(&self, host: &str, port: u16, user: &str, url: &str, content_type: &str) -> Option<usize> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        let now = self.timezone.now();
+        self.rules.iter()
+            .filter(|rule| rule.content_type.as_ref().is_some_and(|pattern| pattern.is_match(media_type)))
+            .find(|rule| rule.matches_request(&host, port, user, Some(url), None, now))
+            .and_then(|rule| matches!(rule.action, Action::Deny).then_some(rule.line))
+    }
+
+    /// Edits of the `header` rules matching a plain-HTTP request, for the
+    /// request or for its response.
+    pub fn header_edits(&self, host: &str, port: u16, user: &str, url: &str, response: bool) -> Vec<&HeaderEdit> {
+        self.matching(host, port, user, url)
+            .into_iter()
+            .filter_map(|rule| match &rule.action {
+                Action::Header { response: applies, edit } if *applies == response => Some(edit),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Body rewrites of the `rewrite` rules matching a plain-HTTP request.
+    pub fn body_rewrites(&self, host: &str, port: u16, user: &str, url: &str) -> Vec<Rewrite> {
+        self.matching(host, port, user, url)
+            .into_iter()
+            .filter_map(|rule| match &rule.action {
+                Action::Rewrite(rewrite) => Some(rewrite.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Smallest upload and download caps of the `cap` rules matching a
+    /// plain-HTTP request.
+    pub fn body_caps(&self, host: &str, port: u16, user: &str, url: &str) -> (Option<u64>, Option<u64>) {
+        let mut caps = (None, None);
+        for rule in self.matching(host, port, user, url) {
+            if let Action::Cap { upload, bytes } = rule.action {
+                let cap = if upload { &mut caps.0 } else { &mut caps.1 };
+                *cap = Some(cap.map_or(bytes, |cap: u64| cap.min(bytes)));
+            }
+        }
+        caps
+    }
+
+    /// Whether a CONNECT tunnel may be intercepted: the first matching
+    /// `intercept` rule decides, and tunnels are intercepted by default.
+    pub fn intercepts(&self, host: &str, port: u16, user: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let now = self.timezone.now();
+        self.rules.iter()
+            .filter(|rule| rule.matches(&host, port, user, None, None, now))
+            .find_map(|rule| match rule.action {
+                Action::Intercept(intercept) => Some(intercept),
+                _ => None,
+            })
+            .unwrap_or(true)
+    }
+
+    /// The faults of the matching `fault` rules that fire this time, in
+    /// order; `url` is `None` for a tunnel, which only `fault` rules without
+    /// a `url` matcher apply to.
+    pub fn faults(&self, host: &str, port: u16, user: &str, url: Option<&str>) -> Vec<Fault> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let now = self.timezone.now();
+        self.rules.iter()
+            .filter(|rule| rule.matches(&host, port, user, url, None, now))
+            .filter_map(|rule| match rule.action {
+                Action::Fault { fault, probability } if random() < probability => Some(fault.settle()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The link the first matching `throttle` rule simulates; `url` is
+    /// `None` for a tunnel.
+    pub fn throttle(&self, host: &str, port: u16, user: &str, url: Option<&str>) -> Option<Link> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let now = self.timezone.now();
+        self.rules.iter()
+            .filter(|rule| rule.matches(&host, port, user, url, None, now))
+            .find_map(|rule| match rule.action {
+                Action::Throttle(link) => Some(link),
+                _ => None,
+            })
+    }
+
+    /// Rules matching a plain-HTTP request, without resolving it.
+    fn matching(&self, host: &str, port: u16, user: &str, url: &str) -> Vec<&Rule> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let now = self.timezone.now();
+        self.rules.iter().filter(|rule| rule.matches(&host, port, user, Some(url), None, now)).collect()
+    }
+
+    /// Proxy auto-config script that sends hosts routed `direct` straight
+    /// to the origin and everything else to `proxy` (`host:port`). Only
+    /// rules matching on the host alone can be expressed; the others are
+    /// left to the proxy.
+    pub fn pac(&self, proxy: &str) -> String {
+        let through_proxy = format!("PROXY {}", proxy);
+        let mut script = String::from("function FindProxyForURL(url, host) {\n");
+        script.push_str("    if (isPlainHostName(host)) {\n        return \"DIRECT\";\n    }\n");
+        for rule in &self.rules {
+            let Some(source) = &rule.host_source else {
+                continue;
+            };
+            if rule.ports.is_some() || rule.user.is_some() || rule.schedule.is_some() || rule.url.is_some() || rule.needs_geo() || rule.content_type.is_some() {
+                continue;
+            }
+            let result = match rule.action {
+                Action::Route(None) => "DIRECT",
+                Action::Limit { .. } | Action::Header { .. } | Action::Rewrite(_) | Action::Cap { .. } | Action::Intercept(_)
+                | Action::Fault { .. } | Action::Throttle(_) => continue,
+                _ => through_proxy.as_str(),
+            };
+            let condition = match source.strip_prefix('~') {
+                Some(regex) => format!("/{}/i.test(host)", regex.replace('/', "\\/")),
+                None => format!("shExpMatch(host.toLowerCase(), \"{}\")", source.to_ascii_lowercase().replace(['\\', '"'], "")),
+            };
+            script.push_str(&format!("    if ({}) {{\n        return \"{}\";\n    }}\n", condition, result));
+        }
+        script.push_str(&format!("    return \"{}\";\n}}\n", through_proxy));
+        script
+    }
+
+    /// Whether any rule matches on the destination's country.
+    pub fn needs_country(&self) -> bool {
+        self.rules.iter().any(|rule| rule.countries.is_some())
+    }
+
+    /// Whether any rule matches on the destination's ASN.
+    pub fn needs_asn(&self) -> bool {
+        self.rules.iter().any(|rule| rule.asns.is_some())
+    }
+
+    /// Upstream proxies named by `route upstream` rules.
+    pub fn upstream_names(&self) -> impl Iterator<Item = &str> {
+        self.rules.iter().filter_map(|rule| match &rule.action {
+            Action::Route(Some(name)) => Some(name.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Count a hit against a `limit` rule; false once the window is full.
+    fn take(&self, index: usize, user: &str, count: u32, window: Duration) -> bool {
+        let mut hits = self.hits.lock().unwrap();
+        let now = Instant::now();
+        let entry = hits.entry((index, user.to_string())).or_insert((now, 0));
+        if now.duration_since(entry.0) >= window {
+            *entry = (now, 0);
+        }
+        entry.1 += 1;
+        entry.1 <= count
+    }
+}
+
+fn parse_rule(line: usize, text: &str) -> Result<Rule, Box<dyn Error>> {
+    let words = split_words(text)?;
+    let mut words = words.iter().map(String::as_str).peekable();
+    let action = match words.next() {
+        Some("allow") => Action::Allow,
+        Some("deny") => Action::Deny,
+        Some("route") => match words.next() {
+            Some("direct") => Action::Route(None),
+            Some("upstream") => Action::Route(Some(words.next().ok_or("missing upstream name")?.to_string())),
+            Some("block") => Action::Deny,
+            Some(target) => return Err(format!("unsupported route target: {}", target).into()),
+            None => return Err("missing route target".into()),
+        },
+        Some("limit") => {
+            let rate = words.next().ok_or("missing rate")?;
+            let (count, unit) = rate.split_once('/').ok_or("expected <count>/<s|min|h>")?;
+            let window = match unit {
+                "s" => Duration::from_secs(1),
+                "min" => Duration::from_secs(60),
+                "h" => Duration::from_secs(3600),
+                unit => return Err(format!("unknown rate unit: {}", unit).into()),
+            };
+            Action::Limit { count: count.parse()?, window }
+        }
+        Some("redirect") => Action::Redirect(words.next().ok_or("missing redirect target")?.to_string()),
+        Some("header") => {
+            let response = match words.next() {
+                Some("request") => false,
+                Some("response") => true,
+                _ => return Err("expected header request|response".into()),
+            };
+            let operation = words.next().ok_or("missing header operation")?;
+            let name = HeaderName::from_bytes(words.next().ok_or("missing header name")?.as_bytes())?;
+            let mut value = || -> Result<HeaderValue, Box<dyn Error>> {
+                Ok(HeaderValue::from_str(words.next().ok_or("missing header value")?)?)
+            };
+            let edit = match operation {
+                "add" => HeaderEdit::Add(name, value()?),
+                "replace" => HeaderEdit::Replace(name, value()?),
+                "remove" => HeaderEdit::Remove(name),
+                operation => return Err(format!("unknown header operation: {}", operation).into()),
+            };
+            Action::Header { response, edit }
+        }
+        Some("cap") => {
+            let upload = match words.next() {
+                Some("upload") => true,
+                Some("download") => false,
+                _ => return Err("expected cap upload|download".into()),
+            };
+            Action::Cap { upload, bytes: parse_size(words.next().ok_or("missing cap size")?)? }
+        }
+        Some("intercept") => match words.next() {
+            Some("on") => Action::Intercept(true),
+            Some("off") => Action::Intercept(false),
+            _ => return Err("expected intercept on|off".into()),
+        },
+        Some("rewrite") => {
+            let find = words.next().ok_or("missing rewrite pattern")?;
+            let replacement = words.next().ok_or("missing rewrite replacement")?;
+            Action::Rewrite(Rewrite::parse(find, replacement)?)
+        }
+        Some("fault") => {
+            let fault = match words.next() {
+                Some("delay") => {
+                    let delay = words.next().ok_or("missing fault delay")?;
+                    let (min, max) = delay.split_once('-').unwrap_or((delay, delay));
+                    let (min, max) = (parse_duration(min)?, parse_duration(max)?);
+                    if min > max {
+                        return Err(format!("invalid delay range: {}", delay).into());
+                    }
+                    FaultKind::Delay(min, max)
+                }
+                Some("reset") => FaultKind::Reset,
+                Some("truncate") => FaultKind::Truncate,
+                Some("status") => {
+                    let status = words.next().ok_or("missing fault status")?;
+                    FaultKind::Status(StatusCode::from_u16(status.parse()?).map_err(|_| format!("invalid status: {}", status))?)
+                }
+                _ => return Err("expected fault delay|reset|truncate|status".into()),
+            };
+            let probability = match words.next_if(|word| word.ends_with('%')) {
+                Some(percent) => {
+                    let percent: f64 = percent.trim_end_matches('%').parse()?;
+                    if !(0.0..=100.0).contains(&percent) {
+                        return Err(format!("invalid fault probability: {}%", percent).into());
+                    }
+                    percent / 100.0
+                }
+                None => 1.0,
+            };
+            Action::Fault { fault, probability }
+        }
+        Some("throttle") => {
+            let name = words.next().ok_or("missing network profile")?;
+            Action::Throttle(Link::named(name).ok_or_else(|| format!("unknown network profile: {}", name))?)
+        }
+        Some(action) => return Err(format!("unknown action: {}", action).into()),
+        None => return Err("missing action".into()),
+    };
+
+    let mut rule = Rule {
+        line,
+        action,
+        host: None,
+        host_source: None,
+        ports: None,
+        user: None,
+        schedule: None,
+        url: None,
+        countries: None,
+        asns: None,
+        content_type: None,
+    };
+    while let Some(key) = words.next() {
+        let value = words.next().ok_or_else(|| format!("missing value for {}", key))?;
+        match key {
+            "host" => {
+                rule.host = Some(host_pattern(value)?);
+                rule.host_source = Some(value.to_string());
+            }
+            // Wildcards are normalized like the URLs they are matched against.
+            "url" if value.starts_with('~') => rule.url = Some(url_pattern(value)?),
+            "url" => rule.url = Some(url_pattern(&normalize_url(value))?),
+            "type" => rule.content_type = Some(host_pattern(value)?),
+            "port" => rule.ports = Some(parse_ports(value)?),
+            "country" => rule.countries = Some(value.split(',').map(str::to_ascii_uppercase).collect()),
+            "asn" => {
+                let asns = value.split(',')
+                    .map(|asn| asn.trim_start_matches("AS").trim_start_matches("as").parse())
+                    .collect::<Result<_, _>>()?;
+                rule.asns = Some(asns);
+            }
+            "user" => rule.user = Some(value.to_string()),
+            "days" => {
+                let schedule = rule.schedule.get_or_insert(Schedule { days: 0x7f, start: 0, end: 24 * 60 });
+                schedule.days = parse_days(value)?;
+            }
+            "time" => {
+                let schedule = rule.schedule.get_or_insert(Schedule { days: 0x7f, start: 0, end: 24 * 60 });
+                (schedule.start, schedule.end) = parse_window(value)?;
+            }
+            key => return Err(format!("unknown matcher: {}", key).into()),
+        }
+    }
+    if rule.content_type.is_some() && !matches!(rule.action, Action::Allow | Action::Deny) {
+        return Err("only allow and deny rules can match on type".into());
+    }
+    if rule.content_type.is_some() && rule.needs_geo() {
+        return Err("type rules can't match on country or asn".into());
+    }
+    // Header, rewrite and cap rules are applied without resolving the
+    // destination.
+    if matches!(rule.action, Action::Header { .. } | Action::Rewrite(_) | Action::Cap { .. } | Action::Fault { .. } | Action::Throttle(_))
+        && rule.needs_geo()
+    {
+        return Err("header, rewrite, cap, fault and throttle rules can't match on country or asn".into());
+    }
+    // The decision is made at CONNECT time, before any URL is seen.
+    if matches!(rule.action, Action::Intercept(_)) && (rule.url.is_some() || rule.needs_geo()) {
+        return Err("intercept rules can't match on url, country or asn".into());
+    }
+    Ok(rule)
+}
+
+/// Whitespace-separated words; double quotes keep a value with spaces
+/// together. A `#` starting a word starts a comment, so values and URLs may
+/// hold one.
+fn split_words(text: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut words = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() && !rest.starts_with('#') {
+        let (word, tail) = match rest.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').ok_or("unterminated quote")?,
+            None => rest.split_once(char::is_whitespace).unwrap_or((rest, "")),
+        };
+        words.push(word.to_string());
+        rest = tail.trim_start();
+    }
+    Ok(words)
+}
+
+/// `~regex`, or a wildcard pattern where `*` matches any run of characters.
+fn host_pattern(pattern: &str) -> Result<Regex, Box<dyn Error>> {
+    let regex = match pattern.strip_prefix('~') {
+        Some(regex) => format!("(?i){}", regex),
+        None => format!("(?i)^{}$", regex::escape(pattern).replace(r"\*", ".*")),
+    };
+    Ok(Regex::new(&regex)?)
+}
+
+/// Like `host_pattern`, over the full URL (see `normalize_url`) and
+/// case-sensitive.
+pub fn url_pattern(pattern: &str) -> Result<Regex, Box<dyn Error>> {
+    let regex = match pattern.strip_prefix('~') {
+        Some(regex) => String::from(regex),
+        None => format!("^{}$", regex::escape(pattern).replace(r"\*", ".*")),
+    };
+    Ok(Regex::new(&regex)?)
+}
+
+/// `url` the way URL rules see it, so spelling a URL differently doesn't
+/// get around them: the scheme and host lowercased, the scheme's default
+/// port dropped, percent-escapes of unreserved characters decoded and the
+/// rest written in uppercase hex (RFC 3986, section 6.2.2).
+pub fn normalize_url(url: &str) -> String {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return String::from(url);
+    };
+    let scheme = scheme.to_ascii_lowercase();
+    let (authority, path) = rest.split_at(rest.find(['/', '?', '#']).unwrap_or(rest.len()));
+    let (userinfo, host_port) = match authority.rsplit_once('@') {
+        Some((userinfo, host_port)) => (Some(userinfo), host_port),
+        None => (None, authority),
+    };
+    let (host, port) = match host_port.rfind(':') {
+        Some(colon) if !host_port[colon..].contains(']') => (&host_port[..colon], Some(&host_port[colon + 1..])),
+        _ => (host_port, None),
+    };
+    let default_port = match scheme.as_str() {
+        "http" | "ws" => Some("80"),
+        "https" | "wss" => Some("443"),
+        _ => None,
+    };
+
+    let mut normalized = format!("{}://", scheme);
+    if let Some(userinfo) = userinfo {
+        normalized.push_str(userinfo);
+        normalized.push('@');
+    }
+    normalized.push_str(&host.to_ascii_lowercase());
+    match port {
+        Some(port) if !port.is_empty() && Some(port) != default_port => {
+            normalized.push(':');
+            normalized.push_str(port);
+        }
+        _ => {}
+    }
+    let mut rest = path;
+    while let Some(percent) = rest.find('%') {
+        normalized.push_str(&rest[..percent]);
+        let escaped = rest.get(percent + 1..percent + 3)
+            .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') => normalized.push(byte as char),
+            Some(byte) => normalized.push_str(&format!("%{:02X}", byte)),
+            None => {
+                normalized.push('%');
+                rest = &rest[percent + 1..];
+                continue;
+            }
+        }
+        rest = &rest[percent + 3..];
+    }
+    normalized.push_str(rest);
+    normalized
+}
+
+/*************************************************
+ * parse_ports
+ *************************************************/
+
+/// Parse a comma-separated list of ports and `low-high` ranges.
+pub fn parse_ports(value: &str) -> Result<PortList, Box<dyn Error>> {
+    value.split(',')
+        .map(|item| {
+            let item = item.trim();
+            let (low, high) = item.split_once('-').unwrap_or((item, item));
+            let (low, high): (u16, u16) = (low.parse()?, high.parse()?);
+            if low == 0 || low > high {
+                return Err(format!("Error: Invalid port range: {}", item).into());
+            }
+            Ok(low..=high)
+        })
+        .collect()
+}
+
+/*************************************************
+ * parse_size
+ *************************************************/
+
+/// Parse a byte count with an optional `K`, `M` or `G` suffix (powers of 1024).
+pub fn parse_size(value: &str) -> Result<u64, Box<dyn Error>> {
+    let (number, unit) = match value.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((index, _)) => value.split_at(index),
+        None => (value, ""),
+    };
+    let multiplier: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        _ => return Err(format!("Error: Invalid size: {}", value).into()),
+    };
+    number.parse::<u64>()?.checked_mul(multiplier).ok_or_else(|| format!("Error: Invalid size: {}", value).into())
+}
+
+/// Parse a duration in whole `ms`, `s` or `min`: `250ms`, `2s`.
+fn parse_duration(value: &str) -> Result<Duration, Box<dyn Error>> {
+    let (number, unit) = match value.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((index, _)) => value.split_at(index),
+        None => (value, ""),
+    };
+    let number: u64 = number.parse().map_err(|_| format!("invalid duration: {}", value))?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(number)),
+        "s" => Ok(Duration::from_secs(number)),
+        "min" => Ok(Duration::from_secs(number * 60)),
+        _ => Err(format!("invalid duration: {}", value).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comments_start_only_at_a_word() {
+        assert_eq!(split_words("deny url */page#top # old").unwrap(), ["deny", "url", "*/page#top"]);
+        assert_eq!(split_words(r##"header request add X-Tag "a #1" #note"##).unwrap(), ["header", "request", "add", "X-Tag", "a #1"]);
+        assert_eq!(split_words("allow#all").unwrap(), ["allow#all"]);
+        assert!(split_words("# deny port 25").unwrap().is_empty());
+    }
+
+    #[test]
+    fn load_keeps_values_holding_a_hash() {
+        let path = std::env::temp_dir().join(format!("rdnat-rules-{}.txt", std::process::id()));
+        std::fs::write(&path, "# comment\n\n  # indented comment\ndeny url http://example.com/#frag # why\nheader response add X-Color \"#fff\"\n").unwrap();
+        let mut rules = Rules::default();
+        let loaded = rules.load(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        loaded.unwrap();
+
+        assert_eq!(rules.rules.len(), 2);
+        assert!(matches!(rules.evaluate("example.com", 80, "-", Some("http://example.com/#frag"), None), Verdict::Deny(4)));
+        assert!(matches!(rules.evaluate("example.com", 80, "-", Some("http://example.com/"), None), Verdict::Allow));
+        let mut headers = HeaderMap::new();
+        for edit in rules.header_edits("example.com", 80, "-", "http://example.com/", true) {
+            edit.apply(&mut headers);
+        }
+        assert_eq!(headers["x-color"], "#fff");
+    }
+
+    #[test]
+    fn parses_port_lists() {
+        assert_eq!(parse_ports("80").unwrap(), vec![80..=80]);
+        assert_eq!(parse_ports("80, 443,8000-8100").unwrap(), vec![80..=80, 443..=443, 8000..=8100]);
+        assert_eq!(parse_ports("1-65535").unwrap(), vec![1..=65535]);
+    }
+
+    #[test]
+    fn rejects_port_zero_and_reversed_ranges() {
+        for ports in ["0", "0-80", "9000-80", "80,", "65536", "-80", "http"] {
+            assert!(parse_ports(ports).is_err(), "accepted {:?}", ports);
+        }
+        assert!(parse_rule(1, "deny port 9000-80").is_err());
+    }
+
+    fn rules(text: &str) -> Rules {
+        let mut rules = Rules::default();
+        for (index, line) in text.lines().enumerate() {
+            rules.rules.push(parse_rule(index + 1, line).unwrap());
+        }
+        rules
+    }
+
+    #[test]
+    fn rejects_malformed_rules() {
+        for text in [
+            "",
+            "permit host a.example",
+            "deny host",
+            "deny colour red",
+            "route sideways",
+            "route upstream",
+            "limit 10/day",
+            "limit ten/min",
+            "header request add X-Only-Name",
+            "header both remove X-A",
+            "cap upload lots",
+            "intercept maybe",
+            "fault delay 2s-1s",
+            "fault status 99",
+            "fault reset 150%",
+            "throttle carrier-pigeon",
+            "deny days mon-funday",
+            "deny time 09:00-25:00",
+            r#"header request add X-A "unterminated"#,
+            "route direct type text/html",
+            "deny type text/html country DE",
+            "header request remove X-A country DE",
+            "intercept off url */login",
+        ] {
+            assert!(parse_rule(1, text).is_err(), "accepted {:?}", text);
+        }
+    }
+
+    #[test]
+    fn url_rules_match_however_the_url_is_spelled() {
+        assert_eq!(normalize_url("HTTP://Example.COM:80/a%2db/%7euser?q=%2f%zz"), "http://example.com/a-b/~user?q=%2F%zz");
+        assert_eq!(normalize_url("https://[2001:DB8::1]:443/"), "https://[2001:db8::1]/");
+        assert_eq!(normalize_url("http://user@Example.com:8080"), "http://user@example.com:8080");
+        assert_eq!(normalize_url("/relative/%41"), "/relative/%41");
+
+        let rules = rules("deny url http://Example.com/ads/*");
+        for url in ["http://example.com/ads/x", "http://EXAMPLE.com/ads/x", "http://example.com:80/ads/x", "http://example.com/%61ds/x"] {
+            assert!(matches!(rules.evaluate("example.com", 80, "-", Some(&normalize_url(url)), None), Verdict::Deny(1)), "{}", url);
+        }
+        assert!(matches!(rules.evaluate("example.com", 8080, "-", Some(&normalize_url("http://example.com:8080/ads/x")), None), Verdict::Allow));
+    }
+
+    #[test]
+    fn first_deciding_rule_wins() {
+        let rules = rules("allow host ok.example\ndeny host *.example\nroute upstream vpn host *.stream.test\nroute direct port 8080");
+        assert!(matches!(rules.evaluate("ok.example", 443, "-", None, None), Verdict::Allow));
+        assert!(matches!(rules.evaluate("ads.example", 443, "-", None, None), Verdict::Deny(2)));
+        assert!(matches!(rules.evaluate("ADS.Example.", 443, "-", None, None), Verdict::Deny(2)));
+        assert!(matches!(rules.evaluate("tv.stream.test", 443, "-", None, None), Verdict::Route(Some(name)) if name == "vpn"));
+        assert!(matches!(rules.evaluate("other.test", 8080, "-", None, None), Verdict::Route(None)));
+        assert!(matches!(rules.evaluate("other.test", 443, "-", None, None), Verdict::Allow));
+        assert_eq!(rules.upstream_names().collect::<Vec<_>>(), ["vpn"]);
+    }
+
+    #[test]
+    fn matchers_must_all_match() {
+        let rules = rules("deny host ~^ads?\\d*\\. port 80,8000-8100 user guest\ndeny url */private/*");
+        assert!(matches!(rules.evaluate("ads1.example", 8050, "guest", None, None), Verdict::Deny(1)));
+        assert!(matches!(rules.evaluate("ad.example", 80, "guest", None, None), Verdict::Deny(1)));
+        assert!(matches!(rules.evaluate("ads1.example", 443, "guest", None, None), Verdict::Allow));
+        assert!(matches!(rules.evaluate("ads1.example", 80, "alice", None, None), Verdict::Allow));
+        assert!(matches!(rules.evaluate("bads.example", 80, "guest", None, None), Verdict::Allow));
+        // URL matchers only apply to plain-HTTP requests.
+        assert!(matches!(rules.evaluate("a.example", 80, "-", Some("http://a.example/private/x"), None), Verdict::Deny(2)));
+        assert!(matches!(rules.evaluate("a.example", 443, "-", None, None), Verdict::Allow));
+    }
+
+    #[test]
+    fn redirects_fill_in_url_captures() {
+        let rules = rules(r"redirect https://new.example/$1 url ~^http://old\.example/(.*)");
+        let verdict = rules.evaluate("old.example", 80, "-", Some("http://old.example/a/b?c=1"), None);
+        assert!(matches!(verdict, Verdict::Redirect(location) if location == "https://new.example/a/b?c=1"));
+    }
+
+    #[test]
+    fn limits_count_per_user_and_fall_through() {
+        let rules = rules("limit 2/min user guest\ndeny host blocked.example");
+        for _ in 0..2 {
+            assert!(matches!(rules.evaluate("a.example", 443, "guest", None, None), Verdict::Allow));
+        }
+        assert!(matches!(rules.evaluate("a.example", 443, "guest", None, None), Verdict::RateLimited(1)));
+        assert!(matches!(rules.evaluate("a.example", 443, "alice", None, None), Verdict::Allow));
+        assert!(matches!(rules.evaluate("blocked.example", 443, "alice", None, None), Verdict::Deny(2)));
+    }
+
+    #[test]
+    fn applies_response_types_caps_and_interception() {
+        let rules = rules("deny type application/x-msdownload user guest\ncap download 10M\ncap download 1M host *.cdn.example\ncap upload 5K\nintercept off host *.bank.example");
+        let url = "http://a.example/setup.exe";
+        assert_eq!(rules.evaluate_response("a.example", 80, "guest", url, "application/x-msdownload; q=1"), Some(1));
+        assert_eq!(rules.evaluate_response("a.example", 80, "alice", url, "application/x-msdownload"), None);
+        assert!(matches!(rules.evaluate("a.example", 80, "guest", Some(url), None), Verdict::Allow));
+        assert_eq!(rules.body_caps("a.cdn.example", 80, "-", url), (Some(5 << 10), Some(1 << 20)));
+        assert_eq!(rules.body_caps("a.example", 80, "-", url), (Some(5 << 10), Some(10 << 20)));
+        assert!(!rules.intercepts("www.bank.example", 443, "-"));
+        assert!(rules.intercepts("www.example", 443, "-"));
+    }
+
+    #[test]
+    fn pac_only_routes_host_rules_direct() {
+        let pac = rules("route direct host *.corp.example\nroute direct host intranet port 80\ndeny host ads.example").pac("proxy:8000");
+        assert!(pac.contains(r#"shExpMatch(host.toLowerCase(), "*.corp.example")"#));
+        assert!(!pac.contains("intranet"));
+        assert!(pac.contains("return \"PROXY proxy:8000\";"));
+    }
+
+    #[test]
+    fn parses_timezones() {
+        assert!(matches!(Timezone::parse("UTC").unwrap(), Timezone::Fixed(0)));
+        assert!(matches!(Timezone::parse("+05:30").unwrap(), Timezone::Fixed(19800)));
+        assert!(matches!(Timezone::parse("-08").unwrap(), Timezone::Fixed(-28800)));
+        assert!(matches!(Timezone::parse("local").unwrap(), Timezone::Local));
+        assert!(Timezone::parse("Mars/Olympus_Mons").is_err());
+        assert!(Timezone::parse("+xx:00").is_err());
+    }
+
+    #[test]
+    fn schedules_wrap_past_midnight() {
+        let (mon, tue, sat) = (0, 1, 5);
+        let office = Schedule { days: parse_days("mon-fri").unwrap(), start: 9 * 60, end: 17 * 60 };
+        assert!(office.matches((mon, 9 * 60)));
+        assert!(!office.matches((mon, 17 * 60)));
+        assert!(!office.matches((sat, 12 * 60)));
+
+        let (start, end) = parse_window("21:00-07:00").unwrap();
+        let night = Schedule { days: parse_days("mon").unwrap(), start, end };
+        assert!(night.matches((mon, 22 * 60)));
+        // Tuesday morning is still Monday night.
+        assert!(night.matches((tue, 3 * 60)));
+        assert!(!night.matches((tue, 22 * 60)));
+        assert!(!night.matches((mon, 3 * 60)));
+
+        assert_eq!(parse_days("fri-mon").unwrap(), 0b111_0001);
+        assert_eq!(parse_days("sat,sun").unwrap(), 0b110_0000);
+        assert!(parse_window("24:00-24:01").is_err());
+    }
+}
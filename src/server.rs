@@ -0,0 +1,129 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use std::error::Error;
+use std::future::Future;
+use crate::rules::parse_size;
+use crate::upstream::Pool;
+use crate::{build_runtime, serve, Config};
+
+/*************************************************
+ * ProxyServer
+ *************************************************/
+
+/// An rdnat proxy set up to run inside another program, built with
+/// [`ProxyServer::builder`].
+pub struct ProxyServer {
+    config: Config,
+}
+
+impl ProxyServer {
+    pub fn builder() -> ProxyServerBuilder {
+        ProxyServerBuilder { config: Config::default(), error: None }
+    }
+
+    /// Run the proxy on a runtime of its own until Ctrl-C.
+    pub fn run(self) -> Result<(), Box<dyn Error>> {
+        build_runtime(&self.config)?.block_on(self.serve())
+    }
+
+    /// Run the proxy on the current tokio runtime until Ctrl-C.
+    pub async fn serve(self) -> Result<(), Box<dyn Error>> {
+        serve(self.config, false, tokio::signal::ctrl_c()).await
+    }
+
+    /// Run the proxy on the current tokio runtime until `signal`
+    /// completes, then stop accepting connections; those open are left
+    /// to finish.
+    pub async fn serve_with_shutdown(self, signal: impl Future<Output = ()>) -> Result<(), Box<dyn Error>> {
+        serve(self.config, false, async {
+            signal.await;
+            Ok(())
+        }).await
+    }
+}
+
+/*************************************************
+ * ProxyServerBuilder
+ *************************************************/
+
+/// Sets up a [`ProxyServer`]. A setting that doesn't take is reported by
+/// `build`, which also checks the settings against each other.
+pub struct ProxyServerBuilder {
+    config: Config,
+    /// The first setting that failed.
+    error: Option<Box<dyn Error>>,
+}
+
+impl ProxyServerBuilder {
+    /// Port the proxy listens on, 8000 by default.
+    pub fn port(mut self, port: u16) -> Self {
+        self.config.port = port.to_string();
+        self
+    }
+
+    /// Require clients to log in with `username` and `password`.
+    pub fn auth(mut self, username: &str, password: &str) -> Self {
+        self.config.username = username.to_string();
+        self.config.password = password.to_string();
+        self
+    }
+
+    /// Chain through upstream proxies, `http://` or `socks5://` URLs
+    /// separated by commas.
+    pub fn upstream(self, url: &str) -> Self {
+        self.try_set(|config| {
+            config.upstream = Some(Pool::parse(url)?);
+            Ok(())
+        })
+    }
+
+    /// Load access rules from a file.
+    pub fn rules(self, path: &str) -> Self {
+        self.try_set(|config| config.rules.load(path))
+    }
+
+    /// Let clients reach private and loopback addresses.
+    pub fn allow_private(mut self) -> Self {
+        self.config.allow_private = true;
+        self
+    }
+
+    /// Cache responses in memory, up to `size` (e.g. `64M`).
+    pub fn cache_size(self, size: &str) -> Self {
+        self.try_set(|config| {
+            config.cache_size = Some(parse_size(size)? as usize);
+            Ok(())
+        })
+    }
+
+    /// Log at debug level to the file at `path`; otherwise log records go
+    /// to the logger of the host program, if it installed one.
+    pub fn log_file(mut self, path: &str) -> Self {
+        self.config.log_path = Some(path.to_string());
+        self
+    }
+
+    /// Take commands like `rdnat ctl` sends on a Unix socket at `path`;
+    /// off by default.
+    pub fn ctl_socket(mut self, path: &str) -> Self {
+        self.config.ctl_socket = Some(path.to_string());
+        self
+    }
+
+    pub fn build(mut self) -> Result<ProxyServer, Box<dyn Error>> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        self.config.finish()?;
+        Ok(ProxyServer { config: self.config })
+    }
+
+    fn try_set(mut self, set: impl FnOnce(&mut Config) -> Result<(), Box<dyn Error>>) -> Self {
+        if self.error.is_none() {
+            self.error = set(&mut self.config).err();
+        }
+        self
+    }
+}
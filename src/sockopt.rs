@@ -0,0 +1,195 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use std::io;
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, RawFd};
+use std::time::Duration;
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+/// Bit 1 enables Fast Open for outgoing connections, bit 2 for listeners.
+#[cfg(target_os = "linux")]
+const FASTOPEN_SYSCTL: &str = "/proc/sys/net/ipv4/tcp_fastopen";
+pub const FASTOPEN_CLIENT: u32 = 1;
+pub const FASTOPEN_SERVER: u32 = 2;
+/// The option setting the idle time before keepalive probes start.
+#[cfg(all(unix, target_vendor = "apple"))]
+const KEEPALIVE_IDLE: libc::c_int = libc::TCP_KEEPALIVE;
+#[cfg(all(unix, not(target_vendor = "apple")))]
+const KEEPALIVE_IDLE: libc::c_int = libc::TCP_KEEPIDLE;
+
+/*************************************************
+ * SocketOptions
+ *************************************************/
+
+/// TCP options applied to both sides of the relay: accepted client
+/// connections and connections to targets and upstream proxies.
+#[derive(Clone, Default)]
+pub struct SocketOptions {
+    keepalive: Option<Keepalive>,
+    user_timeout: Option<Duration>,
+    nodelay: bool,
+    send_buffer: Option<u32>,
+    recv_buffer: Option<u32>,
+}
+
+/// Keepalive probing: after `idle` without traffic, a probe every
+/// `interval` (the kernel default when unset), giving up after `count`.
+#[derive(Clone, Copy)]
+pub struct Keepalive {
+    pub idle: Duration,
+    pub interval: Option<Duration>,
+    pub count: Option<u32>,
+}
+
+impl SocketOptions {
+    pub fn set_keepalive(&mut self, keepalive: Option<Keepalive>) {
+        self.keepalive = keepalive;
+    }
+
+    pub fn keepalive(&self) -> Option<Keepalive> {
+        self.keepalive
+    }
+
+    /// How long sent data may stay unacknowledged before the connection
+    /// is dropped (TCP_USER_TIMEOUT, Linux only).
+    pub fn set_user_timeout(&mut self, timeout: Option<Duration>) {
+        self.user_timeout = timeout;
+    }
+
+    /// Send small writes at once rather than coalescing them (TCP_NODELAY).
+    pub fn set_nodelay(&mut self, nodelay: bool) {
+        self.nodelay = nodelay;
+    }
+
+    /// Socket buffer sizes in bytes (SO_SNDBUF / SO_RCVBUF); the kernel
+    /// doubles them and caps them at net.core.wmem_max / rmem_max.
+    pub fn set_buffers(&mut self, send: Option<u32>, recv: Option<u32>) {
+        self.send_buffer = send;
+        self.recv_buffer = recv;
+    }
+}
+
+#[cfg(unix)]
+impl SocketOptions {
+    /// Set the buffer sizes on a listener too: the window scale is agreed
+    /// in the handshake, before an accepted connection can be configured,
+    /// so accepted connections have to inherit them.
+    pub fn apply_listener<S: AsRawFd>(&self, listener: &S) -> io::Result<()> {
+        self.apply_buffers(listener.as_raw_fd())
+    }
+
+    pub fn apply<S: AsRawFd>(&self, socket: &S) -> io::Result<()> {
+        let fd = socket.as_raw_fd();
+        self.apply_buffers(fd)?;
+        if self.nodelay {
+            set_option(fd, libc::IPPROTO_TCP, libc::TCP_NODELAY, 1)?;
+        }
+        if let Some(keepalive) = self.keepalive {
+            set_option(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+            set_option(fd, libc::IPPROTO_TCP, KEEPALIVE_IDLE, secs(keepalive.idle))?;
+            if let Some(interval) = keepalive.interval {
+                set_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, secs(interval))?;
+            }
+            if let Some(count) = keepalive.count {
+                set_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, count.min(i32::MAX as u32) as libc::c_int)?;
+            }
+        }
+        if let Some(timeout) = self.user_timeout {
+            #[cfg(target_os = "linux")]
+            set_option(fd, libc::IPPROTO_TCP, libc::TCP_USER_TIMEOUT, timeout.as_millis().min(i32::MAX as u128) as libc::c_int)?;
+            #[cfg(not(target_os = "linux"))]
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("TCP_USER_TIMEOUT of {:?} is not supported on this platform", timeout)));
+        }
+        Ok(())
+    }
+
+    fn apply_buffers(&self, fd: RawFd) -> io::Result<()> {
+        if let Some(size) = self.send_buffer {
+            set_option(fd, libc::SOL_SOCKET, libc::SO_SNDBUF, size.min(i32::MAX as u32) as libc::c_int)?;
+        }
+        if let Some(size) = self.recv_buffer {
+            set_option(fd, libc::SOL_SOCKET, libc::SO_RCVBUF, size.min(i32::MAX as u32) as libc::c_int)?;
+        }
+        Ok(())
+    }
+}
+
+/// Without setsockopt none of the options can be set, so asking for any
+/// fails.
+#[cfg(not(unix))]
+impl SocketOptions {
+    pub fn apply_listener<S>(&self, _listener: &S) -> io::Result<()> {
+        self.unsupported()
+    }
+
+    pub fn apply<S>(&self, _socket: &S) -> io::Result<()> {
+        self.unsupported()
+    }
+
+    fn unsupported(&self) -> io::Result<()> {
+        let any = self.nodelay || self.keepalive.is_some() || self.user_timeout.is_some()
+            || self.send_buffer.is_some() || self.recv_buffer.is_some();
+        if any {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "TCP socket options are not supported on this platform"));
+        }
+        Ok(())
+    }
+}
+
+/*************************************************
+ * Fast Open
+ *************************************************/
+
+/// Accept data in the SYN of clients holding a Fast Open cookie, with up
+/// to `queue` such connections pending the handshake. Takes effect on a
+/// listening socket.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_vendor = "apple"))]
+pub fn set_fastopen<S: AsRawFd>(listener: &S, queue: u32) -> io::Result<()> {
+    set_option(listener.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_FASTOPEN, queue.min(i32::MAX as u32) as libc::c_int)
+}
+
+/// Whether the kernel has Fast Open turned on for `side`
+/// (`FASTOPEN_CLIENT` or `FASTOPEN_SERVER`); `None` when unknown.
+#[cfg(target_os = "linux")]
+pub fn fastopen_enabled(side: u32) -> Option<bool> {
+    let value: u32 = std::fs::read_to_string(FASTOPEN_SYSCTL).ok()?.trim().parse().ok()?;
+    Some(value & side != 0)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn fastopen_enabled(_side: u32) -> Option<bool> {
+    None
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_vendor = "apple")))]
+pub fn set_fastopen<S>(_listener: &S, _queue: u32) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "TCP Fast Open is not supported on this platform"))
+}
+
+/// Whole seconds, at least one (the kernel rejects zero).
+#[cfg(unix)]
+fn secs(duration: Duration) -> libc::c_int {
+    duration.as_secs().clamp(1, i32::MAX as u64) as libc::c_int
+}
+
+#[cfg(unix)]
+pub fn set_option(fd: RawFd, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,246 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use hyper::HeaderMap;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use std::error::Error;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::Duration;
+use log::{error, info};
+use crate::outbound::Outbound;
+use crate::{same_secret, AcceptBackoff};
+use crate::upstream::{Upstream, SOCKS_AUTH_NONE, SOCKS_AUTH_PASSWORD, SOCKS_VERSION};
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+/// Loopback only: anything that can reach the listener gets the proxy's
+/// credentials.
+const DEFAULT_LISTEN: &str = "127.0.0.1:1080";
+/// Longest the remote proxy may take to open a tunnel.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Longest a client may take to greet and name its target.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const SOCKS_AUTH_UNACCEPTABLE: u8 = 0xff;
+const SOCKS_CMD_CONNECT: u8 = 1;
+const SOCKS_ATYP_IPV4: u8 = 1;
+const SOCKS_ATYP_DOMAIN: u8 = 3;
+const SOCKS_ATYP_IPV6: u8 = 4;
+/// SOCKS5 reply codes sent back.
+const REPLY_SUCCEEDED: u8 = 0;
+const REPLY_GENERAL_FAILURE: u8 = 1;
+const REPLY_HOST_UNREACHABLE: u8 = 4;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 7;
+const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+/// Where SOCKS clients are forwarded and who may use the listener.
+struct Client {
+    upstream: Upstream,
+    outbound: Outbound,
+    /// Username and password SOCKS clients must log in with, if any.
+    login: Option<(String, String)>,
+    verbose: bool,
+}
+
+/*************************************************
+ * run
+ *************************************************/
+
+/// Handle `rdnat socks --via [http://][user:pass@]<host>:<port>
+/// [-l <addr>] [-a <user> <passwd>] [--login <user> <passwd>] [-v]`:
+/// listen locally as a SOCKS5 proxy and carry every connection through
+/// a CONNECT tunnel of the remote HTTP proxy, so applications that only
+/// speak SOCKS can use it. Host names are passed on for the remote proxy
+/// to resolve.
+pub async fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut listen = String::from(DEFAULT_LISTEN);
+    let mut via = None;
+    let mut credentials = None;
+    let mut login = None;
+    let mut verbose = false;
+
+    let mut i = 0;
+    while i < args.len() {
+        let value = args.get(i + 1).cloned();
+        match (args[i].as_str(), value) {
+            ("-l" | "--listen", Some(value)) => listen = value,
+            ("-x" | "--via", Some(value)) => via = Some(value),
+            ("-a" | "--auth", Some(user)) => {
+                let passwd = args.get(i + 2).ok_or("Error: Missing password for --auth")?;
+                credentials = Some(format!("{}:{}", user, passwd));
+                i += 3;
+                continue;
+            }
+            ("--login", Some(user)) => {
+                let passwd = args.get(i + 2).ok_or("Error: Missing password for --login")?;
+                if user.is_empty() || user.len() > 255 || passwd.len() > 255 {
+                    return Err("Error: SOCKS5 username and password must be 1 to 255 bytes".into());
+                }
+                login = Some((user, passwd.clone()));
+                i += 3;
+                continue;
+            }
+            ("-v" | "--verbose", _) => {
+                verbose = true;
+                i += 1;
+                continue;
+            }
+            (arg, _) => return Err(format!("Error: Invalid socks argument: {}", arg).into()),
+        }
+        i += 2;
+    }
+
+    let via = via.ok_or("Error: Expected the HTTP proxy to forward through with --via")?;
+    // Report to stderr; RUST_LOG picks another level.
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).try_init()?;
+    let url = if via.contains("://") { via.clone() } else { format!("http://{}", via) };
+    if !url.starts_with("http://") {
+        return Err(format!("Error: Can only forward through an http:// proxy: {}", via).into());
+    }
+    let mut upstream = Upstream::parse(&url)?;
+    if let Some(credentials) = credentials {
+        upstream.authorization = Some(format!("Basic {}", base64::encode(credentials)));
+    }
+    let listener = TcpListener::bind(&listen).await.map_err(|e| format!("Error: Cannot listen on {}: {}", listen, e))?;
+    info!("SOCKS5 proxy listening on {}, forwarding through {}", listener.local_addr()?, upstream.address);
+
+    let client = Arc::new(Client { upstream, outbound: Outbound::default(), login, verbose });
+    let mut backoff = AcceptBackoff::default();
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                backoff.failed(&e).await;
+                continue;
+            }
+        };
+        backoff.reset();
+        let client = client.clone();
+        tokio::spawn(async move {
+            if let Err(e) = forward(stream, &client).await {
+                error!("{}: {}", peer, e);
+            }
+        });
+    }
+}
+
+/*************************************************
+ * forward
+ *************************************************/
+
+/// Serve one SOCKS5 connection: take the client's CONNECT, open a tunnel
+/// to its target through the HTTP proxy and relay between the two.
+async fn forward(mut stream: TcpStream, client: &Client) -> Result<(), Box<dyn Error + Send + Sync>> {
+    stream.set_nodelay(true)?;
+    let target = tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(&mut stream, client)).await
+        .unwrap_or_else(|_| Err("SOCKS5 handshake timed out".into()))?;
+
+    let tunnel = tokio::time::timeout(CONNECT_TIMEOUT, client.upstream.connect(&target, &HeaderMap::new(), &client.outbound)).await;
+    let mut tunnel = match tunnel {
+        Ok(Ok(tunnel)) => tunnel,
+        Ok(Err(e)) => {
+            reply(&mut stream, REPLY_GENERAL_FAILURE).await?;
+            return Err(e);
+        }
+        Err(_) => {
+            reply(&mut stream, REPLY_HOST_UNREACHABLE).await?;
+            return Err(format!("CONNECT {} timed out", target).into());
+        }
+    };
+    tunnel.set_nodelay(true)?;
+    reply(&mut stream, REPLY_SUCCEEDED).await?;
+    if client.verbose {
+        info!("{} -> {}", stream.peer_addr()?, target);
+    }
+    tokio::io::copy_bidirectional(&mut stream, &mut tunnel).await?;
+    Ok(())
+}
+
+/// Negotiate authentication and read the request (RFC 1928, with RFC
+/// 1929 username and password when a login is required); the target
+/// as `host:port`.
+async fn handshake(stream: &mut TcpStream, client: &Client) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let mut head = [0u8; 2];
+    stream.read_exact(&mut head).await?;
+    if head[0] != SOCKS_VERSION {
+        return Err(format!("Not a SOCKS5 client (version {})", head[0]).into());
+    }
+    let mut methods = vec![0u8; head[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    let method = if client.login.is_some() { SOCKS_AUTH_PASSWORD } else { SOCKS_AUTH_NONE };
+    if !methods.contains(&method) {
+        stream.write_all(&[SOCKS_VERSION, SOCKS_AUTH_UNACCEPTABLE]).await?;
+        return Err("No acceptable SOCKS5 authentication method offered".into());
+    }
+    stream.write_all(&[SOCKS_VERSION, method]).await?;
+
+    if let Some((user, password)) = &client.login {
+        let _version = stream.read_u8().await?;
+        let given_user = read_field(stream).await?;
+        let given_password = read_field(stream).await?;
+        // Both are compared either way, so timing doesn't tell which was wrong.
+        let accepted = same_secret(&given_user, user.as_bytes()) & same_secret(&given_password, password.as_bytes());
+        stream.write_all(&[1, if accepted { 0 } else { 1 }]).await?;
+        if !accepted {
+            return Err(format!("SOCKS5 login rejected for {}", String::from_utf8_lossy(&given_user)).into());
+        }
+    }
+
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request).await?;
+    let host = match request[3] {
+        SOCKS_ATYP_IPV4 => {
+            let mut ip = [0u8; 4];
+            stream.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        SOCKS_ATYP_IPV6 => {
+            let mut ip = [0u8; 16];
+            stream.read_exact(&mut ip).await?;
+            format!("[{}]", Ipv6Addr::from(ip))
+        }
+        SOCKS_ATYP_DOMAIN => {
+            // The name goes into the CONNECT request line, so anything but
+            // a plain host name could inject headers or a second request.
+            let name = read_field(stream).await?;
+            if !valid_host_name(&name) {
+                stream.read_u16().await?;
+                reply(stream, REPLY_ADDRESS_NOT_SUPPORTED).await?;
+                return Err(format!("Invalid SOCKS5 host name {:?}", String::from_utf8_lossy(&name)).into());
+            }
+            String::from_utf8(name).map_err(|_| "Host name is not UTF-8")?
+        }
+        atyp => {
+            reply(stream, REPLY_ADDRESS_NOT_SUPPORTED).await?;
+            return Err(format!("Unsupported SOCKS5 address type {}", atyp).into());
+        }
+    };
+    let port = stream.read_u16().await?;
+    if request[1] != SOCKS_CMD_CONNECT {
+        reply(stream, REPLY_COMMAND_NOT_SUPPORTED).await?;
+        return Err(format!("Unsupported SOCKS5 command {} for {}:{}", request[1], host, port).into());
+    }
+    Ok(format!("{}:{}", host, port))
+}
+
+/// Whether `name` is a host name: letters, digits, `-` and `.` only.
+fn valid_host_name(name: &[u8]) -> bool {
+    !name.is_empty() && name.iter().all(|&b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.'))
+}
+
+/// A field prefixed with its length in one byte.
+async fn read_field(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    let mut value = vec![0u8; stream.read_u8().await? as usize];
+    stream.read_exact(&mut value).await?;
+    Ok(value)
+}
+
+/// Answer the request with `code`. The address the tunnel is bound to
+/// at the remote proxy is unknown, so it is given as 0.0.0.0:0.
+async fn reply(stream: &mut TcpStream, code: u8) -> std::io::Result<()> {
+    stream.write_all(&[SOCKS_VERSION, code, 0, SOCKS_ATYP_IPV4, 0, 0, 0, 0, 0, 0]).await
+}
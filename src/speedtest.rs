@@ -0,0 +1,281 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use hyper::Uri;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use std::error::Error;
+use std::time::{Duration, Instant};
+use crate::bench::Mode;
+use crate::probe::{millis, read_body, read_head};
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+const DEFAULT_SAMPLES: usize = 5;
+/// Longest the download is timed for; it is cut off after that.
+const DEFAULT_DURATION: Duration = Duration::from_secs(10);
+/// Longest a latency sample may take before it counts as failed.
+const SAMPLE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/*************************************************
+ * Path
+ *************************************************/
+
+/// How the URL is reached: through the proxy, or straight from here.
+struct Path<'a> {
+    /// `host:port` connected to.
+    address: &'a str,
+    /// CONNECT head to open a tunnel with, before the request.
+    connect: Option<&'a [u8]>,
+    request: &'a [u8],
+}
+
+/// What was measured over one path; the medians of the samples.
+#[derive(Default)]
+struct Measurement {
+    connect: Option<Duration>,
+    tunnel: Option<Duration>,
+    first_byte: Option<Duration>,
+    bytes: u64,
+    transfer: Duration,
+    error: Option<String>,
+}
+
+impl Measurement {
+    fn throughput(&self) -> Option<f64> {
+        (self.bytes > 0).then(|| self.bytes as f64 / self.transfer.as_secs_f64().max(f64::EPSILON))
+    }
+}
+
+/// The timings of one exchange.
+struct Sample {
+    connect: Duration,
+    tunnel: Option<Duration>,
+    first_byte: Duration,
+}
+
+/*************************************************
+ * run
+ *************************************************/
+
+/// Handle `rdnat speedtest --via <proxy> [--mode connect|get] [-n <samples>]
+/// [-d <secs>] [-a <user> <passwd>] [--no-direct] <url>`: measure the
+/// latency and throughput of fetching a URL through a proxy and, for
+/// comparison, directly, to tell whether slowness comes from the path
+/// through the proxy or from the origin.
+pub async fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut proxy = None;
+    let mut mode = Mode::Connect;
+    let mut samples = DEFAULT_SAMPLES;
+    let mut duration = DEFAULT_DURATION;
+    let mut credentials = None;
+    let mut direct = true;
+    let mut url = None;
+
+    let mut i = 0;
+    while i < args.len() {
+        let value = args.get(i + 1).cloned();
+        match (args[i].as_str(), value) {
+            ("--via" | "-x", Some(value)) => proxy = Some(value),
+            ("--mode", Some(value)) => {
+                mode = match value.as_str() {
+                    "get" => Mode::Get,
+                    "connect" => Mode::Connect,
+                    _ => return Err(format!("Error: Invalid speedtest mode: {}", value).into()),
+                }
+            }
+            ("-n" | "--samples", Some(value)) => samples = value.parse()?,
+            ("-d" | "--duration", Some(value)) => duration = Duration::from_secs(value.parse()?),
+            ("-a" | "--auth", Some(user)) => {
+                let passwd = args.get(i + 2).ok_or("Error: Missing password for --auth")?;
+                credentials = Some(format!("{}:{}", user, passwd));
+                i += 3;
+                continue;
+            }
+            ("--no-direct", _) => {
+                direct = false;
+                i += 1;
+                continue;
+            }
+            (arg, _) if !arg.starts_with('-') && url.is_none() => {
+                url = Some(arg.to_string());
+                i += 1;
+                continue;
+            }
+            (arg, _) => return Err(format!("Error: Invalid speedtest argument: {}", arg).into()),
+        }
+        i += 2;
+    }
+    if samples == 0 {
+        return Err("Error: --samples must be at least 1".into());
+    }
+
+    let proxy = proxy.ok_or("Error: Expected a proxy to test through with --via")?;
+    let url = url.ok_or("Error: Expected a URL to fetch")?;
+    let uri: Uri = url.parse().map_err(|_| format!("Error: Invalid URL: {}", url))?;
+    if uri.scheme_str() != Some("http") {
+        return Err(format!("Error: Only http URLs can be speed-tested: {}", url).into());
+    }
+    let host = uri.host().ok_or_else(|| format!("Error: Invalid URL: {}", url))?;
+    let authority = format!("{}:{}", host, uri.port_u16().unwrap_or(80));
+    let proxy_authorization = credentials.map(|credentials| format!("Proxy-Authorization: Basic {}\r\n", base64::encode(credentials)));
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    let head = |target: &str, authorization: Option<&str>| {
+        format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n{}\r\n",
+            target,
+            uri.authority().unwrap(),
+            authorization.unwrap_or_default()
+        )
+    };
+    let (via_request, connect) = match mode {
+        Mode::Get => (head(&url, proxy_authorization.as_deref()), None),
+        Mode::Connect => {
+            let connect = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n{1}\r\n", authority, proxy_authorization.as_deref().unwrap_or_default());
+            (head(path, None), Some(connect))
+        }
+    };
+    let direct_request = head(path, None);
+
+    let via = Path { address: &proxy, connect: connect.as_deref().map(str::as_bytes), request: via_request.as_bytes() };
+    let mode_name = if mode == Mode::Connect { "CONNECT" } else { "GET" };
+    println!("Speed test of {} through {} ({}): {} samples, {}s download", url, proxy, mode_name, samples, duration.as_secs());
+    let through = measure(&via, samples, duration).await;
+    let direct = match direct {
+        true => Some(measure(&Path { address: &authority, connect: None, request: direct_request.as_bytes() }, samples, duration).await),
+        false => None,
+    };
+    report(&through, direct.as_ref());
+    Ok(())
+}
+
+/*************************************************
+ * measure
+ *************************************************/
+
+/// Median timings of `samples` exchanges, each on a new connection and
+/// cut off at the first byte, then one download timed for at most
+/// `duration`.
+async fn measure(path: &Path<'_>, samples: usize, duration: Duration) -> Measurement {
+    let mut measurement = Measurement::default();
+    let mut timings = Vec::new();
+    for _ in 0..samples {
+        match tokio::time::timeout(SAMPLE_TIMEOUT, sample(path)).await.unwrap_or_else(|_| Err("Error: Timed out".into())) {
+            Ok((timing, _, _)) => timings.push(timing),
+            Err(e) => {
+                measurement.error = Some(e.to_string());
+                return measurement;
+            }
+        }
+    }
+    measurement.connect = median(timings.iter().map(|timing| timing.connect));
+    measurement.tunnel = median(timings.iter().filter_map(|timing| timing.tunnel));
+    measurement.first_byte = median(timings.iter().map(|timing| timing.first_byte));
+
+    let download = async {
+        let (_, mut stream, bytes) = sample(path).await?;
+        let start = Instant::now();
+        let bytes = read_body(&mut stream, bytes, u64::MAX, Some(start + duration)).await?;
+        Ok::<_, Box<dyn Error + Send + Sync>>((bytes, start.elapsed()))
+    };
+    match download.await {
+        Ok((bytes, transfer)) => (measurement.bytes, measurement.transfer) = (bytes, transfer),
+        Err(e) => measurement.error = Some(e.to_string()),
+    }
+    measurement
+}
+
+/// Connect over `path` and send the request; the timings up to the
+/// response head, the stream, and how much of the body came with the head.
+async fn sample(path: &Path<'_>) -> Result<(Sample, TcpStream, u64), Box<dyn Error + Send + Sync>> {
+    let start = Instant::now();
+    let mut stream = TcpStream::connect(path.address).await?;
+    stream.set_nodelay(true)?;
+    let connect = start.elapsed();
+    let tunnel = match path.connect {
+        Some(head) => {
+            let start = Instant::now();
+            stream.write_all(head).await?;
+            let (status, rest) = read_head(&mut stream).await?;
+            if status != 200 {
+                return Err(format!("Error: CONNECT answered with {}", status).into());
+            }
+            if !rest.is_empty() {
+                return Err("Error: Data before the tunnel was used".into());
+            }
+            Some(start.elapsed())
+        }
+        None => None,
+    };
+    let start = Instant::now();
+    stream.write_all(path.request).await?;
+    let (status, rest) = read_head(&mut stream).await?;
+    if !(200..300).contains(&status) {
+        return Err(format!("Error: Answered with {}", status).into());
+    }
+    Ok((Sample { connect, tunnel, first_byte: start.elapsed() }, stream, rest.len() as u64))
+}
+
+fn median(durations: impl Iterator<Item = Duration>) -> Option<Duration> {
+    let mut durations: Vec<Duration> = durations.collect();
+    durations.sort_unstable();
+    durations.get(durations.len().checked_sub(1)? / 2).copied()
+}
+
+/*************************************************
+ * report
+ *************************************************/
+
+fn report(through: &Measurement, direct: Option<&Measurement>) {
+    let millis = |duration: Option<Duration>| duration.map_or(String::from("-"), |duration| format!("{:.2}ms", millis(duration)));
+    let rate = |measurement: &Measurement| measurement.throughput().map_or(String::from("-"), |rate| format!("{:.2} MB/s", rate / (1024.0 * 1024.0)));
+    let size = |measurement: &Measurement| format!("{:.1} MB", measurement.bytes as f64 / (1024.0 * 1024.0));
+    let columns = |name: &str, through: String, direct: Option<String>| {
+        let line = format!("  {:<14} {:>14} {:>14}", name, through, direct.unwrap_or_default());
+        println!("{}", line.trim_end());
+    };
+
+    println!();
+    columns("", String::from("Through proxy"), direct.map(|_| String::from("Direct")));
+    columns("Connect", millis(through.connect), direct.map(|direct| millis(direct.connect)));
+    if through.tunnel.is_some() {
+        columns("Tunnel setup", millis(through.tunnel), direct.map(|_| String::from("-")));
+    }
+    columns("First byte", millis(through.first_byte), direct.map(|direct| millis(direct.first_byte)));
+    columns("Throughput", rate(through), direct.map(rate));
+    columns("Downloaded", size(through), direct.map(size));
+    if let Some(error) = &through.error {
+        println!("\nThrough the proxy: {}", error);
+    }
+    let Some(direct) = direct else {
+        return;
+    };
+    if let Some(error) = &direct.error {
+        println!("\nDirect: {}", error);
+        return;
+    }
+    if through.error.is_some() {
+        return;
+    }
+
+    // What the proxy adds to a request: setting up the tunnel and any
+    // extra wait for the first byte.
+    let total = |measurement: &Measurement| {
+        [measurement.connect, measurement.tunnel, measurement.first_byte].into_iter().flatten().sum::<Duration>()
+    };
+    let added = total(through).as_secs_f64() - total(direct).as_secs_f64();
+    println!();
+    println!("The proxy adds {:.2}ms to each request.", added * 1000.0);
+    if let (Some(through_rate), Some(direct_rate)) = (through.throughput(), direct.throughput()) {
+        let share = through_rate / direct_rate;
+        println!("Transfers through the proxy run at {:.0}% of direct speed.", share * 100.0);
+        if share < 0.5 {
+            println!("The path through the proxy is the bottleneck.");
+        } else if share > 0.8 && added < total(direct).as_secs_f64() {
+            println!("The proxy isn't the bottleneck; slowness comes from the origin or the network to it.");
+        }
+    }
+}
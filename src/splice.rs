@@ -0,0 +1,82 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+use crate::relay::{Chunk, Flow};
+
+pub use crate::relay::DEFAULT_PIPE_SIZE;
+
+/*************************************************
+ * Pipe
+ *************************************************/
+
+/// A kernel pipe that data is spliced through from one socket to another,
+/// so it never gets copied into userspace.
+pub struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+    /// Most moved per transfer.
+    size: usize,
+}
+
+impl Pipe {
+    /// A pipe holding about `size` bytes; the kernel rounds it to whole
+    /// pages and keeps the default size when `size` is over its limit.
+    pub fn new(size: usize) -> io::Result<Self> {
+        let mut fds = [0 as libc::c_int; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        let mut pipe = Pipe { read, write, size: DEFAULT_PIPE_SIZE };
+        if size != DEFAULT_PIPE_SIZE {
+            let resized = unsafe { libc::fcntl(pipe.write.as_raw_fd(), libc::F_SETPIPE_SZ, size.min(i32::MAX as usize) as libc::c_int) };
+            if resized > 0 {
+                pipe.size = resized as usize;
+            }
+        }
+        Ok(pipe)
+    }
+
+    /// Move what `from` has to read, up to a pipe full, on to `to`,
+    /// counting the bytes in the pipe as in flight in `flow`. Returns how
+    /// many bytes were moved; zero once `from` is at EOF.
+    pub async fn transfer(&self, from: &TcpStream, to: &TcpStream, flow: &Flow) -> io::Result<usize> {
+        // The pipe is empty here, so a would-block means `from` has
+        // nothing to read.
+        let moved = from.async_io(Interest::READABLE, || splice(from.as_raw_fd(), self.write.as_raw_fd(), self.size)).await?;
+        let mut chunk = Chunk::new(flow, moved);
+        while chunk.left() > 0 {
+            let written = match splice(self.read.as_raw_fd(), to.as_raw_fd(), chunk.left()) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    chunk.stalled();
+                    to.async_io(Interest::WRITABLE, || splice(self.read.as_raw_fd(), to.as_raw_fd(), chunk.left())).await?
+                }
+                written => written?,
+            };
+            chunk.written(written);
+        }
+        Ok(moved)
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let moved = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if moved < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(moved as usize)
+}
@@ -0,0 +1,232 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use crate::dns::Resolver;
+
+/*************************************************
+ * Blocked
+ *************************************************/
+
+/// The destination only resolves to addresses the proxy refuses to reach.
+#[derive(Debug)]
+pub struct Blocked(pub String);
+
+impl fmt::Display for Blocked {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Destination {} is on a private or local network", self.0)
+    }
+}
+
+impl Error for Blocked {}
+
+/*************************************************
+ * AddressGuard
+ *************************************************/
+
+/// Keeps clients away from loopback, private, link-local (including cloud
+/// metadata endpoints) and the proxy's own addresses, unless `allow_private`.
+pub struct AddressGuard {
+    allow_private: bool,
+    local: Vec<IpAddr>,
+    resolver: Resolver,
+}
+
+impl AddressGuard {
+    pub fn new(allow_private: bool, resolver: Resolver) -> Self {
+        AddressGuard {
+            allow_private,
+            local: if allow_private { Vec::new() } else { local_addresses() },
+            resolver,
+        }
+    }
+
+    pub fn resolver(&self) -> &Resolver {
+        &self.resolver
+    }
+
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        if self.allow_private {
+            return false;
+        }
+        let ip = match ip {
+            IpAddr::V6(v6) => embedded_v4(v6).map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        let internal = match ip {
+            IpAddr::V4(v4) => is_internal_v4(v4),
+            IpAddr::V6(v6) => is_internal_v6(v6),
+        };
+        internal || self.local.contains(&ip)
+    }
+
+    /// Resolve `host` and keep only the addresses that may be reached.
+    /// Connecting to the returned addresses (rather than resolving again)
+    /// keeps DNS rebinding from slipping past the check.
+    pub async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, Box<dyn Error + Send + Sync>> {
+        let resolved: Vec<SocketAddr> = self.resolver.lookup(host, port).await?;
+        let allowed: Vec<SocketAddr> = resolved.iter()
+            .copied()
+            .filter(|addr| !self.is_blocked(addr.ip()))
+            .collect();
+        if allowed.is_empty() && !resolved.is_empty() {
+            return Err(Box::new(Blocked(host.to_string())));
+        }
+        Ok(allowed)
+    }
+}
+
+fn is_internal_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || a == 0
+        // Shared address space (carrier-grade NAT).
+        || (a == 100 && (64..128).contains(&b))
+        // IETF protocol assignments.
+        || (a == 192 && b == 0 && c == 0)
+        // Benchmarking.
+        || (a == 198 && (b == 18 || b == 19))
+        // Reserved.
+        || a >= 240
+}
+
+fn is_internal_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local.
+        || (first & 0xfe00) == 0xfc00
+        // Link-local and the deprecated site-local.
+        || (first & 0xffc0) == 0xfe80
+        || (first & 0xffc0) == 0xfec0
+}
+
+/// IPv4 address carried in an IPv4-mapped or NAT64 IPv6 address.
+fn embedded_v4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        return Some(v4);
+    }
+    let segments = ip.segments();
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        let [.., a, b, c, d] = ip.octets();
+        return Some(Ipv4Addr::new(a, b, c, d));
+    }
+    None
+}
+
+/*************************************************
+ * local_addresses
+ *************************************************/
+
+#[cfg(unix)]
+fn local_addresses() -> Vec<IpAddr> {
+    let mut addrs = Vec::new();
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return addrs;
+    }
+    let mut cursor = ifaddrs;
+    while !cursor.is_null() {
+        let ifaddr = unsafe { &*cursor };
+        if !ifaddr.ifa_addr.is_null() {
+            match unsafe { (*ifaddr.ifa_addr).sa_family } as libc::c_int {
+                libc::AF_INET => {
+                    let sin = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in) };
+                    addrs.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr))));
+                }
+                libc::AF_INET6 => {
+                    let sin6 = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in6) };
+                    addrs.push(IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr)));
+                }
+                _ => {}
+            }
+        }
+        cursor = ifaddr.ifa_next;
+    }
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    addrs
+}
+
+/// Without getifaddrs only the fixed ranges are blocked.
+#[cfg(not(unix))]
+fn local_addresses() -> Vec<IpAddr> {
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hosts::HostOverrides;
+
+    fn guard(hosts: &[(&str, &str)]) -> AddressGuard {
+        let mut overrides = HostOverrides::default();
+        for (name, ip) in hosts {
+            overrides.add(name, ip.parse().unwrap());
+        }
+        let mut resolver = Resolver::default();
+        resolver.set_hosts(overrides);
+        AddressGuard::new(false, resolver)
+    }
+
+    #[test]
+    fn blocks_internal_v4_ranges() {
+        let guard = guard(&[]);
+        for ip in [
+            "0.0.0.0", "0.1.2.3", "127.0.0.1", "127.255.0.1", "10.0.0.1", "172.16.0.1", "172.31.255.255", "192.168.1.1",
+            "169.254.169.254", "100.64.0.1", "100.127.255.255", "192.0.0.8", "198.18.0.1", "198.19.255.255", "224.0.0.1",
+            "239.255.255.250", "240.0.0.1", "255.255.255.255",
+        ] {
+            assert!(guard.is_blocked(ip.parse().unwrap()), "allowed {}", ip);
+        }
+        for ip in ["8.8.8.8", "100.63.255.255", "100.128.0.1", "172.32.0.1", "192.0.1.1", "198.17.255.255", "198.20.0.1", "223.255.255.255"] {
+            assert!(!guard.is_blocked(ip.parse().unwrap()), "blocked {}", ip);
+        }
+    }
+
+    #[test]
+    fn blocks_internal_v6_ranges() {
+        let guard = guard(&[]);
+        for ip in ["::", "::1", "fc00::1", "fd12:3456::1", "fe80::1", "febf::1", "fec0::1", "feff::1", "ff02::1"] {
+            assert!(guard.is_blocked(ip.parse().unwrap()), "allowed {}", ip);
+        }
+        for ip in ["2001:4860:4860::8888", "2606:4700::1111", "fbff::1"] {
+            assert!(!guard.is_blocked(ip.parse().unwrap()), "blocked {}", ip);
+        }
+    }
+
+    #[test]
+    fn blocks_embedded_v4_addresses() {
+        let guard = guard(&[]);
+        for ip in ["::ffff:127.0.0.1", "::ffff:10.0.0.1", "::ffff:169.254.169.254", "64:ff9b::127.0.0.1", "64:ff9b::a9fe:a9fe"] {
+            assert!(guard.is_blocked(ip.parse().unwrap()), "allowed {}", ip);
+        }
+        for ip in ["::ffff:8.8.8.8", "64:ff9b::8.8.8.8"] {
+            assert!(!guard.is_blocked(ip.parse().unwrap()), "blocked {}", ip);
+        }
+    }
+
+    #[test]
+    fn allow_private_lets_everything_through() {
+        let guard = AddressGuard::new(true, Resolver::default());
+        assert!(!guard.is_blocked("127.0.0.1".parse().unwrap()));
+        assert!(!guard.is_blocked("::ffff:10.0.0.1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn resolve_keeps_only_reachable_addresses() {
+        let guard = guard(&[("mixed.example", "10.0.0.1"), ("mixed.example", "8.8.8.8"), ("internal.example", "127.0.0.1"), ("internal.example", "fec0::1")]);
+        assert_eq!(guard.resolve("mixed.example", 80).await.unwrap(), ["8.8.8.8:80".parse().unwrap()]);
+        let error = guard.resolve("internal.example", 80).await.unwrap_err();
+        assert!(error.downcast_ref::<Blocked>().is_some());
+        assert!(guard.resolve("169.254.169.254", 80).await.unwrap_err().downcast_ref::<Blocked>().is_some());
+    }
+}
@@ -0,0 +1,121 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+pub const DEFAULT_WINDOW_MINS: u64 = 5;
+pub const DEFAULT_TOP_LIMIT: usize = 10;
+
+const RETENTION_MINS: u64 = 60;
+
+pub type Ranking = Vec<(String, Counter)>;
+
+/*************************************************
+ * Counter
+ *************************************************/
+
+#[derive(Default, Clone, Copy)]
+pub struct Counter {
+    pub connections: u64,
+    pub bytes: u64,
+}
+
+impl Counter {
+    fn add(&mut self, other: &Counter) {
+        self.connections += other.connections;
+        self.bytes += other.bytes;
+    }
+}
+
+/*************************************************
+ * Bucket
+ *************************************************/
+
+struct Bucket {
+    minute: u64,
+    destinations: HashMap<String, Counter>,
+    users: HashMap<String, Counter>,
+    labels: HashMap<String, String>,
+}
+
+/*************************************************
+ * Stats
+ *************************************************/
+
+/// Per-minute traffic counters kept in memory for the last hour.
+#[derive(Default)]
+pub struct Stats {
+    buckets: Mutex<VecDeque<Bucket>>,
+}
+
+impl Stats {
+    pub fn record(&self, user: &str, destination: &str, label: Option<String>, bytes: u64) {
+        let minute = current_minute();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.back().is_none_or(|bucket| bucket.minute != minute) {
+            buckets.push_back(Bucket {
+                minute,
+                destinations: HashMap::new(),
+                users: HashMap::new(),
+                labels: HashMap::new(),
+            });
+        }
+        while buckets.front().is_some_and(|bucket| bucket.minute + RETENTION_MINS <= minute) {
+            buckets.pop_front();
+        }
+
+        let counter = Counter { connections: 1, bytes };
+        let bucket = buckets.back_mut().unwrap();
+        bucket.destinations.entry(destination.to_string()).or_default().add(&counter);
+        bucket.users.entry(user.to_string()).or_default().add(&counter);
+        if let Some(label) = label {
+            bucket.labels.insert(destination.to_string(), label);
+        }
+    }
+
+    /// Busiest destinations and heaviest users over the last `window` minutes.
+    /// Destinations are annotated with their most recent label, if any.
+    pub fn top(&self, window: u64, limit: usize) -> (Ranking, Ranking) {
+        let since = current_minute().saturating_sub(window.saturating_sub(1));
+        let mut destinations: HashMap<String, Counter> = HashMap::new();
+        let mut users: HashMap<String, Counter> = HashMap::new();
+        let mut labels: HashMap<String, String> = HashMap::new();
+
+        let buckets = self.buckets.lock().unwrap();
+        for bucket in buckets.iter().filter(|bucket| bucket.minute >= since) {
+            for (name, counter) in &bucket.destinations {
+                destinations.entry(name.clone()).or_default().add(counter);
+            }
+            for (name, counter) in &bucket.users {
+                users.entry(name.clone()).or_default().add(counter);
+            }
+            labels.extend(bucket.labels.clone());
+        }
+
+        let mut destinations = rank(destinations, limit);
+        for (name, _) in destinations.iter_mut() {
+            if let Some(label) = labels.get(name) {
+                *name = format!("{} [{}]", name, label);
+            }
+        }
+        (destinations, rank(users, limit))
+    }
+}
+
+fn rank(counters: HashMap<String, Counter>, limit: usize) -> Ranking {
+    let mut ranked: Ranking = counters.into_iter().collect();
+    ranked.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes).then_with(|| a.0.cmp(&b.0)));
+    ranked.truncate(limit);
+    ranked
+}
+
+fn current_minute() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 60
+}
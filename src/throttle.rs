@@ -0,0 +1,176 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use hyper::body::{Bytes, HttpBody};
+use hyper::Body;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+use crate::rules::random;
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+/// Payload of one TCP segment, the unit a lost packet costs.
+const SEGMENT: usize = 1460;
+/// Pacing interval: data moves in slices of this much transfer time.
+const SLICE: Duration = Duration::from_millis(100);
+/// Shortest retransmission timeout, as in Linux.
+const MIN_RTO: Duration = Duration::from_millis(200);
+
+/*************************************************
+ * Link
+ *************************************************/
+
+/// Conditions of a simulated network link, as seen from the client.
+#[derive(Clone, Copy)]
+pub struct Link {
+    pub name: &'static str,
+    /// Bytes per second towards the client.
+    pub download: u64,
+    /// Bytes per second from the client.
+    pub upload: u64,
+    pub rtt: Duration,
+    /// Largest deviation of a round trip from `rtt`, either way.
+    pub jitter: Duration,
+    /// Share of segments lost, from 0 to 1.
+    pub loss: f64,
+}
+
+/// Built-in links, after typical mobile and satellite connections.
+pub const LINKS: [Link; 4] = [
+    Link { name: "2g", download: 240_000 / 8, upload: 200_000 / 8, rtt: Duration::from_millis(650), jitter: Duration::from_millis(150), loss: 0.01 },
+    Link { name: "3g", download: 1_600_000 / 8, upload: 750_000 / 8, rtt: Duration::from_millis(300), jitter: Duration::from_millis(50), loss: 0.005 },
+    Link { name: "4g", download: 12_000_000 / 8, upload: 4_000_000 / 8, rtt: Duration::from_millis(70), jitter: Duration::from_millis(15), loss: 0.001 },
+    Link { name: "satellite", download: 20_000_000 / 8, upload: 2_000_000 / 8, rtt: Duration::from_millis(600), jitter: Duration::from_millis(50), loss: 0.005 },
+];
+
+impl Link {
+    pub fn named(name: &str) -> Option<Link> {
+        LINKS.iter().find(|link| link.name.eq_ignore_ascii_case(name)).copied()
+    }
+
+    /// One round trip over the link, jitter included.
+    pub fn round_trip(&self) -> Duration {
+        let jitter = self.jitter.mul_f64(random() * 2.0);
+        (self.rtt + jitter).saturating_sub(self.jitter)
+    }
+
+    /// How long `bytes` take at `rate`, plus a retransmission timeout
+    /// when one of their segments is lost.
+    fn transfer_time(&self, bytes: usize, rate: u64) -> Duration {
+        let time = Duration::from_secs_f64(bytes as f64 / rate as f64);
+        let segments = bytes.div_ceil(SEGMENT) as i32;
+        if random() < 1.0 - (1.0 - self.loss).powi(segments) {
+            time + self.rtt.max(MIN_RTO)
+        } else {
+            time
+        }
+    }
+
+    /// Most bytes moved at `rate` in one pacing slice.
+    fn slice(rate: u64) -> usize {
+        ((rate as f64 * SLICE.as_secs_f64()) as usize).max(SEGMENT)
+    }
+
+    /// `body` as it would arrive over the link from the client.
+    pub fn pace_upload(&self, mut body: Body) -> Body {
+        let (link, rate) = (*self, self.upload);
+        let (mut sender, paced) = Body::channel();
+        tokio::spawn(async move {
+            while let Some(chunk) = body.data().await {
+                let Ok(mut chunk) = chunk else {
+                    sender.abort();
+                    return;
+                };
+                while !chunk.is_empty() {
+                    let slice: Bytes = chunk.split_to(chunk.len().min(Link::slice(rate)));
+                    tokio::time::sleep(link.transfer_time(slice.len(), rate)).await;
+                    if sender.send_data(slice).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        paced
+    }
+}
+
+/*************************************************
+ * Throttled
+ *************************************************/
+
+/// A client stream seen through a simulated link: reads and writes are
+/// paced to the link's upload and download rates, and the first write
+/// after the client has sent something waits out a round trip, so every
+/// exchange pays the link's latency.
+pub struct Throttled<S> {
+    inner: S,
+    link: Link,
+    /// When the next read may happen.
+    read_wait: Option<Pin<Box<Sleep>>>,
+    /// When the next write may happen.
+    write_wait: Option<Pin<Box<Sleep>>>,
+    /// Whether the client sent something since the last write.
+    turned: bool,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(inner: S, link: Link) -> Self {
+        Throttled { inner, link, read_wait: None, write_wait: None, turned: false }
+    }
+}
+
+/// Wait out `wait`, if any.
+fn poll_wait(wait: &mut Option<Pin<Box<Sleep>>>, cx: &mut Context<'_>) -> Poll<()> {
+    if let Some(sleep) = wait {
+        ready!(sleep.as_mut().poll(cx));
+        *wait = None;
+    }
+    Poll::Ready(())
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(poll_wait(&mut this.read_wait, cx));
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        // What was read is paid for before the next read.
+        let n = buf.filled().len() - before;
+        if n > 0 {
+            this.turned = true;
+            this.read_wait = Some(Box::pin(tokio::time::sleep(this.link.transfer_time(n, this.link.upload))));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.turned {
+            this.turned = false;
+            this.write_wait = Some(Box::pin(tokio::time::sleep(this.link.round_trip())));
+        }
+        ready!(poll_wait(&mut this.write_wait, cx));
+        let slice = buf.len().min(Link::slice(this.link.download));
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..slice]))?;
+        this.write_wait = Some(Box::pin(tokio::time::sleep(this.link.transfer_time(n, this.link.download))));
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,424 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use ring::digest::{digest, SHA256};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use tokio_rustls::rustls::version::{TLS12, TLS13};
+use tokio_rustls::rustls::{self, Certificate, ClientConfig, ConfigBuilder, KeyLog, PrivateKey, RootCertStore, ServerConfig, ServerName};
+use tokio_rustls::rustls::{SupportedCipherSuite, SupportedProtocolVersion, ALL_CIPHER_SUITES, DEFAULT_CIPHER_SUITES};
+use tokio_rustls::rustls::server::{Acceptor, WantsServerCert};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::LazyConfigAcceptor;
+use x509_parser::prelude::{FromDer, X509Certificate};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+use log::{error, info};
+
+/*************************************************
+ * OriginTls
+ *************************************************/
+
+/// How rdnat verifies the servers it makes TLS connections to: the trust
+/// anchors (the system roots unless a bundle is given), public keys pinned
+/// per host, or no verification at all.
+#[derive(Default)]
+pub struct OriginTls {
+    ca_bundle: Option<String>,
+    pins: HashMap<String, Vec<Vec<u8>>>,
+    insecure: bool,
+}
+
+impl OriginTls {
+    /// Trust only the certificates in `path` (PEM).
+    pub fn set_ca_bundle(&mut self, path: &str) {
+        self.ca_bundle = Some(path.to_string());
+    }
+
+    /// Pin `host=sha256/<base64>`: the SHA-256 of a SubjectPublicKeyInfo in
+    /// the chain `host` presents, as printed by
+    /// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64`.
+    pub fn add_pin(&mut self, value: &str) -> Result<(), Box<dyn Error>> {
+        let invalid = || format!("Error: Invalid pin, expected <host>=sha256/<base64>: {}", value);
+        let (host, pin) = value.split_once('=').ok_or_else(invalid)?;
+        let hash = pin.strip_prefix("sha256/").ok_or_else(invalid)?.trim_start_matches('/');
+        let hash = base64::decode(hash).map_err(|_| invalid())?;
+        if hash.len() != SHA256.output_len() {
+            return Err(invalid().into());
+        }
+        self.pins.entry(host.to_ascii_lowercase()).or_default().push(hash);
+        Ok(())
+    }
+
+    pub fn set_insecure(&mut self) {
+        self.insecure = true;
+    }
+
+    pub fn is_insecure(&self) -> bool {
+        self.insecure
+    }
+
+    /// Client settings implementing this policy.
+    pub fn client_config(&self, key_log: Option<Arc<dyn KeyLog>>) -> Result<ClientConfig, Box<dyn Error>> {
+        let verifier = OriginVerifier {
+            webpki: (!self.insecure).then(|| self.roots().map(|roots| WebPkiVerifier::new(roots, None))).transpose()?,
+            pins: self.pins.clone(),
+        };
+        let mut config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+        if let Some(key_log) = key_log {
+            config.key_log = key_log;
+        }
+        Ok(config)
+    }
+
+    fn roots(&self) -> Result<RootCertStore, Box<dyn Error>> {
+        let mut roots = RootCertStore::empty();
+        match &self.ca_bundle {
+            Some(path) => {
+                let pem = std::fs::read(path).map_err(|e| format!("Error: Cannot read CA bundle {}: {}", path, e))?;
+                let (added, _) = roots.add_parsable_certificates(&rustls_pemfile::certs(&mut pem.as_slice())?);
+                if added == 0 {
+                    return Err(format!("Error: No certificates in CA bundle {}", path).into());
+                }
+            }
+            None => match rustls_native_certs::load_native_certs() {
+                Ok(certs) => {
+                    let certs: Vec<Vec<u8>> = certs.into_iter().map(|cert| cert.0).collect();
+                    roots.add_parsable_certificates(&certs);
+                }
+                Err(e) => log::warn!("Cannot load system root certificates: {}", e),
+            },
+        }
+        Ok(roots)
+    }
+}
+
+/*************************************************
+ * OriginVerifier
+ *************************************************/
+
+/// The usual chain and name checks (skipped when `webpki` is `None`), then
+/// the pins of the host, if it has any.
+struct OriginVerifier {
+    webpki: Option<WebPkiVerifier>,
+    pins: HashMap<String, Vec<Vec<u8>>>,
+}
+
+impl ServerCertVerifier for OriginVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(webpki) = &self.webpki {
+            webpki.verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now)?;
+        }
+        let host = match server_name {
+            ServerName::DnsName(name) => name.as_ref().to_ascii_lowercase(),
+            ServerName::IpAddress(ip) => ip.to_string(),
+            _ => String::new(),
+        };
+        let Some(pins) = self.pins.get(&host) else {
+            return Ok(ServerCertVerified::assertion());
+        };
+        let pinned = std::iter::once(end_entity).chain(intermediates).any(|cert| {
+            X509Certificate::from_der(&cert.0)
+                .is_ok_and(|(_, cert)| pins.iter().any(|pin| digest(&SHA256, cert.public_key().raw).as_ref() == pin))
+        });
+        if !pinned {
+            return Err(rustls::Error::General(format!("certificate of {} matches no pinned key", host)));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+/// ALPN protocol of ACME TLS-ALPN-01 validation handshakes (RFC 8737).
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+/*************************************************
+ * ListenerPolicy
+ *************************************************/
+
+/// Protocol versions, cipher suites and ALPN protocols offered to clients
+/// by rdnat's TLS endpoints: the TLS listener and, except for ALPN (which
+/// must stay HTTP/1.1 there), intercepted tunnels.
+#[derive(Clone)]
+pub struct ListenerPolicy {
+    versions: Vec<&'static SupportedProtocolVersion>,
+    suites: Vec<SupportedCipherSuite>,
+    alpn: Vec<Vec<u8>>,
+}
+
+impl Default for ListenerPolicy {
+    fn default() -> Self {
+        ListenerPolicy {
+            versions: vec![&TLS12, &TLS13],
+            suites: DEFAULT_CIPHER_SUITES.to_vec(),
+            alpn: vec![b"http/1.1".to_vec()],
+        }
+    }
+}
+
+impl ListenerPolicy {
+    /// `1.2` or `1.3`.
+    pub fn set_min_version(&mut self, version: &str) -> Result<(), Box<dyn Error>> {
+        self.versions = match version {
+            "1.2" => vec![&TLS12, &TLS13],
+            "1.3" => vec![&TLS13],
+            version => return Err(format!("Error: Unsupported TLS version: {} (expected 1.2 or 1.3)", version).into()),
+        };
+        Ok(())
+    }
+
+    /// Comma-separated IANA names such as `TLS13_AES_256_GCM_SHA384` or
+    /// `TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384`.
+    pub fn set_cipher_suites(&mut self, list: &str) -> Result<(), Box<dyn Error>> {
+        self.suites = list.split(',')
+            .map(|name| {
+                ALL_CIPHER_SUITES.iter()
+                    .find(|suite| format!("{:?}", suite.suite()).eq_ignore_ascii_case(name.trim()))
+                    .copied()
+                    .ok_or_else(|| format!("Error: Unknown cipher suite: {}", name))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(())
+    }
+
+    /// Comma-separated protocol IDs; `none` to not negotiate ALPN.
+    pub fn set_alpn(&mut self, list: &str) {
+        self.alpn = match list {
+            "none" => Vec::new(),
+            list => list.split(',').map(|protocol| protocol.trim().as_bytes().to_vec()).collect(),
+        };
+    }
+
+    /// A server config builder limited to the allowed versions and suites.
+    pub fn builder(&self) -> Result<ConfigBuilder<ServerConfig, WantsServerCert>, Box<dyn Error>> {
+        let builder = ServerConfig::builder()
+            .with_cipher_suites(&self.suites)
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&self.versions)
+            .map_err(|e| format!("Error: Invalid TLS policy: {}", e))?;
+        Ok(builder.with_no_client_auth())
+    }
+
+    /// Settings for the TLS listener, serving the chain and key in the PEM
+    /// files.
+    pub fn server_config(&self, cert_path: &str, key_path: &str) -> Result<ServerConfig, Box<dyn Error>> {
+        let (certs, key) = (load_certs(cert_path)?, load_key(key_path)?);
+        if !key_matches(&certs[0], &key) {
+            return Err(format!("Error: Private key {} doesn't match certificate {}", key_path, cert_path).into());
+        }
+        let mut config = self.builder()?
+            .with_single_cert(certs, key)
+            .map_err(|e| format!("Error: Invalid certificate {}: {}", cert_path, e))?;
+        config.alpn_protocols = self.alpn.clone();
+        Ok(config)
+    }
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>, Box<dyn Error>> {
+    let pem = std::fs::read(path).map_err(|e| format!("Error: Cannot read {}: {}", path, e))?;
+    let certs = rustls_pemfile::certs(&mut pem.as_slice())?;
+    if certs.is_empty() {
+        return Err(format!("Error: No certificate in {}", path).into());
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &str) -> Result<PrivateKey, Box<dyn Error>> {
+    let pem = std::fs::read(path).map_err(|e| format!("Error: Cannot read {}: {}", path, e))?;
+    rustls_pemfile::read_all(&mut pem.as_slice())?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::ECKey(key) => {
+                Some(PrivateKey(key))
+            }
+            _ => None,
+        })
+        .ok_or_else(|| format!("Error: No private key in {}", path).into())
+}
+
+/// Whether `key` belongs to `cert`. rustls doesn't check, and a mismatched
+/// pair (say, a renewal caught between writing the two files) would fail
+/// every handshake. Keys rcgen can't parse, such as PKCS#1 RSA ones, are
+/// given the benefit of the doubt.
+fn key_matches(cert: &Certificate, key: &PrivateKey) -> bool {
+    let (Ok((_, cert)), Ok(key)) = (X509Certificate::from_der(&cert.0), rcgen::KeyPair::from_der(&key.0)) else {
+        return true;
+    };
+    cert.public_key().subject_public_key.data == key.public_key_raw()
+}
+
+/*************************************************
+ * TlsListener
+ *************************************************/
+
+/// The settings of the TLS listener. They are rebuilt when its certificate
+/// files change (or on `rdnat ctl reload-tls`) and swapped in for new
+/// handshakes; established connections keep the ones they started with.
+pub struct TlsListener {
+    policy: ListenerPolicy,
+    cert_path: String,
+    key_path: String,
+    config: RwLock<Arc<ServerConfig>>,
+    loaded: Mutex<[Option<SystemTime>; 2]>,
+    /// Settings answering ACME TLS-ALPN-01 validations, by domain.
+    challenges: Mutex<HashMap<String, Arc<ServerConfig>>>,
+}
+
+impl TlsListener {
+    pub fn load(policy: ListenerPolicy, cert_path: &str, key_path: &str) -> Result<Self, Box<dyn Error>> {
+        let listener = TlsListener {
+            config: RwLock::new(Arc::new(policy.server_config(cert_path, key_path)?)),
+            policy,
+            cert_path: cert_path.to_string(),
+            key_path: key_path.to_string(),
+            loaded: Mutex::new([None; 2]),
+            challenges: Mutex::new(HashMap::new()),
+        };
+        *listener.loaded.lock().unwrap() = listener.modified();
+        Ok(listener)
+    }
+
+    /// Complete the handshake of an accepted connection, with the settings
+    /// in use at the time or, for an ACME validation, the challenge ones.
+    pub async fn accept(&self, stream: TcpStream) -> io::Result<TlsStream<TcpStream>> {
+        let start = LazyConfigAcceptor::new(Acceptor::default(), stream).await?;
+        let config = {
+            let hello = start.client_hello();
+            let validation = hello.alpn().is_some_and(|mut alpn| alpn.any(|protocol| protocol == ACME_TLS_ALPN));
+            let challenge = match hello.server_name() {
+                Some(name) if validation => self.challenges.lock().unwrap().get(&name.to_ascii_lowercase()).cloned(),
+                _ => None,
+            };
+            challenge.unwrap_or_else(|| self.config.read().unwrap().clone())
+        };
+        start.into_stream(config).await
+    }
+
+    /// Answer TLS-ALPN-01 validations for `domain` with `config`, or stop
+    /// answering them.
+    pub fn set_challenge(&self, domain: &str, config: Option<ServerConfig>) {
+        let mut challenges = self.challenges.lock().unwrap();
+        match config {
+            Some(config) => challenges.insert(domain.to_ascii_lowercase(), Arc::new(config)),
+            None => challenges.remove(&domain.to_ascii_lowercase()),
+        };
+    }
+
+    /// Read the certificate files again. On error the current settings
+    /// stay in use.
+    pub fn reload(&self) -> Result<(), Box<dyn Error>> {
+        let modified = self.modified();
+        let config = self.policy.server_config(&self.cert_path, &self.key_path)?;
+        *self.config.write().unwrap() = Arc::new(config);
+        *self.loaded.lock().unwrap() = modified;
+        Ok(())
+    }
+
+    pub fn cert_path(&self) -> &str {
+        &self.cert_path
+    }
+
+    fn modified(&self) -> [Option<SystemTime>; 2] {
+        [&self.cert_path, &self.key_path].map(|path| std::fs::metadata(path).and_then(|meta| meta.modified()).ok())
+    }
+
+    /// Check the files every `interval` and reload them when they changed.
+    /// A failed reload is retried on the next check, so a renewal caught
+    /// half-written is picked up once complete.
+    pub fn spawn_watcher(self: Arc<Self>, interval: Duration) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if self.modified() == *self.loaded.lock().unwrap() {
+                    continue;
+                }
+                match self.reload() {
+                    Ok(()) => info!("Reloaded TLS certificate {}", self.cert_path),
+                    Err(e) => error!("Cannot reload TLS certificate {}, keeping the current one: {}", self.cert_path, e),
+                }
+            }
+        });
+    }
+}
+
+/*************************************************
+ * ClientStream
+ *************************************************/
+
+/// A client connection, over TLS when it came in on the TLS listener.
+pub enum ClientStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl ClientStream {
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            ClientStream::Plain(stream) => stream,
+            ClientStream::Tls(stream) => stream.get_ref().0,
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().local_addr()
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            ClientStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            ClientStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            ClientStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            ClientStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,209 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use io_uring::{opcode, squeue, types, IoUring};
+use std::collections::HashMap;
+use std::io;
+use std::ops::Range;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+/// Relay buffer per tunnel direction unless configured.
+pub const DEFAULT_BUFFER_SIZE: usize = 65536;
+/// Submission slots; more operations than this can be in flight.
+const SQ_ENTRIES: u32 = 1024;
+/// Completion slots, sized for tens of thousands of tunnels.
+const CQ_ENTRIES: u32 = 16384;
+/// `user_data` of the no-op that stops the driver.
+const STOP: u64 = u64::MAX;
+/// `user_data` of cancellations, whose completions nobody waits for.
+const CANCEL: u64 = u64::MAX - 1;
+
+/*************************************************
+ * Ring
+ *************************************************/
+
+/// An io_uring instance for accepting and relaying: tasks submit
+/// accepts, receives and sends, a driver thread reaps the completions and
+/// wakes the waiting tasks, so a busy proxy waits in one io_uring_enter()
+/// instead of a read, a would-block and an epoll_wait() per chunk. The
+/// driver stops once the last clone is dropped.
+#[derive(Clone)]
+pub struct Ring {
+    handle: Arc<Handle>,
+}
+
+/// Stops the driver when dropped.
+struct Handle {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    ring: IoUring,
+    /// Serializes pushes to the submission queue.
+    sq: Mutex<()>,
+    /// Operations in flight by id.
+    pending: Mutex<HashMap<u64, Pending>>,
+    next_id: AtomicU64,
+    stopping: AtomicBool,
+}
+
+/// An operation in flight: where its result goes, the buffer the kernel
+/// is using, and whether it completes with a new file descriptor.
+struct Pending {
+    completion: oneshot::Sender<(i32, Vec<u8>)>,
+    buf: Vec<u8>,
+    accept: bool,
+}
+
+impl Ring {
+    /// Set up the ring and start its driver thread.
+    pub fn new() -> io::Result<Self> {
+        let ring = IoUring::builder().setup_cqsize(CQ_ENTRIES).build(SQ_ENTRIES)?;
+        let shared = Arc::new(Shared {
+            ring,
+            sq: Mutex::new(()),
+            pending: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            stopping: AtomicBool::new(false),
+        });
+        let driver = shared.clone();
+        std::thread::Builder::new()
+            .name(String::from("rdnat-uring"))
+            .spawn(move || driver.drive())?;
+        Ok(Ring { handle: Arc::new(Handle { shared }) })
+    }
+
+    /// Accept a connection on `listener`.
+    pub async fn accept(&self, listener: &TcpListener) -> io::Result<TcpStream> {
+        let entry = opcode::Accept::new(types::Fd(listener.as_raw_fd()), std::ptr::null_mut(), std::ptr::null_mut())
+            .flags(libc::SOCK_CLOEXEC)
+            .build();
+        let (fd, _) = self.handle.shared.submit(entry, Vec::new(), true).await;
+        // The descriptor is a fresh socket that nothing else owns.
+        let stream = std::net::TcpStream::from(unsafe { OwnedFd::from_raw_fd(fd? as RawFd) });
+        stream.set_nonblocking(true)?;
+        TcpStream::from_std(stream)
+    }
+
+    /// Receive into `buf`; hands the buffer back with the byte count.
+    pub async fn recv(&self, fd: RawFd, mut buf: Vec<u8>) -> (io::Result<usize>, Vec<u8>) {
+        let entry = opcode::Recv::new(types::Fd(fd), buf.as_mut_ptr(), buf.len() as u32).build();
+        self.handle.shared.submit(entry, buf, false).await
+    }
+
+    /// Send `buf[range]`; hands the buffer back with the byte count.
+    pub async fn send(&self, fd: RawFd, buf: Vec<u8>, range: Range<usize>) -> (io::Result<usize>, Vec<u8>) {
+        let entry = opcode::Send::new(types::Fd(fd), buf[range.clone()].as_ptr(), range.len() as u32)
+            .flags(libc::MSG_NOSIGNAL)
+            .build();
+        self.handle.shared.submit(entry, buf, false).await
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.shared.stopping.store(true, Ordering::Release);
+        if let Err(e) = self.shared.push(&opcode::Nop::new().build().user_data(STOP)) {
+            log::debug!("Cannot wake the io_uring driver to stop: {}", e);
+        }
+    }
+}
+
+/// Cancels its operation if dropped before the operation completes, so
+/// an aborted accept doesn't keep the listener in use.
+struct InFlight<'a> {
+    shared: &'a Shared,
+    id: u64,
+    done: bool,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.shared.push(&opcode::AsyncCancel::new(self.id).build().user_data(CANCEL));
+        }
+    }
+}
+
+impl Shared {
+    async fn submit(&self, entry: squeue::Entry, buf: Vec<u8>, accept: bool) -> (io::Result<usize>, Vec<u8>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (completion, receiver) = oneshot::channel();
+        // The kernel writes to or reads from `buf` until the operation
+        // completes, so it stays here rather than with the caller, whose
+        // future may be dropped in the meantime.
+        self.pending.lock().unwrap().insert(id, Pending { completion, buf, accept });
+        if let Err(e) = self.push(&entry.user_data(id)) {
+            let pending = self.pending.lock().unwrap().remove(&id).unwrap();
+            return (Err(e), pending.buf);
+        }
+        let mut in_flight = InFlight { shared: self, id, done: false };
+        let result = receiver.await;
+        in_flight.done = true;
+        match result {
+            Ok((res, buf)) if res < 0 => (Err(io::Error::from_raw_os_error(-res)), buf),
+            Ok((res, buf)) => (Ok(res as usize), buf),
+            Err(_) => (Err(io::Error::other("io_uring driver stopped")), Vec::new()),
+        }
+    }
+
+    fn push(&self, entry: &squeue::Entry) -> io::Result<()> {
+        let sq = self.sq.lock().unwrap();
+        // Pushes are serialized by the lock, and the buffer an entry
+        // points to is held in `pending` until the entry completes.
+        let pushed = unsafe { self.ring.submission_shared().push(entry) };
+        drop(sq);
+        pushed.map_err(|_| io::Error::from(io::ErrorKind::WouldBlock))?;
+        // Once pushed the entry is the kernel's: if this submit fails, the
+        // driver's next one submits it.
+        if let Err(e) = self.ring.submit() {
+            log::debug!("io_uring submit deferred: {}", e);
+        }
+        Ok(())
+    }
+
+    /// Wait for completions and hand them to the waiting operations, until
+    /// the last `Ring` is dropped.
+    fn drive(&self) {
+        loop {
+            if let Err(e) = self.ring.submit_and_wait(1) {
+                if e.kind() != io::ErrorKind::Interrupted {
+                    log::error!("io_uring wait failed: {}", e);
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                }
+                continue;
+            }
+            let mut pending = self.pending.lock().unwrap();
+            // Only this thread reads completions.
+            for cqe in unsafe { self.ring.completion_shared() } {
+                let Some(op) = pending.remove(&cqe.user_data()) else {
+                    continue;
+                };
+                if let Err((res, _)) = op.completion.send((cqe.result(), op.buf)) {
+                    // Accepted after its task went away: nobody will
+                    // close the connection otherwise.
+                    if op.accept && res >= 0 {
+                        drop(unsafe { OwnedFd::from_raw_fd(res) });
+                    }
+                }
+            }
+            if self.stopping.load(Ordering::Acquire) {
+                // The kernel may still be using the buffers of operations
+                // that never completed, so they are leaked, not freed.
+                for (_, op) in pending.drain() {
+                    std::mem::forget(op.buf);
+                }
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,298 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request};
+use hyper_rustls::HttpsConnector;
+use std::collections::HashMap;
+use std::error::Error;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use tokio_rustls::rustls::ClientConfig;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::{error, info};
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+pub const DEFAULT_AUTH_FAILURES: u32 = 5;
+pub const DEFAULT_UPSTREAM_FAILURES: u32 = 3;
+
+const DEFAULT_TEMPLATE: &str = r#"{"event":"{{event}}","message":"{{message}}","time":{{time}}}"#;
+const FAILURE_WINDOW: Duration = Duration::from_secs(60);
+const EVENT_COOLDOWN: Duration = Duration::from_secs(300);
+/// Clients and targets tracked at most per map; past this, new ones are
+/// not counted until old ones expire.
+const MAX_TRACKED: usize = 4096;
+
+/*************************************************
+ * Webhooks
+ *************************************************/
+
+/// Posts a JSON payload rendered from `template` to every URL when an
+/// operational event crosses its threshold. Counts are kept per client and
+/// per target; once `MAX_TRACKED` of them pile up, those past the window or
+/// the cooldown they matter for are dropped.
+pub struct Webhooks {
+    urls: Vec<String>,
+    template: String,
+    auth_threshold: u32,
+    upstream_threshold: u32,
+    client: Client<HttpsConnector<HttpConnector>>,
+    /// When the first failure in the window was, and how many there were.
+    auth_failures: Mutex<HashMap<IpAddr, (Instant, u32)>>,
+    /// When the last failure was, and how many in a row.
+    upstream_failures: Mutex<HashMap<String, (Instant, u32)>>,
+    last_fired: Mutex<HashMap<String, Instant>>,
+}
+
+impl Webhooks {
+    /// `tls` verifies `https://` receivers, so `--tls-ca-bundle` applies.
+    pub fn new(
+        urls: Vec<String>,
+        template_path: Option<&str>,
+        auth_threshold: u32,
+        upstream_threshold: u32,
+        tls: ClientConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        let template = match template_path {
+            Some(path) => std::fs::read_to_string(path)?,
+            None => String::from(DEFAULT_TEMPLATE),
+        };
+        for url in &urls {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err(format!("Error: Unsupported webhook URL (only http:// and https:// are supported): {}", url).into());
+            }
+        }
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_tls_config(tls)
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Ok(Webhooks {
+            urls,
+            template,
+            auth_threshold: auth_threshold.max(1),
+            upstream_threshold: upstream_threshold.max(1),
+            client: Client::builder().build(connector),
+            auth_failures: Mutex::new(HashMap::new()),
+            upstream_failures: Mutex::new(HashMap::new()),
+            last_fired: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn auth_failed(&self, client: IpAddr) {
+        let count = {
+            let mut failures = self.auth_failures.lock().unwrap();
+            let now = Instant::now();
+            if !failures.contains_key(&client) && !make_room(&mut failures, |entry| entry.0, FAILURE_WINDOW, now) {
+                return;
+            }
+            let entry = failures.entry(client).or_insert((now, 0));
+            if now.duration_since(entry.0) > FAILURE_WINDOW {
+                *entry = (now, 0);
+            }
+            entry.1 += 1;
+            entry.1
+        };
+        if count >= self.auth_threshold {
+            self.fire(
+                "auth_failures",
+                &format!("auth:{}", client),
+                &format!("{} failed authentication attempts from {}", count, client),
+            );
+        }
+    }
+
+    pub fn upstream_failed(&self, target: &str) {
+        let count = {
+            let mut failures = self.upstream_failures.lock().unwrap();
+            let now = Instant::now();
+            // A target that last failed longer ago than the cooldown starts
+            // over, as if it had succeeded in between.
+            if !failures.contains_key(target) && !make_room(&mut failures, |entry| entry.0, EVENT_COOLDOWN, now) {
+                return;
+            }
+            let entry = failures.entry(target.to_string()).or_insert((now, 0));
+            if now.duration_since(entry.0) > EVENT_COOLDOWN {
+                entry.1 = 0;
+            }
+            *entry = (now, entry.1 + 1);
+            entry.1
+        };
+        if count >= self.upstream_threshold {
+            self.fire(
+                "upstream_outage",
+                &format!("upstream:{}", target),
+                &format!("{} consecutive connection failures to {}", count, target),
+            );
+        }
+    }
+
+    pub fn upstream_succeeded(&self, target: &str) {
+        self.upstream_failures.lock().unwrap().remove(target);
+    }
+
+    /// `user` has just used up the daily transfer quota of `quota` bytes.
+    pub fn quota_exhausted(&self, user: &str, quota: u64) {
+        self.fire(
+            "quota_exhausted",
+            &format!("quota:{}", user),
+            &format!("{} used up the daily transfer quota of {} bytes", user, quota),
+        );
+    }
+
+    /// A tunnel of `client` ended in an error (a reset or broken pipe)
+    /// rather than a close.
+    pub fn tunnel_dropped(&self, client: IpAddr, target: &str, error: &str) {
+        self.fire(
+            "tunnel_disconnect",
+            &format!("tunnel:{}", client),
+            &format!("Tunnel from {} to {} dropped: {}", client, target, error),
+        );
+    }
+
+    fn fire(&self, event: &str, key: &str, message: &str) {
+        {
+            let mut last_fired = self.last_fired.lock().unwrap();
+            let now = Instant::now();
+            if last_fired.get(key).is_some_and(|last| now.duration_since(*last) < EVENT_COOLDOWN) {
+                return;
+            }
+            if !last_fired.contains_key(key) && !make_room(&mut last_fired, |last| *last, EVENT_COOLDOWN, now) {
+                return;
+            }
+            last_fired.insert(key.to_string(), now);
+        }
+
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let payload = self.template
+            .replace("{{event}}", &escape_json(event))
+            .replace("{{message}}", &escape_json(message))
+            .replace("{{time}}", &time.to_string());
+        info!("Webhook event {}: {}", event, message);
+
+        for url in self.urls.clone() {
+            let payload = payload.clone();
+            let client = self.client.clone();
+            tokio::spawn(async move {
+                if let Err(e) = post(&client, &url, payload).await {
+                    error!("Webhook to {} failed: {}", url, e);
+                }
+            });
+        }
+    }
+}
+
+/// Whether `map` has room for another entry, after dropping those last
+/// `updated` more than `expiry` before `now` if it is full.
+fn make_room<K: Hash + Eq, V>(map: &mut HashMap<K, V>, updated: impl Fn(&V) -> Instant, expiry: Duration, now: Instant) -> bool {
+    if map.len() >= MAX_TRACKED {
+        map.retain(|_, value| now.duration_since(updated(value)) <= expiry);
+    }
+    map.len() < MAX_TRACKED
+}
+
+/*************************************************
+ * post
+ *************************************************/
+
+async fn post(client: &Client<HttpsConnector<HttpConnector>>, url: &str, payload: String) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header("Content-Type", "application/json")
+        .body(Body::from(payload))?;
+    let response = client.request(request).await?;
+    if !response.status().is_success() {
+        return Err(format!("unexpected status {}", response.status()).into());
+    }
+    Ok(())
+}
+
+pub fn escape_json(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use crate::tls::OriginTls;
+
+    async fn receiver() -> (TcpListener, Webhooks) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let mut tls = OriginTls::default();
+        tls.set_insecure();
+        let webhooks = Webhooks::new(vec![url], None, DEFAULT_AUTH_FAILURES, DEFAULT_UPSTREAM_FAILURES, tls.client_config(None).unwrap()).unwrap();
+        (listener, webhooks)
+    }
+
+    /// The payload of the next webhook POST.
+    async fn next_payload(listener: &TcpListener) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !request.ends_with(b"}") {
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "connection closed early");
+            request.extend_from_slice(&buf[..n]);
+        }
+        stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+        let request = String::from_utf8(request).unwrap();
+        request.split_once("\r\n\r\n").unwrap().1.to_string()
+    }
+
+    #[tokio::test]
+    async fn notifies_when_a_quota_is_used_up() {
+        let (listener, webhooks) = receiver().await;
+        webhooks.quota_exhausted("alice", 1000);
+        let payload = next_payload(&listener).await;
+        assert!(payload.starts_with(r#"{"event":"quota_exhausted","message":"alice used up the daily transfer quota of 1000 bytes""#), "{}", payload);
+    }
+
+    #[tokio::test]
+    async fn notifies_when_a_tunnel_is_dropped() {
+        let (listener, webhooks) = receiver().await;
+        let client = "192.0.2.7".parse().unwrap();
+        webhooks.tunnel_dropped(client, "example.com:443", "Connection reset by peer");
+        let payload = next_payload(&listener).await;
+        assert!(payload.contains(r#""event":"tunnel_disconnect""#), "{}", payload);
+        assert!(payload.contains("Tunnel from 192.0.2.7 to example.com:443 dropped: Connection reset by peer"), "{}", payload);
+
+        // Further drops of the same client wait out the cooldown.
+        webhooks.tunnel_dropped(client, "example.com:443", "Broken pipe");
+        assert!(tokio::time::timeout(Duration::from_millis(200), listener.accept()).await.is_err());
+    }
+
+    #[test]
+    fn make_room_drops_only_expired_entries() {
+        let now = Instant::now();
+        let old = now - Duration::from_secs(120);
+        let mut map: HashMap<usize, Instant> = (0..MAX_TRACKED).map(|i| (i, if i % 2 == 0 { old } else { now })).collect();
+        assert!(make_room(&mut map, |updated| *updated, FAILURE_WINDOW, now));
+        assert_eq!(map.len(), MAX_TRACKED / 2);
+        assert!(map.values().all(|updated| *updated == now));
+
+        let mut map: HashMap<usize, Instant> = (0..MAX_TRACKED).map(|i| (i, now)).collect();
+        assert!(!make_room(&mut map, |updated| *updated, FAILURE_WINDOW, now));
+        assert_eq!(map.len(), MAX_TRACKED);
+    }
+}
@@ -0,0 +1,156 @@
+/*************************************************
+ * Use
+ *************************************************/
+
+use hyper::body::HttpBody;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use std::error::Error;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use crate::request::ClientConn;
+use crate::rules::Rules;
+use crate::upstream::Attempts;
+use crate::Context;
+
+/*************************************************
+ * Predefine
+ *************************************************/
+
+/// Host the endpoint answers for; it never reaches the resolver.
+pub const HOST: &str = "rdnat.internal";
+pub const PATH: &str = "/whoami";
+/// Longest the IP echo service may take to answer.
+const ECHO_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest echo answer read; an address is a few dozen bytes.
+const MAX_ECHO: usize = 256;
+
+/*************************************************
+ * serve
+ *************************************************/
+
+/// Whether a request for `host` and `url` is meant for the endpoint.
+pub fn is_whoami(host: &str, url: &str) -> bool {
+    host.trim_end_matches('.').eq_ignore_ascii_case(HOST)
+        && url.split_once("://")
+            .and_then(|(_, rest)| rest.find('/').map(|slash| &rest[slash..]))
+            .is_some_and(|path| path.split('?').next() == Some(PATH))
+}
+
+/// Answer `GET http://rdnat.internal/whoami` with who the proxy takes the
+/// client for and how its traffic leaves: the authenticated user, the
+/// source address, the route (direct or the upstream proxy the user is
+/// given) and the egress address, as the `--whoami-echo` service sees it
+/// through that route or else as configured with `--outbound-bind`.
+/// Returns whether the connection stays open for another request.
+pub async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut ClientConn<S>,
+    method: &Method,
+    keep_alive: bool,
+    ctx: &Context,
+    user: &str,
+    rules: &Rules,
+    client: SocketAddr,
+) -> Result<bool, Box<dyn Error>> {
+    if method != Method::GET && method != Method::HEAD {
+        return reply(conn, method, false, StatusCode::METHOD_NOT_ALLOWED, &json!({ "error": "Only GET is supported" })).await;
+    }
+    let config = &ctx.config;
+    // The route the echo service is reached by, which is the one traffic
+    // to it takes; the default route without one.
+    let echo = config.whoami_echo.as_ref().map(|url| {
+        let port = url.port_u16().unwrap_or(if url.scheme_str() == Some("https") { 443 } else { 80 });
+        (url, url.host().unwrap_or_default(), port)
+    });
+    let pool = match echo {
+        Some((url, host, port)) => ctx.check_destination(rules, host, port, user, Some(&url.to_string())).await,
+        None => Ok(config.upstream.as_ref()),
+    };
+    let mut report = json!({
+        "user": user,
+        "client_ip": client.ip().to_string(),
+        "client_port": client.port(),
+        "source_addresses": config.outbound.binds().iter().map(IpAddr::to_string).collect::<Vec<_>>(),
+    });
+    match pool {
+        Ok(pool) => {
+            let attempts = Attempts::new(pool, config.retry, client.ip(), user);
+            let upstream = attempts.first();
+            report["route"] = match &upstream {
+                Some(upstream) => json!(format!("upstream {}", upstream.address)),
+                None => json!("direct"),
+            };
+            let egress = match echo {
+                Some((url, _, _)) => {
+                    let request = Request::get(url.clone()).body(Body::empty())?;
+                    let response = crate::forward_request(ctx, upstream.as_ref().map(|upstream| &upstream.upstream), request);
+                    tokio::time::timeout(ECHO_TIMEOUT, echo_address(response)).await
+                        .unwrap_or_else(|_| Err("Timed out".into()))
+                        .map(Some)
+                        .map_err(|e| format!("Cannot reach {}: {}", url, e))
+                }
+                // Without an echo service, only a single source address
+                // of a direct route is known to be the egress.
+                None => match (config.outbound.binds(), &upstream) {
+                    ([bind], None) => Ok(Some(*bind)),
+                    _ => Ok(None),
+                },
+            };
+            match egress {
+                Ok(egress) => report["egress_ip"] = json!(egress.map(|ip| ip.to_string())),
+                Err(detail) => {
+                    report["egress_ip"] = Value::Null;
+                    report["egress_error"] = json!(detail);
+                }
+            }
+        }
+        Err((_, detail)) => {
+            report["route"] = Value::Null;
+            report["egress_ip"] = Value::Null;
+            report["egress_error"] = json!(detail);
+        }
+    }
+    reply(conn, method, keep_alive, StatusCode::OK, &report).await
+}
+
+/// The address an IP echo service answered with, as plain text.
+async fn echo_address(
+    response: impl Future<Output = Result<Response<Body>, Box<dyn Error + Send + Sync>>>,
+) -> Result<IpAddr, Box<dyn Error + Send + Sync>> {
+    let mut response = response.await?;
+    if !response.status().is_success() {
+        return Err(format!("Answered with {}", response.status()).into());
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.body_mut().data().await {
+        body.extend_from_slice(&chunk?);
+        if body.len() > MAX_ECHO {
+            return Err("Answer too long for an IP address".into());
+        }
+    }
+    let text = String::from_utf8_lossy(&body).trim().to_string();
+    text.parse().map_err(|_| format!("Not an IP address: {}", text).into())
+}
+
+async fn reply<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut ClientConn<S>,
+    method: &Method,
+    keep_alive: bool,
+    status: StatusCode,
+    report: &Value,
+) -> Result<bool, Box<dyn Error>> {
+    let body = format!("{:#}\n", report);
+    let mut response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nCache-Control: no-store\r\n",
+        status,
+        body.len()
+    );
+    response.push_str(if keep_alive { "\r\n" } else { "Connection: close\r\n\r\n" });
+    if method != Method::HEAD {
+        response.push_str(&body);
+    }
+    conn.stream.write_all(response.as_bytes()).await?;
+    Ok(keep_alive)
+}